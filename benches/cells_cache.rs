@@ -0,0 +1,129 @@
+//! Compares concurrent `load_cell` throughput and the cache hit ratio
+//! for different numbers of cells cache shards, with and without
+//! skipping contended cache inserts.
+//!
+//! Run with `cargo bench --features bench --bench cells_cache`
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ton_indexer::bench::{CellStorage, CellStorageOptions, Db};
+use ton_types::UInt256;
+
+/// Cells cache configurations: `(shards per CPU, skip contended inserts)`.
+///
+/// `(4, false)` matches the previous behaviour (default `DashMap` shard amount)
+const CONFIGS: &[(usize, bool)] = &[(4, false), (4, true), (16, false), (16, true), (64, true)];
+
+/// Depth of the stored tree, each cell has 4 children
+const TREE_DEPTH: u32 = 7;
+
+fn make_tree(depth: u32, counter: &mut u32, hashes: &mut Vec<UInt256>) -> ton_types::Cell {
+    let mut builder = ton_types::BuilderData::new();
+    builder.append_u32(*counter).unwrap();
+    *counter += 1;
+    if depth > 0 {
+        for _ in 0..4 {
+            let child = make_tree(depth - 1, counter, hashes);
+            builder.checked_append_reference(child).unwrap();
+        }
+    }
+    let cell = builder.into_cell().unwrap();
+    hashes.push(cell.repr_hash());
+    cell
+}
+
+/// Loads all cells from every thread, starting at different offsets.
+/// Loaded cells are kept alive until all threads are finished,
+/// so that the weak cache entries stay valid
+fn load_all(cell_storage: &Arc<CellStorage>, hashes: &Arc<Vec<UInt256>>, threads: usize) {
+    let handles = (0..threads)
+        .map(|i| {
+            let cell_storage = cell_storage.clone();
+            let hashes = hashes.clone();
+            std::thread::spawn(move || {
+                let offset = i * hashes.len() / threads;
+                (0..hashes.len())
+                    .map(|j| {
+                        let hash = hashes[(offset + j) % hashes.len()];
+                        cell_storage.load_cell(hash).unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let loaded = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Vec<_>>();
+    drop(loaded);
+}
+
+fn new_cell_storage(db: &Arc<Db>, shards_per_cpu: usize, skip: bool) -> Result<Arc<CellStorage>> {
+    CellStorage::new(
+        db.clone(),
+        num_cpus::get() * shards_per_cpu,
+        CellStorageOptions {
+            skip_contended_cache_inserts: skip,
+            ..Default::default()
+        },
+    )
+}
+
+fn cells_cache(c: &mut Criterion) {
+    let path = std::env::temp_dir().join(format!("ton-indexer-bench-{}", std::process::id()));
+    let db = Db::open(path.clone(), 256 << 20, Default::default()).unwrap();
+
+    let mut hashes = Vec::new();
+    let root = make_tree(TREE_DEPTH, &mut 0, &mut hashes);
+    {
+        let cell_storage = new_cell_storage(&db, 1, true).unwrap();
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, root).unwrap();
+        db.raw().write(batch).unwrap();
+    }
+    let hashes = Arc::new(hashes);
+    let threads = num_cpus::get();
+
+    // Hit ratio of the cache after all threads loaded all cells
+    for &(shards_per_cpu, skip) in CONFIGS {
+        let cell_storage = new_cell_storage(&db, shards_per_cpu, skip).unwrap();
+        load_all(&cell_storage, &hashes, threads);
+        println!(
+            "shards per cpu: {shards_per_cpu}, skip contended: {skip}, hit ratio: {:.4}",
+            cell_storage.cache_hit_ratio().unwrap_or_default()
+        );
+    }
+
+    let mut group = c.benchmark_group("concurrent_load_cell");
+    group.throughput(Throughput::Elements((hashes.len() * threads) as u64));
+    for &(shards_per_cpu, skip) in CONFIGS {
+        group.bench_with_input(
+            BenchmarkId::new(format!("skip_contended={skip}"), shards_per_cpu),
+            &(shards_per_cpu, skip),
+            |b, &(shards_per_cpu, skip)| {
+                b.iter_custom(|iters| {
+                    let mut total = Duration::ZERO;
+                    for _ in 0..iters {
+                        // NOTE: each iteration starts with an empty cache
+                        let cell_storage = new_cell_storage(&db, shards_per_cpu, skip).unwrap();
+                        let started_at = Instant::now();
+                        load_all(&cell_storage, &hashes, threads);
+                        total += started_at.elapsed();
+                    }
+                    total
+                })
+            },
+        );
+    }
+    group.finish();
+
+    drop(db);
+    std::fs::remove_dir_all(path).ok();
+}
+
+criterion_group!(benches, cells_cache);
+criterion_main!(benches);
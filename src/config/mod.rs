@@ -7,6 +7,7 @@ use serde::{Deserialize, Serialize};
 use sysinfo::SystemExt;
 
 pub use self::node_keys::*;
+use crate::db::ArchivesColumnOptions;
 use crate::network::NeighboursOptions;
 
 mod node_keys;
@@ -22,13 +23,58 @@ pub struct NodeConfig {
 
     pub state_gc_options: Option<StateGcOptions>,
     pub blocks_gc_options: Option<BlocksGcOptions>,
+    /// Periodic GC of blocks, archives and states using the retention policy.
+    /// Disabled if `None`
+    pub storage_gc_options: Option<StorageGcOptions>,
     pub shard_state_cache_options: Option<ShardStateCacheOptions>,
 
     pub max_db_memory_usage: usize,
+    /// Max number of concurrent cell reads from the db on cache misses.
+    /// Unlimited if `None`
+    pub max_concurrent_cell_reads: Option<usize>,
+    /// Don't insert cells without references into the cells cache.
+    /// Reduces the cache size for workloads dominated by leaf cells. Default: false
+    pub skip_leaf_cells_cache: bool,
+    /// Append a checksum to each newly stored cell and verify it on load.
+    /// Cells stored without a checksum are still readable. Default: false
+    pub cell_checksums: bool,
+    /// Store reference lists of new cells deduplicated in a separate column.
+    /// Reduces the cells column size on reference-heavy states. Default: false
+    ///
+    /// NOTE: databases with compact cells can't be read by earlier versions
+    pub compact_cell_references: bool,
+    /// Store cells of the masterchain states in a separate column, so that
+    /// it can be compacted and tuned independently. Default: false
+    ///
+    /// NOTE: cells shared with the shard states are stored in both columns,
+    /// states stored before enabling remain in the shared column.
+    /// Must not be disabled after masterchain states were stored in the separate column,
+    /// the storage refuses to open in this case.
+    pub split_masterchain_cells: bool,
+    /// How loaded cells are cached. Default: `weak_unbounded`
+    pub cells_cache_strategy: CellCacheStrategy,
+    /// In-memory bloom filter over the stored cell hashes, which allows to skip
+    /// db reads of absent cells. Disabled if `None`
+    ///
+    /// NOTE: the filter is populated by scanning the cells columns in background
+    /// on startup, reads are not skipped until it is filled
+    pub cells_filter_options: Option<CellsFilterOptions>,
+    /// RocksDB tuning of the archives column
+    pub archives_column_options: ArchivesColumnOptions,
 
     pub archive_options: Option<ArchiveOptions>,
     pub sync_options: SyncOptions,
 
+    /// Drop broadcasts from peers which recently sent invalid ones. Disabled if `None`
+    pub broadcast_reputation_options: Option<BroadcastReputationOptions>,
+    /// Validate signatures of only a part of masterchain broadcasts under load.
+    /// Disabled if `None`
+    ///
+    /// WARNING: reduces the protection against invalid broadcasts. Block proofs of
+    /// the skipped broadcasts are still checked with signatures before apply,
+    /// shard broadcasts are always fully validated
+    pub broadcast_sampling_options: Option<BroadcastSamplingOptions>,
+
     pub adnl_options: adnl::NodeOptions,
     pub rldp_options: rldp::NodeOptions,
     pub dht_options: dht::NodeOptions,
@@ -45,10 +91,21 @@ impl Default for NodeConfig {
             file_db_path: "db/file".into(),
             state_gc_options: None,
             blocks_gc_options: None,
+            storage_gc_options: None,
             shard_state_cache_options: Some(Default::default()),
             archive_options: Some(Default::default()),
             max_db_memory_usage: default_max_db_memory_usage(),
+            max_concurrent_cell_reads: None,
+            skip_leaf_cells_cache: false,
+            cell_checksums: false,
+            compact_cell_references: false,
+            split_masterchain_cells: false,
+            cells_cache_strategy: Default::default(),
+            cells_filter_options: None,
+            archives_column_options: Default::default(),
             sync_options: Default::default(),
+            broadcast_reputation_options: None,
+            broadcast_sampling_options: None,
             adnl_options: Default::default(),
             rldp_options: Default::default(),
             dht_options: Default::default(),
@@ -62,10 +119,101 @@ impl Default for NodeConfig {
 #[serde(deny_unknown_fields)]
 pub struct ArchiveOptions {
     pub gc_interval: ArchivesGcInterval,
+    /// Verify one stored archive every `verify_interval_sec` seconds.
+    /// Disabled if `None`
+    #[serde(default)]
+    pub verify_interval_sec: Option<u64>,
+    /// Start a new archive once the current one exceeds this size in bytes.
+    /// Archives are split only by masterchain seqno if `None`
+    #[serde(default)]
+    pub max_archive_size: Option<u64>,
+    /// Archives GC always keeps at least this number of the newest archives.
+    /// Default: 0
+    #[serde(default)]
+    pub min_retained_archives: usize,
+    /// Archives GC also removes key block entries below the oldest retained archive.
+    /// Ignored for `utime` archive ids. Default: false
+    #[serde(default)]
+    pub remove_key_blocks: bool,
+    /// Keep the newest archive id in the db and use it to compute archive ids
+    /// of new blocks without scanning all archive ids.
+    /// Ignored for `utime` archive ids. Default: false
+    #[serde(default)]
+    pub persist_current_archive_id: bool,
+    /// Number of threads used to verify archives on startup.
+    /// Default: CPU count
+    #[serde(default)]
+    pub preload_threads: Option<usize>,
+    /// Record archive mutations and keep them for this number of seconds.
+    /// Disabled if `None`
+    #[serde(default)]
+    pub event_log_retention_sec: Option<u32>,
+    /// How blocks are grouped into archives
+    #[serde(default)]
+    pub id_strategy: ArchiveIdStrategy,
+    /// Max rate of blocks moving into archives in bytes per second.
+    /// Unlimited if `None`
+    #[serde(default)]
+    pub max_write_rate: Option<u64>,
+    /// Log blocks archiving which holds the handle data locks longer than this.
+    /// Disabled if `None`
+    #[serde(default)]
+    pub slow_lock_threshold_ms: Option<u64>,
+    /// Max number of blocks moved into archives concurrently. Other blocks
+    /// wait for their turn. Unlimited if `None`
+    #[serde(default)]
+    pub max_concurrent_moves: Option<usize>,
     #[cfg(feature = "archive-uploader")]
     pub uploader_options: Option<archive_uploader::ArchiveUploaderConfig>,
 }
 
+/// NOTE: strategies are not mixable within one database,
+/// the strategy must not be changed after the first archive was created
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, tag = "type", rename_all = "snake_case")]
+pub enum ArchiveIdStrategy {
+    /// Archive id is the masterchain seqno of its first block.
+    /// New archive starts on each key block or after `ARCHIVE_SLICE_SIZE` blocks
+    Seqno,
+    /// Archive id is the start of the block utime bucket.
+    ///
+    /// NOTE: archives are not served to peers and are not uploaded in this mode,
+    /// `max_archive_size` is ignored
+    Utime {
+        /// Default: 3600
+        #[serde(default = "default_archive_bucket_sec")]
+        bucket_sec: u32,
+    },
+}
+
+impl Default for ArchiveIdStrategy {
+    fn default() -> Self {
+        Self::Seqno
+    }
+}
+
+fn default_archive_bucket_sec() -> u32 {
+    3600
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, tag = "type", rename_all = "snake_case")]
+pub enum CellCacheStrategy {
+    /// Cells are cached only while they are referenced elsewhere
+    WeakUnbounded,
+    /// At most `capacity` recently used cells are kept in memory
+    /// even without external references.
+    ///
+    /// NOTE: capacity is split evenly between the cache shards
+    StrongLru { capacity: usize },
+}
+
+impl Default for CellCacheStrategy {
+    fn default() -> Self {
+        Self::WeakUnbounded
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, tag = "type", rename_all = "snake_case")]
 pub enum ArchivesGcInterval {
@@ -97,6 +245,47 @@ pub struct SyncOptions {
     pub max_block_applier_depth: u32,
     /// Ignore archives. Default: false.
     pub force_use_get_next_block: bool,
+    /// Skip checking next masterchain blocks against the previous masterchain state
+    /// and rely only on the block proof pre-check. Default: false.
+    ///
+    /// NOTE: Block proof signatures are not verified against the validator set in this mode,
+    /// so it must only be enabled when blocks come from a trusted source.
+    pub trust_mode: bool,
+    /// Time after which a stuck shard blocks loading task no longer blocks
+    /// the next masterchain block. Disabled if `None`. Default: `None`
+    pub shard_blocks_timeout_sec: Option<u64>,
+    /// Whether to abort the stuck shard blocks loading task instead of
+    /// letting it finish in the background. Default: false
+    pub abort_stuck_shard_blocks: bool,
+    /// Stop retrying a shard block after this number of failed attempts
+    /// and report it via `Engine::failed_shard_blocks`. Reported blocks
+    /// are retried again with the next masterchain blocks.
+    /// Retried forever if `None`. Default: `None`
+    pub max_shard_block_attempts: Option<u32>,
+    /// Remove shard block data right after the block is applied, keeping only
+    /// its handle and proof. Default: false
+    ///
+    /// NOTE: data of the applied shard blocks can't be loaded in this mode
+    /// (unless archives are enabled), so it should only be used when
+    /// subscribers need just the state transitions.
+    pub drop_shard_blocks_data: bool,
+    /// Download and verify masterchain blocks without applying them. Default: false
+    ///
+    /// NOTE: shard blocks are not processed in this mode, verification results
+    /// are reported via logs and engine metrics. Only key block proofs and
+    /// the last audited block id are stored.
+    pub audit_mode: bool,
+    /// Download shard blocks of up to this number of masterchain blocks ahead,
+    /// while shard blocks are still applied in order. Disabled if `None`. Default: `None`
+    pub shard_download_queue_len: Option<usize>,
+    /// Check that the state of each applied masterchain block matches
+    /// the new state hash from its state update. Default: false
+    ///
+    /// NOTE: requires an additional state load for each masterchain block
+    pub verify_applied_states: bool,
+    /// Check that the next masterchain block from a broadcast references
+    /// the last applied block as its previous block. Default: false
+    pub verify_broadcast_prev_block: bool,
 }
 
 impl Default for SyncOptions {
@@ -107,6 +296,15 @@ impl Default for SyncOptions {
             save_to_disk_threshold: 1024 * 1024 * 1024,
             max_block_applier_depth: 32,
             force_use_get_next_block: false,
+            trust_mode: false,
+            shard_blocks_timeout_sec: None,
+            abort_stuck_shard_blocks: false,
+            max_shard_block_attempts: None,
+            drop_shard_blocks_data: false,
+            audit_mode: false,
+            shard_download_queue_len: None,
+            verify_applied_states: false,
+            verify_broadcast_prev_block: false,
         }
     }
 }
@@ -142,6 +340,30 @@ impl Default for StateGcOptions {
     }
 }
 
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageGcOptions {
+    pub policy: RetentionPolicyConfig,
+    /// Default: 900
+    #[serde(default = "default_storage_gc_interval_sec")]
+    pub interval_sec: u64,
+}
+
+fn default_storage_gc_interval_sec() -> u64 {
+    900
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, tag = "type", rename_all = "snake_case")]
+pub enum RetentionPolicyConfig {
+    /// Keep the specified number of the latest masterchain blocks
+    SeqnoWindow { mc_blocks: u32 },
+    /// Keep blocks since the latest key block older than `retention_sec`
+    TimeWindow { retention_sec: u32 },
+    /// Keep the newest archives which fit into `max_archives_bytes`
+    DiskBudget { max_archives_bytes: u64 },
+}
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct BlocksGcOptions {
@@ -156,6 +378,21 @@ pub struct BlocksGcOptions {
 
     /// Max `WriteBatch` entries before apply
     pub max_blocks_per_batch: Option<usize>,
+
+    /// Min age of proof entries before they can be removed, even if
+    /// block data was already removed. Default: `None`
+    pub proofs_retention_sec: Option<u32>,
+
+    /// Never remove proof entries, only block data. Default: false
+    pub keep_proofs: bool,
+
+    /// Compact package entries in background after enough entries were removed.
+    /// Disabled if `None`. Default: `None`
+    pub compaction: Option<PackageEntriesCompactionOptions>,
+
+    /// Read-ahead size in bytes for the package entries scan. Scanned entries
+    /// are not added to the block cache if specified. Default: `None`
+    pub scan_readahead_size: Option<usize>,
 }
 
 impl Default for BlocksGcOptions {
@@ -164,6 +401,29 @@ impl Default for BlocksGcOptions {
             kind: BlocksGcKind::BeforePreviousPersistentState,
             enable_for_sync: true,
             max_blocks_per_batch: Some(100_000),
+            proofs_retention_sec: None,
+            keep_proofs: false,
+            compaction: None,
+            scan_readahead_size: None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PackageEntriesCompactionOptions {
+    /// Number of package entries removed by blocks GC since the last
+    /// compaction which triggers the next one. Default: 1000000
+    pub tombstones_threshold: u64,
+    /// Min interval between compactions. Default: 3600
+    pub min_interval_sec: u64,
+}
+
+impl Default for PackageEntriesCompactionOptions {
+    fn default() -> Self {
+        Self {
+            tombstones_threshold: 1_000_000,
+            min_interval_sec: 3600,
         }
     }
 }
@@ -175,16 +435,101 @@ pub enum BlocksGcKind {
     BeforePreviousPersistentState,
 }
 
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BroadcastReputationOptions {
+    /// Number of recent validation failures after which broadcasts
+    /// from the peer are dropped. Default: `10`
+    pub max_failures: u32,
+    /// Interval after which one failure is forgotten. Default: `60`
+    pub decay_interval_sec: u64,
+}
+
+impl Default for BroadcastReputationOptions {
+    fn default() -> Self {
+        Self {
+            max_failures: 10,
+            decay_interval_sec: 60,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BroadcastSamplingOptions {
+    /// Number of broadcasts per second after which signatures
+    /// are validated only for the sampled ones. Default: `50`
+    pub min_broadcasts_per_sec: u32,
+    /// Part of broadcasts above the rate which are fully validated. Default: `0.1`
+    pub sample_ratio: f64,
+}
+
+impl Default for BroadcastSamplingOptions {
+    fn default() -> Self {
+        Self {
+            min_broadcasts_per_sec: 50,
+            sample_ratio: 0.1,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct ShardStateCacheOptions {
     /// LRU cache item duration. Default: `120`
     pub ttl_sec: u64,
+    /// Share cached states with equal root cells between block ids. Default: `false`
+    pub dedup_by_root: bool,
+    /// Max number of cached states. Unlimited if `None`. Default: `None`
+    pub capacity: Option<usize>,
+    /// How new states are inserted into the full cache. Default: `always_insert`
+    pub insert_policy: ShardStateCacheInsertPolicy,
 }
 
 impl Default for ShardStateCacheOptions {
     fn default() -> Self {
-        Self { ttl_sec: 120 }
+        Self {
+            ttl_sec: 120,
+            dedup_by_root: false,
+            capacity: None,
+            insert_policy: ShardStateCacheInsertPolicy::AlwaysInsert,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardStateCacheInsertPolicy {
+    /// The least recently used state is evicted
+    AlwaysInsert,
+    /// New states are not inserted (and not even built)
+    InsertIfRoom,
+    /// The least recently used state is evicted only if it was not accessed
+    /// since the new state was requested (e.g. while it was being built)
+    InsertIfHotterThanTail,
+}
+
+impl Default for ShardStateCacheInsertPolicy {
+    fn default() -> Self {
+        Self::AlwaysInsert
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CellsFilterOptions {
+    /// Expected number of stored cells. Default: `100000000`
+    pub capacity: usize,
+    /// Target false positive rate at the expected number of cells. Default: `0.01`
+    pub false_positive_rate: f64,
+}
+
+impl Default for CellsFilterOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 100_000_000,
+            false_positive_rate: 0.01,
+        }
     }
 }
 
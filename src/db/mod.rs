@@ -3,6 +3,7 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
 
 pub mod refcount;
 pub mod tables;
@@ -11,11 +12,16 @@ mod migrations;
 
 pub struct Db {
     pub archives: Table<tables::Archives>,
+    pub archive_events: Table<tables::ArchiveEvents>,
+    pub archive_block_index: Table<tables::ArchiveBlockIndex>,
+    pub archive_block_hashes: Table<tables::ArchiveBlockHashes>,
     pub block_handles: Table<tables::BlockHandles>,
     pub key_blocks: Table<tables::KeyBlocks>,
     pub package_entries: Table<tables::PackageEntries>,
     pub shard_states: Table<tables::ShardStates>,
     pub cells: Table<tables::Cells>,
+    pub mc_cells: Table<tables::McCells>,
+    pub cell_ref_lists: Table<tables::CellRefLists>,
     pub node_states: Table<tables::NodeStates>,
     pub prev1: Table<tables::Prev1>,
     pub prev2: Table<tables::Prev2>,
@@ -27,7 +33,11 @@ pub struct Db {
 }
 
 impl Db {
-    pub fn open(path: PathBuf, mem_limit: usize) -> Result<Arc<Self>> {
+    pub fn open(
+        path: PathBuf,
+        mem_limit: usize,
+        archives_options: ArchivesColumnOptions,
+    ) -> Result<Arc<Self>> {
         let limit = match fdlimit::raise_fd_limit() {
             // New fd limit
             Some(limit) => limit,
@@ -69,11 +79,16 @@ impl Db {
                 // opts.enable_statistics();
                 // opts.set_stats_dump_period_sec(30);
             })
-            .column::<tables::Archives>()
+            .column_with::<tables::Archives, _>(|opts, caches| archives_options.apply(opts, caches))
+            .column::<tables::ArchiveEvents>()
+            .column::<tables::ArchiveBlockIndex>()
+            .column::<tables::ArchiveBlockHashes>()
             .column::<tables::BlockHandles>()
             .column::<tables::KeyBlocks>()
             .column::<tables::ShardStates>()
             .column::<tables::Cells>()
+            .column::<tables::McCells>()
+            .column::<tables::CellRefLists>()
             .column::<tables::NodeStates>()
             .column::<tables::Prev1>()
             .column::<tables::Prev2>()
@@ -89,11 +104,16 @@ impl Db {
 
         Ok(Arc::new(Self {
             archives: tables.get(),
+            archive_events: tables.get(),
+            archive_block_index: tables.get(),
+            archive_block_hashes: tables.get(),
             block_handles: tables.get(),
             key_blocks: tables.get(),
             package_entries: tables.get(),
             shard_states: tables.get(),
             cells: tables.get(),
+            mc_cells: tables.get(),
+            cell_ref_lists: tables.get(),
             node_states: tables.get(),
             prev1: tables.get(),
             prev2: tables.get(),
@@ -148,6 +168,8 @@ impl Db {
             (self.archives.cf(), "archives"),
             (self.shard_states.cf(), "shard states"),
             (self.cells.cf(), "cells"),
+            (self.mc_cells.cf(), "masterchain cells"),
+            (self.cell_ref_lists.cf(), "cell reference lists"),
         ];
 
         for (cf, title) in tables {
@@ -164,6 +186,21 @@ impl Db {
             );
         }
     }
+
+    /// Compacts only the package entries column
+    pub async fn compact_package_entries(&self) {
+        let _compaction_guard = self.compaction_lock.write().await;
+
+        let instant = std::time::Instant::now();
+        let bound = Option::<[u8; 0]>::None;
+        self.raw()
+            .compact_range_cf(&self.package_entries.cf(), bound, bound);
+
+        tracing::info!(
+            elapsed_ms = instant.elapsed().as_millis(),
+            "package entries compaction finished"
+        );
+    }
 }
 
 impl Drop for Db {
@@ -180,6 +217,56 @@ pub struct RocksdbStats {
     pub compressed_block_cache_pined_usage: usize,
 }
 
+/// Tuning of the archives column. Unset values keep the defaults
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchivesColumnOptions {
+    /// Size of the data block in bytes. Default: 4096
+    pub block_size: Option<usize>,
+    /// Target size of the SST file on the first level in bytes. Default: 67108864 (64 MB)
+    pub target_file_size_base: Option<u64>,
+    /// Default: zstd
+    pub compression: Option<ColumnCompression>,
+}
+
+impl ArchivesColumnOptions {
+    fn apply(&self, opts: &mut rocksdb::Options, caches: &Caches) {
+        if let Some(block_size) = self.block_size {
+            let mut block_factory = rocksdb::BlockBasedOptions::default();
+            block_factory.set_block_cache(&caches.block_cache);
+            block_factory.set_block_cache_compressed(&caches.compressed_block_cache);
+            block_factory.set_block_size(block_size);
+            opts.set_block_based_table_factory(&block_factory);
+        }
+
+        if let Some(target_file_size_base) = self.target_file_size_base {
+            opts.set_target_file_size_base(target_file_size_base);
+        }
+
+        if let Some(compression) = self.compression {
+            opts.set_compression_type(compression.into());
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnCompression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl From<ColumnCompression> for rocksdb::DBCompressionType {
+    fn from(value: ColumnCompression) -> Self {
+        match value {
+            ColumnCompression::None => Self::None,
+            ColumnCompression::Lz4 => Self::Lz4,
+            ColumnCompression::Zstd => Self::Zstd,
+        }
+    }
+}
+
 struct Builder<'a> {
     path: PathBuf,
     options: rocksdb::Options,
@@ -216,6 +303,20 @@ impl<'a> Builder<'a> {
         self
     }
 
+    /// Same as [`Builder::column`] but with additional tuning applied after the defaults
+    pub fn column_with<T, F>(mut self, f: F) -> Self
+    where
+        T: ColumnFamily,
+        F: FnOnce(&mut rocksdb::Options, &Caches),
+    {
+        let mut opts = Default::default();
+        T::options(&mut opts, self.caches);
+        f(&mut opts, self.caches);
+        self.descriptors
+            .push(rocksdb::ColumnFamilyDescriptor::new(T::NAME, opts));
+        self
+    }
+
     pub fn build(self) -> Result<Arc<rocksdb::DB>, rocksdb::Error> {
         Ok(Arc::new(rocksdb::DB::open_cf_descriptors(
             &self.options,
@@ -449,6 +550,49 @@ where
     }
 }
 
+/// Calls `f` for `sample` items at random positions if specified,
+/// otherwise for all items of the iterator
+pub fn for_each_sampled_item<F>(
+    iter: &mut rocksdb::DBRawIterator<'_>,
+    sample: Option<usize>,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(&[u8], &[u8]) -> Result<()>,
+{
+    use rand::Rng;
+
+    match sample {
+        Some(sample) => {
+            let mut rng = rand::thread_rng();
+            let mut key = [0u8; 32];
+            for _ in 0..sample {
+                // Seek to the random position to reduce the bias of the sequential scan
+                rng.fill(&mut key);
+                iter.seek(key);
+                if !iter.valid() {
+                    iter.seek_to_first();
+                }
+
+                match iter.item() {
+                    Some((key, value)) => f(key, value)?,
+                    None => break,
+                }
+            }
+        }
+        None => {
+            iter.seek_to_first();
+            while let Some((key, value)) = iter.item() {
+                f(key, value)?;
+                iter.next();
+            }
+        }
+    }
+    iter.status()?;
+
+    Ok(())
+}
+
 #[derive(Copy, Clone)]
 pub struct BoundedCfHandle<'a> {
     inner: *mut librocksdb_sys::rocksdb_column_family_handle_t,
@@ -29,11 +29,19 @@ pub fn merge_operator(
     })
 }
 
+/// Drops entries with zero refcount (empty values written by the merge operator
+/// or explicitly encoded zero counters).
+///
+/// NOTE: values with negative refcount are kept to preserve counters
+/// for the pending positive merges. Truncated values are kept as is,
+/// so that they could still be inspected
 pub fn compaction_filter(_level: u32, _key: &[u8], value: &[u8]) -> Decision {
     if value.is_empty() {
-        Decision::Remove
-    } else {
-        Decision::Keep
+        return Decision::Remove;
+    }
+    match value.get(..RC_BYTES) {
+        Some(rc) if RcType::from_le_bytes(rc.try_into().unwrap()) == 0 => Decision::Remove,
+        _ => Decision::Keep,
     }
 }
 
@@ -69,6 +77,11 @@ pub fn add_positive_refount(rc: u32, data: &[u8], target: &mut Vec<u8>) {
     target.extend_from_slice(data);
 }
 
+pub fn encode_value_with_rc(rc: RcType, data: &[u8], target: &mut Vec<u8>) {
+    target.extend_from_slice(&rc.to_le_bytes());
+    target.extend_from_slice(data);
+}
+
 pub fn encode_positive_refcount(rc: u32) -> [u8; RC_BYTES] {
     RcType::from(rc).to_le_bytes()
 }
@@ -80,3 +93,37 @@ pub fn encode_negative_refcount(rc: u32) -> [u8; RC_BYTES] {
 type RcType = i64;
 
 const RC_BYTES: usize = std::mem::size_of::<RcType>();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compaction_filter_agrees_with_decoding() {
+        let mut value = Vec::new();
+        add_positive_refount(2, &[1, 2, 3], &mut value);
+        assert!(matches!(compaction_filter(0, &[], &value), Decision::Keep));
+        assert_eq!(strip_refcount(&value), Some([1, 2, 3].as_slice()));
+
+        let negative = encode_negative_refcount(1);
+        assert!(matches!(
+            compaction_filter(0, &[], &negative),
+            Decision::Keep
+        ));
+        assert_eq!(strip_refcount(&negative), None);
+
+        let zero = 0i64.to_le_bytes();
+        assert!(matches!(compaction_filter(0, &[], &zero), Decision::Remove));
+        assert_eq!(strip_refcount(&zero), None);
+
+        assert!(matches!(compaction_filter(0, &[], &[]), Decision::Remove));
+
+        // Truncated values are not dropped silently
+        assert!(matches!(compaction_filter(0, &[], &[1, 2]), Decision::Keep));
+        assert!(matches!(
+            compaction_filter(0, &[], &[0; RC_BYTES - 1]),
+            Decision::Keep
+        ));
+        assert_eq!(strip_refcount(&[1, 2]), None);
+    }
+}
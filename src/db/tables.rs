@@ -19,6 +19,44 @@ impl ColumnFamily for Archives {
     }
 }
 
+/// Stores archive mutation events (see `ArchiveEventLog`)
+/// - Key: `u32 (BE)` (unix time), `u32 (BE)` (sequence number)
+/// - Value: `ArchiveEventKind`
+pub struct ArchiveEvents;
+impl ColumnFamily for ArchiveEvents {
+    const NAME: &'static str = "archive_events";
+
+    fn options(opts: &mut Options, caches: &Caches) {
+        default_block_based_table_factory(opts, caches);
+    }
+}
+
+/// Maps block root hash to its location in archives
+/// - Key: `ton_types::UInt256`
+/// - Value: `u32 (BE)` (archive id), optional `u64 (LE)` (offset), `u64 (LE)` (length)
+pub struct ArchiveBlockIndex;
+impl ColumnFamily for ArchiveBlockIndex {
+    const NAME: &'static str = "archive_block_index";
+
+    fn options(opts: &mut Options, caches: &Caches) {
+        default_block_based_table_factory(opts, caches);
+
+        opts.optimize_for_point_lookup(10);
+    }
+}
+
+/// Lists root hashes of the indexed blocks of each archive (see [`ArchiveBlockIndex`])
+/// - Key: `u32 (BE)` (archive id), `ton_types::UInt256` (root hash)
+/// - Value: empty
+pub struct ArchiveBlockHashes;
+impl ColumnFamily for ArchiveBlockHashes {
+    const NAME: &'static str = "archive_block_hashes";
+
+    fn options(opts: &mut Options, caches: &Caches) {
+        default_block_based_table_factory(opts, caches);
+    }
+}
+
 /// Maps block root hash to block meta
 /// - Key: `ton_types::UInt256`
 /// - Value: `BlockMeta`
@@ -114,6 +152,38 @@ impl ColumnFamily for Cells {
     }
 }
 
+/// Stores cells data of the masterchain states if cell columns are split
+/// - Key: `ton_types::UInt256` (cell repr hash)
+/// - Value: `StorageCell`
+pub struct McCells;
+impl ColumnFamily for McCells {
+    const NAME: &'static str = "mc_cells";
+
+    fn options(opts: &mut Options, caches: &Caches) {
+        Cells::options(opts, caches);
+    }
+
+    fn read_options(opts: &mut ReadOptions) {
+        Cells::read_options(opts);
+    }
+}
+
+/// Stores deduplicated reference lists of compact cells
+/// - Key: `u64 (BE)` (reference list id)
+/// - Value: `[ton_types::UInt256]` (child cell hashes)
+pub struct CellRefLists;
+impl ColumnFamily for CellRefLists {
+    const NAME: &'static str = "cell_ref_lists";
+
+    fn options(opts: &mut Options, caches: &Caches) {
+        opts.set_merge_operator_associative("cell_ref_list_merge", refcount::merge_operator);
+        opts.set_compaction_filter("cell_ref_list_compaction", refcount::compaction_filter);
+
+        default_block_based_table_factory(opts, caches);
+        opts.set_optimize_filters_for_hits(true);
+    }
+}
+
 /// Stores generic node parameters
 /// - Key: `...`
 /// - Value: `...`
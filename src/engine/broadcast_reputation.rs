@@ -0,0 +1,120 @@
+use std::time::{Duration, Instant};
+
+use everscale_network::adnl;
+use parking_lot::Mutex;
+
+use crate::config::BroadcastReputationOptions;
+use crate::utils::FastDashMap;
+
+/// Tracks recent broadcast validation failures for each source peer
+pub struct BroadcastReputation {
+    max_failures: u32,
+    decay_interval: Duration,
+    peers: FastDashMap<adnl::NodeIdShort, PeerReputation>,
+    last_pruned_at: Mutex<Instant>,
+}
+
+impl BroadcastReputation {
+    pub fn new(options: BroadcastReputationOptions) -> Self {
+        Self {
+            max_failures: options.max_failures,
+            decay_interval: Duration::from_secs(options.decay_interval_sec.max(1)),
+            peers: Default::default(),
+            last_pruned_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Returns `true` if broadcasts from this peer should be dropped without validation
+    pub fn is_banned(&self, peer_id: &adnl::NodeIdShort) -> bool {
+        let mut entry = match self.peers.get_mut(peer_id) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        let failures = entry.decay(self.decay_interval);
+        if failures == 0 {
+            drop(entry);
+            self.peers
+                .remove_if(peer_id, |_, entry| entry.failures == 0);
+            return false;
+        }
+
+        failures >= self.max_failures
+    }
+
+    /// Registers a broadcast which failed validation
+    pub fn report_failure(&self, peer_id: &adnl::NodeIdShort) {
+        let mut entry = self
+            .peers
+            .entry(*peer_id)
+            .or_insert_with(|| PeerReputation {
+                failures: 0,
+                updated_at: Instant::now(),
+            });
+        entry.decay(self.decay_interval);
+        entry.failures = entry.failures.saturating_add(1);
+        drop(entry);
+
+        self.prune_stale();
+    }
+
+    /// Removes peers whose failures have fully decayed, at most once per decay interval.
+    /// Peers which stop sending broadcasts are never checked again otherwise
+    fn prune_stale(&self) {
+        {
+            let mut last_pruned_at = self.last_pruned_at.lock();
+            if last_pruned_at.elapsed() < self.decay_interval {
+                return;
+            }
+            *last_pruned_at = Instant::now();
+        }
+
+        self.peers
+            .retain(|_, entry| entry.decay(self.decay_interval) > 0);
+    }
+}
+
+struct PeerReputation {
+    failures: u32,
+    updated_at: Instant,
+}
+
+impl PeerReputation {
+    /// Forgets one failure for each elapsed decay interval
+    fn decay(&mut self, decay_interval: Duration) -> u32 {
+        let elapsed = self.updated_at.elapsed();
+        let intervals = (elapsed.as_millis() / decay_interval.as_millis()) as u32;
+        if intervals > 0 {
+            self.failures = self.failures.saturating_sub(intervals);
+            self.updated_at += decay_interval * intervals;
+        }
+        self.failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_peers_are_pruned() {
+        let reputation = BroadcastReputation::new(BroadcastReputationOptions {
+            max_failures: 10,
+            decay_interval_sec: 1,
+        });
+
+        let stale_peer = adnl::NodeIdShort::new([1; 32]);
+        reputation.report_failure(&stale_peer);
+        assert_eq!(reputation.peers.len(), 1);
+
+        // Pretend that the stale peer failed long ago
+        reputation.peers.get_mut(&stale_peer).unwrap().updated_at -= Duration::from_secs(5);
+        *reputation.last_pruned_at.lock() -= Duration::from_secs(5);
+
+        let active_peer = adnl::NodeIdShort::new([2; 32]);
+        reputation.report_failure(&active_peer);
+
+        assert_eq!(reputation.peers.len(), 1);
+        assert!(reputation.peers.contains_key(&active_peer));
+    }
+}
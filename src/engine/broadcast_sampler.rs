@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::config::BroadcastSamplingOptions;
+
+/// Selects masterchain broadcasts whose signatures are validated under load
+pub struct BroadcastSampler {
+    min_broadcasts_per_sec: u32,
+    sample_ratio: f64,
+    current_sec: AtomicU32,
+    current_count: AtomicU32,
+    skipped: AtomicU64,
+}
+
+impl BroadcastSampler {
+    pub fn new(options: BroadcastSamplingOptions) -> Self {
+        Self {
+            min_broadcasts_per_sec: options.min_broadcasts_per_sec,
+            sample_ratio: options.sample_ratio.clamp(0.0, 1.0),
+            current_sec: AtomicU32::new(0),
+            current_count: AtomicU32::new(0),
+            skipped: AtomicU64::new(0),
+        }
+    }
+
+    /// Registers a broadcast and returns whether its signatures must be validated
+    pub fn should_validate(&self) -> bool {
+        self.should_validate_at(broxus_util::now(), rand::random())
+    }
+
+    /// Total number of broadcasts whose signatures were not validated
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    fn should_validate_at(&self, now: u32, sample: f64) -> bool {
+        let sec = self.current_sec.load(Ordering::Acquire);
+        if sec != now
+            && self
+                .current_sec
+                .compare_exchange(sec, now, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            // NOTE: concurrent broadcasts between the exchange and the reset
+            // could be lost, which is fine for the approximate rate
+            self.current_count.store(0, Ordering::Release);
+        }
+
+        let count = self.current_count.fetch_add(1, Ordering::Relaxed);
+        if count < self.min_broadcasts_per_sec || sample < self.sample_ratio {
+            return true;
+        }
+
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_only_above_rate() {
+        let sampler = BroadcastSampler::new(BroadcastSamplingOptions {
+            min_broadcasts_per_sec: 2,
+            sample_ratio: 0.5,
+        });
+
+        // Below the rate everything is validated
+        assert!(sampler.should_validate_at(100, 0.9));
+        assert!(sampler.should_validate_at(100, 0.9));
+
+        // Above the rate only the sampled part is validated
+        assert!(!sampler.should_validate_at(100, 0.9));
+        assert!(sampler.should_validate_at(100, 0.1));
+        assert_eq!(sampler.skipped(), 1);
+
+        // Rate is reset each second
+        assert!(sampler.should_validate_at(101, 0.9));
+    }
+}
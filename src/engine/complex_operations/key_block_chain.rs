@@ -0,0 +1,61 @@
+use anyhow::Result;
+
+use crate::engine::Engine;
+use crate::storage::KeyBlocksDirection;
+use crate::utils::*;
+
+/// Collects proofs of all key blocks in the specified seqno range into
+/// a single archive package, which can be used to bootstrap trust
+/// without syncing the whole masterchain.
+///
+/// NOTE: zerostate has no proof, so it is always skipped
+pub async fn export_key_block_chain(
+    engine: &Engine,
+    from_seqno: u32,
+    to_seqno: u32,
+) -> Result<Vec<u8>> {
+    if from_seqno > to_seqno {
+        return Err(KeyBlockChainError::InvalidRange.into());
+    }
+
+    let block_handle_storage = engine.storage.block_handle_storage();
+    let block_storage = engine.storage.block_storage();
+
+    // Collect key block seqnos first to release the iterator before loading proofs
+    let mut key_blocks = Vec::new();
+    for block_id in
+        block_handle_storage.key_blocks_iterator(KeyBlocksDirection::ForwardFrom(from_seqno))
+    {
+        let block_id = block_id?;
+        if block_id.seq_no > to_seqno {
+            break;
+        }
+        if block_id.seq_no != 0 {
+            key_blocks.push(block_id.seq_no);
+        }
+    }
+
+    let mut bundle = ARCHIVE_PREFIX.to_vec();
+    for seq_no in key_blocks {
+        let handle = block_handle_storage.load_key_block_handle(seq_no)?;
+        if !handle.meta().has_proof() {
+            return Err(KeyBlockChainError::KeyBlockProofNotFound(seq_no).into());
+        }
+
+        let proof = block_storage.load_block_proof_raw(&handle, false).await?;
+        bundle.extend_from_slice(&make_archive_segment(
+            &PackageEntryId::Proof(handle.id()).filename(),
+            &proof,
+        ));
+    }
+
+    Ok(bundle)
+}
+
+#[derive(Debug, thiserror::Error)]
+enum KeyBlockChainError {
+    #[error("Invalid key blocks range")]
+    InvalidRange,
+    #[error("Key block proof not found: {0}")]
+    KeyBlockProofNotFound(u32),
+}
@@ -1,11 +1,13 @@
 pub use self::apply_block::*;
 pub use self::boot::*;
 pub use self::download_state::*;
+pub use self::key_block_chain::*;
 pub use self::shard_client::*;
 pub use self::sync::*;
 
 mod apply_block;
 mod boot;
 mod download_state;
+mod key_block_chain;
 mod shard_client;
 mod sync;
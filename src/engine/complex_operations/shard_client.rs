@@ -4,45 +4,270 @@
 /// - replaced old `failure` crate with `anyhow`
 /// - simplified block walking
 ///
+use std::future::Future;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
-use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use everscale_network::adnl;
+use tokio::sync::Semaphore;
 
-use crate::engine::Engine;
+use crate::engine::{BroadcastReputation, BroadcastSampler, DownloaderTimeouts, Engine};
+use crate::network::Neighbour;
 use crate::proto;
-use crate::storage::BlockConnection;
+use crate::storage::{BlockConnection, Storage};
 use crate::utils::*;
 
 pub async fn walk_masterchain_blocks(
     engine: &Arc<Engine>,
     mut block_id: ton_block::BlockIdExt,
 ) -> Result<()> {
+    if engine.sync_options.audit_mode {
+        return audit_masterchain_blocks(engine, block_id).await;
+    }
+
+    let mut retry_delay = None::<DownloaderTimeouts>;
+    let mut failed_attempts = 0u32;
+    // Neighbour for the first download attempt after a failure
+    let mut neighbour = None::<Arc<Neighbour>>;
+
     while engine.is_working() {
         tracing::info!(
             block_id = %block_id.display(),
             "walking through masterchain blocks"
         );
-        block_id = match load_next_masterchain_block(engine, &block_id).await {
-            Ok(id) => id,
+        block_id = match load_next_masterchain_block(engine, &block_id, neighbour.as_ref()).await {
+            Ok(id) => {
+                if failed_attempts > 0 {
+                    tracing::info!(
+                        block_id = %id.display(),
+                        failed_attempts,
+                        "recovered after masterchain block loading failures"
+                    );
+                }
+                retry_delay = None;
+                failed_attempts = 0;
+                neighbour = None;
+
+                // NOTE: fails only if there are no subscribers
+                engine.mc_block_ids.send(id.clone()).ok();
+                id
+            }
             Err(e) => {
+                failed_attempts += 1;
                 tracing::error!(
                     block_id = %block_id.display(),
+                    failed_attempts,
                     "failed to load next masterchain block: {e:?}"
                 );
+
+                // NOTE: the delay also gives the failed neighbour time to be penalized
+                let delay = retry_delay.get_or_insert(MC_BLOCK_RETRY_TIMEOUTS).update();
+                neighbour = engine.rotate_masterchain_neighbour(neighbour.as_ref());
+                tracing::warn!(
+                    block_id = %block_id.display(),
+                    delay_ms = delay,
+                    peer_id = ?neighbour.as_ref().map(|neighbour| neighbour.peer_id()),
+                    "retrying with another neighbour"
+                );
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                continue;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Downloads and verifies masterchain blocks starting after `block_id` (or after
+/// the last audited block if it is newer) without applying them.
+///
+/// Blocks which failed verification are downloaded again from another neighbour
+async fn audit_masterchain_blocks(
+    engine: &Arc<Engine>,
+    mut block_id: ton_block::BlockIdExt,
+) -> Result<()> {
+    let node_state = engine.storage.node_state();
+    if let Ok(last_audited) = node_state.load_last_audited_mc_block_id() {
+        if last_audited.seq_no > block_id.seq_no {
+            tracing::info!(
+                block_id = %last_audited.display(),
+                "resuming audit from the last audited block"
+            );
+            block_id = last_audited;
+        }
+    }
+
+    let mut retry_delay = None::<DownloaderTimeouts>;
+    let mut key_block_proof = None;
+    let mut neighbour = None::<Arc<Neighbour>>;
+
+    while engine.is_working() {
+        let result = match engine
+            .download_next_masterchain_block(&block_id, None, neighbour.as_ref())
+            .await
+        {
+            Ok((block, block_proof)) => audit_masterchain_block(
+                engine,
+                &block_id,
+                &block,
+                &block_proof,
+                &mut key_block_proof,
+            )
+            .await
+            .map(|_| block.id().clone()),
+            Err(e) => {
+                tracing::error!(
+                    block_id = %block_id.display(),
+                    "failed to download next masterchain block: {e:?}"
+                );
+                let delay = retry_delay.get_or_insert(MC_BLOCK_RETRY_TIMEOUTS).update();
+                tokio::time::sleep(Duration::from_millis(delay)).await;
                 continue;
             }
+        };
+
+        match result {
+            Ok(next_block_id) => {
+                tracing::info!(block_id = %next_block_id.display(), "masterchain block verified");
+                node_state.store_last_audited_mc_block_id(&next_block_id)?;
+                engine
+                    .metrics
+                    .last_audited_mc_block_seqno
+                    .store(next_block_id.seq_no, Ordering::Release);
+                retry_delay = None;
+                neighbour = None;
+                block_id = next_block_id;
+            }
+            Err(e) => {
+                engine
+                    .metrics
+                    .mc_audit_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                tracing::error!(
+                    prev_block_id = %block_id.display(),
+                    "masterchain block verification failed: {e:?}"
+                );
+                let delay = retry_delay.get_or_insert(MC_BLOCK_RETRY_TIMEOUTS).update();
+                neighbour = engine.rotate_masterchain_neighbour(neighbour.as_ref());
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+            }
         }
     }
     Ok(())
 }
 
+/// Checks the block proof against the previous key block proof
+/// (or against the zerostate for blocks before the first key block).
+///
+/// `key_block_proof` caches the last used key block proof
+async fn audit_masterchain_block(
+    engine: &Arc<Engine>,
+    prev_block_id: &ton_block::BlockIdExt,
+    block: &BlockStuff,
+    block_proof: &BlockProofStuffAug,
+    key_block_proof: &mut Option<BlockProofStuff>,
+) -> Result<()> {
+    if block.id().seq_no != prev_block_id.seq_no + 1 {
+        return Err(ShardClientError::BlockIdMismatch.into());
+    } else if block_proof.is_link() {
+        return Err(ShardClientError::InvalidBlockProof.into());
+    }
+
+    let (virt_block, virt_block_info) = block_proof.pre_check_block_proof()?;
+
+    let prev_key_block_seqno = virt_block_info.prev_key_block_seqno();
+    if prev_key_block_seqno == 0 {
+        let zerostate = engine.load_mc_zero_state().await?;
+        check_with_master_state(block_proof, &zerostate, &virt_block, &virt_block_info)?;
+    } else {
+        let prev_key_block_proof = match key_block_proof.take() {
+            Some(proof) if proof.id().seq_no == prev_key_block_seqno => proof,
+            _ => {
+                let handle = engine
+                    .storage
+                    .block_handle_storage()
+                    .load_key_block_handle(prev_key_block_seqno)?;
+                engine
+                    .storage
+                    .block_storage()
+                    .load_block_proof(&handle, false)
+                    .await?
+            }
+        };
+        let result = check_with_prev_key_block_proof(
+            block_proof,
+            &prev_key_block_proof,
+            &virt_block,
+            &virt_block_info,
+        );
+        *key_block_proof = Some(prev_key_block_proof);
+        result?;
+    }
+
+    if virt_block_info.key_block() {
+        // NOTE: key block proofs are loaded from the storage after restart
+        let meta_data = BriefBlockInfo::from(&virt_block_info).with_mc_seq_no(block.id().seq_no);
+        engine
+            .storage
+            .block_storage()
+            .store_block_proof(block_proof, meta_data.into())
+            .await?;
+
+        *key_block_proof = Some(block_proof.data.clone());
+    }
+
+    Ok(())
+}
+
+/// Applies the block after the gap, downloading all missing blocks as its ancestors.
+///
+/// NOTE: fails if the gap is deeper than `max_block_applier_depth`
+async fn backfill_masterchain_gap(
+    engine: &Arc<Engine>,
+    prev_block_id: &ton_block::BlockIdExt,
+    block_id: &ton_block::BlockIdExt,
+) -> Result<ton_block::BlockIdExt> {
+    let gap_start = prev_block_id.seq_no + 1;
+    let gap_end = block_id.seq_no - 1;
+
+    engine.metrics.mc_seqno_gaps.fetch_add(1, Ordering::Relaxed);
+    tracing::warn!(
+        prev_block_id = %prev_block_id.display(),
+        next_block_id = %block_id.display(),
+        gap_start,
+        gap_end,
+        "masterchain seqno gap detected, backfilling"
+    );
+
+    engine
+        .download_and_apply_block(block_id, block_id.seq_no, false, 0)
+        .await?;
+
+    tracing::info!(gap_start, gap_end, "masterchain seqno gap backfilled");
+    Ok(block_id.clone())
+}
+
+const MAX_FAILED_SHARD_BLOCKS: usize = 1024;
+
+const MC_BLOCK_RETRY_TIMEOUTS: DownloaderTimeouts = DownloaderTimeouts {
+    initial: 50,
+    max: 2000,
+    multiplier: 2.0,
+};
+
 pub async fn walk_shard_blocks(
     engine: &Arc<Engine>,
     mc_block_id: ton_block::BlockIdExt,
 ) -> Result<()> {
     let semaphore = Arc::new(Semaphore::new(1));
 
+    // Shard blocks are downloaded ahead of applying if the queue is enabled
+    let queue = engine
+        .sync_options
+        .shard_download_queue_len
+        .map(|capacity| spawn_shard_blocks_applier(engine, semaphore.clone(), capacity));
+
     let block_handle_storage = engine.storage.block_handle_storage();
     let mut handle = block_handle_storage
         .load_handle(&mc_block_id)?
@@ -56,20 +281,195 @@ pub async fn walk_shard_blocks(
         let (next_handle, next_block) = engine.wait_next_applied_mc_block(&handle, None).await?;
         handle = next_handle;
 
-        let engine = engine.clone();
+        if let Some(queue) = &queue {
+            let downloads = prefetch_shard_blocks(engine, &next_block)?;
+            engine
+                .metrics
+                .shard_apply_queue_depth
+                .fetch_add(1, Ordering::Relaxed);
+            if queue.send((next_block, downloads)).await.is_err() {
+                return Err(ShardClientError::ShardBlocksApplierClosed.into());
+            }
+            continue;
+        }
+
         let permit = semaphore.clone().acquire_owned().await?;
-        tokio::spawn(async move {
-            if let Err(e) = load_shard_blocks(&engine, permit, next_block).await {
+        tokio::spawn(load_shard_blocks_guarded(
+            engine.clone(),
+            next_block,
+            permit,
+        ));
+    }
+    Ok(())
+}
+
+type ShardBlocksQueueItem = (BlockStuff, Vec<tokio::task::JoinHandle<()>>);
+
+/// Spawns a task which applies shard blocks of the queued masterchain blocks
+/// in order, after their shard blocks were downloaded
+fn spawn_shard_blocks_applier(
+    engine: &Arc<Engine>,
+    semaphore: Arc<Semaphore>,
+    capacity: usize,
+) -> tokio::sync::mpsc::Sender<ShardBlocksQueueItem> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<ShardBlocksQueueItem>(capacity.max(1));
+
+    let engine = engine.clone();
+    tokio::spawn(async move {
+        while let Some((next_block, downloads)) = rx.recv().await {
+            engine
+                .metrics
+                .shard_apply_queue_depth
+                .fetch_sub(1, Ordering::Relaxed);
+
+            let started_at = Instant::now();
+            futures_util::future::join_all(downloads).await;
+            engine
+                .metrics
+                .shard_apply_download_wait_ms
+                .fetch_add(started_at.elapsed().as_millis() as u64, Ordering::Relaxed);
+
+            // NOTE: shard blocks of the next masterchain block are applied
+            // only after the previous ones, so the per-shard order is preserved
+            let permit = match semaphore.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+            tokio::spawn(load_shard_blocks_guarded(
+                engine.clone(),
+                next_block,
+                permit,
+            ));
+        }
+    });
+
+    tx
+}
+
+/// Starts downloading shard blocks of the masterchain block without applying them
+fn prefetch_shard_blocks(
+    engine: &Arc<Engine>,
+    masterchain_block: &BlockStuff,
+) -> Result<Vec<tokio::task::JoinHandle<()>>> {
+    let block_handle_storage = engine.storage.block_handle_storage();
+
+    let mc_seq_no = masterchain_block.id().seq_no;
+    let mut downloads = Vec::new();
+    for (_, shard_block_id) in masterchain_block.shard_blocks()? {
+        if matches!(
+            block_handle_storage.load_handle(&shard_block_id)?,
+            Some(handle) if handle.meta().has_data() || handle.meta().is_applied()
+        ) {
+            continue;
+        }
+
+        let engine = engine.clone();
+        downloads.push(tokio::spawn(async move {
+            // NOTE: failed downloads are retried while applying
+            if let Err(e) = engine
+                .download_block_for_apply(&shard_block_id, mc_seq_no)
+                .await
+            {
+                tracing::warn!(
+                    block_id = %shard_block_id.display(),
+                    "failed to prefetch shard block: {e:?}"
+                );
+            }
+        }));
+    }
+
+    Ok(downloads)
+}
+
+/// Loads shard blocks of the masterchain block, releasing the permit
+/// when loading is finished or is considered stuck
+async fn load_shard_blocks_guarded(
+    engine: Arc<Engine>,
+    next_block: BlockStuff,
+    permit: tokio::sync::OwnedSemaphorePermit,
+) {
+    let timeout = engine
+        .sync_options
+        .shard_blocks_timeout_sec
+        .map(Duration::from_secs);
+    let abort_stuck = engine.sync_options.abort_stuck_shard_blocks;
+
+    let mc_block_id = next_block.id().clone();
+
+    // Shard blocks which are left unapplied if the task is aborted
+    let shard_block_ids = match (timeout, abort_stuck) {
+        (Some(_), true) => next_block
+            .shard_blocks()
+            .map(|ids| ids.into_values().collect::<Vec<_>>())
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    };
+
+    let mut task = tokio::spawn({
+        let engine = engine.clone();
+        async move {
+            if let Err(e) = load_shard_blocks(&engine, next_block).await {
                 tracing::error!("failed to load shard blocks: {e:?}");
             }
-        });
+        }
+    });
+
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => {
+            task.await.ok();
+            return;
+        }
+    };
+
+    if tokio::time::timeout(timeout, &mut task).await.is_ok() {
+        return;
+    }
+
+    // Let the walker proceed with the next masterchain block
+    drop(permit);
+
+    if abort_stuck {
+        tracing::warn!(
+            mc_block_id = %mc_block_id.display(),
+            timeout_sec = timeout.as_secs(),
+            "shard blocks loading is stuck, aborting"
+        );
+        task.abort();
+
+        // NOTE: the shards client id must not advance past the skipped blocks
+        let block_handle_storage = engine.storage.block_handle_storage();
+        for shard_block_id in shard_block_ids {
+            let is_applied = matches!(
+                block_handle_storage.load_handle(&shard_block_id),
+                Ok(Some(handle)) if handle.meta().is_applied()
+            );
+            if !is_applied {
+                record_failed_shard_block(
+                    &engine.failed_shard_blocks,
+                    shard_block_id,
+                    mc_block_id.seq_no,
+                );
+            }
+        }
+    } else {
+        tracing::warn!(
+            mc_block_id = %mc_block_id.display(),
+            timeout_sec = timeout.as_secs(),
+            "shard blocks loading is stuck, continuing in background"
+        );
+        task.await.ok();
+        tracing::info!(
+            mc_block_id = %mc_block_id.display(),
+            "stuck shard blocks loading finished"
+        );
     }
-    Ok(())
 }
 
 async fn load_next_masterchain_block(
     engine: &Arc<Engine>,
     prev_block_id: &ton_block::BlockIdExt,
+    neighbour: Option<&Arc<Neighbour>>,
 ) -> Result<ton_block::BlockIdExt> {
     let block_handle_storage = engine.storage.block_handle_storage();
     let block_connection_storage = engine.storage.block_connection_storage();
@@ -89,11 +489,13 @@ async fn load_next_masterchain_block(
     }
 
     let (block, block_proof) = engine
-        .download_next_masterchain_block(prev_block_id, None)
+        .download_next_masterchain_block(prev_block_id, None, neighbour)
         .await?;
     let block_id = block.id();
 
-    if block_id.seq_no != prev_block_id.seq_no + 1 {
+    if block_id.seq_no > prev_block_id.seq_no + 1 {
+        return backfill_masterchain_gap(engine, prev_block_id, block_id).await;
+    } else if block_id.seq_no != prev_block_id.seq_no + 1 {
         return Err(ShardClientError::BlockIdMismatch.into());
     } else if block_proof.is_link() {
         return Err(ShardClientError::InvalidBlockProof.into());
@@ -102,9 +504,12 @@ async fn load_next_masterchain_block(
     let (virt_block, virt_block_info) = block_proof.pre_check_block_proof()?;
     let brief_info = BriefBlockInfo::from(&virt_block_info);
 
-    // TODO: use key block proof
-    let prev_state = engine.wait_state(prev_block_id, None, true).await?;
-    check_with_master_state(&block_proof, &prev_state, &virt_block, &virt_block_info)?;
+    // NOTE: in trust mode the block proof is only pre-checked
+    if !engine.sync_options.trust_mode {
+        // TODO: use key block proof
+        let prev_state = engine.wait_state(prev_block_id, None, true).await?;
+        check_with_master_state(&block_proof, &prev_state, &virt_block, &virt_block_info)?;
+    }
 
     let mut handle = match block_handle_storage.load_handle(block_id)? {
         // Handle exists and it has block data specified
@@ -135,17 +540,395 @@ async fn load_next_masterchain_block(
         .apply_block_ext(&handle, &block, handle.id().seq_no, false, 0)
         .await?;
 
+    if engine.sync_options.verify_applied_states {
+        verify_applied_state(engine, &block).await?;
+    }
+
     Ok(block_id.clone())
 }
 
-async fn load_shard_blocks(
-    engine: &Arc<Engine>,
-    permit: OwnedSemaphorePermit,
-    masterchain_block: BlockStuff,
-) -> Result<()> {
+async fn verify_applied_state(engine: &Arc<Engine>, block: &BlockStuff) -> Result<()> {
+    let block_id = block.id();
+    let state_update = block.block().read_state_update()?;
+
+    let state = engine.wait_state(block_id, None, false).await?;
+    let state_hash = state.root_cell().repr_hash();
+    if state_hash != state_update.new_hash {
+        tracing::error!(
+            block_id = %block_id.display(),
+            expected = %state_update.new_hash.to_hex_string(),
+            actual = %state_hash.to_hex_string(),
+            "applied state hash mismatch"
+        );
+        return Err(ShardClientError::AppliedStateHashMismatch.into());
+    }
+
+    Ok(())
+}
+
+async fn load_shard_blocks(engine: &Arc<Engine>, masterchain_block: BlockStuff) -> Result<()> {
     let block_handle_storage = engine.storage.block_handle_storage();
 
     let mc_seq_no = masterchain_block.id().seq_no;
+
+    let skip_applied = |block_id: &ton_block::BlockIdExt| -> Result<bool> {
+        let is_applied = matches!(
+            block_handle_storage.load_handle(block_id)?,
+            Some(handle) if handle.meta().is_applied()
+        );
+        if is_applied {
+            engine.update_shard_status(block_id, None);
+        }
+        Ok(is_applied)
+    };
+
+    let apply = {
+        let engine = engine.clone();
+        move |block_id: ton_block::BlockIdExt, mc_seq_no: u32| {
+            let engine = engine.clone();
+            async move {
+                let started_at = Instant::now();
+                engine
+                    .download_and_apply_block(&block_id, mc_seq_no, false, 0)
+                    .await?;
+                engine.update_shard_status(&block_id, Some(started_at.elapsed()));
+                engine.shard_block_ids.send((mc_seq_no, block_id)).ok();
+                Ok(())
+            }
+        }
+    };
+
+    // NOTE: the id must not advance past the missing shard blocks, so that
+    // they are not hidden from the backfill after restart. Failed blocks are
+    // not persisted, since they are loaded again from the stored id after restart
+    let failed = apply_shard_blocks(
+        &engine.failed_shard_blocks,
+        mc_seq_no,
+        masterchain_block.shard_blocks()?.into_values(),
+        engine.sync_options.max_shard_block_attempts,
+        skip_applied,
+        apply,
+    )
+    .await?;
+    if failed > 0 {
+        tracing::warn!(
+            mc_seq_no,
+            failed,
+            "shard blocks are not applied, shards client id is not advanced"
+        );
+        return Err(ShardClientError::ShardBlocksNotApplied.into());
+    }
+
+    let block_utime = masterchain_block.block().info.read_struct()?.gen_utime().0;
+
+    // NOTE: stuck task could finish after the next ones
+    if engine.load_shards_client_mc_block_id()?.seq_no < mc_seq_no {
+        engine.store_shards_client_mc_block_id(masterchain_block.id())?;
+        engine.store_shards_client_mc_block_utime(block_utime);
+    }
+
+    Ok(())
+}
+
+/// Restores the utime of the shards client masterchain block from its handle,
+/// so that it matches the stored shards client block id without reprocessing
+/// the shard blocks.
+///
+/// Returns the restored utime
+pub fn recover_shards_client_utime(engine: &Arc<Engine>) -> Result<u32> {
+    let block_id = engine.load_shards_client_mc_block_id()?;
+    let handle = engine
+        .storage
+        .block_handle_storage()
+        .load_handle(&block_id)?
+        .ok_or(ShardClientError::MasterchainBlockNotFound)?;
+
+    let block_utime = handle.meta().gen_utime();
+    engine.store_shards_client_mc_block_utime(block_utime);
+
+    tracing::debug!(
+        mc_block_id = %block_id.display(),
+        block_utime,
+        "recovered shards client block utime"
+    );
+    Ok(block_utime)
+}
+
+/// Computes blocks which must be protected from GC for the stored masterchain block:
+/// the masterchain block itself and the top shard blocks it references
+pub async fn build_top_blocks(
+    engine: &Arc<Engine>,
+    mc_block_id: &ton_block::BlockIdExt,
+) -> Result<TopBlocks> {
+    load_top_blocks(&engine.storage, mc_block_id).await
+}
+
+async fn load_top_blocks(
+    storage: &Storage,
+    mc_block_id: &ton_block::BlockIdExt,
+) -> Result<TopBlocks> {
+    if !mc_block_id.shard_id.is_masterchain() {
+        return Err(ShardClientError::NotMasterchainBlock.into());
+    }
+
+    let handle = storage
+        .block_handle_storage()
+        .load_handle(mc_block_id)?
+        .ok_or(ShardClientError::MasterchainBlockNotFound)?;
+    let block = storage.block_storage().load_block_data(&handle).await?;
+
+    TopBlocks::from_mc_block(&block)
+}
+
+/// Applies shard blocks of the masterchain block together with the blocks
+/// which failed with the previous masterchain blocks.
+///
+/// Each block is retried up to `max_attempts` times. Blocks which are still
+/// not applied are remembered in `failed_shard_blocks` and retried on the next pass.
+///
+/// Returns the number of not applied blocks up to this masterchain block
+async fn apply_shard_blocks<S, A, F>(
+    failed_shard_blocks: &FastDashMap<ton_block::BlockIdExt, u32>,
+    mc_seq_no: u32,
+    shard_block_ids: impl IntoIterator<Item = ton_block::BlockIdExt>,
+    max_attempts: Option<u32>,
+    skip_applied: S,
+    apply: A,
+) -> Result<usize>
+where
+    S: Fn(&ton_block::BlockIdExt) -> Result<bool>,
+    A: Fn(ton_block::BlockIdExt, u32) -> F + Clone + Send + 'static,
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    // NOTE: failed blocks of the previous masterchain blocks are retried
+    // together with the shard blocks of this one
+    let mut block_ids = failed_shard_blocks
+        .iter()
+        .filter(|item| *item.value() < mc_seq_no)
+        .map(|item| (item.key().clone(), *item.value()))
+        .collect::<Vec<_>>();
+    for shard_block_id in shard_block_ids {
+        if !block_ids.iter().any(|(id, _)| id == &shard_block_id) {
+            block_ids.push((shard_block_id, mc_seq_no));
+        }
+    }
+
+    let mut tasks = Vec::new();
+    for (block_id, block_mc_seq_no) in block_ids {
+        if skip_applied(&block_id)? {
+            // NOTE: failed blocks could be applied later (e.g. by the next shard blocks)
+            failed_shard_blocks.remove(&block_id);
+            continue;
+        }
+
+        let apply = apply.clone();
+        let task_block_id = block_id.clone();
+        let task = tokio::spawn(async move {
+            let mut attempts = 0;
+            while let Err(e) = apply(block_id.clone(), block_mc_seq_no).await {
+                attempts += 1;
+                tracing::error!(
+                    block_id = %block_id.display(),
+                    attempts,
+                    "failed to apply shard block: {e:?}"
+                );
+
+                if matches!(max_attempts, Some(max_attempts) if attempts >= max_attempts) {
+                    tracing::error!(
+                        block_id = %block_id.display(),
+                        "shard block is retried with the next masterchain block"
+                    );
+                    return false;
+                }
+            }
+            true
+        });
+        tasks.push((task_block_id, task));
+    }
+
+    let task_block_ids = tasks
+        .iter()
+        .map(|(block_id, _)| block_id.clone())
+        .collect::<Vec<_>>();
+    let failed_now = join_shard_block_tasks(tasks).await;
+    for block_id in task_block_ids {
+        if !failed_now.contains(&block_id) {
+            failed_shard_blocks.remove(&block_id);
+        }
+    }
+
+    let mut all_recorded = true;
+    for block_id in &failed_now {
+        all_recorded &= record_failed_shard_block(failed_shard_blocks, block_id.clone(), mc_seq_no);
+    }
+
+    // NOTE: a block which is not remembered would never be retried
+    if !all_recorded {
+        return Err(ShardClientError::ShardBlocksNotApplied.into());
+    }
+
+    // NOTE: blocks remembered by the stuck shard blocks loading are not retried here
+    let remembered = failed_shard_blocks
+        .iter()
+        .filter(|item| *item.value() <= mc_seq_no && !failed_now.contains(item.key()))
+        .count();
+
+    Ok(failed_now.len() + remembered)
+}
+
+/// Remembers the shard block which was not applied, so that it is retried
+/// with the next masterchain blocks.
+///
+/// NOTE: the number of remembered blocks is bounded. Applying a later block of
+/// the same shard applies the previous ones too, so only the oldest are kept.
+///
+/// Returns `false` if the block was not remembered
+fn record_failed_shard_block(
+    failed_shard_blocks: &FastDashMap<ton_block::BlockIdExt, u32>,
+    block_id: ton_block::BlockIdExt,
+    mc_seq_no: u32,
+) -> bool {
+    if failed_shard_blocks.len() >= MAX_FAILED_SHARD_BLOCKS
+        && !failed_shard_blocks.contains_key(&block_id)
+    {
+        tracing::warn!(
+            block_id = %block_id.display(),
+            "too many failed shard blocks, the block is not remembered"
+        );
+        return false;
+    }
+    failed_shard_blocks.entry(block_id).or_insert(mc_seq_no);
+    true
+}
+
+/// Waits for the shard block tasks and returns ids of the blocks which were not applied
+async fn join_shard_block_tasks(
+    tasks: Vec<(ton_block::BlockIdExt, tokio::task::JoinHandle<bool>)>,
+) -> Vec<ton_block::BlockIdExt> {
+    let (block_ids, tasks): (Vec<_>, Vec<_>) = tasks.into_iter().unzip();
+    let results = futures_util::future::join_all(tasks).await;
+
+    block_ids
+        .into_iter()
+        .zip(results)
+        .filter_map(|(block_id, result)| match result {
+            Ok(true) => None,
+            Ok(false) => Some(block_id),
+            Err(e) => {
+                tracing::error!(
+                    block_id = %block_id.display(),
+                    "shard block task failed: {e:?}"
+                );
+                Some(block_id)
+            }
+        })
+        .collect()
+}
+
+/// Checks whether `to` is reachable from `from` by following stored `Next1` connections.
+///
+/// NOTE: only `Next1` links are followed, so merges are not detected
+pub fn are_connected(
+    engine: &Engine,
+    from: &ton_block::BlockIdExt,
+    to: &ton_block::BlockIdExt,
+) -> Result<bool> {
+    let block_handle_storage = engine.storage.block_handle_storage();
+    let block_connection_storage = engine.storage.block_connection_storage();
+
+    let mut block_id = from.clone();
+    while block_id.seq_no < to.seq_no {
+        match block_handle_storage.load_handle(&block_id)? {
+            Some(handle) if handle.meta().has_next1() => {
+                block_id =
+                    block_connection_storage.load_connection(&block_id, BlockConnection::Next1)?;
+            }
+            _ => return Ok(false),
+        }
+    }
+
+    Ok(&block_id == to)
+}
+
+/// Applies stored masterchain blocks after the applied block with `from_mc_seqno`
+/// without downloading them.
+///
+/// Stops at the first block which has no stored data or `Next1` connection.
+/// Returns the number of applied blocks
+pub async fn apply_pending_from_storage(engine: &Arc<Engine>, from_mc_seqno: u32) -> Result<u32> {
+    let block_handle_storage = engine.storage.block_handle_storage();
+    let block_connection_storage = engine.storage.block_connection_storage();
+    let block_storage = engine.storage.block_storage();
+
+    // Find the starting block from the nearest key block
+    let mut handle = block_handle_storage
+        .find_prev_key_block(from_mc_seqno.saturating_add(1))?
+        .ok_or(ShardClientError::MasterchainBlockNotFound)?;
+    while handle.id().seq_no < from_mc_seqno {
+        if !handle.meta().has_next1() {
+            return Err(ShardClientError::MasterchainBlockNotFound.into());
+        }
+        let next_id =
+            block_connection_storage.load_connection(handle.id(), BlockConnection::Next1)?;
+        handle = block_handle_storage
+            .load_handle(&next_id)?
+            .ok_or(ShardClientError::MasterchainBlockNotFound)?;
+    }
+    if !handle.meta().is_applied() {
+        return Err(ShardClientError::MasterchainBlockNotApplied.into());
+    }
+
+    let mut applied = 0;
+    while engine.is_working() && handle.meta().has_next1() {
+        let next_id =
+            block_connection_storage.load_connection(handle.id(), BlockConnection::Next1)?;
+        handle = match block_handle_storage.load_handle(&next_id)? {
+            Some(handle) => handle,
+            None => break,
+        };
+
+        if handle.meta().is_applied() {
+            continue;
+        } else if !handle.meta().has_data() {
+            break;
+        }
+
+        let block = block_storage.load_block_data(&handle).await?;
+        engine
+            .apply_block_ext(&handle, &block, next_id.seq_no, false, 0)
+            .await?;
+        applied += 1;
+    }
+
+    tracing::info!(
+        from_mc_seqno,
+        applied,
+        "applied pending blocks from storage"
+    );
+
+    Ok(applied)
+}
+
+/// Applies all missing shard blocks referenced by the already applied masterchain block.
+///
+/// Returns the number of shard blocks which were applied
+pub async fn backfill_shards(
+    engine: &Arc<Engine>,
+    mc_block_id: &ton_block::BlockIdExt,
+) -> Result<usize> {
+    let block_handle_storage = engine.storage.block_handle_storage();
+    let block_storage = engine.storage.block_storage();
+
+    let handle = block_handle_storage
+        .load_handle(mc_block_id)?
+        .ok_or(ShardClientError::MasterchainBlockNotFound)?;
+    if !handle.meta().is_applied() {
+        return Err(ShardClientError::MasterchainBlockNotApplied.into());
+    }
+
+    let masterchain_block = block_storage.load_block_data(&handle).await?;
+
+    let mc_seq_no = mc_block_id.seq_no;
     let mut tasks = Vec::new();
     for (_, shard_block_id) in masterchain_block.shard_blocks()? {
         if matches!(
@@ -157,36 +940,59 @@ async fn load_shard_blocks(
 
         let engine = engine.clone();
         tasks.push(tokio::spawn(async move {
-            while let Err(e) = engine
+            let result = engine
                 .download_and_apply_block(&shard_block_id, mc_seq_no, false, 0)
-                .await
-            {
+                .await;
+            if let Err(e) = &result {
                 tracing::error!(
                     block_id = %shard_block_id.display(),
-                    "failed to apply shard block: {e:?}"
+                    "failed to backfill shard block: {e:?}"
                 );
             }
+            result
         }));
     }
 
-    futures_util::future::join_all(tasks)
-        .await
-        .into_iter()
-        .find(|item| item.is_err())
-        .unwrap_or(Ok(()))?;
-    let block_utime = masterchain_block.block().info.read_struct()?.gen_utime().0;
+    let mut applied = 0;
+    for result in futures_util::future::join_all(tasks).await {
+        if matches!(result, Ok(Ok(()))) {
+            applied += 1;
+        }
+    }
 
-    engine.store_shards_client_mc_block_id(masterchain_block.id())?;
-    engine.store_shards_client_mc_block_utime(block_utime);
+    tracing::info!(
+        block_id = %mc_block_id.display(),
+        applied,
+        "finished shard blocks backfill"
+    );
 
-    drop(permit);
-    Ok(())
+    Ok(applied)
 }
 
+/// Validates and applies the block broadcast.
+///
+/// If `reputation` is specified, broadcasts from the peers with too many recent
+/// validation failures are dropped without validation.
+///
+/// If `sampler` is specified, signatures of masterchain broadcasts which were not
+/// sampled are not validated. The block proof is still checked with signatures
 pub async fn process_block_broadcast(
     engine: &Arc<Engine>,
     mut broadcast: proto::BlockBroadcast,
-) -> Result<()> {
+    reputation: Option<(&BroadcastReputation, &adnl::NodeIdShort)>,
+    sampler: Option<&BroadcastSampler>,
+) -> Result<BroadcastOutcome> {
+    if let Some((reputation, peer_id)) = reputation {
+        if reputation.is_banned(peer_id) {
+            tracing::debug!(
+                %peer_id,
+                block_id = %broadcast.id.display(),
+                "dropped broadcast from peer with bad reputation"
+            );
+            return Ok(BroadcastOutcome::Skipped(BroadcastSkipReason::BannedPeer));
+        }
+    }
+
     let block_handle_storage = engine.storage.block_handle_storage();
     let block_storage = engine.storage.block_storage();
 
@@ -194,7 +1000,9 @@ pub async fn process_block_broadcast(
         block_handle_storage.load_handle(&broadcast.id)?,
         Some(handle) if handle.meta().has_data()
     ) {
-        return Ok(());
+        return Ok(BroadcastOutcome::Skipped(
+            BroadcastSkipReason::AlreadyStored,
+        ));
     }
 
     let proof = BlockProofStuff::deserialize(
@@ -208,7 +1016,9 @@ pub async fn process_block_broadcast(
     let prev_key_block_seqno = virt_block_info.prev_key_block_seqno();
     let last_applied_mc_block_id = engine.load_last_applied_mc_block_id()?;
     if prev_key_block_seqno > last_applied_mc_block_id.seq_no {
-        return Ok(());
+        return Ok(BroadcastOutcome::Skipped(
+            BroadcastSkipReason::KeyBlockNotSynced,
+        ));
     }
 
     #[allow(clippy::large_enum_variant)]
@@ -232,21 +1042,73 @@ pub async fn process_block_broadcast(
         }
     };
 
-    validate_broadcast(&mut broadcast, &validator_set, &catchain_config)?;
+    let report_failure = || {
+        if let Some((reputation, peer_id)) = reputation {
+            reputation.report_failure(peer_id);
+        }
+    };
+
+    // NOTE: masterchain block proofs are checked with signatures below,
+    // so only the redundant broadcast signatures check could be skipped
+    let skip_signatures = broadcast.id.shard_id.is_masterchain()
+        && matches!(sampler, Some(sampler) if !sampler.should_validate());
+
+    let validated = if skip_signatures {
+        // Only cheap structural checks
+        if broadcast.signatures.is_empty() {
+            Err(anyhow!(
+                "No signatures in broadcast with block {}",
+                broadcast.id
+            ))
+        } else {
+            Ok(())
+        }
+    } else {
+        validate_broadcast(&mut broadcast, &validator_set, &catchain_config)
+    };
+    if let Err(e) = validated {
+        report_failure();
+        return Err(e);
+    }
 
     let block_id = &broadcast.id;
-    if block_id.shard_id.is_masterchain() {
+    let proof_checked = if block_id.shard_id.is_masterchain() {
         match key_block_proof {
             CheckWith::KeyBlock(key_block_proof) => {
-                proof.check_with_prev_key_block_proof(&key_block_proof)?
+                proof.check_with_prev_key_block_proof(&key_block_proof)
             }
-            CheckWith::State(state) => proof.check_with_master_state(&state)?,
+            CheckWith::State(state) => proof.check_with_master_state(&state),
         }
     } else {
-        proof.check_proof_link()?;
+        proof.check_proof_link()
+    };
+    if let Err(e) = proof_checked {
+        if skip_signatures {
+            report_failure();
+        }
+        return Err(e);
     }
 
     let block = BlockStuff::deserialize_checked(block_id.clone(), &broadcast.data)?;
+
+    // NOTE: checked before storing, so that the competing block is not stored
+    if engine.sync_options.verify_broadcast_prev_block
+        && block_id.shard_id.is_masterchain()
+        && block_id.seq_no == last_applied_mc_block_id.seq_no + 1
+    {
+        let (prev_id, _) = block.construct_prev_id()?;
+        if prev_id.root_hash != last_applied_mc_block_id.root_hash {
+            tracing::warn!(
+                block_id = %block_id.display(),
+                prev_block_id = %prev_id.display(),
+                last_applied_mc_block_id = %last_applied_mc_block_id.display(),
+                "masterchain broadcast doesn't continue the last applied block"
+            );
+            report_failure();
+            return Err(ShardClientError::PrevBlockMismatch.into());
+        }
+    }
+
     let block = BlockStuffAug::new(block, broadcast.data);
     let mut handle = match block_storage
         .store_block_data(&block, meta_data.with_mc_seq_no(0))
@@ -254,7 +1116,7 @@ pub async fn process_block_broadcast(
     {
         result if result.updated => result.handle,
         // Skipped apply for block broadcast because the block is already being processed
-        _ => return Ok(()),
+        _ => return Ok(BroadcastOutcome::Skipped(BroadcastSkipReason::InProgress)),
     };
 
     if !handle.meta().has_proof() {
@@ -267,7 +1129,7 @@ pub async fn process_block_broadcast(
         {
             result if result.updated => result.handle,
             // Skipped apply for block broadcast because the block is already being processed
-            _ => return Ok(()),
+            _ => return Ok(BroadcastOutcome::Skipped(BroadcastSkipReason::InProgress)),
         };
     }
 
@@ -276,6 +1138,7 @@ pub async fn process_block_broadcast(
             engine
                 .apply_block_ext(&handle, &block, block.id().seq_no, false, 0)
                 .await?;
+            return Ok(BroadcastOutcome::Applied);
         }
     } else {
         let master_ref = block
@@ -289,10 +1152,156 @@ pub async fn process_block_broadcast(
             engine
                 .apply_block_ext(&handle, &block, shards_client_mc_block_id.seq_no, true, 0)
                 .await?;
+            return Ok(BroadcastOutcome::Applied);
         }
     }
 
-    Ok(())
+    Ok(BroadcastOutcome::Stored)
+}
+
+/// Action taken by [`process_block_broadcast`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BroadcastOutcome {
+    Skipped(BroadcastSkipReason),
+    /// Block data and proof were stored, but the block is too far
+    /// from the last applied block to be applied
+    Stored,
+    Applied,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BroadcastSkipReason {
+    /// Broadcast was sent by a peer with a bad reputation
+    BannedPeer,
+    /// Block data is already stored
+    AlreadyStored,
+    /// Previous key block of the block is not applied yet
+    KeyBlockNotSynced,
+    /// Block is already being processed
+    InProgress,
+}
+
+/// Verifies the block proof against the stored key block chain without network.
+///
+/// Masterchain proofs are checked against the previous key block proof
+/// (or the zerostate), shard proofs are checked as proof links.
+///
+/// Returns `false` if the proof is invalid, or an error if the required
+/// key block is not stored
+pub async fn verify_proof_against_key_block(
+    engine: &Arc<Engine>,
+    block_id: &ton_block::BlockIdExt,
+    proof_bytes: &[u8],
+) -> Result<bool> {
+    let is_link = !block_id.shard_id.is_masterchain();
+    let proof = match BlockProofStuff::deserialize(block_id.clone(), proof_bytes, is_link) {
+        Ok(proof) => proof,
+        Err(e) => {
+            tracing::debug!(block_id = %block_id.display(), "invalid block proof: {e:?}");
+            return Ok(false);
+        }
+    };
+
+    let result = if is_link {
+        proof.check_proof_link()
+    } else {
+        let prev_key_block_seqno = match proof.virtualize_block() {
+            Ok((virt_block, _)) => virt_block.read_info()?.prev_key_block_seqno(),
+            Err(e) => {
+                tracing::debug!(block_id = %block_id.display(), "invalid block proof: {e:?}");
+                return Ok(false);
+            }
+        };
+
+        let handle = engine
+            .storage
+            .block_handle_storage()
+            .load_key_block_handle(prev_key_block_seqno)?;
+        if handle.id().seq_no == 0 {
+            let zerostate = engine.load_mc_zero_state().await?;
+            proof.check_with_master_state(&zerostate)
+        } else {
+            let key_block_proof = engine
+                .storage
+                .block_storage()
+                .load_block_proof(&handle, false)
+                .await?;
+            proof.check_with_prev_key_block_proof(&key_block_proof)
+        }
+    };
+
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            tracing::debug!(block_id = %block_id.display(), "block proof check failed: {e:?}");
+            Ok(false)
+        }
+    }
+}
+
+/// Checks signatures of the stored masterchain block proof against
+/// the validator set from the previous key block.
+///
+/// NOTE: unlike broadcast validation, insufficient weight is not an error
+pub async fn revalidate_block_signatures(
+    engine: &Engine,
+    block_id: &ton_block::BlockIdExt,
+) -> Result<SignatureWeightReport> {
+    if !block_id.shard_id.is_masterchain() {
+        return Err(ShardClientError::NotMasterchainBlock.into());
+    }
+
+    let block_handle_storage = engine.storage.block_handle_storage();
+    let block_storage = engine.storage.block_storage();
+
+    let handle = match block_handle_storage.load_handle(block_id)? {
+        Some(handle) if handle.meta().has_proof() => handle,
+        _ => return Err(ShardClientError::MasterchainBlockProofNotFound.into()),
+    };
+
+    let proof = block_storage.load_block_proof(&handle, false).await?;
+    let signatures = proof
+        .proof()
+        .signatures
+        .as_ref()
+        .ok_or(ShardClientError::InvalidBlockProof)?;
+
+    let prev_key_block_seqno = proof
+        .virtualize_block()?
+        .0
+        .read_info()?
+        .prev_key_block_seqno();
+    let (validator_set, catchain_config) = {
+        let handle = block_handle_storage.load_key_block_handle(prev_key_block_seqno)?;
+        if handle.id().seq_no == 0 {
+            let zerostate = engine.load_mc_zero_state().await?;
+            let config_params = zerostate.config_params()?;
+            (
+                config_params.validator_set()?,
+                config_params.catchain_config()?,
+            )
+        } else {
+            let proof = block_storage.load_block_proof(&handle, false).await?;
+            proof.get_cur_validators_set()?
+        }
+    };
+
+    check_signatures_weight(
+        block_id,
+        &validator_set,
+        &catchain_config,
+        signatures.validator_info.catchain_seqno,
+        signatures.validator_info.validator_list_hash_short,
+        &signatures.pure_signatures,
+    )
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct SignatureWeightReport {
+    pub weight: u64,
+    pub total_weight: u64,
+    /// Whether the weight is more than 2/3 of the total weight
+    pub passed: bool,
 }
 
 fn validate_broadcast(
@@ -300,50 +1309,79 @@ fn validate_broadcast(
     validator_set: &ton_block::ValidatorSet,
     catchain_config: &ton_block::CatchainConfig,
 ) -> Result<()> {
-    let block_id = &broadcast.id;
+    // Extract signatures
+    let mut block_pure_signatures = ton_block::BlockSignaturesPure::default();
+    for signature in std::mem::take(&mut broadcast.signatures) {
+        block_pure_signatures.add_sigpair(signature);
+    }
+
+    let report = check_signatures_weight(
+        &broadcast.id,
+        validator_set,
+        catchain_config,
+        broadcast.catchain_seqno,
+        broadcast.validator_set_hash,
+        &block_pure_signatures,
+    )?;
 
+    if !report.passed {
+        return Err(anyhow!(
+            "Too small signatures weight in broadcast with block {}",
+            broadcast.id
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_signatures_weight(
+    block_id: &ton_block::BlockIdExt,
+    validator_set: &ton_block::ValidatorSet,
+    catchain_config: &ton_block::CatchainConfig,
+    catchain_seqno: u32,
+    validator_set_hash: u32,
+    signatures: &ton_block::BlockSignaturesPure,
+) -> Result<SignatureWeightReport> {
     let (validators, validators_hash_short) = validator_set.calc_subset(
         catchain_config,
         block_id.shard_id.shard_prefix_with_tag(),
         block_id.shard_id.workchain_id(),
-        broadcast.catchain_seqno,
+        catchain_seqno,
         ton_block::UnixTime32(0),
     )?;
 
-    if validators_hash_short != broadcast.validator_set_hash {
+    if validators_hash_short != validator_set_hash {
         return Err(anyhow!(
-            "Bad validator set hash in broadcast with block {}, calculated: {}, found: {}",
+            "Bad validator set hash for block {}, calculated: {}, found: {}",
             block_id,
             validators_hash_short,
-            broadcast.validator_set_hash
+            validator_set_hash
         ));
     }
 
-    // Extract signatures
-    let mut block_pure_signatures = ton_block::BlockSignaturesPure::default();
-    for signature in std::mem::take(&mut broadcast.signatures) {
-        block_pure_signatures.add_sigpair(signature);
-    }
-
     // Check signatures
     let data_to_sign =
         ton_block::Block::build_data_for_sign(&block_id.root_hash, &block_id.file_hash);
     let total_weight: u64 = validators.iter().map(|v| v.weight).sum();
-    let weight = block_pure_signatures.check_signatures(&validators, &data_to_sign)?;
-
-    if weight * 3 <= total_weight * 2 {
-        return Err(anyhow!(
-            "Too small signatures weight in broadcast with block {block_id}"
-        ));
-    }
+    let weight = signatures.check_signatures(&validators, &data_to_sign)?;
 
-    Ok(())
+    Ok(SignatureWeightReport {
+        weight,
+        total_weight,
+        passed: weight * 3 > total_weight * 2,
+    })
 }
 
 #[derive(thiserror::Error, Debug)]
 enum ShardClientError {
     #[error("Masterchain block not found")]
     MasterchainBlockNotFound,
+    #[error("Masterchain block is not applied")]
+    MasterchainBlockNotApplied,
+    #[error("Masterchain block proof not found")]
+    MasterchainBlockProofNotFound,
+    #[error("Not a masterchain block")]
+    NotMasterchainBlock,
     #[error("Shardchain block handle not found")]
     ShardchainBlockHandleNotFound,
     #[error("Block id mismatch")]
@@ -352,4 +1390,227 @@ enum ShardClientError {
     InvalidBlockProof,
     #[error("Invalid block extra")]
     InvalidBlockExtra,
+    #[error("Shard blocks applier closed")]
+    ShardBlocksApplierClosed,
+    #[error("Applied state hash mismatch")]
+    AppliedStateHashMismatch,
+    #[error("Some shard blocks are not applied")]
+    ShardBlocksNotApplied,
+    #[error("Previous block doesn't match the last applied block")]
+    PrevBlockMismatch,
+}
+
+#[cfg(test)]
+mod tests {
+    use ton_block::Serializable;
+
+    use super::*;
+    use crate::db::Db;
+    use crate::storage::BlockMetaData;
+
+    fn make_block_id(seq_no: u32) -> ton_block::BlockIdExt {
+        ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::full(0),
+            seq_no,
+            root_hash: Default::default(),
+            file_hash: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_shard_block_tasks() {
+        let tasks = vec![
+            (make_block_id(1), tokio::spawn(async { true })),
+            (make_block_id(2), tokio::spawn(async { false })),
+            (make_block_id(3), tokio::spawn(async { true })),
+        ];
+        assert_eq!(join_shard_block_tasks(tasks).await, vec![make_block_id(2)]);
+
+        async fn panicking_task() -> bool {
+            panic!("shard task panicked")
+        }
+
+        let tasks = vec![
+            (make_block_id(4), tokio::spawn(async { true })),
+            (make_block_id(5), tokio::spawn(panicking_task())),
+        ];
+        assert_eq!(join_shard_block_tasks(tasks).await, vec![make_block_id(5)]);
+    }
+
+    #[tokio::test]
+    async fn failed_shard_blocks_are_retried() -> Result<()> {
+        let failed_shard_blocks = FastDashMap::default();
+        let applied = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let broken = Arc::new(parking_lot::Mutex::new(FastHashSet::default()));
+        broken.lock().insert(make_block_id(2));
+
+        let skip_applied = |block_id: &ton_block::BlockIdExt| -> Result<bool> {
+            Ok(applied.lock().iter().any(|(id, _)| id == block_id))
+        };
+        let apply = {
+            let applied = applied.clone();
+            let broken = broken.clone();
+            move |block_id: ton_block::BlockIdExt, mc_seq_no: u32| {
+                let applied = applied.clone();
+                let broken = broken.clone();
+                async move {
+                    if broken.lock().contains(&block_id) {
+                        return Err(anyhow!("broken block"));
+                    }
+                    applied.lock().push((block_id, mc_seq_no));
+                    Ok(())
+                }
+            }
+        };
+
+        // Block exceeds its attempts and is remembered
+        let failed = apply_shard_blocks(
+            &failed_shard_blocks,
+            10,
+            [make_block_id(1), make_block_id(2)],
+            Some(2),
+            skip_applied,
+            apply.clone(),
+        )
+        .await?;
+        assert_eq!(failed, 1);
+        assert_eq!(
+            failed_shard_blocks.get(&make_block_id(2)).map(|x| *x),
+            Some(10)
+        );
+
+        // Next masterchain blocks are not complete while it still fails
+        let failed = apply_shard_blocks(
+            &failed_shard_blocks,
+            11,
+            [make_block_id(3)],
+            Some(2),
+            skip_applied,
+            apply.clone(),
+        )
+        .await?;
+        assert_eq!(failed, 1);
+
+        // Block is applied on the next pass with its original masterchain block
+        broken.lock().clear();
+        let failed = apply_shard_blocks(
+            &failed_shard_blocks,
+            12,
+            [make_block_id(4)],
+            Some(2),
+            skip_applied,
+            apply,
+        )
+        .await?;
+        assert_eq!(failed, 0);
+        assert!(failed_shard_blocks.is_empty());
+        assert!(applied.lock().contains(&(make_block_id(2), 10)));
+        assert_eq!(applied.lock().len(), 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn not_remembered_shard_block_fails() {
+        let failed_shard_blocks = FastDashMap::default();
+        for i in 0..MAX_FAILED_SHARD_BLOCKS {
+            failed_shard_blocks.insert(make_block_id(1000 + i as u32), 20);
+        }
+
+        let result = apply_shard_blocks(
+            &failed_shard_blocks,
+            10,
+            [make_block_id(1)],
+            Some(1),
+            |_: &ton_block::BlockIdExt| -> Result<bool> { Ok(false) },
+            |_: ton_block::BlockIdExt, _: u32| async { Err(anyhow!("broken block")) },
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(!failed_shard_blocks.contains_key(&make_block_id(1)));
+    }
+
+    fn make_mc_block(seq_no: u32, shard_seq_no: u32) -> Result<BlockStuffAug> {
+        let descr = ton_block::ShardDescr {
+            seq_no: shard_seq_no,
+            ..Default::default()
+        };
+        let mut shards = ton_block::ShardHashes::default();
+        shards.set(
+            &0i32,
+            &ton_block::InRefValue(ton_block::BinTree::with_item(&descr)?),
+        )?;
+
+        let mut mc_extra = ton_block::McBlockExtra::default();
+        *mc_extra.hashes_mut() = shards;
+        let mut extra = ton_block::BlockExtra::default();
+        extra.write_custom(Some(&mc_extra))?;
+        let mut block = ton_block::Block::default();
+        block.write_extra(&extra)?;
+
+        let root = block.serialize()?;
+        let data = ton_types::serialize_toc(&root)?;
+        let id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no,
+            root_hash: root.repr_hash(),
+            file_hash: ton_types::UInt256::calc_file_hash(&data),
+        };
+        let block = BlockStuff::deserialize_checked(id, &data)?;
+        Ok(BlockStuffAug::new(block, data))
+    }
+
+    #[tokio::test]
+    async fn top_blocks_from_stored_mc_block() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("ton-indexer-top-blocks-{}", std::process::id()));
+        let db = Db::open(path.join("rocksdb"), 64 << 20, Default::default())?;
+        let storage = Storage::new(
+            db.clone(),
+            path.join("file"),
+            Default::default(),
+            Default::default(),
+        )
+        .await?;
+
+        let block = make_mc_block(100, 1000)?;
+        let mc_block_id = block.id().clone();
+
+        // Not stored yet
+        let error = load_top_blocks(&storage, &mc_block_id).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ShardClientError>(),
+            Some(ShardClientError::MasterchainBlockNotFound)
+        ));
+
+        let meta_data = BlockMetaData {
+            is_key_block: false,
+            gen_utime: 0,
+            mc_ref_seqno: Some(100),
+        };
+        storage
+            .block_storage()
+            .store_block_data(&block, meta_data)
+            .await?;
+
+        let top_blocks = load_top_blocks(&storage, &mc_block_id).await?;
+        assert_eq!(top_blocks.seqno(), 100);
+        assert_eq!(top_blocks.len(), 2);
+        assert!(top_blocks.contains_shard_seq_no(&ton_block::ShardIdent::full(0), 1000));
+        assert!(!top_blocks.contains_shard_seq_no(&ton_block::ShardIdent::full(0), 999));
+
+        // Only masterchain blocks are accepted
+        let error = load_top_blocks(&storage, &make_block_id(1000))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ShardClientError>(),
+            Some(ShardClientError::NotMasterchainBlock)
+        ));
+
+        drop(storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
 }
@@ -246,12 +246,14 @@ impl Engine {
 
         // Archive block
         if self.archive_options.is_some() {
-            block_storage.move_into_archive_with_data(
-                &handle,
-                proof.is_link(),
-                block.new_archive_data()?,
-                proof.new_archive_data()?,
-            )?;
+            block_storage
+                .move_into_archive_with_data(
+                    &handle,
+                    proof.is_link(),
+                    block.new_archive_data()?,
+                    proof.new_archive_data()?,
+                )
+                .await?;
         }
 
         // Notify subscribers
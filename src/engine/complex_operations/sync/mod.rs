@@ -58,6 +58,44 @@ pub async fn sync(engine: &Arc<Engine>) -> Result<()> {
     Ok(())
 }
 
+/// Applies blocks of the stored archive without downloading them
+/// (e.g. to bootstrap from an imported archive).
+///
+/// Masterchain blocks are stored and applied in seqno order right after
+/// the last applied block, then their shard blocks are applied.
+/// Returns the number of applied masterchain blocks
+pub async fn apply_from_archive(engine: &Arc<Engine>, archive_id: u32) -> Result<u32> {
+    // NOTE: the archive is parsed from the pinned slice without copying it
+    let mut maps = None;
+    engine.storage.block_storage().for_each_archive(
+        archive_id..=archive_id,
+        usize::MAX,
+        |_, data| {
+            maps = Some(BlockMaps::new(data)?);
+            Ok(())
+        },
+    )?;
+    let maps = maps.ok_or(SyncError::ArchiveNotFound)?;
+    if maps.mc_block_ids.is_empty() {
+        return Err(SyncError::EmptyArchivePackage.into());
+    }
+
+    let last_mc_block_id = engine.last_applied_block()?;
+    let mut last_gen_utime = 0;
+    let applied =
+        import_mc_blocks_with_apply(engine, &maps, &last_mc_block_id, &mut last_gen_utime).await?;
+    import_shard_blocks_with_apply(engine, &maps).await?;
+
+    tracing::info!(
+        target: "sync",
+        archive_id,
+        applied,
+        last_mc_block_id = %engine.last_applied_block()?.display(),
+        "applied blocks from archive"
+    );
+    Ok(applied)
+}
+
 #[tracing::instrument(
     skip(engine, maps, last_mc_block_id),
     fields(last_mc_block_id = %last_mc_block_id.display())
@@ -87,14 +125,16 @@ async fn import_package_with_apply(
     Ok(())
 }
 
+/// Returns the number of applied blocks
 async fn import_mc_blocks_with_apply(
     engine: &Arc<Engine>,
     maps: &BlockMaps,
     mut last_mc_block_id: &ton_block::BlockIdExt,
     last_gen_utime: &mut u32,
-) -> Result<()> {
+) -> Result<u32> {
     let db = &engine.storage;
 
+    let mut applied = 0;
     for id in maps.mc_block_ids.values() {
         // Skip already processed blocks
         if id.seq_no <= last_mc_block_id.seq_no {
@@ -139,6 +179,7 @@ async fn import_mc_blocks_with_apply(
         engine
             .apply_block_ext(&handle, block, id.seq_no, false, 0)
             .await?;
+        applied += 1;
     }
 
     tracing::info!(
@@ -146,7 +187,7 @@ async fn import_mc_blocks_with_apply(
         last_mc_block_id = %last_mc_block_id.display(),
         "imported masterchain blocks from archive"
     );
-    Ok(())
+    Ok(applied)
 }
 
 async fn import_shard_blocks_with_apply(engine: &Arc<Engine>, maps: &Arc<BlockMaps>) -> Result<()> {
@@ -291,6 +332,8 @@ impl Engine {
 
 #[derive(thiserror::Error, Debug)]
 enum SyncError {
+    #[error("Archive not found")]
+    ArchiveNotFound,
     #[error("Empty archive package")]
     EmptyArchivePackage,
     #[error("Masterchain block id mismatch")]
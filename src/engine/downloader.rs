@@ -118,7 +118,7 @@ impl Downloader for NextBlockDownloader {
 
         context
             .client
-            .download_next_block_full(context.block_id)
+            .download_next_block_full(context.block_id, context.explicit_neighbour)
             .await
     }
 }
@@ -205,7 +205,7 @@ pub struct DownloaderTimeouts {
 }
 
 impl DownloaderTimeouts {
-    fn update(&mut self) -> u64 {
+    pub fn update(&mut self) -> u64 {
         self.initial = std::cmp::min(self.max, (self.initial as f64 * self.multiplier) as u64);
         self.initial
     }
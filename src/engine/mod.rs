@@ -6,7 +6,7 @@
 /// - slightly changed application of blocks
 ///
 use std::io::Write;
-use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -25,10 +25,14 @@ use crate::network::*;
 use crate::storage::*;
 use crate::utils::*;
 
+pub use self::broadcast_reputation::BroadcastReputation;
+pub use self::broadcast_sampler::BroadcastSampler;
 use self::complex_operations::*;
 use self::downloader::*;
 pub use self::node_rpc::*;
 
+mod broadcast_reputation;
+mod broadcast_sampler;
 pub mod complex_operations;
 mod downloader;
 mod node_rpc;
@@ -45,6 +49,7 @@ pub struct Engine {
     storage: Arc<Storage>,
     states_gc_options: Option<StateGcOptions>,
     blocks_gc_state: Option<BlocksGcState>,
+    storage_gc_options: Option<StorageGcOptions>,
     subscribers: Vec<Arc<dyn Subscriber>>,
     network: Arc<NodeNetwork>,
 
@@ -58,6 +63,8 @@ pub struct Engine {
 
     archive_options: Option<ArchiveOptions>,
     sync_options: SyncOptions,
+    broadcast_reputation: Option<BroadcastReputation>,
+    broadcast_sampler: Option<BroadcastSampler>,
 
     shard_states_operations: ShardStatesOperationsPool,
     block_applying_operations: BlockApplyingOperationsPool,
@@ -66,8 +73,23 @@ pub struct Engine {
     shard_states_cache: ShardStateCache,
 
     metrics: Arc<EngineMetrics>,
+    shard_statuses: FastDashMap<ton_block::ShardIdent, ShardStatus>,
+    /// Shard blocks which were not applied (e.g. exceeded `max_shard_block_attempts`)
+    /// with the seqno of the masterchain block which references them.
+    /// The shards client id doesn't advance past them until they are applied
+    failed_shard_blocks: FastDashMap<ton_block::BlockIdExt, u32>,
+    /// Masterchain blocks applied by the walker (see [`Engine::masterchain_block_stream`])
+    mc_block_ids: tokio::sync::broadcast::Sender<ton_block::BlockIdExt>,
+    /// Shard blocks applied by the shard client (see [`Engine::shard_block_stream`])
+    shard_block_ids: tokio::sync::broadcast::Sender<(u32, ton_block::BlockIdExt)>,
+    /// States evicted from the cache (see [`Engine::evicted_state_stream`])
+    evicted_state_ids: tokio::sync::broadcast::Sender<ton_block::BlockIdExt>,
 }
 
+const MC_BLOCK_IDS_CAPACITY: usize = 256;
+const SHARD_BLOCK_IDS_CAPACITY: usize = 1024;
+const EVICTED_STATE_IDS_CAPACITY: usize = 1024;
+
 type ShardStatesOperationsPool = OperationsPool<ton_block::BlockIdExt, Arc<ShardStateStuff>>;
 type BlockApplyingOperationsPool = OperationsPool<ton_block::BlockIdExt, ()>;
 type NextBlockApplyingOperationsPool = OperationsPool<ton_block::BlockIdExt, ton_block::BlockIdExt>;
@@ -77,6 +99,9 @@ type DownloadBlockOperationsPool =
 struct BlocksGcState {
     ty: BlocksGcKind,
     max_blocks_per_batch: Option<usize>,
+    proofs_retention_sec: Option<u32>,
+    keep_proofs: bool,
+    compaction: Option<PackageEntriesCompactionOptions>,
     enabled: AtomicBool,
 }
 
@@ -93,10 +118,47 @@ impl Engine {
         subscribers: Vec<Arc<dyn Subscriber>>,
     ) -> Result<Arc<Self>> {
         let old_blocks_policy = config.sync_options.old_blocks_policy;
-        let db = Db::open(config.rocks_db_path, config.max_db_memory_usage)?;
-        let storage = Storage::new(db.clone(), config.file_db_path)
-            .await
-            .context("Failed to create DB")?;
+        let db = Db::open(
+            config.rocks_db_path,
+            config.max_db_memory_usage,
+            config.archives_column_options,
+        )?;
+        let mut block_storage_options = BlockStorageOptions::default();
+        if let Some(options) = &config.blocks_gc_options {
+            block_storage_options.gc_readahead_size = options.scan_readahead_size;
+        }
+        if let Some(options) = &config.archive_options {
+            block_storage_options.max_archive_size = options.max_archive_size;
+            block_storage_options.min_retained_archives = options.min_retained_archives;
+            block_storage_options.remove_key_blocks = options.remove_key_blocks;
+            block_storage_options.persist_current_archive_id = options.persist_current_archive_id;
+            if let Some(threads) = options.preload_threads {
+                block_storage_options.preload_threads = threads;
+            }
+            block_storage_options.event_log_retention_sec = options.event_log_retention_sec;
+            block_storage_options.archive_id_strategy = options.id_strategy;
+            block_storage_options.max_archive_write_rate = options.max_write_rate;
+            block_storage_options.slow_archive_lock_threshold =
+                options.slow_lock_threshold_ms.map(Duration::from_millis);
+            block_storage_options.max_concurrent_archiving = options.max_concurrent_moves;
+        }
+        let storage = Storage::new(
+            db.clone(),
+            config.file_db_path,
+            block_storage_options,
+            CellStorageOptions {
+                max_concurrent_reads: config.max_concurrent_cell_reads,
+                cache_leaf_cells: !config.skip_leaf_cells_cache,
+                skip_contended_cache_inserts: true,
+                checksums: config.cell_checksums,
+                compact_references: config.compact_cell_references,
+                split_masterchain_cells: config.split_masterchain_cells,
+                cache_strategy: config.cells_cache_strategy,
+                cells_filter: config.cells_filter_options,
+            },
+        )
+        .await
+        .context("Failed to create DB")?;
 
         let zero_state_id = global_config.zero_state.clone();
 
@@ -149,6 +211,16 @@ impl Engine {
 
         tracing::info!("network started");
 
+        let evicted_state_ids = tokio::sync::broadcast::channel(EVICTED_STATE_IDS_CAPACITY).0;
+        let shard_states_cache = ShardStateCache::new(config.shard_state_cache_options)
+            .with_on_evict({
+                let evicted_state_ids = evicted_state_ids.clone();
+                move |block_id| {
+                    // NOTE: fails only if there are no subscribers
+                    evicted_state_ids.send(block_id.clone()).ok();
+                }
+            });
+
         Ok(Arc::new(Self {
             is_working: AtomicBool::new(true),
             db,
@@ -157,8 +229,12 @@ impl Engine {
             blocks_gc_state: config.blocks_gc_options.map(|options| BlocksGcState {
                 ty: options.kind,
                 max_blocks_per_batch: options.max_blocks_per_batch,
+                proofs_retention_sec: options.proofs_retention_sec,
+                keep_proofs: options.keep_proofs,
+                compaction: options.compaction,
                 enabled: AtomicBool::new(options.enable_for_sync),
             }),
+            storage_gc_options: config.storage_gc_options,
             subscribers,
             network,
             masterchain_client,
@@ -169,12 +245,21 @@ impl Engine {
             hard_forks,
             archive_options: config.archive_options,
             sync_options: config.sync_options,
+            broadcast_reputation: config
+                .broadcast_reputation_options
+                .map(BroadcastReputation::new),
+            broadcast_sampler: config.broadcast_sampling_options.map(BroadcastSampler::new),
             shard_states_operations: OperationsPool::new("shard_states_operations"),
             block_applying_operations: OperationsPool::new("block_applying_operations"),
             next_block_applying_operations: OperationsPool::new("next_block_applying_operations"),
             download_block_operations: OperationsPool::new("download_block_operations"),
-            shard_states_cache: ShardStateCache::new(config.shard_state_cache_options),
+            shard_states_cache,
             metrics: Arc::new(Default::default()),
+            shard_statuses: Default::default(),
+            failed_shard_blocks: Default::default(),
+            mc_block_ids: tokio::sync::broadcast::channel(MC_BLOCK_IDS_CAPACITY).0,
+            shard_block_ids: tokio::sync::broadcast::channel(SHARD_BLOCK_IDS_CAPACITY).0,
+            evicted_state_ids,
         }))
     }
 
@@ -217,11 +302,80 @@ impl Engine {
         self.prepare_blocks_gc().await?;
         self.start_walking_blocks()?;
         self.start_states_gc();
+        self.start_storage_gc();
+        self.start_archives_verifier();
+        self.start_package_entries_compaction();
 
         // Engine started
         Ok(())
     }
 
+    /// Returns a stream of masterchain blocks applied by the masterchain walker.
+    ///
+    /// NOTE: lagging subscribers skip the oldest blocks
+    pub fn masterchain_block_stream(
+        &self,
+    ) -> impl futures_util::Stream<Item = ton_block::BlockIdExt> {
+        use tokio::sync::broadcast::error::RecvError;
+
+        let rx = self.mc_block_ids.subscribe();
+        futures_util::stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(block_id) => return Some((block_id, rx)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "masterchain block stream lagged");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Returns a stream of block ids whose states were evicted from the shard states cache.
+    ///
+    /// NOTE: lagging subscribers skip the oldest ids
+    pub fn evicted_state_stream(&self) -> impl futures_util::Stream<Item = ton_block::BlockIdExt> {
+        use tokio::sync::broadcast::error::RecvError;
+
+        let rx = self.evicted_state_ids.subscribe();
+        futures_util::stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(block_id) => return Some((block_id, rx)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "evicted state stream lagged");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Returns a stream of shard blocks applied by the shard client
+    /// along with the seqno of the referencing masterchain block.
+    ///
+    /// NOTE: lagging subscribers skip the oldest blocks, shard blocks
+    /// of the same masterchain block are not ordered
+    pub fn shard_block_stream(
+        &self,
+    ) -> impl futures_util::Stream<Item = (u32, ton_block::BlockIdExt)> {
+        use tokio::sync::broadcast::error::RecvError;
+
+        let rx = self.shard_block_ids.subscribe();
+        futures_util::stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(item) => return Some((item, rx)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "shard block stream lagged");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
     pub fn network(&self) -> &Arc<NodeNetwork> {
         &self.network
     }
@@ -230,6 +384,63 @@ impl Engine {
         self.db.trigger_compaction().await;
     }
 
+    /// Applies shard blocks which are missing for the already applied masterchain block.
+    ///
+    /// Returns the number of fixed shard blocks
+    pub async fn backfill_shards(
+        self: &Arc<Self>,
+        mc_block_id: &ton_block::BlockIdExt,
+    ) -> Result<usize> {
+        backfill_shards(self, mc_block_id).await
+    }
+
+    /// Computes blocks which must be protected from GC for the stored masterchain block
+    pub async fn build_top_blocks(
+        self: &Arc<Self>,
+        mc_block_id: &ton_block::BlockIdExt,
+    ) -> Result<TopBlocks> {
+        build_top_blocks(self, mc_block_id).await
+    }
+
+    /// Applies stored masterchain blocks after the applied block with `from_mc_seqno`
+    /// without downloading them.
+    ///
+    /// Returns the number of applied blocks
+    pub async fn apply_pending_from_storage(self: &Arc<Self>, from_mc_seqno: u32) -> Result<u32> {
+        apply_pending_from_storage(self, from_mc_seqno).await
+    }
+
+    /// Applies blocks of the stored archive without downloading them.
+    ///
+    /// Returns the number of applied masterchain blocks
+    pub async fn apply_from_archive(self: &Arc<Self>, archive_id: u32) -> Result<u32> {
+        apply_from_archive(self, archive_id).await
+    }
+
+    /// Verifies the block proof against the stored key block chain without network.
+    ///
+    /// Returns `false` if the proof is invalid
+    pub async fn verify_proof_against_key_block(
+        self: &Arc<Self>,
+        block_id: &ton_block::BlockIdExt,
+        proof_bytes: &[u8],
+    ) -> Result<bool> {
+        verify_proof_against_key_block(self, block_id, proof_bytes).await
+    }
+
+    /// Checks the stored signatures of the masterchain block using its validator set
+    pub async fn revalidate_block_signatures(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+    ) -> Result<SignatureWeightReport> {
+        revalidate_block_signatures(self, block_id).await
+    }
+
+    /// Exports proofs of the key blocks in the specified seqno range as an archive package
+    pub async fn export_key_block_chain(&self, from_seqno: u32, to_seqno: u32) -> Result<Vec<u8>> {
+        export_key_block_chain(self, from_seqno, to_seqno).await
+    }
+
     async fn prepare_blocks_gc(self: &Arc<Self>) -> Result<()> {
         let blocks_gc_state = match &self.blocks_gc_state {
             Some(state) => state,
@@ -245,6 +456,8 @@ impl Engine {
                 handle.id(),
                 blocks_gc_state.max_blocks_per_batch,
                 blocks_gc_state.ty,
+                blocks_gc_state.proofs_retention_sec,
+                blocks_gc_state.keep_proofs,
             )
             .await
     }
@@ -359,6 +572,7 @@ impl Engine {
         match options.gc_interval {
             ArchivesGcInterval::Manual => Ok(()),
             ArchivesGcInterval::PersistentStates { offset_sec } => {
+                let id_strategy = options.id_strategy;
                 let engine = self.clone();
                 tokio::spawn(async move {
                     let persistent_state_keeper =
@@ -369,8 +583,13 @@ impl Engine {
 
                         let (until_id, untile_time) = match persistent_state_keeper.current() {
                             Some(state) => {
-                                let untile_time = state.meta().gen_utime() as u64 + offset_sec;
-                                (state.id().seq_no, untile_time)
+                                let gen_utime = state.meta().gen_utime();
+                                let untile_time = gen_utime as u64 + offset_sec;
+                                let until_id = match id_strategy {
+                                    ArchiveIdStrategy::Seqno => state.id().seq_no,
+                                    ArchiveIdStrategy::Utime { .. } => gen_utime,
+                                };
+                                (until_id, untile_time)
                             }
                             None => {
                                 new_state_found.await;
@@ -433,6 +652,16 @@ impl Engine {
             }
         });
 
+        // NOTE: shard blocks are not processed in audit mode
+        if self.sync_options.audit_mode {
+            return Ok(());
+        }
+
+        // NOTE: the utime is not stored with the id, so it is restored from the handle
+        if let Err(e) = recover_shards_client_utime(self) {
+            tracing::warn!("failed to recover shards client block utime: {e:?}");
+        }
+
         // Start walking through the shards blocks
         let engine = self.clone();
         tokio::spawn(async move {
@@ -444,6 +673,81 @@ impl Engine {
         Ok(())
     }
 
+    fn start_archives_verifier(self: &Arc<Self>) {
+        let interval = match &self.archive_options {
+            Some(ArchiveOptions {
+                verify_interval_sec: Some(interval_sec),
+                ..
+            }) => Duration::from_secs(*interval_sec),
+            _ => return,
+        };
+
+        let engine = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+
+                let engine = match engine.upgrade() {
+                    Some(engine) if engine.is_working() => engine,
+                    _ => return,
+                };
+
+                let result = tokio::task::spawn_blocking(move || {
+                    engine.storage.block_storage().verify_next_archive()
+                })
+                .await;
+
+                match result {
+                    Ok(Ok(Some((archive_id, false)))) => {
+                        tracing::warn!(archive_id, "archive quarantined");
+                    }
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => tracing::error!("failed to verify archive: {e:?}"),
+                    Err(e) => tracing::error!("archive verification task failed: {e:?}"),
+                }
+            }
+        });
+    }
+
+    fn start_package_entries_compaction(self: &Arc<Self>) {
+        let options = match &self.blocks_gc_state {
+            Some(BlocksGcState {
+                compaction: Some(options),
+                ..
+            }) => *options,
+            _ => return,
+        };
+
+        const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+        let engine = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(CHECK_INTERVAL).await;
+
+                let engine = match engine.upgrade() {
+                    Some(engine) if engine.is_working() => engine,
+                    _ => return,
+                };
+
+                let block_storage = engine.storage.block_storage();
+                if block_storage.package_entries_tombstones() < options.tombstones_threshold {
+                    continue;
+                }
+
+                let now = broxus_util::now_sec_u64();
+                if matches!(
+                    block_storage.last_package_entries_compaction(),
+                    Some(last) if now < last as u64 + options.min_interval_sec
+                ) {
+                    continue;
+                }
+
+                block_storage.compact_package_entries().await;
+            }
+        });
+    }
+
     fn start_states_gc(self: &Arc<Self>) {
         let options = match self.states_gc_options {
             Some(options) => options,
@@ -458,7 +762,7 @@ impl Engine {
                 loop {
                     tokio::time::sleep(ttl).await;
                     match engine.upgrade() {
-                        Some(engine) => engine.shard_states_cache.clear(),
+                        Some(engine) => engine.shard_states_cache.clear_expired(),
                         None => break,
                     }
                 }
@@ -517,16 +821,156 @@ impl Engine {
         });
     }
 
+    fn start_storage_gc(self: &Arc<Self>) {
+        let options = match self.storage_gc_options {
+            Some(options) => options,
+            None => return,
+        };
+
+        let policy: Box<dyn RetentionPolicy> = options.policy.into();
+        let interval = Duration::from_secs(options.interval_sec);
+
+        let engine = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+
+                let engine = match engine.upgrade() {
+                    Some(engine) if engine.is_working() => engine,
+                    _ => return,
+                };
+
+                if let Err(e) = engine.run_storage_gc(policy.as_ref()).await {
+                    tracing::error!("failed to run storage GC: {e:?}");
+                }
+            }
+        });
+    }
+
+    /// Removes blocks, archives and states before the edge computed by the policy.
+    ///
+    /// Returns `None` if the top blocks edge was not found
+    pub async fn run_storage_gc(
+        &self,
+        policy: &dyn RetentionPolicy,
+    ) -> Result<Option<StorageGcStats>> {
+        let block_id = self.load_shards_client_mc_block_id()?;
+
+        let gc = self.storage.gc();
+        let cutoff = match gc.compute_retention(policy, block_id.seq_no).await? {
+            Some(cutoff) => cutoff,
+            None => return Ok(None),
+        };
+
+        let (max_blocks_per_batch, proofs_retention_sec, keep_proofs) = match &self.blocks_gc_state
+        {
+            Some(state) => (
+                state.max_blocks_per_batch,
+                state.proofs_retention_sec,
+                state.keep_proofs,
+            ),
+            None => (None, None, false),
+        };
+
+        let stats = gc
+            .run(GcRetention {
+                mc_seq_no: cutoff.mc_seq_no,
+                archives_until_id: cutoff.archives_until_id,
+                max_blocks_per_batch,
+                proofs_retention_sec,
+                keep_proofs,
+            })
+            .await?;
+
+        Ok(Some(stats))
+    }
+
     /// Initiates shutdown
     pub fn shutdown(&self) {
         self.is_working.store(false, Ordering::Release);
         self.network.shutdown();
     }
 
+    /// Stops the engine and waits until the blocks which are being moved
+    /// into archives are written, so that no handles are left with the moving flag.
+    ///
+    /// NOTE: archiving can't be resumed after this call
+    pub async fn shutdown_archiving(&self) -> Result<()> {
+        self.shutdown();
+        self.storage.block_storage().shutdown().await
+    }
+
     pub fn is_working(&self) -> bool {
         self.is_working.load(Ordering::Acquire)
     }
 
+    /// Returns the progress of the background archives verification
+    pub fn archive_verification_status(&self) -> ArchiveVerificationStatus {
+        self.storage.block_storage().archive_verification_status()
+    }
+
+    /// Returns the archives composition collected at startup
+    pub fn archives_composition_report(&self) -> CompositionReport {
+        self.storage.block_storage().composition_report()
+    }
+
+    /// Sets the callback which is called for each new cell of the stored states
+    /// (e.g. to build an external index).
+    ///
+    /// NOTE: the callback is called after the state is written to the db
+    pub fn set_on_cell_stored(&self, callback: Option<CellStoredCallback>) {
+        self.storage
+            .shard_state_storage()
+            .set_on_cell_stored(callback);
+    }
+
+    /// Pauses or resumes archiving of new blocks (e.g. during maintenance).
+    ///
+    /// NOTE: blocks skipped while paused are archived by [`Engine::drain_pending_archiving`]
+    pub fn set_archiving_paused(&self, paused: bool) {
+        self.storage.block_storage().set_archiving_paused(paused);
+    }
+
+    /// Archives blocks which were skipped while archiving was paused
+    pub async fn drain_pending_archiving(
+        &self,
+        concurrency: usize,
+    ) -> Result<MoveIntoArchiveStats> {
+        self.storage
+            .block_storage()
+            .drain_pending_archiving(concurrency)
+            .await
+    }
+
+    /// Lists blocks which started moving into the archive, but were not archived
+    pub fn pending_archiving(&self) -> Result<Vec<ton_block::BlockIdExt>> {
+        self.storage.block_storage().pending_archiving()
+    }
+
+    /// Aggregated storage state for readiness probes
+    pub fn storage_health(&self) -> Result<StorageHealth> {
+        let last_applied_mc_block_id = self.load_last_applied_mc_block_id()?;
+        Ok(self.storage.health(last_applied_mc_block_id.seq_no))
+    }
+
+    /// Returns recorded archive events starting from the specified unix time
+    pub fn archive_event_log(&self, from_unix: u32) -> Result<Vec<ArchiveEvent>> {
+        self.storage.block_storage().archive_event_log(from_unix)
+    }
+
+    /// Estimated number of package entries tombstones written by blocks GC
+    /// since the last compaction
+    pub fn package_entries_tombstones(&self) -> u64 {
+        self.storage.block_storage().package_entries_tombstones()
+    }
+
+    /// Unix timestamp of the last package entries compaction
+    pub fn last_package_entries_compaction(&self) -> Option<u32> {
+        self.storage
+            .block_storage()
+            .last_package_entries_compaction()
+    }
+
     pub fn get_db_metrics(&self) -> DbMetrics {
         self.storage.metrics()
     }
@@ -539,9 +983,33 @@ impl Engine {
         &self.metrics
     }
 
+    /// Returns the latest known state of each shard processed by the shard client
+    pub fn shard_sync_status(&self) -> Vec<ShardStatus> {
+        self.shard_statuses
+            .iter()
+            .map(|item| *item.value())
+            .collect()
+    }
+
+    /// Returns shard blocks which exceeded `max_shard_block_attempts`
+    /// (or whose loading task failed). They are retried with the next masterchain blocks
+    pub fn failed_shard_blocks(&self) -> Vec<ton_block::BlockIdExt> {
+        self.failed_shard_blocks
+            .iter()
+            .map(|item| item.key().clone())
+            .collect()
+    }
+
     pub fn internal_metrics(&self) -> InternalEngineMetrics {
+        let evictions = self.shard_states_cache.eviction_stats();
         InternalEngineMetrics {
             shard_states_cache_len: self.shard_states_cache.len(),
+            shard_states_cache_dedups: self.shard_states_cache.dedup_count(),
+            shard_states_cache_ttl_evictions: evictions.ttl,
+            shard_states_cache_outdated_evictions: evictions.outdated,
+            shard_states_cache_cleared: evictions.cleared,
+            shard_states_cache_capacity_evictions: evictions.capacity,
+            shard_states_cache_rejected_inserts: self.shard_states_cache.rejected_inserts(),
             shard_states_operations_len: self.shard_states_operations.len(),
             block_applying_operations_len: self.block_applying_operations.len(),
             next_block_applying_operations_len: self.next_block_applying_operations.len(),
@@ -589,15 +1057,27 @@ impl Engine {
 
         tokio::spawn(async move {
             loop {
-                let block = match client.wait_broadcast().await {
+                let (block, source) = match client.wait_broadcast().await {
                     Ok(block) => block,
                     Err(_) => continue,
                 };
 
                 let engine = engine.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = process_block_broadcast(&engine, block).await {
-                        tracing::error!("failed to process block broadcast: {e:?}");
+                    let reputation = engine
+                        .broadcast_reputation
+                        .as_ref()
+                        .map(|reputation| (reputation, &source));
+                    let sampler = engine.broadcast_sampler.as_ref();
+                    let block_id = block.id.clone();
+                    match process_block_broadcast(&engine, block, reputation, sampler).await {
+                        Ok(BroadcastOutcome::Skipped(reason)) => tracing::debug!(
+                            block_id = %block_id.display(),
+                            ?reason,
+                            "skipped block broadcast"
+                        ),
+                        Ok(_) => {}
+                        Err(e) => tracing::error!("failed to process block broadcast: {e:?}"),
                     }
                 });
             }
@@ -652,10 +1132,13 @@ impl Engine {
         Ok((handle, state))
     }
 
+    /// Downloads the next masterchain block. The first attempt uses the explicit
+    /// neighbour if it is specified, the rest use random neighbours
     async fn download_next_masterchain_block(
         &self,
         prev_block_id: &ton_block::BlockIdExt,
         max_attempts: Option<u32>,
+        neighbour: Option<&Arc<Neighbour>>,
     ) -> Result<(BlockStuffAug, BlockProofStuffAug)> {
         if !prev_block_id.is_masterchain() {
             return Err(EngineError::NonMasterchainNextBlock.into());
@@ -672,10 +1155,23 @@ impl Engine {
                 multiplier: 1.1,
             }),
         )
+        .with_explicit_neighbour(neighbour)
         .download()
         .await
     }
 
+    /// Chooses a masterchain overlay neighbour other than the previous one
+    fn rotate_masterchain_neighbour(
+        &self,
+        prev: Option<&Arc<Neighbour>>,
+    ) -> Option<Arc<Neighbour>> {
+        let neighbours = self.masterchain_client.0.neighbours();
+        match prev {
+            Some(prev) => neighbours.choose_neighbour_except(prev.peer_id()),
+            None => neighbours.choose_neighbour(),
+        }
+    }
+
     async fn download_block(
         &self,
         block_id: &ton_block::BlockIdExt,
@@ -947,6 +1443,8 @@ impl Engine {
 
         if handle.id().shard_id.is_masterchain() {
             self.on_masterchain_block(handle).await?;
+        } else if self.sync_options.drop_shard_blocks_data {
+            block_storage.remove_block_data(handle).await?;
         }
 
         Ok(applied)
@@ -962,6 +1460,8 @@ impl Engine {
                             handle.id(),
                             blocks_gc.max_blocks_per_batch,
                             blocks_gc.ty,
+                            blocks_gc.proofs_retention_sec,
+                            blocks_gc.keep_proofs,
                         )
                         .await?
                 }
@@ -1006,6 +1506,30 @@ impl Engine {
             .store(block_utime, Ordering::Release);
     }
 
+    fn update_shard_status(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        apply_latency: Option<Duration>,
+    ) {
+        let mut status = self
+            .shard_statuses
+            .entry(block_id.shard_id)
+            .or_insert_with(|| ShardStatus {
+                shard_ident: block_id.shard_id,
+                last_applied_seqno: 0,
+                last_apply_latency: None,
+                updated_at: 0,
+            });
+
+        if block_id.seq_no >= status.last_applied_seqno {
+            status.last_applied_seqno = block_id.seq_no;
+            status.updated_at = now();
+        }
+        if apply_latency.is_some() {
+            status.last_apply_latency = apply_latency;
+        }
+    }
+
     async fn download_and_apply_block(
         self: &Arc<Self>,
         block_id: &ton_block::BlockIdExt,
@@ -1083,29 +1607,10 @@ impl Engine {
             };
 
             // Download next block
-            if let Some((block, block_proof)) = self
-                .download_block_operations
-                .do_or_wait(
-                    block_id,
-                    None,
-                    self.download_block_worker(block_id, max_attempts, timeouts),
-                )
+            if let Some((handle, block)) = self
+                .download_and_store_block(block_id, mc_seq_no, max_attempts, timeouts)
                 .await?
             {
-                if block_handle_storage.load_handle(block_id)?.is_some() {
-                    continue;
-                }
-
-                let info = self.check_block_proof(&block_proof).await?;
-                let handle = block_storage
-                    .store_block_data(&block, info.with_mc_seq_no(mc_seq_no))
-                    .await?
-                    .handle;
-                let handle = block_storage
-                    .store_block_proof(&block_proof, handle.into())
-                    .await?
-                    .handle;
-
                 tracing::trace!(
                     block_id = %block_id.display(),
                     "downloaded block for apply"
@@ -1117,6 +1622,68 @@ impl Engine {
         }
     }
 
+    /// Downloads and stores the block with its proof without applying it
+    async fn download_block_for_apply(
+        self: &Arc<Self>,
+        block_id: &ton_block::BlockIdExt,
+        mc_seq_no: u32,
+    ) -> Result<()> {
+        if self
+            .storage
+            .block_handle_storage()
+            .load_handle(block_id)?
+            .is_none()
+        {
+            self.download_and_store_block(block_id, mc_seq_no, None, None)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Downloads the block with its proof, checks the proof and stores both.
+    ///
+    /// Returns `None` if the block was not downloaded by this call
+    /// or its handle was created while downloading
+    async fn download_and_store_block(
+        self: &Arc<Self>,
+        block_id: &ton_block::BlockIdExt,
+        mc_seq_no: u32,
+        max_attempts: Option<u32>,
+        timeouts: Option<DownloaderTimeouts>,
+    ) -> Result<Option<(Arc<BlockHandle>, BlockStuffAug)>> {
+        let block_storage = self.storage.block_storage();
+        let block_handle_storage = self.storage.block_handle_storage();
+
+        let (block, block_proof) = match self
+            .download_block_operations
+            .do_or_wait(
+                block_id,
+                None,
+                self.download_block_worker(block_id, max_attempts, timeouts),
+            )
+            .await?
+        {
+            Some(downloaded) => downloaded,
+            None => return Ok(None),
+        };
+
+        if block_handle_storage.load_handle(block_id)?.is_some() {
+            return Ok(None);
+        }
+
+        let info = self.check_block_proof(&block_proof).await?;
+        let handle = block_storage
+            .store_block_data(&block, info.with_mc_seq_no(mc_seq_no))
+            .await?
+            .handle;
+        let handle = block_storage
+            .store_block_proof(&block_proof, handle.into())
+            .await?
+            .handle;
+
+        Ok(Some((handle, block)))
+    }
+
     async fn apply_block_ext(
         self: &Arc<Self>,
         handle: &Arc<BlockHandle>,
@@ -1451,11 +2018,44 @@ pub struct EngineMetrics {
     pub last_mc_utime: AtomicU32,
     pub mc_time_diff: AtomicI64,
     pub shard_client_time_diff: AtomicI64,
+    /// Number of detected gaps between the downloaded masterchain blocks
+    pub mc_seqno_gaps: AtomicU32,
+    /// Seqno of the last masterchain block verified in audit mode
+    pub last_audited_mc_block_seqno: AtomicU32,
+    /// Number of masterchain blocks which failed verification in audit mode
+    pub mc_audit_failures: AtomicU32,
+    /// Number of masterchain blocks with downloaded shard blocks waiting to be applied.
+    /// Stays near `shard_download_queue_len` if applying is the bottleneck
+    pub shard_apply_queue_depth: AtomicU32,
+    /// Total time spent by the shard blocks applier waiting for downloads
+    pub shard_apply_download_wait_ms: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShardStatus {
+    pub shard_ident: ton_block::ShardIdent,
+    pub last_applied_seqno: u32,
+    /// Time spent on applying the last shard block (if it was applied by the shard client)
+    pub last_apply_latency: Option<Duration>,
+    /// Unix timestamp of the last seqno update
+    pub updated_at: u32,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone, Copy)]
 pub struct InternalEngineMetrics {
     pub shard_states_cache_len: usize,
+    /// Number of cached states reused for other block ids with the same root
+    pub shard_states_cache_dedups: u64,
+    /// Number of states removed from the cache by the periodic TTL cleanup
+    pub shard_states_cache_ttl_evictions: u64,
+    /// Number of states removed from the cache after the states GC
+    pub shard_states_cache_outdated_evictions: u64,
+    /// Number of states removed by explicit cache clears
+    pub shard_states_cache_cleared: u64,
+    /// Number of states removed from the full cache to insert new ones
+    pub shard_states_cache_capacity_evictions: u64,
+    /// Number of states which were not inserted into the full cache
+    pub shard_states_cache_rejected_inserts: u64,
     pub shard_states_operations_len: usize,
     pub block_applying_operations_len: usize,
     pub next_block_applying_operations_len: usize,
@@ -8,6 +8,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
+use everscale_network::adnl;
 
 use crate::network::{Neighbour, OverlayClient};
 use crate::proto;
@@ -265,6 +266,7 @@ impl NodeRpcClient {
     pub async fn download_next_block_full(
         &self,
         prev_id: &ton_block::BlockIdExt,
+        explicit_neighbour: Option<&Arc<Neighbour>>,
     ) -> Result<Option<(BlockStuffAug, BlockProofStuffAug)>> {
         const NO_NEIGHBOURS_DELAY: u64 = 1000; // Milliseconds
 
@@ -274,11 +276,15 @@ impl NodeRpcClient {
             prev_block: prev_id.clone(),
         };
 
-        let neighbour = if let Some(neighbour) = this.neighbours().choose_neighbour() {
-            neighbour
-        } else {
-            tokio::time::sleep(Duration::from_millis(NO_NEIGHBOURS_DELAY)).await;
-            return Err(NodeRpcClientError::NeighbourNotFound.into());
+        let neighbour = match explicit_neighbour {
+            Some(neighbour) => neighbour.clone(),
+            None => match this.neighbours().choose_neighbour() {
+                Some(neighbour) => neighbour,
+                None => {
+                    tokio::time::sleep(Duration::from_millis(NO_NEIGHBOURS_DELAY)).await;
+                    return Err(NodeRpcClientError::NeighbourNotFound.into());
+                }
+            },
         };
 
         // Download
@@ -425,10 +431,11 @@ impl NodeRpcClient {
         }
     }
 
-    pub async fn wait_broadcast(&self) -> Result<proto::BlockBroadcast> {
+    /// Waits for the next block broadcast. Returns it along with the source peer id
+    pub async fn wait_broadcast(&self) -> Result<(proto::BlockBroadcast, adnl::NodeIdShort)> {
         let info = self.0.wait_for_broadcast().await;
         let broadcast = tl_proto::deserialize(&info.data)?;
-        Ok(broadcast)
+        Ok((broadcast, info.from))
     }
 }
 
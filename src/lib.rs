@@ -1,15 +1,30 @@
 pub use crate::config::*;
-pub use crate::db::RocksdbStats;
+pub use crate::db::{ArchivesColumnOptions, ColumnCompression, RocksdbStats};
+pub use crate::engine::complex_operations::SignatureWeightReport;
 pub use crate::engine::{
-    Engine, EngineMetrics, EngineStatus, InternalEngineMetrics, ProcessBlockContext, Subscriber,
+    Engine, EngineMetrics, EngineStatus, InternalEngineMetrics, ProcessBlockContext, ShardStatus,
+    Subscriber,
 };
 pub use crate::network::{NeighboursOptions, NetworkMetrics, NodeNetwork};
-pub use crate::storage::{BriefBlockMeta, DbMetrics};
+pub use crate::storage::{
+    ArchiveEvent, ArchiveEventKind, ArchiveLifetimeCounters, ArchiveVerificationStatus,
+    BriefBlockMeta, CellStoredCallback, CompositionReport, DataLocksWaitMetrics, DbMetrics,
+    DiskBudget, MoveIntoArchiveStats, RetentionContext, RetentionCutoff, RetentionPolicy,
+    SeqnoWindow, StorageGcStats, StorageHealth, TimeWindow,
+};
 
 #[cfg(feature = "archive-uploader")]
 pub use archive_uploader;
 pub use global_config::*;
 
+/// Internals which are used by the benchmarks
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::db::Db;
+    pub use crate::storage::{CellStorage, CellStorageOptions};
+}
+
 mod config;
 mod db;
 mod engine;
@@ -229,6 +229,24 @@ impl Neighbours {
             .choose_neighbour(&mut rand::thread_rng(), self.average_failures())
     }
 
+    /// Same as [`Neighbours::choose_neighbour`], but prefers neighbours other than `excluded`.
+    ///
+    /// NOTE: returns the excluded neighbour if it is the only one available
+    pub fn choose_neighbour_except(&self, excluded: &adnl::NodeIdShort) -> Option<Arc<Neighbour>> {
+        let mut rng = rand::thread_rng();
+        let average_failures = self.average_failures();
+
+        let mut chosen = None;
+        for _ in 0..std::cmp::max(self.len(), 1) {
+            chosen = self.cache.choose_neighbour(&mut rng, average_failures);
+            match &chosen {
+                Some(neighbour) if neighbour.peer_id() == excluded => continue,
+                _ => break,
+            }
+        }
+        chosen
+    }
+
     pub fn average_failures(&self) -> f64 {
         self.failed_attempts.load(Ordering::Acquire) as f64
             / std::cmp::max(self.all_attempts.load(Ordering::Acquire), 1) as f64
@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
 use anyhow::Result;
+use ton_types::UInt256;
 
 use super::models::BlockHandle;
 use crate::db::*;
@@ -94,6 +95,41 @@ impl BlockConnectionStorage {
             BlockConnection::Next2 => load_block_connection_impl(&self.db.next2, block_id),
         }
     }
+
+    /// Checks that targets of the `Next1` and `Prev1` links of the stored handles
+    /// still have handles.
+    ///
+    /// Only `sample` links at random positions of each connection column are checked
+    /// if specified, otherwise all links are scanned. Links of the removed handles
+    /// are skipped since they are never loaded
+    pub fn audit_connections(&self, sample: Option<usize>) -> Result<Vec<DanglingLink>> {
+        let mut result = Vec::new();
+        audit_connections_impl(
+            &self.db,
+            &self.db.next1,
+            BlockConnection::Next1,
+            sample,
+            &mut result,
+        )?;
+        audit_connections_impl(
+            &self.db,
+            &self.db.prev1,
+            BlockConnection::Prev1,
+            sample,
+            &mut result,
+        )?;
+        Ok(result)
+    }
+}
+
+/// Link to the block without a handle (see [`BlockConnectionStorage::audit_connections`])
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DanglingLink {
+    /// Root hash of the block which has the link
+    pub source_root_hash: UInt256,
+    pub direction: BlockConnection,
+    /// Linked block without a handle
+    pub target: ton_block::BlockIdExt,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -119,6 +155,37 @@ where
     )
 }
 
+fn audit_connections_impl<T>(
+    db: &Db,
+    table: &Table<T>,
+    direction: BlockConnection,
+    sample: Option<usize>,
+    result: &mut Vec<DanglingLink>,
+) -> Result<()>
+where
+    T: ColumnFamily,
+{
+    let check = |key: &[u8], value: &[u8]| -> Result<()> {
+        // Skip links of the removed handles
+        if db.block_handles.get(key)?.is_none() {
+            return Ok(());
+        }
+
+        let target = read_block_id_le(value).ok_or(BlockConnectionStorageError::InvalidBlockId)?;
+        if db.block_handles.get(target.root_hash.as_slice())?.is_none() {
+            result.push(DanglingLink {
+                source_root_hash: UInt256::from_slice(key),
+                direction,
+                target,
+            });
+        }
+        Ok(())
+    };
+
+    let mut iter = table.raw_iterator();
+    for_each_sampled_item(&mut iter, sample, check)
+}
+
 #[inline]
 fn load_block_connection_impl<T>(
     db: &Table<T>,
@@ -141,3 +208,74 @@ enum BlockConnectionStorageError {
     #[error("Block connection not found")]
     NotFound,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{BlockHandleStorage, BlockMetaData};
+
+    #[test]
+    fn dangling_connections() -> Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "ton-indexer-connections-audit-{}",
+            std::process::id()
+        ));
+        let db = Db::open(path.clone(), 64 << 20, Default::default())?;
+        let block_handle_storage = BlockHandleStorage::new(db.clone())?;
+        let block_connection_storage = BlockConnectionStorage::new(db.clone())?;
+
+        let block_id = |seq_no: u32| ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no,
+            root_hash: UInt256::from([seq_no as u8; 32]),
+            file_hash: UInt256::from([seq_no as u8; 32]),
+        };
+
+        let mut handles = Vec::new();
+        for seq_no in 1..=2 {
+            let (handle, _) = block_handle_storage.create_or_load_handle(
+                &block_id(seq_no),
+                BlockMetaData {
+                    is_key_block: false,
+                    gen_utime: 0,
+                    mc_ref_seqno: Some(seq_no),
+                },
+            )?;
+            block_handle_storage.store_handle(&handle)?;
+            handles.push(handle);
+        }
+
+        block_connection_storage.store_connection(
+            &handles[0],
+            BlockConnection::Next1,
+            &block_id(2),
+        )?;
+        block_connection_storage.store_connection(
+            &handles[1],
+            BlockConnection::Prev1,
+            &block_id(1),
+        )?;
+        block_connection_storage.store_connection(
+            &handles[1],
+            BlockConnection::Next1,
+            &block_id(3),
+        )?;
+
+        let dangling = block_connection_storage.audit_connections(None)?;
+        assert_eq!(
+            dangling,
+            [DanglingLink {
+                source_root_hash: block_id(2).root_hash,
+                direction: BlockConnection::Next1,
+                target: block_id(3),
+            }]
+        );
+
+        drop(handles);
+        drop(block_handle_storage);
+        drop(block_connection_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+}
@@ -7,6 +7,7 @@
 use std::sync::{Arc, Weak};
 
 use anyhow::Result;
+use ton_types::UInt256;
 
 use super::models::*;
 use crate::db::*;
@@ -233,6 +234,90 @@ impl BlockHandleStorage {
         }
     }
 
+    /// Clears the `is_moving_to_archive` flag of the stored handles which were
+    /// not archived (e.g. after a crash), so that archiving can be retried
+    /// when the block is applied again.
+    ///
+    /// NOTE: must be called on startup, before any handle is loaded
+    pub fn recover_stuck_archiving(&self) -> Result<usize> {
+        let block_handles_cf = self.db.block_handles.cf();
+
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut total = 0;
+
+        let mut read_options = self.db.block_handles.new_read_config();
+        read_options.set_total_order_seek(true);
+
+        let mut iter = self
+            .db
+            .raw()
+            .raw_iterator_cf_opt(&block_handles_cf, read_options);
+        iter.seek_to_first();
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            let meta = BlockMeta::from_slice(value)?;
+            if !meta.is_archived() && meta.clear_is_moving_to_archive() {
+                batch.put_cf(&block_handles_cf, key, meta.to_vec());
+                total += 1;
+            }
+            iter.next();
+        }
+        iter.status()?;
+
+        if total > 0 {
+            self.db.raw().write(batch)?;
+        }
+        Ok(total)
+    }
+
+    /// Scans stored handles which started moving into the archive, but were not archived.
+    ///
+    /// Returns ids of the cached handles and root hashes of the rest
+    /// (block handles column doesn't contain full ids)
+    pub fn find_pending_archiving(
+        &self,
+    ) -> Result<(Vec<ton_block::BlockIdExt>, FastHashSet<UInt256>)> {
+        let block_handles_cf = self.db.block_handles.cf();
+
+        let mut read_options = self.db.block_handles.new_read_config();
+        read_options.set_total_order_seek(true);
+
+        let mut root_hashes = FastHashSet::default();
+
+        let mut iter = self
+            .db
+            .raw()
+            .raw_iterator_cf_opt(&block_handles_cf, read_options);
+        iter.seek_to_first();
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            let meta = BlockMeta::from_slice(value)?;
+            if meta.is_moving_to_archive() && !meta.is_archived() && key.len() == 32 {
+                root_hashes.insert(UInt256::from_be_bytes(key));
+            }
+            iter.next();
+        }
+        iter.status()?;
+
+        let mut resolved = Vec::new();
+        for entry in self.cache.iter() {
+            if root_hashes.remove(&entry.key().root_hash) {
+                resolved.push(entry.key().clone());
+            }
+        }
+
+        Ok((resolved, root_hashes))
+    }
+
+    /// Counts cached handles which started moving into the archive, but were not archived.
+    ///
+    /// NOTE: only handles in the cache are checked
+    pub fn count_stuck_archiving_handles(&self) -> usize {
+        self.cache
+            .iter()
+            .filter_map(|entry| entry.value().upgrade())
+            .filter(|handle| handle.meta().is_moving_to_archive() && !handle.meta().is_archived())
+            .count()
+    }
+
     pub fn gc_handles_cache(&self, top_blocks: &TopBlocks) -> usize {
         let mut total_removed = 0;
 
@@ -0,0 +1,171 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use parking_lot::Mutex;
+
+use crate::db::Db;
+
+/// Opt-in log of archive mutations with a time-based retention.
+///
+/// Events are appended to the write batch of the mutation itself,
+/// so the log always matches the stored archives.
+///
+/// Key structure:
+/// - `[unix time, 4 bytes BE][sequence number, 4 bytes BE]`
+pub struct ArchiveEventLog {
+    retention_sec: u32,
+    seqno: AtomicU32,
+    /// Events of the in-memory archive changes which are not stored yet
+    deferred: Mutex<Vec<ArchiveEventKind>>,
+}
+
+impl ArchiveEventLog {
+    pub fn new(retention_sec: u32) -> Self {
+        Self {
+            retention_sec,
+            // NOTE: random start reduces collisions with events stored
+            // in the same second before restart
+            seqno: AtomicU32::new(rand::random()),
+            deferred: Default::default(),
+        }
+    }
+
+    /// Postpones the event until the next [`ArchiveEventLog::append`]
+    pub fn defer(&self, kind: ArchiveEventKind) {
+        self.deferred.lock().push(kind);
+    }
+
+    /// Appends all deferred events and the specified event to the batch.
+    /// Prunes outdated events when a new archive is created
+    pub fn append(&self, db: &Db, batch: &mut rocksdb::WriteBatch, kind: ArchiveEventKind) {
+        let cf = db.archive_events.cf();
+        let now = broxus_util::now();
+
+        let deferred = std::mem::take(&mut *self.deferred.lock());
+        if deferred
+            .iter()
+            .any(|kind| matches!(kind, ArchiveEventKind::Created { .. }))
+        {
+            let until = now.saturating_sub(self.retention_sec);
+            batch.delete_range_cf(&cf, [0u8; 8], [until.to_be_bytes(), [0; 4]].concat());
+        }
+
+        for kind in deferred.into_iter().chain(std::iter::once(kind)) {
+            let mut key = [0; 8];
+            key[..4].copy_from_slice(&now.to_be_bytes());
+            key[4..].copy_from_slice(&self.seqno.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+            batch.put_cf(&cf, key, kind.encode());
+        }
+    }
+}
+
+/// Reads all events starting from the specified unix time
+pub fn read_archive_events(db: &Db, from_unix: u32) -> Result<Vec<ArchiveEvent>, rocksdb::Error> {
+    let mut iter = db.archive_events.raw_iterator();
+    iter.seek(from_unix.to_be_bytes());
+
+    let mut result = Vec::new();
+    while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+        if let (Some(timestamp), Some(kind)) = (key.get(..4), ArchiveEventKind::decode(value)) {
+            let mut timestamp_bytes = [0; 4];
+            timestamp_bytes.copy_from_slice(timestamp);
+            result.push(ArchiveEvent {
+                timestamp: u32::from_be_bytes(timestamp_bytes),
+                kind,
+            });
+        }
+        iter.next();
+    }
+    iter.status()?;
+
+    Ok(result)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ArchiveEvent {
+    pub timestamp: u32,
+    pub kind: ArchiveEventKind,
+}
+
+/// Value structure:
+/// - `[kind, 1 byte][id, 4 bytes LE][extra, 4 bytes LE]`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ArchiveEventKind {
+    /// New archive was started
+    Created { archive_id: u32 },
+    /// Archive will no longer receive new blocks (except late shard blocks)
+    Sealed { archive_id: u32 },
+    /// Block was moved into the archive
+    BlockMoved { archive_id: u32, mc_seq_no: u32 },
+    /// All archives before `until_id` were removed
+    Removed { until_id: u32 },
+}
+
+impl ArchiveEventKind {
+    fn encode(&self) -> [u8; EVENT_LEN] {
+        let (tag, id, extra) = match *self {
+            Self::Created { archive_id } => (0, archive_id, 0),
+            Self::Sealed { archive_id } => (1, archive_id, 0),
+            Self::BlockMoved {
+                archive_id,
+                mc_seq_no,
+            } => (2, archive_id, mc_seq_no),
+            Self::Removed { until_id } => (3, until_id, 0),
+        };
+
+        let mut result = [0; EVENT_LEN];
+        result[0] = tag;
+        result[1..5].copy_from_slice(&u32::to_le_bytes(id));
+        result[5..9].copy_from_slice(&u32::to_le_bytes(extra));
+        result
+    }
+
+    fn decode(value: &[u8]) -> Option<Self> {
+        if value.len() != EVENT_LEN {
+            return None;
+        }
+
+        let mut id = [0; 4];
+        id.copy_from_slice(&value[1..5]);
+        let id = u32::from_le_bytes(id);
+
+        let mut extra = [0; 4];
+        extra.copy_from_slice(&value[5..9]);
+        let extra = u32::from_le_bytes(extra);
+
+        Some(match value[0] {
+            0 => Self::Created { archive_id: id },
+            1 => Self::Sealed { archive_id: id },
+            2 => Self::BlockMoved {
+                archive_id: id,
+                mc_seq_no: extra,
+            },
+            3 => Self::Removed { until_id: id },
+            _ => return None,
+        })
+    }
+}
+
+const EVENT_LEN: usize = 1 + 4 + 4;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_roundtrip() {
+        for kind in [
+            ArchiveEventKind::Created { archive_id: 100 },
+            ArchiveEventKind::Sealed { archive_id: 100 },
+            ArchiveEventKind::BlockMoved {
+                archive_id: 100,
+                mc_seq_no: 123,
+            },
+            ArchiveEventKind::Removed { until_id: 200 },
+        ] {
+            assert_eq!(ArchiveEventKind::decode(&kind.encode()), Some(kind));
+        }
+
+        assert_eq!(ArchiveEventKind::decode(&[4; EVENT_LEN]), None);
+        assert_eq!(ArchiveEventKind::decode(&[]), None);
+    }
+}
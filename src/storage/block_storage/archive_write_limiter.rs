@@ -0,0 +1,86 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Token bucket which limits the rate of archive writes.
+///
+/// Writes are accounted after they are done, so the bucket can go into debt
+/// which is paid by the next writers before they start
+pub struct ArchiveWriteLimiter {
+    bytes_per_sec: f64,
+    state: Mutex<LimiterState>,
+}
+
+impl ArchiveWriteLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self {
+            bytes_per_sec,
+            state: Mutex::new(LimiterState {
+                // NOTE: allows bursts of up to one second of writes
+                tokens: bytes_per_sec,
+                updated_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until the debt of the previous writes is paid.
+    ///
+    /// NOTE: must not be called while holding the handle data locks
+    pub async fn wait(&self) {
+        loop {
+            let delay = {
+                let mut state = self.state.lock();
+                state.refill(self.bytes_per_sec);
+                if state.tokens >= 0.0 {
+                    return;
+                }
+                Duration::from_secs_f64(-state.tokens / self.bytes_per_sec)
+            };
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Accounts the written bytes
+    pub fn consume(&self, bytes: u64) {
+        let mut state = self.state.lock();
+        state.refill(self.bytes_per_sec);
+        state.tokens -= bytes as f64;
+    }
+}
+
+struct LimiterState {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl LimiterState {
+    fn refill(&mut self, bytes_per_sec: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * bytes_per_sec).min(bytes_per_sec);
+        self.updated_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_debt() {
+        let limiter = ArchiveWriteLimiter::new(1000);
+
+        // Burst is allowed
+        limiter.consume(500);
+        let started_at = Instant::now();
+        limiter.wait().await;
+        assert!(started_at.elapsed() < Duration::from_millis(100));
+
+        // Debt must be paid
+        limiter.consume(1000);
+        let started_at = Instant::now();
+        limiter.wait().await;
+        assert!(started_at.elapsed() >= Duration::from_millis(400));
+    }
+}
@@ -0,0 +1,62 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Accumulates wait times of the handle data locks
+#[derive(Default)]
+pub struct DataLockMetrics {
+    block_data: LockWaitTime,
+    proof_data: LockWaitTime,
+}
+
+impl DataLockMetrics {
+    /// Acquires the lock and accounts the time spent waiting for it
+    pub async fn timed<F: Future>(&self, kind: DataLockKind, lock: F) -> F::Output {
+        let started_at = Instant::now();
+        let guard = lock.await;
+        let wait_us = started_at.elapsed().as_micros() as u64;
+
+        let wait_time = match kind {
+            DataLockKind::Block => &self.block_data,
+            DataLockKind::Proof => &self.proof_data,
+        };
+        wait_time.total_us.fetch_add(wait_us, Ordering::Relaxed);
+        wait_time.max_us.fetch_max(wait_us, Ordering::Relaxed);
+
+        guard
+    }
+
+    /// Returns the accumulated wait times and resets the max wait times
+    pub fn snapshot(&self) -> DataLocksWaitMetrics {
+        DataLocksWaitMetrics {
+            block_data_total_wait_us: self.block_data.total_us.load(Ordering::Relaxed),
+            block_data_max_wait_us: self.block_data.max_us.swap(0, Ordering::Relaxed),
+            proof_data_total_wait_us: self.proof_data.total_us.load(Ordering::Relaxed),
+            proof_data_max_wait_us: self.proof_data.max_us.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum DataLockKind {
+    Block,
+    Proof,
+}
+
+#[derive(Default)]
+struct LockWaitTime {
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct DataLocksWaitMetrics {
+    /// Cumulative wait time of the block data locks
+    pub block_data_total_wait_us: u64,
+    /// Max wait time of the block data lock since the previous snapshot
+    pub block_data_max_wait_us: u64,
+    /// Cumulative wait time of the proof data locks
+    pub proof_data_total_wait_us: u64,
+    /// Max wait time of the proof data lock since the previous snapshot
+    pub proof_data_max_wait_us: u64,
+}
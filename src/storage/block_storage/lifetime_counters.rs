@@ -0,0 +1,114 @@
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use parking_lot::Mutex;
+
+use crate::db::*;
+
+/// Cumulative archive counters which are persisted across restarts
+#[derive(Default)]
+pub struct LifetimeCounters {
+    archived_blocks: AtomicU64,
+    archived_bytes: AtomicU64,
+    gc_removed_entries: AtomicU64,
+    /// Counters which are already added to the stored snapshot
+    flushed: Mutex<ArchiveLifetimeCounters>,
+}
+
+impl LifetimeCounters {
+    /// Restores counters from the stored snapshot. Unknown or truncated
+    /// snapshots are ignored, so the counters start from zero
+    pub fn load(stored: Option<&[u8]>) -> Self {
+        let counters = Self::default();
+        if let Some(snapshot) = stored.and_then(decode_snapshot) {
+            counters
+                .archived_blocks
+                .store(snapshot.archived_blocks, Ordering::Relaxed);
+            counters
+                .archived_bytes
+                .store(snapshot.archived_bytes, Ordering::Relaxed);
+            counters
+                .gc_removed_entries
+                .store(snapshot.gc_removed_entries, Ordering::Relaxed);
+            *counters.flushed.lock() = snapshot;
+        }
+        counters
+    }
+
+    /// Returns `true` if the counters should be flushed
+    pub fn add_archived(&self, bytes: u64) -> bool {
+        let archived_blocks = self.archived_blocks.fetch_add(1, Ordering::Relaxed) + 1;
+        self.archived_bytes.fetch_add(bytes, Ordering::Relaxed);
+        archived_blocks % ARCHIVED_BLOCKS_FLUSH_INTERVAL == 0
+    }
+
+    pub fn add_gc_removed_entries(&self, count: u64) {
+        self.gc_removed_entries.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ArchiveLifetimeCounters {
+        ArchiveLifetimeCounters {
+            archived_blocks: self.archived_blocks.load(Ordering::Relaxed),
+            archived_bytes: self.archived_bytes.load(Ordering::Relaxed),
+            gc_removed_entries: self.gc_removed_entries.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Adds the counters accumulated since the previous flush to the stored snapshot,
+    /// so that the counters of other storages on the same db are not overwritten
+    pub fn flush(&self, node_states: &Table<tables::NodeStates>, key: &[u8]) -> Result<()> {
+        let mut flushed = self.flushed.lock();
+
+        let current = self.snapshot();
+        let mut stored = node_states
+            .get(key)?
+            .as_deref()
+            .and_then(decode_snapshot)
+            .unwrap_or_default();
+
+        stored.archived_blocks += current.archived_blocks - flushed.archived_blocks;
+        stored.archived_bytes += current.archived_bytes - flushed.archived_bytes;
+        stored.gc_removed_entries += current.gc_removed_entries - flushed.gc_removed_entries;
+
+        node_states.insert(key, encode_snapshot(&stored))?;
+        *flushed = current;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ArchiveLifetimeCounters {
+    /// Number of blocks moved into archives
+    pub archived_blocks: u64,
+    /// Size of the block data and proofs moved into archives
+    pub archived_bytes: u64,
+    /// Number of package entries removed by blocks GC
+    pub gc_removed_entries: u64,
+}
+
+fn decode_snapshot(data: &[u8]) -> Option<ArchiveLifetimeCounters> {
+    if data.len() != SNAPSHOT_LEN {
+        return None;
+    }
+
+    let read = |i: usize| u64::from_le_bytes(data[i * 8..i * 8 + 8].try_into().unwrap());
+    Some(ArchiveLifetimeCounters {
+        archived_blocks: read(0),
+        archived_bytes: read(1),
+        gc_removed_entries: read(2),
+    })
+}
+
+fn encode_snapshot(snapshot: &ArchiveLifetimeCounters) -> [u8; SNAPSHOT_LEN] {
+    let mut result = [0; SNAPSHOT_LEN];
+    result[0..8].copy_from_slice(&snapshot.archived_blocks.to_le_bytes());
+    result[8..16].copy_from_slice(&snapshot.archived_bytes.to_le_bytes());
+    result[16..24].copy_from_slice(&snapshot.gc_removed_entries.to_le_bytes());
+    result
+}
+
+const SNAPSHOT_LEN: usize = 8 * 3;
+
+/// Number of archived blocks after which the counters are flushed
+const ARCHIVED_BLOCKS_FLUSH_INTERVAL: u64 = 100;
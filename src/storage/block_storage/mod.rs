@@ -6,70 +6,347 @@
 /// - removed all temporary unused code
 ///
 use std::borrow::Borrow;
-use std::collections::BTreeSet;
+use std::collections::{hash_map, BTreeMap, BTreeSet};
 use std::convert::TryInto;
 use std::hash::Hash;
-use std::ops::{Bound, RangeBounds};
+use std::ops::{Bound, Range, RangeBounds};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
-use parking_lot::RwLock;
+use arc_swap::ArcSwap;
+use parking_lot::{Mutex, RwLock};
+use tokio_util::sync::CancellationToken;
+use ton_types::UInt256;
 
+pub use self::archive_event_log::{ArchiveEvent, ArchiveEventKind};
+pub use self::data_lock_metrics::DataLocksWaitMetrics;
+pub use self::lifetime_counters::ArchiveLifetimeCounters;
+
+use self::archive_event_log::*;
+use self::archive_write_limiter::*;
+use self::data_lock_metrics::*;
+use self::lifetime_counters::*;
+use self::package_entry_meta::*;
 use super::block_handle_storage::{BlockHandleStorage, HandleCreationStatus};
 use super::models::*;
-use crate::config::BlocksGcKind;
+use crate::config::{ArchiveIdStrategy, BlocksGcKind};
 use crate::db::*;
 use crate::utils::*;
 
+mod archive_event_log;
+mod archive_write_limiter;
+mod data_lock_metrics;
+mod lifetime_counters;
+mod package_entry_meta;
+
 pub struct BlockStorage {
     db: Arc<Db>,
     block_handle_storage: Arc<BlockHandleStorage>,
     archive_ids: RwLock<BTreeSet<u32>>,
+    /// Archive ids which were started by key blocks
+    key_block_archive_ids: RwLock<BTreeSet<u32>>,
+    pinned_archive_ids: RwLock<BTreeSet<u32>>,
+    archive_verification: Mutex<ArchiveVerificationState>,
+    /// Archives composition collected during preload
+    composition_report: Mutex<CompositionReport>,
+    max_archive_size: Option<u64>,
+    archive_id_strategy: ArchiveIdStrategy,
+    min_retained_archives: usize,
+    remove_key_blocks: bool,
+    /// Whether the newest archive id is tracked by `current_archive_id`
+    persist_current_archive_id: bool,
+    /// Newest archive id, mirrored to the db. `NO_CURRENT_ARCHIVE` if there are no archives
+    current_archive_id: AtomicU32,
+    /// Serializes writes of `current_archive_id` to the db
+    current_archive_id_store_lock: Mutex<()>,
+    /// Stored sizes of the archives, including the header
+    archive_sizes: Mutex<BTreeMap<u32, u64>>,
+    /// Archives removed by defragmentation, mapped to the archives which received their data
+    merged_archive_ids: Mutex<FastHashMap<u32, u32>>,
+    /// The last masterchain seqno of each full archive. Blocks of the greater
+    /// seqnos start a new archive (see [`BlockStorage::is_archive_full`])
+    archive_seal_seqnos: Mutex<BTreeMap<u32, u32>>,
+    archive_events: Option<ArchiveEventLog>,
+    archive_write_limiter: Option<ArchiveWriteLimiter>,
+    /// Bounds the number of blocks which are moved into archives concurrently.
+    /// Closed on shutdown
+    archiving_semaphore: ArchivingSemaphore,
+    archiving_stopped: AtomicBool,
+    data_lock_metrics: DataLockMetrics,
+    /// Flushed into the db periodically and on drop (see [`BlockStorage::flush_metrics`])
+    lifetime_counters: LifetimeCounters,
+    slow_archive_lock_threshold: Option<Duration>,
+    gc_readahead_size: Option<usize>,
+    /// Blocks GC writes package entry removals under the exclusive lock, while
+    /// archiving holds the shared lock from reading the first entry of the block
+    /// until the archive is updated. So the block entries can't disappear mid-archive
+    entries_gc_lock: Arc<tokio::sync::RwLock<()>>,
+    /// Root hashes of the blocks which are being moved into archives.
+    /// Blocks GC defers their entries until archiving is finished
+    archiving_blocks: Arc<Mutex<FastHashSet<[u8; 32]>>>,
+    /// Package entries removed by blocks GC since the last compaction
+    package_entries_tombstones: AtomicU64,
+    /// Unix timestamp of the last package entries compaction, 0 if there was none
+    last_package_entries_compaction: AtomicU32,
+    archiving_paused: AtomicBool,
+    /// Blocks which were not archived while archiving was paused, by root hash.
+    /// Persisted in the node states, so they are archived after a restart too
+    paused_archiving: Mutex<FastHashMap<[u8; 32], ton_block::BlockIdExt>>,
 }
 
 impl BlockStorage {
-    pub fn new(db: Arc<Db>, block_handle_storage: Arc<BlockHandleStorage>) -> Result<Self> {
+    pub fn new(
+        db: Arc<Db>,
+        block_handle_storage: Arc<BlockHandleStorage>,
+        options: BlockStorageOptions,
+    ) -> Result<Self> {
+        let lifetime_counters =
+            LifetimeCounters::load(db.node_states.get(LIFETIME_COUNTERS)?.as_deref());
+
+        // NOTE: blocks GC keeps entries of the remembered blocks
+        let paused_archiving = load_paused_archiving(&db)?;
+        let archiving_blocks = paused_archiving.keys().copied().collect::<FastHashSet<_>>();
+
         let manager = Self {
             db,
             block_handle_storage,
             archive_ids: Default::default(),
+            key_block_archive_ids: Default::default(),
+            pinned_archive_ids: Default::default(),
+            archive_verification: Default::default(),
+            composition_report: Default::default(),
+            max_archive_size: options.max_archive_size,
+            archive_id_strategy: options.archive_id_strategy,
+            min_retained_archives: options.min_retained_archives,
+            remove_key_blocks: options.remove_key_blocks,
+            persist_current_archive_id: options.persist_current_archive_id
+                && options.archive_id_strategy == ArchiveIdStrategy::Seqno,
+            current_archive_id: AtomicU32::new(NO_CURRENT_ARCHIVE),
+            current_archive_id_store_lock: Default::default(),
+            archive_sizes: Default::default(),
+            merged_archive_ids: Default::default(),
+            archive_seal_seqnos: Default::default(),
+            archive_events: options.event_log_retention_sec.map(ArchiveEventLog::new),
+            archive_write_limiter: options.max_archive_write_rate.map(ArchiveWriteLimiter::new),
+            archiving_semaphore: ArchivingSemaphore::new(options.max_concurrent_archiving),
+            archiving_stopped: AtomicBool::new(false),
+            data_lock_metrics: Default::default(),
+            lifetime_counters,
+            slow_archive_lock_threshold: options.slow_archive_lock_threshold,
+            gc_readahead_size: options.gc_readahead_size,
+            entries_gc_lock: Default::default(),
+            archiving_blocks: Arc::new(Mutex::new(archiving_blocks)),
+            package_entries_tombstones: AtomicU64::new(0),
+            last_package_entries_compaction: AtomicU32::new(0),
+            archiving_paused: AtomicBool::new(false),
+            paused_archiving: Mutex::new(paused_archiving),
         };
 
-        manager.preload()?;
+        manager.preload(options.preload_threads)?;
 
         Ok(manager)
     }
 
-    fn preload(&self) -> Result<()> {
-        fn check_archive(value: &[u8]) -> Result<(), ArchivePackageError> {
-            let mut verifier = ArchivePackageVerifier::default();
-            verifier.verify(value)?;
-            verifier.final_check()
-        }
-
+    fn preload(&self, threads: usize) -> Result<()> {
         let mut iter = self.db.archives.raw_iterator();
         iter.seek_to_first();
 
-        let mut archive_ids = self.archive_ids.write();
+        let mut archive_sizes = BTreeMap::new();
+        let composition = Mutex::new(CompositionReport::default());
 
-        while let (Some(key), value) = (iter.key(), iter.value()) {
-            let archive_id = u32::from_be_bytes(
-                key.try_into()
-                    .with_context(|| format!("Invalid archive key: {}", hex::encode(key)))?,
-            );
+        std::thread::scope(|scope| {
+            // NOTE: workers read archives themselves through pinned slices,
+            // so that only ids are sent and archives are not copied
+            let threads = threads.max(1);
+            let (tx, rx) = std::sync::mpsc::sync_channel::<u32>(threads);
+            let rx = Mutex::new(rx);
+
+            for _ in 0..threads {
+                let (rx, composition) = (&rx, &composition);
+                scope.spawn(move || loop {
+                    let archive_id = match rx.lock().recv() {
+                        Ok(item) => item,
+                        Err(_) => break,
+                    };
+
+                    let value = match self.db.archives.get(archive_id.to_be_bytes()) {
+                        Ok(Some(value)) => value,
+                        // NOTE: archives are not removed during preload
+                        Ok(None) => continue,
+                        Err(e) => {
+                            tracing::error!(archive_id, "failed to read archive: {e:?}");
+                            self.archive_ids.write().insert(archive_id);
+                            continue;
+                        }
+                    };
+
+                    match check_archive(&value) {
+                        Ok(()) => match ArchiveComposition::compute(&value) {
+                            Ok(archive) => composition.lock().add(archive_id, archive),
+                            Err(e) => {
+                                tracing::error!(archive_id, "failed to parse archive: {e:?}")
+                            }
+                        },
+                        Err(e) => tracing::error!(archive_id, "failed to read archive: {e:?}"),
+                    }
+
+                    self.archive_ids.write().insert(archive_id);
+                });
+            }
+
+            while let (Some(key), value) = (iter.key(), iter.value()) {
+                let archive_id = u32::from_be_bytes(
+                    key.try_into()
+                        .with_context(|| format!("Invalid archive key: {}", hex::encode(key)))?,
+                );
+
+                archive_sizes.insert(
+                    archive_id,
+                    value.map(<[u8]>::len).unwrap_or_default() as u64,
+                );
 
-            if let Some(Err(e)) = value.map(check_archive) {
-                tracing::error!(archive_id, "failed to read archive: {e:?}")
+                // NOTE: workers only stop when the sender is dropped
+                tx.send(archive_id).ok();
+                iter.next();
             }
 
-            archive_ids.insert(archive_id);
+            Ok::<_, anyhow::Error>(())
+        })?;
+
+        // NOTE: sizes are then updated with each change of the archives
+        *self.archive_sizes.lock() = archive_sizes;
+
+        self.preload_key_block_archive_ids()?;
+        self.reconcile_current_archive_id()?;
+
+        let composition = composition.into_inner();
+        if !composition.archives_without_blocks.is_empty() {
+            tracing::warn!(
+                archive_ids = ?composition.archives_without_blocks,
+                "found archives with proofs but without blocks"
+            );
+        }
+        tracing::info!(
+            blocks = composition.blocks,
+            proofs = composition.proofs,
+            proof_links = composition.proof_links,
+            unknown_entries = composition.unknown_entries,
+            "selfcheck complete"
+        );
+        *self.composition_report.lock() = composition;
+
+        Ok(())
+    }
+
+    fn preload_key_block_archive_ids(&self) -> Result<()> {
+        // NOTE: archives grouped by utime are not started by key blocks
+        if self.archive_id_strategy != ArchiveIdStrategy::Seqno {
+            return Ok(());
+        }
+
+        let archive_ids = self.archive_ids.read();
+        let mut key_block_archive_ids = self.key_block_archive_ids.write();
+
+        let mut iter = self.db.key_blocks.raw_iterator();
+        iter.seek_to_first();
+        while let Some(key) = iter.key() {
+            if let Ok(seqno) = key.try_into() {
+                let seqno = u32::from_be_bytes(seqno);
+                if archive_ids.contains(&seqno) {
+                    key_block_archive_ids.insert(seqno);
+                }
+            }
             iter.next();
         }
+        iter.status()?;
+
+        Ok(())
+    }
+
+    /// Restores the newest archive id from the db. The stored id is adopted
+    /// only if its archive exists, so an archive which was started, but has
+    /// no blocks yet, is started again by the next block
+    fn reconcile_current_archive_id(&self) -> Result<()> {
+        if !self.persist_current_archive_id {
+            return Ok(());
+        }
+
+        let stored = match self.db.node_states.get(CURRENT_ARCHIVE_ID)? {
+            Some(data) if data.len() >= 4 => {
+                Some(u32::from_le_bytes(data[..4].try_into().unwrap()))
+            }
+            _ => None,
+        };
+        let stored = match stored {
+            Some(id) if self.db.archives.get(id.to_be_bytes())?.is_some() => Some(id),
+            _ => None,
+        };
+
+        let mut archive_ids = self.archive_ids.write();
+        if let Some(stored) = stored {
+            if archive_ids
+                .last()
+                .map(|last| *last < stored)
+                .unwrap_or(true)
+            {
+                archive_ids.insert(stored);
+            }
+        }
+        let changed = self.update_current_archive_id(&archive_ids);
+        drop(archive_ids);
+
+        if changed {
+            self.store_current_archive_id();
+        }
 
-        tracing::info!("selfcheck complete");
         Ok(())
     }
 
+    /// Newest archive id if it is tracked
+    fn current_archive_id(&self) -> Option<u32> {
+        if !self.persist_current_archive_id {
+            return None;
+        }
+        match self.current_archive_id.load(Ordering::Acquire) {
+            NO_CURRENT_ARCHIVE => None,
+            id => Some(id),
+        }
+    }
+
+    /// Syncs the tracked newest archive id with the archive ids.
+    ///
+    /// Returns `true` if the id changed. The caller must then release the archive
+    /// ids lock and call [`BlockStorage::store_current_archive_id`]
+    #[must_use]
+    fn update_current_archive_id(&self, archive_ids: &BTreeSet<u32>) -> bool {
+        if !self.persist_current_archive_id {
+            return false;
+        }
+
+        let id = archive_ids.last().copied().unwrap_or(NO_CURRENT_ARCHIVE);
+        self.current_archive_id.swap(id, Ordering::AcqRel) != id
+    }
+
+    /// Writes the tracked newest archive id to the db
+    fn store_current_archive_id(&self) {
+        // NOTE: the id is loaded under the lock, so the newest id is always written last
+        let _guard = self.current_archive_id_store_lock.lock();
+
+        let id = self.current_archive_id.load(Ordering::Acquire);
+        let result = match id {
+            NO_CURRENT_ARCHIVE => self.db.node_states.remove(CURRENT_ARCHIVE_ID),
+            id => self
+                .db
+                .node_states
+                .insert(CURRENT_ARCHIVE_ID, id.to_le_bytes()),
+        };
+        if let Err(e) = result {
+            tracing::warn!(archive_id = id, "failed to store current archive id: {e:?}");
+        }
+    }
+
     pub async fn store_block_data(
         &self,
         block: &BlockStuffAug,
@@ -85,7 +362,10 @@ impl BlockStorage {
         if !handle.meta().has_data() {
             let data = block.new_archive_data()?;
 
-            let _lock = handle.block_data_lock().write().await;
+            let _lock = self
+                .data_lock_metrics
+                .timed(DataLockKind::Block, handle.block_data_lock().write())
+                .await;
             if !handle.meta().has_data() {
                 self.add_data(&archive_id, data)?;
                 if handle.meta().set_has_data() {
@@ -126,6 +406,41 @@ impl BlockStorage {
             .await
     }
 
+    /// Removes block data from the package entries and clears the data flag
+    /// of the handle, the proof is kept.
+    ///
+    /// NOTE: archived data is not affected
+    pub async fn remove_block_data(&self, handle: &BlockHandle) -> Result<()> {
+        // NOTE: acquired before the handle data lock, same as while archiving
+        let _gc_guard = self.entries_gc_lock.read().await;
+        let _lock = self
+            .data_lock_metrics
+            .timed(DataLockKind::Block, handle.block_data_lock().write())
+            .await;
+
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.delete_cf(
+            &self.db.package_entries.cf(),
+            PackageEntryId::Block(handle.id()).to_vec(),
+        );
+        let cleared = handle.meta().clear_has_data();
+        if cleared {
+            batch.put_cf(
+                &self.db.block_handles.cf(),
+                handle.id().root_hash.as_slice(),
+                handle.meta().to_vec(),
+            );
+        }
+
+        if let Err(e) = self.db.raw().write(batch) {
+            if cleared {
+                handle.meta().set_has_data();
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
     pub async fn store_block_proof(
         &self,
         proof: &BlockProofStuffAug,
@@ -149,7 +464,10 @@ impl BlockStorage {
             if !handle.meta().has_proof_link() {
                 let data = proof.new_archive_data()?;
 
-                let _lock = handle.proof_data_lock().write().await;
+                let _lock = self
+                    .data_lock_metrics
+                    .timed(DataLockKind::Proof, handle.proof_data_lock().write())
+                    .await;
                 if !handle.meta().has_proof_link() {
                     self.add_data(&archive_id, data)?;
                     if handle.meta().set_has_proof_link() {
@@ -163,7 +481,10 @@ impl BlockStorage {
             if !handle.meta().has_proof() {
                 let data = proof.new_archive_data()?;
 
-                let _lock = handle.proof_data_lock().write().await;
+                let _lock = self
+                    .data_lock_metrics
+                    .timed(DataLockKind::Proof, handle.proof_data_lock().write())
+                    .await;
                 if !handle.meta().has_proof() {
                     self.add_data(&archive_id, data)?;
                     if handle.meta().set_has_proof() {
@@ -239,13 +560,197 @@ impl BlockStorage {
     }
 
     pub async fn move_into_archive(&self, handle: &BlockHandle) -> Result<()> {
-        if handle.meta().is_archived() {
+        self.move_into_archive_impl(handle).await?;
+        Ok(())
+    }
+
+    /// Same as [`BlockStorage::move_into_archive`], but returns the id
+    /// of the archive which the block was moved into.
+    ///
+    /// Returns `None` if the block was already archived or is being moved concurrently
+    pub async fn move_into_archive_ret(&self, handle: &BlockHandle) -> Result<Option<u32>> {
+        Ok(match self.move_into_archive_impl(handle).await? {
+            MoveIntoArchiveStatus::Moved { archive_id } => Some(archive_id),
+            MoveIntoArchiveStatus::AlreadyArchived
+            | MoveIntoArchiveStatus::InProgress
+            | MoveIntoArchiveStatus::Paused => None,
+        })
+    }
+
+    /// Moves many handles into archives with at most `concurrency` handles
+    /// processed at the same time
+    pub async fn move_batch_into_archive(
+        &self,
+        handles: &[Arc<BlockHandle>],
+        concurrency: usize,
+    ) -> Result<MoveIntoArchiveStats> {
+        use futures_util::{StreamExt, TryStreamExt};
+
+        let mut stats = MoveIntoArchiveStats::default();
+        let mut results = futures_util::stream::iter(handles)
+            .map(|handle| self.move_into_archive_impl(handle))
+            .buffer_unordered(std::cmp::max(concurrency, 1));
+
+        while let Some(status) = results.try_next().await? {
+            match status {
+                MoveIntoArchiveStatus::Moved { .. } => stats.archived += 1,
+                MoveIntoArchiveStatus::Paused => stats.paused += 1,
+                MoveIntoArchiveStatus::AlreadyArchived => stats.already_archived += 1,
+                MoveIntoArchiveStatus::InProgress => stats.in_progress += 1,
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Pauses or resumes archiving of new blocks.
+    ///
+    /// While paused, [`BlockStorage::move_into_archive`] only remembers the blocks,
+    /// which must be archived later with [`BlockStorage::drain_pending_archiving`].
+    /// Remembered blocks are persisted and their entries are kept by blocks GC.
+    ///
+    /// NOTE: at most [`MAX_PAUSED_ARCHIVING_BLOCKS`] blocks are remembered,
+    /// then moving into archive fails until archiving is resumed
+    pub fn set_archiving_paused(&self, paused: bool) {
+        self.archiving_paused.store(paused, Ordering::Release);
+    }
+
+    pub fn is_archiving_paused(&self) -> bool {
+        self.archiving_paused.load(Ordering::Acquire)
+    }
+
+    /// Archives blocks which were skipped while archiving was paused.
+    ///
+    /// NOTE: blocks stay remembered until they are archived
+    pub async fn drain_pending_archiving(
+        &self,
+        concurrency: usize,
+    ) -> Result<MoveIntoArchiveStats> {
+        let block_ids = self
+            .paused_archiving
+            .lock()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut handles = Vec::with_capacity(block_ids.len());
+        let mut forgotten = Vec::new();
+        for block_id in block_ids {
+            match self.block_handle_storage.load_handle(&block_id)? {
+                Some(handle) => handles.push(handle),
+                None => {
+                    tracing::warn!(
+                        block_id = %block_id.display(),
+                        "block handle not found while draining paused archiving"
+                    );
+                    forgotten.push(*block_id.root_hash.as_array());
+                }
+            }
+        }
+
+        // NOTE: archive ids depend on the order of archived blocks
+        handles.sort_by_key(|handle| handle.masterchain_ref_seqno());
+
+        let result = self.move_batch_into_archive(&handles, concurrency).await;
+
+        {
+            let mut paused_archiving = self.paused_archiving.lock();
+            let mut archiving_blocks = self.archiving_blocks.lock();
+            for handle in &handles {
+                let root_hash = *handle.id().root_hash.as_array();
+                if handle.meta().is_archived() {
+                    forgotten.push(root_hash);
+                } else if paused_archiving.contains_key(&root_hash) {
+                    // NOTE: the failed move could release the protection from blocks GC
+                    archiving_blocks.insert(root_hash);
+                }
+            }
+
+            let node_states_cf = self.db.node_states.cf();
+            let mut batch = rocksdb::WriteBatch::default();
+            for root_hash in &forgotten {
+                paused_archiving.remove(root_hash);
+                archiving_blocks.remove(root_hash);
+                batch.delete_cf(&node_states_cf, paused_archiving_key(root_hash));
+            }
+            self.db.raw().write(batch)?;
+        }
+
+        let stats = result?;
+        tracing::info!(
+            archived = stats.archived,
+            paused = stats.paused,
+            "drained paused archiving"
+        );
+        Ok(stats)
+    }
+
+    /// Remembers the block which was skipped while archiving is paused
+    fn remember_paused_archiving(&self, block_id: &ton_block::BlockIdExt) -> Result<()> {
+        let root_hash = *block_id.root_hash.as_array();
+
+        let mut paused_archiving = self.paused_archiving.lock();
+        if paused_archiving.contains_key(&root_hash) {
             return Ok(());
         }
+        if paused_archiving.len() >= MAX_PAUSED_ARCHIVING_BLOCKS {
+            return Err(BlockStorageError::TooManyPausedBlocks.into());
+        }
+
+        self.db
+            .node_states
+            .insert(paused_archiving_key(&root_hash), block_id.to_vec())?;
+        self.archiving_blocks.lock().insert(root_hash);
+        paused_archiving.insert(root_hash, block_id.clone());
+
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            block_id = %handle.id().display(),
+            mc_seq_no = handle.masterchain_ref_seqno(),
+            archive_id = tracing::field::Empty,
+        )
+    )]
+    async fn move_into_archive_impl(&self, handle: &BlockHandle) -> Result<MoveIntoArchiveStatus> {
+        if handle.meta().is_archived() {
+            return Ok(MoveIntoArchiveStatus::AlreadyArchived);
+        }
+        if self.archiving_stopped.load(Ordering::Acquire) {
+            return Err(BlockStorageError::ArchivingStopped.into());
+        }
+        if self.archiving_paused.load(Ordering::Acquire) {
+            // NOTE: the moving flag is not set, so the block can be archived later
+            self.remember_paused_archiving(handle.id())?;
+            return Ok(MoveIntoArchiveStatus::Paused);
+        }
+
+        // NOTE: blocks wait for the permit instead of failing. It is acquired
+        // before the moving flag is set, so that a cancelled wait doesn't leave
+        // the block stuck. The semaphore is closed on shutdown
+        let _archiving_permit = self
+            .archiving_semaphore
+            .inner
+            .acquire()
+            .await
+            .map_err(|_| BlockStorageError::ArchivingStopped)?;
+
         if !handle.meta().set_is_moving_to_archive() {
-            return Ok(());
+            // Either moved concurrently or stuck after an interrupted move
+            return Ok(MoveIntoArchiveStatus::InProgress);
+        }
+        let _archiving_guard = ArchivingGuard::new(&self.archiving_blocks, handle);
+
+        // NOTE: wait before acquiring the handle data locks
+        if let Some(limiter) = &self.archive_write_limiter {
+            limiter.wait().await;
         }
 
+        // NOTE: held until the archive is updated
+        let _gc_guard = self.entries_gc_lock.read().await;
+
         // Prepare data
         let block_id = handle.id();
 
@@ -254,7 +759,10 @@ impl BlockStorage {
         let has_proof = handle.has_proof_or_link(&mut is_link);
 
         let block_data = if has_data {
-            let lock = handle.block_data_lock().write().await;
+            let lock = self
+                .data_lock_metrics
+                .timed(DataLockKind::Block, handle.block_data_lock().write())
+                .await;
 
             let entry_id = PackageEntryId::Block(block_id);
             let data = self.make_archive_segment(&entry_id)?;
@@ -265,7 +773,10 @@ impl BlockStorage {
         };
 
         let block_proof_data = if has_proof {
-            let lock = handle.proof_data_lock().write().await;
+            let lock = self
+                .data_lock_metrics
+                .timed(DataLockKind::Proof, handle.proof_data_lock().write())
+                .await;
 
             let entry_id = if is_link {
                 PackageEntryId::ProofLink(block_id)
@@ -279,19 +790,40 @@ impl BlockStorage {
             None
         };
 
+        // NOTE: approximate, the block data lock could be acquired a bit earlier
+        let locked_at = Instant::now();
+
         // Prepare cf
         let storage_cf = self.db.archives.cf();
         let handle_cf = self.db.block_handles.cf();
 
         // Prepare archive
-        let archive_id = self.compute_archive_id(handle);
+        let data_size = [&block_data, &block_proof_data]
+            .into_iter()
+            .flatten()
+            .map(|(_, data)| data.len() as u64)
+            .sum();
+        let archive_id = self.compute_checked_archive_id(handle)?;
         let archive_id_bytes = archive_id.to_be_bytes();
+        tracing::Span::current().record("archive_id", archive_id);
+
+        // NOTE: held until the transaction is executed, so that segments
+        // are appended in the same order as their offsets are computed
+        let mut archive_sizes = self.archive_sizes.lock();
+        let archive_size = self.archive_size(&mut archive_sizes, archive_id)?;
 
         // 0. Create transaction
         let mut batch = rocksdb::WriteBatch::default();
         // 1. Append archive segment with block data
         if let Some((_, data)) = &block_data {
             batch.merge_cf(&storage_cf, archive_id_bytes, data);
+            self.put_archive_index_entry(
+                &mut batch,
+                &block_id.root_hash,
+                archive_id,
+                archive_size,
+                data,
+            );
         }
         // 2. Append archive segment with block proof data
         if let Some((_, data)) = &block_proof_data {
@@ -305,16 +837,51 @@ impl BlockStorage {
                 handle.meta().to_vec(),
             );
         }
+        // 4. Record archive event
+        self.log_archive_event(
+            &mut batch,
+            ArchiveEventKind::BlockMoved {
+                archive_id,
+                mc_seq_no: handle.masterchain_ref_seqno(),
+            },
+        );
         // 5. Execute transaction
         self.db.raw().write(batch)?;
+        archive_sizes.insert(archive_id, archive_size + data_size);
+        drop(archive_sizes);
+
+        if let Some(limiter) = &self.archive_write_limiter {
+            limiter.consume(data_size);
+        }
+        if self.lifetime_counters.add_archived(data_size) {
+            self.flush_metrics_logged();
+        }
+
+        if let Some(threshold) = self.slow_archive_lock_threshold {
+            let held = locked_at.elapsed();
+            if held > threshold {
+                tracing::warn!(
+                    held_ms = held.as_millis(),
+                    "handle data locks were held for too long while archiving"
+                );
+            }
+        }
 
         // Block will be removed after blocks gc
 
         // Done
-        Ok(())
+        Ok(MoveIntoArchiveStatus::Moved { archive_id })
     }
 
-    pub fn move_into_archive_with_data(
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            block_id = %handle.id().display(),
+            mc_seq_no = handle.masterchain_ref_seqno(),
+            archive_id = tracing::field::Empty,
+        )
+    )]
+    pub async fn move_into_archive_with_data(
         &self,
         handle: &BlockHandle,
         is_link: bool,
@@ -324,9 +891,22 @@ impl BlockStorage {
         if handle.meta().is_archived() {
             return Ok(());
         }
+        if self.archiving_stopped.load(Ordering::Acquire) {
+            return Err(BlockStorageError::ArchivingStopped.into());
+        }
+
+        // NOTE: shutdown waits for the permit (see `move_into_archive_impl`)
+        let _archiving_permit = self
+            .archiving_semaphore
+            .inner
+            .acquire()
+            .await
+            .map_err(|_| BlockStorageError::ArchivingStopped)?;
+
         if !handle.meta().set_is_moving_to_archive() {
             return Ok(());
         }
+        let _archiving_guard = ArchivingGuard::new(&self.archiving_blocks, handle);
 
         let block_id = handle.id();
 
@@ -334,31 +914,44 @@ impl BlockStorage {
         let archives_cf = self.db.archives.cf();
         let block_handles_cf = self.db.block_handles.cf();
 
+        let block_data =
+            make_archive_segment(&PackageEntryId::Block(handle.id()).filename(), block_data);
+        let block_proof_data = make_archive_segment(
+            &if is_link {
+                PackageEntryId::ProofLink(block_id)
+            } else {
+                PackageEntryId::Proof(block_id)
+            }
+            .filename(),
+            block_proof_data,
+        );
+
         // Prepare archive
-        let archive_id = self.compute_archive_id(handle);
+        let data_size = (block_data.len() + block_proof_data.len()) as u64;
+        let archive_id = self.compute_checked_archive_id(handle)?;
+
+        // NOTE: held until the batch is written (see `move_into_archive_impl`)
+        let mut archive_sizes = self.archive_sizes.lock();
+
+        // NOTE: this path doesn't hold the entries GC lock, so the archive
+        // could have been merged by defragmentation after its id was computed
+        let archive_id = self.resolve_merged_archive_id(archive_id);
         let archive_id_bytes = archive_id.to_be_bytes();
+        tracing::Span::current().record("archive_id", archive_id);
 
-        let mut batch = rocksdb::WriteBatch::default();
+        let archive_size = self.archive_size(&mut archive_sizes, archive_id)?;
 
-        batch.merge_cf(
-            &archives_cf,
-            archive_id_bytes,
-            make_archive_segment(&PackageEntryId::Block(handle.id()).filename(), block_data),
-        );
+        let mut batch = rocksdb::WriteBatch::default();
 
-        batch.merge_cf(
-            &archives_cf,
-            archive_id_bytes,
-            make_archive_segment(
-                &if is_link {
-                    PackageEntryId::ProofLink(block_id)
-                } else {
-                    PackageEntryId::Proof(block_id)
-                }
-                .filename(),
-                block_proof_data,
-            ),
+        self.put_archive_index_entry(
+            &mut batch,
+            &block_id.root_hash,
+            archive_id,
+            archive_size,
+            &block_data,
         );
+        batch.merge_cf(&archives_cf, archive_id_bytes, block_data);
+        batch.merge_cf(&archives_cf, archive_id_bytes, block_proof_data);
 
         if handle.meta().set_is_archived() {
             batch.put_cf(
@@ -368,453 +961,5966 @@ impl BlockStorage {
             );
         }
 
+        self.log_archive_event(
+            &mut batch,
+            ArchiveEventKind::BlockMoved {
+                archive_id,
+                mc_seq_no: handle.masterchain_ref_seqno(),
+            },
+        );
+
         self.db.raw().write(batch)?;
+        archive_sizes.insert(archive_id, archive_size + data_size);
+        drop(archive_sizes);
+        if self.lifetime_counters.add_archived(data_size) {
+            self.flush_metrics_logged();
+        }
 
         Ok(())
     }
 
+    /// Returns the id of the archive which contains blocks referenced by the specified
+    /// masterchain block.
+    ///
+    /// NOTE: always `None` for archives grouped by utime
     pub fn get_archive_id(&self, mc_seq_no: u32) -> Option<u32> {
-        match self.archive_ids.read().range(..=mc_seq_no).next_back() {
-            // NOTE: handles case when mc_seq_no is far in the future.
-            // However if there is a key block between `id` and `mc_seq_no`,
-            // this will return an archive without that specified block.
-            Some(id) if mc_seq_no < id + ARCHIVE_PACKAGE_SIZE => Some(*id),
-            _ => None,
+        if self.archive_id_strategy != ArchiveIdStrategy::Seqno {
+            return None;
+        }
+
+        let prev_id = match self.current_archive_id() {
+            Some(current) if current <= mc_seq_no => Some(current),
+            _ => self
+                .archive_ids
+                .read()
+                .range(..=mc_seq_no)
+                .next_back()
+                .copied(),
+        };
+
+        let id = match prev_id {
+            // NOTE: handles case when mc_seq_no is far in the future
+            Some(id) if mc_seq_no < id + ARCHIVE_PACKAGE_SIZE => id,
+            _ => return None,
+        };
+
+        // Blocks after the key block which was not archived yet
+        // belong to the future archive of that key block
+        match self.has_key_block_between(id, mc_seq_no) {
+            Ok(false) => Some(id),
+            Ok(true) => None,
+            Err(e) => {
+                tracing::error!(mc_seq_no, "failed to check key blocks: {e:?}");
+                None
+            }
         }
     }
 
-    #[allow(unused)]
-    pub fn get_archives(
-        &self,
-        range: impl RangeBounds<u32> + 'static,
-    ) -> impl Iterator<Item = (u32, Vec<u8>)> + '_ {
-        struct ArchivesIterator<'a> {
-            first: bool,
-            ids: (Bound<u32>, Bound<u32>),
-            iter: rocksdb::DBRawIterator<'a>,
+    /// Returns ids of the archives which contain blocks referenced by
+    /// the masterchain blocks in `[from, to]` (see [`BlockStorage::get_archive_id`]).
+    ///
+    /// NOTE: always empty for archives grouped by utime
+    pub fn archives_for_seqno_range(&self, from: u32, to: u32) -> Vec<u32> {
+        if self.archive_id_strategy != ArchiveIdStrategy::Seqno || from > to {
+            return Vec::new();
         }
 
-        impl<'a> Iterator for ArchivesIterator<'a> {
-            type Item = (u32, Vec<u8>);
+        let archive_ids = self.archive_ids.read();
 
-            fn next(&mut self) -> Option<Self::Item> {
-                if self.first {
-                    match self.ids.0 {
-                        Bound::Included(id) => {
-                            self.iter.seek(id.to_be_bytes());
-                        }
-                        Bound::Excluded(id) => {
-                            self.iter.seek((id + 1).to_be_bytes());
-                        }
-                        Bound::Unbounded => {
-                            self.iter.seek_to_first();
-                        }
-                    }
-                    self.first = false;
-                } else {
-                    self.iter.next();
-                }
-
-                match (self.iter.key(), self.iter.value()) {
-                    (Some(key), Some(value)) => {
-                        let id = u32::from_be_bytes(key.try_into().unwrap_or_default());
-                        match self.ids.1 {
-                            Bound::Included(bound_id) if id > bound_id => None,
-                            Bound::Excluded(bound_id) if id >= bound_id => None,
-                            _ => Some((id, value.to_vec())),
-                        }
-                    }
-                    _ => None,
-                }
+        // The archive which covers `from` could start before it
+        let first_id = archive_ids
+            .range(..=from)
+            .next_back()
+            .copied()
+            .unwrap_or(from);
+
+        let mut result = Vec::new();
+        let mut ids = archive_ids.range(first_id..=to).copied().peekable();
+        while let Some(id) = ids.next() {
+            let mut end = id.saturating_add(ARCHIVE_PACKAGE_SIZE);
+            if let Some(next_id) = ids.peek() {
+                end = std::cmp::min(end, *next_id);
+            }
+
+            if end > from {
+                result.push(id);
             }
         }
+        result
+    }
 
-        ArchivesIterator {
-            first: true,
-            ids: (range.start_bound().cloned(), range.end_bound().cloned()),
-            iter: self.db.archives.raw_iterator(),
+    /// Returns the `[start, end]` range of masterchain seqnos covered by the archive.
+    ///
+    /// NOTE: always `None` for archives grouped by utime
+    pub fn archive_seqno_range(&self, id: u32) -> Option<(u32, u32)> {
+        if self.archive_id_strategy != ArchiveIdStrategy::Seqno {
+            return None;
+        }
+
+        let archive_ids = self.archive_ids.read();
+        if !archive_ids.contains(&id) {
+            return None;
+        }
+
+        let mut end = id.saturating_add(ARCHIVE_PACKAGE_SIZE);
+        match archive_ids.range(id + 1..).next() {
+            Some(next_id) => end = std::cmp::min(end, *next_id),
+            // The last archive is also bounded by the key block which is not archived yet
+            None => match self.next_key_block_after(id) {
+                Ok(Some(key_block)) => end = std::cmp::min(end, key_block),
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!(id, "failed to check key blocks: {e:?}");
+                    return None;
+                }
+            },
         }
+
+        Some((id, end - 1))
     }
 
-    pub fn get_archive_slice(
-        &self,
-        id: u32,
-        offset: usize,
-        limit: usize,
-    ) -> Result<Option<Vec<u8>>> {
-        match self.db.archives.get(id.to_be_bytes())? {
-            Some(slice) if offset < slice.len() => {
-                let end = std::cmp::min(offset.saturating_add(limit), slice.len());
-                Ok(Some(slice[offset..end].to_vec()))
+    /// Returns seqnos of the known key blocks within the archive seqno range
+    /// (see [`BlockStorage::archive_seqno_range`]) without reading the archive
+    pub fn key_blocks_in_archive(&self, id: u32) -> Result<Vec<u32>> {
+        let (start, end) = self
+            .archive_seqno_range(id)
+            .ok_or(BlockStorageError::ArchiveNotFound)?;
+
+        let mut result = Vec::new();
+        let mut iter = self.db.key_blocks.raw_iterator();
+        iter.seek(start.to_be_bytes());
+        while let Some(key) = iter.key() {
+            let seqno = match key.try_into() {
+                Ok(seqno) => u32::from_be_bytes(seqno),
+                Err(_) => break,
+            };
+            if seqno > end {
+                break;
             }
-            Some(_) => Err(BlockStorageError::InvalidOffset.into()),
-            None => Ok(None),
+            result.push(seqno);
+            iter.next();
         }
+        iter.status()?;
+
+        Ok(result)
     }
 
-    pub async fn remove_outdated_blocks(
-        &self,
-        key_block_id: &ton_block::BlockIdExt,
-        max_blocks_per_batch: Option<usize>,
-        gc_type: BlocksGcKind,
-    ) -> Result<()> {
-        let _compaction_guard = self.db.delay_compaction().await;
+    /// Returns whether the archive was started by a key block
+    pub fn is_key_block_archive(&self, id: u32) -> bool {
+        self.key_block_archive_ids.read().contains(&id)
+    }
 
-        // Find target block
-        let target_block = match gc_type {
-            BlocksGcKind::BeforePreviousKeyBlock => self
-                .block_handle_storage
-                .find_prev_key_block(key_block_id.seq_no)?,
-            BlocksGcKind::BeforePreviousPersistentState => self
-                .block_handle_storage
-                .find_prev_persistent_key_block(key_block_id.seq_no)?,
-        };
+    /// Checks whether there is a known key block in `(from, to]`
+    fn has_key_block_between(&self, from: u32, to: u32) -> Result<bool> {
+        if from >= to {
+            return Ok(false);
+        }
 
-        // Load target block data
-        let top_blocks = match target_block {
-            Some(handle) if handle.meta().has_data() => {
-                tracing::info!(
-                    key_block_id = %key_block_id.display(),
-                    target_block_id = %handle.id().display(),
-                    "starting blocks GC",
-                );
-                self.load_block_data(&handle)
-                    .await
-                    .context("Failed to load target key block data")
-                    .and_then(|block_data| TopBlocks::from_mc_block(&block_data))
-                    .context("Failed to compute top blocks for target block")?
+        {
+            let archive_ids = self.archive_ids.read();
+            if self
+                .key_block_archive_ids
+                .read()
+                .range(from + 1..=to)
+                .next()
+                .is_some()
+            {
+                return Ok(true);
             }
-            _ => {
-                tracing::info!(
-                    key_block_id = %key_block_id.display(),
-                    "blocks GC skipped"
-                );
-                return Ok(());
+
+            // NOTE: blocks are archived in masterchain order, so only key blocks
+            // after the newest archive could be not archived yet
+            if archive_ids.range(from + 1..).next().is_some() {
+                return Ok(false);
             }
-        };
+        }
 
-        // Remove all expired entries
-        let total_cached_handles_removed = self.block_handle_storage.gc_handles_cache(&top_blocks);
+        Ok(matches!(self.next_key_block_after(from)?, Some(seqno) if seqno <= to))
+    }
 
-        let db = self.db.clone();
-        let BlockGcStats {
-            mc_package_entries_removed,
-            total_package_entries_removed,
-            total_handles_removed,
-        } = tokio::task::spawn_blocking(move || {
-            remove_blocks(db, max_blocks_per_batch, &top_blocks)
-        })
-        .await??;
+    /// Returns the seqno of the first known key block after `from`
+    fn next_key_block_after(&self, from: u32) -> Result<Option<u32>> {
+        let from = match from.checked_add(1) {
+            Some(from) => from,
+            None => return Ok(None),
+        };
 
-        tracing::info!(
-            key_block_id = %key_block_id.display(),
-            total_cached_handles_removed,
-            mc_package_entries_removed,
-            total_package_entries_removed,
-            total_handles_removed,
-            "finished blocks GC"
-        );
+        let mut iter = self.db.key_blocks.raw_iterator();
+        iter.seek(from.to_be_bytes());
+        if let Some(key) = iter.key() {
+            if let Ok(seqno) = key.try_into() {
+                return Ok(Some(u32::from_be_bytes(seqno)));
+            }
+        }
+        iter.status()?;
 
-        // Done
-        Ok(())
+        Ok(None)
     }
 
-    pub async fn remove_outdated_archives(&self, until_id: u32) -> Result<()> {
-        let _compaction_guard = self.db.delay_compaction().await;
+    /// Returns the id of the archive which contains blocks with the specified utime.
+    ///
+    /// NOTE: always `None` for archives grouped by seqno
+    pub fn get_archive_id_by_utime(&self, utime: u32) -> Option<u32> {
+        let bucket_sec = match self.archive_id_strategy {
+            ArchiveIdStrategy::Utime { bucket_sec } => bucket_sec,
+            ArchiveIdStrategy::Seqno => return None,
+        };
 
-        let mut archive_ids = self.archive_ids.write();
+        match self.archive_ids.read().range(..=utime).next_back() {
+            Some(id) if utime - id < bucket_sec.max(1) => Some(*id),
+            _ => None,
+        }
+    }
 
-        let retained_ids = match archive_ids.iter().rev().find(|&id| *id < until_id).cloned() {
-            // Splits `archive_ids` into two parts - [..until_id] and [until_id..]
-            // `archive_ids` will now contain [..until_id]
-            Some(until_id) => archive_ids.split_off(&until_id),
-            None => {
-                tracing::info!("archives GC: nothing to remove");
-                return Ok(());
+    /// Returns the archive id of the archived block (using the current strategy)
+    fn get_archive_id_for_meta(&self, meta: &BlockMeta) -> Option<u32> {
+        match self.archive_id_strategy {
+            ArchiveIdStrategy::Seqno => self.get_archive_id(meta.masterchain_ref_seqno()),
+            ArchiveIdStrategy::Utime { .. } => self.get_archive_id_by_utime(meta.gen_utime()),
+        }
+    }
+
+    /// Verifies the next archive after the last verified one (in a round-robin manner).
+    ///
+    /// Archives which failed verification are quarantined: they are no longer
+    /// served, but their data is not removed.
+    ///
+    /// Returns `None` if there are no archives to verify
+    pub fn verify_next_archive(&self) -> Result<Option<(u32, bool)>> {
+        // NOTE: the status lock is not held while the archive is read
+        let last_verified_id = self.archive_verification.lock().last_verified_id;
+
+        let archive_id = {
+            let archive_ids = self.archive_ids.read();
+            let next = match last_verified_id {
+                Some(id) => archive_ids
+                    .range((Bound::Excluded(id), Bound::Unbounded))
+                    .next(),
+                None => None,
+            };
+            match next.or_else(|| archive_ids.first()) {
+                Some(id) => *id,
+                None => return Ok(None),
             }
         };
-        // so we must swap maps to retain [until_id..] and get ids to remove
-        let removed_ids = std::mem::replace(&mut *archive_ids, retained_ids);
-
-        // Print removed range bounds and compute real `until_id`
-        let until_id = match (removed_ids.first(), removed_ids.last()) {
-            (Some(first), Some(last)) => {
-                let len = removed_ids.len();
-                tracing::info!(
-                    archive_count = len,
-                    first,
-                    last,
-                    "archives GC: removing archives"
-                );
 
-                match archive_ids.first() {
-                    Some(until_id) => *until_id,
-                    None => *last + 1,
+        let is_valid = match self.db.archives.get(archive_id.to_be_bytes())? {
+            Some(data) => match check_archive(&data) {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::error!(archive_id, "archive verification failed: {e:?}");
+                    false
                 }
-            }
-            _ => {
-                tracing::info!("archives GC: nothing to remove");
-                return Ok(());
-            }
+            },
+            // Archive was removed by GC
+            None => true,
         };
 
-        // Remove archives
-        let archives_cf = self.db.archives.cf();
-        let write_options = self.db.archives.write_config();
+        let mut state = self.archive_verification.lock();
+        state.last_verified_id = Some(archive_id);
+        state.verified_at.insert(archive_id, broxus_util::now());
 
-        self.db.raw().delete_range_cf_opt(
-            &archives_cf,
-            [0; 4],
-            until_id.to_be_bytes(),
-            write_options,
-        )?;
+        if !is_valid {
+            let mut archive_ids = self.archive_ids.write();
+            archive_ids.remove(&archive_id);
+            let changed = self.update_current_archive_id(&archive_ids);
+            drop(archive_ids);
+            state.quarantined_ids.insert(archive_id);
 
-        tracing::info!("archives GC: done");
-        Ok(())
-    }
+            if changed {
+                self.store_current_archive_id();
+            }
+        }
 
-    fn add_data<I>(&self, id: &PackageEntryId<I>, data: &[u8]) -> Result<(), rocksdb::Error>
-    where
-        I: Borrow<ton_block::BlockIdExt> + Hash,
-    {
-        self.db.package_entries.insert(id.to_vec(), data)
+        Ok(Some((archive_id, is_valid)))
     }
 
-    #[allow(dead_code)]
-    fn has_data<I>(&self, id: &PackageEntryId<I>) -> Result<bool, rocksdb::Error>
-    where
-        I: Borrow<ton_block::BlockIdExt> + Hash,
-    {
-        self.db.package_entries.contains_key(id.to_vec())
+    fn is_archive_quarantined(&self, id: u32) -> bool {
+        self.archive_verification
+            .lock()
+            .quarantined_ids
+            .contains(&id)
     }
 
-    async fn get_data<I>(&self, handle: &BlockHandle, id: &PackageEntryId<I>) -> Result<Vec<u8>>
-    where
-        I: Borrow<ton_block::BlockIdExt> + Hash,
-    {
-        let _lock = match &id {
-            PackageEntryId::Block(_) => handle.block_data_lock().read().await,
-            PackageEntryId::Proof(_) | PackageEntryId::ProofLink(_) => {
-                handle.proof_data_lock().read().await
-            }
-        };
+    /// Returns the progress of the background archives verification
+    pub fn archive_verification_status(&self) -> ArchiveVerificationStatus {
+        let state = self.archive_verification.lock();
+        let archive_ids = self.archive_ids.read();
 
-        match self.db.package_entries.get(id.to_vec())? {
-            Some(a) => Ok(a.to_vec()),
-            None => Err(BlockStorageError::InvalidBlockData.into()),
+        ArchiveVerificationStatus {
+            total_archives: archive_ids.len(),
+            verified_archives: state
+                .verified_at
+                .keys()
+                .filter(|id| archive_ids.contains(id))
+                .count(),
+            last_verified_id: state.last_verified_id,
+            verified_at: state.verified_at.clone(),
+            quarantined_ids: state.quarantined_ids.clone(),
         }
     }
 
-    async fn get_data_ref<'a, I>(
-        &'a self,
-        handle: &'a BlockHandle,
-        id: &PackageEntryId<I>,
-    ) -> Result<impl AsRef<[u8]> + 'a>
-    where
-        I: Borrow<ton_block::BlockIdExt> + Hash,
-    {
-        let lock = match id {
-            PackageEntryId::Block(_) => handle.block_data_lock().read().await,
-            PackageEntryId::Proof(_) | PackageEntryId::ProofLink(_) => {
-                handle.proof_data_lock().read().await
-            }
+    /// Returns a snapshot of the archive ids index, which is used to resolve
+    /// masterchain seqnos into archive ids (see [`BlockStorage::get_archive_id`])
+    pub fn dump_archive_state(&self) -> ArchiveStateDump {
+        let archive_ids = self.archive_ids.read().clone();
+        let key_block_archive_ids = self.key_block_archive_ids.read().clone();
+        let pinned_archive_ids = self.pinned_archive_ids.read().clone();
+
+        // Only the last archive can still grow
+        let open_archive = archive_ids.last().map(|&id| ArchiveOpenState {
+            id,
+            size: self
+                .archive_sizes
+                .lock()
+                .get(&id)
+                .copied()
+                .unwrap_or_default(),
+        });
+        let sealed_ids = match &open_archive {
+            Some(open) => archive_ids.range(..open.id).copied().collect(),
+            None => BTreeSet::new(),
         };
 
-        match self.db.package_entries.get(id.to_vec())? {
-            Some(data) => Ok(BlockContentsLock { _lock: lock, data }),
-            None => Err(BlockStorageError::InvalidBlockData.into()),
+        ArchiveStateDump {
+            archive_id_strategy: self.archive_id_strategy,
+            archive_ids,
+            sealed_ids,
+            key_block_archive_ids,
+            pinned_archive_ids,
+            open_archive,
+            persisted_current_id: self.current_archive_id(),
         }
     }
 
-    fn compute_archive_id(&self, handle: &BlockHandle) -> u32 {
-        let mc_seq_no = handle.masterchain_ref_seqno();
+    /// Returns recorded archive events starting from the specified unix time.
+    ///
+    /// NOTE: events are recorded only if the event log is enabled
+    pub fn archive_event_log(&self, from_unix: u32) -> Result<Vec<ArchiveEvent>> {
+        Ok(read_archive_events(&self.db, from_unix)?)
+    }
 
-        if handle.meta().is_key_block() {
-            self.archive_ids.write().insert(mc_seq_no);
-            return mc_seq_no;
+    /// Returns wait times of the handle data locks
+    pub fn data_locks_wait_metrics(&self) -> DataLocksWaitMetrics {
+        self.data_lock_metrics.snapshot()
+    }
+
+    /// Returns the number of blocks which are being moved into archives
+    pub fn archiving_permits_in_use(&self) -> usize {
+        let semaphore = &self.archiving_semaphore;
+        semaphore.permits - semaphore.inner.available_permits()
+    }
+
+    /// Stops archiving of new blocks and waits until the blocks which are
+    /// being moved into archives are written, so that no handles are left
+    /// with the moving flag after restart.
+    ///
+    /// NOTE: archiving can't be resumed after shutdown
+    pub async fn shutdown(&self) -> Result<()> {
+        if self.archiving_stopped.swap(true, Ordering::AcqRel) {
+            return Ok(());
         }
 
-        let mut archive_id = mc_seq_no - mc_seq_no % ARCHIVE_SLICE_SIZE;
+        // NOTE: moves which are already waiting for the permit are served first,
+        // the rest fail without setting the moving flag after the semaphore is closed
+        let semaphore = &self.archiving_semaphore;
+        let permits = semaphore
+            .inner
+            .acquire_many(semaphore.permits as u32)
+            .await?;
+        semaphore.inner.close();
+        drop(permits);
 
-        let prev_id = {
-            let latest_archives = self.archive_ids.read();
-            latest_archives.range(..=mc_seq_no).next_back().cloned()
-        };
+        self.db.raw().flush_wal(true)?;
+        self.flush_metrics()?;
 
-        if let Some(prev_id) = prev_id {
-            if archive_id < prev_id {
-                archive_id = prev_id;
-            }
-        }
+        tracing::info!("archiving stopped");
+        Ok(())
+    }
 
-        if mc_seq_no.saturating_sub(archive_id) >= ARCHIVE_PACKAGE_SIZE {
-            self.archive_ids.write().insert(mc_seq_no);
-            archive_id = mc_seq_no;
-        }
+    /// Returns counters accumulated over all runs of the node
+    pub fn lifetime_counters(&self) -> ArchiveLifetimeCounters {
+        self.lifetime_counters.snapshot()
+    }
 
-        archive_id
+    /// Stores the lifetime counters, so that they are restored after restart.
+    ///
+    /// NOTE: called after each blocks GC, periodically while archiving,
+    /// on shutdown and on drop, so only the latest counters are lost on crash
+    pub fn flush_metrics(&self) -> Result<()> {
+        self.lifetime_counters
+            .flush(&self.db.node_states, LIFETIME_COUNTERS)
     }
 
-    fn make_archive_segment<I>(&self, entry_id: &PackageEntryId<I>) -> Result<Vec<u8>>
-    where
-        I: Borrow<ton_block::BlockIdExt> + Hash,
-    {
-        match self.db.package_entries.get(entry_id.to_vec())? {
-            Some(data) => Ok(make_archive_segment(&entry_id.filename(), &data)),
-            None => Err(BlockStorageError::InvalidBlockData.into()),
+    /// Same as [`BlockStorage::flush_metrics`], but only logs the error,
+    /// since the counters are not worth failing the operation
+    fn flush_metrics_logged(&self) {
+        if let Err(e) = self.flush_metrics() {
+            tracing::warn!("failed to flush archive lifetime counters: {e:?}");
         }
     }
-}
 
-#[derive(Clone)]
-pub enum BlockProofHandle {
-    Existing(Arc<BlockHandle>),
-    New(BlockMetaData),
-}
+    /// Returns the number of stored archives
+    pub fn archive_count(&self) -> usize {
+        self.archive_ids.read().len()
+    }
 
-impl From<Arc<BlockHandle>> for BlockProofHandle {
-    fn from(handle: Arc<BlockHandle>) -> Self {
-        Self::Existing(handle)
+    /// Returns the oldest and the newest archive ids
+    pub fn archive_span(&self) -> Option<(u32, u32)> {
+        let archive_ids = self.archive_ids.read();
+        Some((*archive_ids.first()?, *archive_ids.last()?))
     }
-}
 
-impl From<BlockMetaData> for BlockProofHandle {
-    fn from(meta_data: BlockMetaData) -> Self {
-        Self::New(meta_data)
+    /// Returns id of the newest archive, which could still receive new blocks
+    pub fn current_open_archive_id(&self) -> Option<u32> {
+        self.archive_ids.read().last().copied()
     }
-}
 
-pub struct StoreBlockResult {
-    pub handle: Arc<BlockHandle>,
-    pub updated: bool,
-    pub new: bool,
-}
+    /// Returns whether the archive is older than the currently open one.
+    ///
+    /// NOTE: blocks which are archived out of order could still be appended
+    /// to the archive preceding the open one
+    pub fn is_archive_sealed(&self, id: u32) -> bool {
+        matches!(self.current_open_archive_id(), Some(open_id) if id < open_id)
+    }
 
-fn remove_blocks(
-    db: Arc<Db>,
-    max_blocks_per_batch: Option<usize>,
-    top_blocks: &TopBlocks,
-) -> Result<BlockGcStats> {
-    let mut stats = BlockGcStats::default();
+    /// Returns ids of the archives before and after the specified id.
+    ///
+    /// NOTE: the specified id itself doesn't need to exist
+    pub fn adjacent_archive_ids(&self, id: u32) -> (Option<u32>, Option<u32>) {
+        let archive_ids = self.archive_ids.read();
+        let prev = archive_ids.range(..id).next_back().copied();
+        let next = archive_ids
+            .range((Bound::Excluded(id), Bound::Unbounded))
+            .next()
+            .copied();
+        (prev, next)
+    }
 
-    let raw = db.raw().as_ref();
-    let package_entries_cf = db.package_entries.cf();
-    let block_handles_cf = db.block_handles.cf();
-    let key_blocks_cf = db.key_blocks.cf();
+    pub fn get_archives(
+        &self,
+        range: impl RangeBounds<u32> + 'static,
+    ) -> impl Iterator<Item = (u32, Vec<u8>)> + '_ {
+        ArchivesIterator::new(self.db.archives.raw_iterator(), range)
+    }
 
-    // Create batch
-    let mut batch = rocksdb::WriteBatch::default();
-    let mut batch_len = 0;
+    /// Calls `f` for each archive within the range one at a time.
+    ///
+    /// Archives are passed as pinned slices without copying, and only one archive
+    /// is held at once. Fails before calling `f` if the archive is larger than
+    /// `budget_bytes`, so consumers never process more than the budget at a time.
+    ///
+    /// Returns the number of processed archives
+    pub fn for_each_archive<F>(
+        &self,
+        range: impl RangeBounds<u32>,
+        budget_bytes: usize,
+        mut f: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(u32, &[u8]) -> Result<()>,
+    {
+        let ids = self
+            .archive_ids
+            .read()
+            .range(range)
+            .copied()
+            .collect::<Vec<_>>();
 
-    let package_entries_readopts = db.package_entries.new_read_config();
-    let key_blocks_readopts = db.key_blocks.new_read_config();
+        let mut processed = 0;
+        for id in ids {
+            // NOTE: archives removed by GC in the interim are skipped
+            let data = match self.db.archives.get(id.to_be_bytes())? {
+                Some(data) => data,
+                None => continue,
+            };
+            if data.len() > budget_bytes {
+                return Err(BlockStorageError::ArchiveExceedsBudget {
+                    id,
+                    size: data.len(),
+                    budget: budget_bytes,
+                }
+                .into());
+            }
 
-    // Iterate all entries and find expired items
-    let mut blocks_iter = raw.raw_iterator_cf_opt(&package_entries_cf, package_entries_readopts);
-    blocks_iter.seek_to_first();
+            f(id, &data)?;
+            processed += 1;
+        }
 
-    loop {
-        let key = match blocks_iter.key() {
-            Some(key) => key,
-            None => break blocks_iter.status()?,
+        Ok(processed)
+    }
+
+    /// Iterates archives starting from `from_id`. Each item contains the id
+    /// to resume the iteration from (`None` after the archive with the max id).
+    ///
+    /// NOTE: archives removed by GC in the interim are skipped on resume
+    pub fn get_archives_from(
+        &self,
+        from_id: u32,
+    ) -> impl Iterator<Item = (u32, Vec<u8>, Option<u32>)> + '_ {
+        self.get_archives(from_id..)
+            .map(|(id, data)| (id, data, id.checked_add(1)))
+    }
+
+    /// Iterates package entries of the shard within the seqno range
+    pub fn scan_shard_entries(
+        &self,
+        shard: ton_block::ShardIdent,
+        seqno_range: impl RangeBounds<u32>,
+    ) -> impl Iterator<Item = Result<(PackageEntryKey, Vec<u8>)>> + '_ {
+        let mut prefix = [0; 12];
+        prefix[..4].copy_from_slice(&shard.workchain_id().to_be_bytes());
+        prefix[4..].copy_from_slice(&shard.shard_prefix_with_tag().to_be_bytes());
+
+        let from = match seqno_range.start_bound() {
+            Bound::Included(seq_no) => Some(*seq_no),
+            Bound::Excluded(seq_no) => seq_no.checked_add(1),
+            Bound::Unbounded => Some(0),
         };
+        let to = seqno_range.end_bound().cloned();
 
-        // Read only prefix with shard ident and seqno
-        let (shard_ident, seq_no) = BlockIdShort::deserialize(&mut std::convert::identity(key))?;
+        let mut iter = self.db.package_entries.raw_iterator();
+        let mut first = true;
+        let mut finished = from.is_none();
 
-        // Don't gc latest blocks
-        if top_blocks.contains_shard_seq_no(&shard_ident, seq_no) {
-            blocks_iter.next();
-            continue;
-        }
+        std::iter::from_fn(move || {
+            if finished {
+                return None;
+            }
 
-        // Additionally check whether this item is a key block
-        if seq_no == 0
-            || shard_ident.is_masterchain()
-                && raw
-                    .get_pinned_cf_opt(&key_blocks_cf, seq_no.to_be_bytes(), &key_blocks_readopts)?
-                    .is_some()
-        {
-            // Don't remove key blocks
-            blocks_iter.next();
-            continue;
-        }
+            if first {
+                first = false;
+                let mut key = [0; 16];
+                key[..12].copy_from_slice(&prefix);
+                key[12..].copy_from_slice(&from.unwrap_or_default().to_be_bytes());
+                iter.seek(key);
+            } else {
+                iter.next();
+            }
 
-        // Add item to the batch
-        batch.delete_cf(&package_entries_cf, key);
-        stats.total_package_entries_removed += 1;
-        if shard_ident.is_masterchain() {
-            stats.mc_package_entries_removed += 1;
-        }
+            let item = match (iter.key(), iter.value()) {
+                (Some(key), Some(value)) if key.starts_with(&prefix) => {
+                    match PackageEntryKey::from_slice(key) {
+                        Ok(key) => {
+                            let in_range = match to {
+                                Bound::Included(to) => key.seq_no <= to,
+                                Bound::Excluded(to) => key.seq_no < to,
+                                Bound::Unbounded => true,
+                            };
+                            in_range.then(|| Ok((key, PackageEntryMeta::strip(value).to_vec())))
+                        }
+                        Err(e) => Some(Err(e)),
+                    }
+                }
+                (Some(_), Some(_)) => None,
+                _ => iter.status().err().map(|e| Err(e.into())),
+            };
 
-        // Key structure:
-        // [workchain id, 4 bytes]
-        // [shard id, 8 bytes]
-        // [seqno, 4 bytes]
-        // [root hash, 32 bytes] <-
-        // ..
-        if key.len() >= 48 {
-            batch.delete_cf(&block_handles_cf, &key[16..48]);
-            stats.total_handles_removed += 1;
+            finished = !matches!(item, Some(Ok(_)));
+            item
+        })
+    }
+
+    /// Lists blocks which started moving into the archive, but were not archived.
+    ///
+    /// Ids of the uncached handles are reconstructed from the stored block data.
+    ///
+    /// NOTE: scans the whole package entries column if some handles are not cached,
+    /// handles without block data are skipped
+    pub fn pending_archiving(&self) -> Result<Vec<ton_block::BlockIdExt>> {
+        let (mut result, mut unresolved) = self.block_handle_storage.find_pending_archiving()?;
+
+        if !unresolved.is_empty() {
+            let mut iter = self.db.package_entries.raw_iterator();
+            iter.seek_to_first();
+            while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+                let key = PackageEntryKey::from_slice(key)?;
+                if key.kind == PackageEntryKind::Block && unresolved.remove(&key.root_hash) {
+                    result.push(ton_block::BlockIdExt {
+                        shard_id: key.shard_ident,
+                        seq_no: key.seq_no,
+                        root_hash: key.root_hash,
+                        file_hash: UInt256::calc_file_hash(PackageEntryMeta::strip(value)),
+                    });
+                    if unresolved.is_empty() {
+                        break;
+                    }
+                }
+                iter.next();
+            }
+            iter.status()?;
         }
 
-        batch_len += 1;
-        if matches!(
-            max_blocks_per_batch,
-            Some(max_blocks_per_batch) if batch_len >= max_blocks_per_batch
-        ) {
-            tracing::info!(
-                total_package_entries_removed = stats.total_package_entries_removed,
-                "applying intermediate batch",
+        if !unresolved.is_empty() {
+            tracing::warn!(
+                count = unresolved.len(),
+                "failed to resolve ids of blocks pending archiving"
             );
-            let batch = std::mem::take(&mut batch);
-            raw.write(batch)?;
-            batch_len = 0;
         }
 
-        blocks_iter.next();
+        result.sort_unstable_by_key(|id| {
+            (
+                id.shard_id.workchain_id(),
+                id.shard_id.shard_prefix_with_tag(),
+                id.seq_no,
+            )
+        });
+        Ok(result)
     }
 
-    if batch_len > 0 {
-        tracing::info!("applying final batch");
-        raw.write(batch)?;
+    /// Captures a consistent view of the archives column.
+    ///
+    /// NOTE: archives GC doesn't affect the returned snapshot
+    pub fn archives_snapshot(&self) -> ArchivesSnapshot<'_> {
+        let (archive_ids, snapshot) = self.consistent_archive_view();
+        ArchivesSnapshot {
+            db: &self.db,
+            snapshot,
+            archive_ids: archive_ids.into_iter().collect(),
+        }
     }
 
-    // Done
-    Ok(stats)
-}
+    /// Returns sorted archive ids and a db snapshot which contains all of them.
+    ///
+    /// The snapshot is created while holding the archive ids lock, and archives GC
+    /// holds the exclusive lock until the data is removed, so none of the returned
+    /// ids could be removed from the snapshot. Ids of the just started archives
+    /// could still have no data in it.
+    ///
+    /// NOTE: the snapshot borrows the storage and pins the state of all columns,
+    /// so removed data (including GC'ed archives) can't be compacted until
+    /// the snapshot is dropped. It must not be kept longer than the export
+    pub fn consistent_archive_view(&self) -> (Vec<u32>, rocksdb::Snapshot<'_>) {
+        let archive_ids = self.archive_ids.read();
+        let snapshot = self.db.raw().snapshot();
+        (archive_ids.iter().copied().collect(), snapshot)
+    }
 
-#[derive(Debug, Copy, Clone, Default)]
-pub struct BlockGcStats {
-    pub mc_package_entries_removed: usize,
-    pub total_package_entries_removed: usize,
-    pub total_handles_removed: usize,
-}
+    /// Loads info for each specified archive from a single db snapshot
+    pub fn archive_infos(&self, ids: &[u32]) -> Result<Vec<Option<ArchiveInfo>>> {
+        let raw = self.db.raw();
+        let archives_cf = self.db.archives.cf();
 
-struct BlockContentsLock<'a> {
-    _lock: tokio::sync::RwLockReadGuard<'a, ()>,
-    data: rocksdb::DBPinnableSlice<'a>,
-}
+        let snapshot = raw.snapshot();
+        let mut read_options = self.db.archives.new_read_config();
+        read_options.set_snapshot(&snapshot);
 
-impl<'a> AsRef<[u8]> for BlockContentsLock<'a> {
-    fn as_ref(&self) -> &[u8] {
-        self.data.as_ref()
+        let values = raw.multi_get_cf_opt(
+            ids.iter().map(|id| (&archives_cf, id.to_be_bytes())),
+            &read_options,
+        );
+
+        ids.iter()
+            .zip(values)
+            .map(|(&id, value)| {
+                Ok(value?.map(|data| {
+                    let (entry_count, result) = count_readable_archive_entries(&data);
+                    ArchiveInfo {
+                        id,
+                        byte_len: data.len(),
+                        entry_count,
+                        is_valid: result.is_ok(),
+                    }
+                }))
+            })
+            .collect()
     }
-}
 
-pub const ARCHIVE_PACKAGE_SIZE: u32 = 100;
-pub const ARCHIVE_SLICE_SIZE: u32 = 20_000;
+    /// Returns sizes in bytes of all stored archives.
+    ///
+    /// NOTE: sizes are tracked with each change of the archives,
+    /// so archives are not read
+    pub fn archive_byte_lens(&self) -> Result<BTreeMap<u32, u64>> {
+        // NOTE: archives GC removes ids under this lock,
+        // quarantined archives are not in the ids
+        let archive_ids = self.archive_ids.read();
+        let mut archive_sizes = self.archive_sizes.lock();
 
-#[derive(thiserror::Error, Debug)]
-enum BlockStorageError {
-    #[error("Block data not found")]
-    BlockDataNotFound,
-    #[error("Block proof not found")]
-    BlockProofNotFound,
-    #[error("Block handle id mismatch")]
-    BlockHandleIdMismatch,
-    #[error("Invalid block data")]
-    InvalidBlockData,
-    #[error("Offset is outside of the archive slice")]
-    InvalidOffset,
+        let mut result = BTreeMap::new();
+        for &id in archive_ids.iter() {
+            result.insert(id, self.archive_size(&mut archive_sizes, id)?);
+        }
+
+        Ok(result)
+    }
+
+    /// Counts entries of the archive.
+    ///
+    /// Returns `None` if the archive doesn't exist, or [`ArchivePackageError`]
+    /// if the archive is corrupted
+    pub fn archive_entry_count(&self, id: u32) -> Result<Option<usize>> {
+        match self.db.archives.get(id.to_be_bytes())? {
+            Some(data) => Ok(Some(count_archive_entries(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the filename and data of the archive entry with the specified index.
+    ///
+    /// Returns `None` if the archive doesn't exist or has fewer entries
+    pub fn get_archive_entry_by_index(
+        &self,
+        id: u32,
+        index: usize,
+    ) -> Result<Option<(String, Vec<u8>)>> {
+        let data = match self.db.archives.get(id.to_be_bytes())? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        let mut reader = ArchivePackageViewReader::new(&data)?;
+        let mut current = 0;
+        while let Some(entry) = reader.read_next()? {
+            if current == index {
+                return Ok(Some((entry.name.to_owned(), entry.data.to_vec())));
+            }
+            current += 1;
+        }
+
+        Ok(None)
+    }
+
+    /// Verifies the archive in fixed-size windows. Each window is read
+    /// from the same db snapshot and the pinned value is released
+    /// before the next one, so only a single window is kept in memory
+    pub fn verify_archive_streaming(&self, id: u32) -> Result<ArchiveVerifyReport> {
+        let raw = self.db.raw();
+        let archives_cf = self.db.archives.cf();
+
+        let snapshot = raw.snapshot();
+        let mut read_options = self.db.archives.new_read_config();
+        read_options.set_snapshot(&snapshot);
+
+        let mut verifier = UniqueArchiveEntriesVerifier::default();
+        let mut entry_count = 0;
+        let mut byte_len = None;
+        let mut offset = 0;
+        let result = loop {
+            let data = raw
+                .get_pinned_cf_opt(&archives_cf, id.to_be_bytes(), &read_options)?
+                .ok_or(BlockStorageError::ArchiveNotFound)?;
+            let len = *byte_len.get_or_insert(data.len());
+            if offset >= len {
+                break verifier.final_check();
+            }
+
+            let end = std::cmp::min(offset + ARCHIVE_VERIFY_WINDOW, len);
+            match verifier.verify_entries(&data[offset..end]) {
+                Ok(count) => entry_count += count,
+                Err(e) => break Err(e),
+            }
+            offset = end;
+        };
+
+        if let Err(e) = &result {
+            tracing::warn!(archive_id = id, "archive verification failed: {e:?}");
+        }
+
+        Ok(ArchiveVerifyReport {
+            id,
+            byte_len: byte_len.unwrap_or_default(),
+            entry_count,
+            is_valid: result.is_ok(),
+            duplicate_entry: match result {
+                Err(ArchivePackageError::DuplicateEntry { filename }) => Some(filename),
+                _ => None,
+            },
+        })
+    }
+
+    /// Returns the archives composition collected during preload.
+    ///
+    /// NOTE: archives written after startup are not included
+    pub fn composition_report(&self) -> CompositionReport {
+        self.composition_report.lock().clone()
+    }
+
+    /// Finds archives with fewer entries than expected.
+    ///
+    /// Returns archive id, found and expected entry counts for each incomplete archive.
+    /// Fails with the framing error of the first corrupted archive
+    pub fn find_incomplete_archives(
+        &self,
+        expected_per_archive: impl Fn(u32) -> usize,
+    ) -> Result<Vec<(u32, usize, usize)>> {
+        let mut result = Vec::new();
+        for (id, data) in self.get_archives(..) {
+            let found = count_archive_entries(&data)
+                .with_context(|| format!("Archive {id} is corrupted"))?;
+            let expected = expected_per_archive(id);
+            if found < expected {
+                result.push((id, found, expected));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Loads the entry data either from the package entries or from the archive
+    /// it was moved into.
+    ///
+    /// NOTE: doesn't acquire handle data locks
+    pub fn get_entry_anywhere<I>(&self, id: &PackageEntryId<I>) -> Result<Option<Vec<u8>>>
+    where
+        I: Borrow<ton_block::BlockIdExt> + Hash,
+    {
+        if let Some(data) = self.db.package_entries.get(id.to_vec())? {
+            return Ok(Some(PackageEntryMeta::strip(&data).to_vec()));
+        }
+
+        let block_id = match id {
+            PackageEntryId::Block(id)
+            | PackageEntryId::Proof(id)
+            | PackageEntryId::ProofLink(id) => id.borrow(),
+        };
+
+        let archive_id = match self.find_archive_id_for(block_id)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        let data = match self.db.archives.get(archive_id.to_be_bytes())? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        let filename = id.filename();
+        let mut reader = ArchivePackageViewReader::new(&data)?;
+        while let Some(entry) = reader.read_next()? {
+            if entry.name == filename {
+                return Ok(Some(entry.data.to_vec()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Finds the block data within its archive.
+    ///
+    /// Returns archive id, offset and length of the block data which
+    /// can be used with [`BlockStorage::get_archive_slice`]
+    pub fn locate_block_in_archive(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+    ) -> Result<Option<(u32, usize, usize)>> {
+        let archive_id = match self.find_archive_id_for(block_id)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        let data = match self.db.archives.get(archive_id.to_be_bytes())? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        let filename = PackageEntryId::Block(block_id).filename();
+        let mut reader = ArchivePackageViewReader::new(&data)?;
+        while let Some(entry) = reader.read_next()? {
+            if entry.name == filename {
+                let offset = entry.data.as_ptr() as usize - data.as_ptr() as usize;
+                return Ok(Some((archive_id, offset, entry.data.len())));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Finds the block data within its archive by the block root hash.
+    ///
+    /// Returns the same location as [`BlockStorage::locate_block_in_archive`].
+    /// The location is recorded when the block is moved into the archive.
+    ///
+    /// NOTE: entries of earlier versions have no location and are reported as
+    /// [`BlockStorageError::ArchiveIndexEntryNotLocated`] until
+    /// [`BlockStorage::reindex_archive_locations`] is called
+    pub fn locate_by_root_hash(&self, root_hash: &UInt256) -> Result<Option<(u32, usize, usize)>> {
+        let value = match self.db.archive_block_index.get(root_hash.as_slice())? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        match decode_archive_index_entry(&value) {
+            Some((archive_id, Some((offset, len)))) => {
+                Ok(Some((archive_id, offset as usize, len as usize)))
+            }
+            Some((_, None)) => Err(BlockStorageError::ArchiveIndexEntryNotLocated.into()),
+            None => Err(BlockStorageError::InvalidArchiveIndexEntry.into()),
+        }
+    }
+
+    /// Records the data location of the archive index entries written by earlier versions,
+    /// reading each archive once.
+    ///
+    /// Returns the number of updated entries
+    pub fn reindex_archive_locations(&self) -> Result<usize> {
+        // Collect entries without location
+        let mut outdated = BTreeMap::<u32, FastHashSet<[u8; 32]>>::new();
+        let mut read_options = self.db.archive_block_index.new_read_config();
+        read_options.set_total_order_seek(true);
+
+        let mut iter = self
+            .db
+            .raw()
+            .raw_iterator_cf_opt(&self.db.archive_block_index.cf(), read_options);
+        iter.seek_to_first();
+        while let Some((key, value)) = iter.item() {
+            if let (Ok(root_hash), Some((archive_id, None))) =
+                (<[u8; 32]>::try_from(key), decode_archive_index_entry(value))
+            {
+                outdated.entry(archive_id).or_default().insert(root_hash);
+            }
+            iter.next();
+        }
+        iter.status()?;
+        drop(iter);
+
+        let index_cf = self.db.archive_block_index.cf();
+        let hashes_cf = self.db.archive_block_hashes.cf();
+
+        let mut updated = 0;
+        for (archive_id, root_hashes) in outdated {
+            // NOTE: archives are append-only, so the offsets never change
+            let data = match self.db.archives.get(archive_id.to_be_bytes())? {
+                Some(data) => data,
+                None => continue,
+            };
+
+            let mut locations = Vec::with_capacity(root_hashes.len());
+            let mut reader = ArchivePackageViewReader::new(&data)?;
+            while let Some(entry) = reader.read_next()? {
+                if let PackageEntryId::Block(id) = PackageEntryId::from_filename(entry.name)? {
+                    let root_hash = id.root_hash.as_array();
+                    if root_hashes.contains(root_hash) {
+                        let offset = entry.data.as_ptr() as usize - data.as_ptr() as usize;
+                        locations.push((*root_hash, offset as u64, entry.data.len() as u64));
+                    }
+                }
+            }
+
+            // NOTE: archives GC and defragmentation update the index under the write lock
+            let archive_ids = self.archive_ids.read();
+            if !archive_ids.contains(&archive_id) {
+                continue;
+            }
+
+            let mut batch = rocksdb::WriteBatch::default();
+            for (root_hash, offset, len) in locations {
+                // Skip entries which were changed after they were collected
+                let value = self.db.archive_block_index.get(root_hash)?;
+                if !matches!(
+                    value.as_deref().and_then(decode_archive_index_entry),
+                    Some((id, None)) if id == archive_id
+                ) {
+                    continue;
+                }
+
+                batch.put_cf(
+                    &index_cf,
+                    root_hash,
+                    encode_archive_index_entry(archive_id, Some((offset, len))),
+                );
+                batch.put_cf(
+                    &hashes_cf,
+                    archive_block_hashes_key(archive_id, &root_hash),
+                    b"",
+                );
+                updated += 1;
+            }
+            self.db.raw().write(batch)?;
+            drop(archive_ids);
+        }
+
+        Ok(updated)
+    }
+
+    /// Computes the id of the archive which should contain the specified block
+    fn find_archive_id_for(&self, block_id: &ton_block::BlockIdExt) -> Result<Option<u32>> {
+        if let ArchiveIdStrategy::Utime { .. } = self.archive_id_strategy {
+            return Ok(match self.block_handle_storage.load_handle(block_id)? {
+                Some(handle) if handle.meta().is_archived() => {
+                    self.get_archive_id_for_meta(handle.meta())
+                }
+                _ => None,
+            });
+        }
+
+        // Shard blocks are archived by their masterchain ref seqno
+        let mc_seq_no = if block_id.shard_id.is_masterchain() {
+            block_id.seq_no
+        } else {
+            match self.block_handle_storage.load_handle(block_id)? {
+                Some(handle) if handle.meta().is_archived() => handle.masterchain_ref_seqno(),
+                _ => return Ok(None),
+            }
+        };
+
+        Ok(self.get_archive_id(mc_seq_no))
+    }
+
+    /// Describes each entry of the archive (filename, data range and its hash),
+    /// so that restored archives could be verified entry by entry.
+    ///
+    /// Returns `None` if the archive doesn't exist. Framing errors are
+    /// reported with the offset of the invalid entry
+    pub fn archive_manifest(&self, id: u32) -> Result<Option<ArchiveManifest>> {
+        use sha2::Digest;
+
+        let data = match self.db.archives.get(id.to_be_bytes())? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        let framing_error =
+            |offset, reason| BlockStorageError::InvalidArchiveFraming { id, offset, reason };
+
+        let mut reader = ArchivePackageViewReader::new(&data).map_err(|e| framing_error(0, e))?;
+        let mut entries = Vec::new();
+        loop {
+            let offset = reader.offset();
+            let entry = match reader.read_next().map_err(|e| framing_error(offset, e))? {
+                Some(entry) => entry,
+                None => break,
+            };
+
+            entries.push(ArchiveManifestEntry {
+                filename: entry.name.to_owned(),
+                data_offset: reader.offset() - entry.data.len(),
+                data_len: entry.data.len(),
+                data_hash: sha2::Sha256::digest(entry.data).into(),
+            });
+        }
+
+        // Trailing bytes which are not enough for the entry header
+        if reader.offset() != data.len() {
+            return Err(
+                framing_error(reader.offset(), ArchivePackageError::UnexpectedArchiveEof).into(),
+            );
+        }
+
+        Ok(Some(ArchiveManifest {
+            id,
+            byte_len: data.len(),
+            entries,
+        }))
+    }
+
+    /// Computes a hash over the archive entries which doesn't depend on their order,
+    /// so that archives with the same entries could be compared without transferring them.
+    ///
+    /// Entries are hashed as sorted `(filename, SHA-256 of data)` pairs, so repeated
+    /// entries change the fingerprint. Returns `None` if the archive doesn't exist
+    pub fn archive_fingerprint(&self, id: u32) -> Result<Option<[u8; 32]>> {
+        use sha2::Digest;
+
+        let mut entries = match self.archive_manifest(id)? {
+            Some(manifest) => manifest.entries,
+            None => return Ok(None),
+        };
+        entries
+            .sort_unstable_by(|a, b| (&a.filename, &a.data_hash).cmp(&(&b.filename, &b.data_hash)));
+
+        let mut hasher = sha2::Sha256::new();
+        hasher.update((entries.len() as u64).to_le_bytes());
+        for entry in entries {
+            hasher.update((entry.filename.len() as u32).to_le_bytes());
+            hasher.update(entry.filename.as_bytes());
+            hasher.update(entry.data_hash);
+        }
+
+        Ok(Some(hasher.finalize().into()))
+    }
+
+    /// Collects distinct shards of all entries in the archive
+    pub fn archive_shards(&self, id: u32) -> Result<Option<BTreeSet<ton_block::ShardIdent>>> {
+        let data = match self.db.archives.get(id.to_be_bytes())? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        let mut shards = BTreeSet::new();
+        let mut reader = ArchivePackageViewReader::new(&data)?;
+        while let Some(entry) = reader.read_next()? {
+            let block_id = match PackageEntryId::from_filename(entry.name)? {
+                PackageEntryId::Block(id)
+                | PackageEntryId::Proof(id)
+                | PackageEntryId::ProofLink(id) => id,
+            };
+            shards.insert(block_id.shard_id);
+        }
+
+        Ok(Some(shards))
+    }
+
+    pub fn get_archive_slice(
+        &self,
+        id: u32,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        match self.db.archives.get(id.to_be_bytes())? {
+            Some(slice) => match archive_slice_range(slice.len(), offset, limit) {
+                Some(range) => Ok(Some(slice[range].to_vec())),
+                None => Err(BlockStorageError::InvalidOffset.into()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Same as [`BlockStorage::get_archive_slice`], but additionally returns whether
+    /// the chunk reaches the end of the archive
+    pub fn get_archive_slice_ext(
+        &self,
+        id: u32,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Option<(Vec<u8>, bool)>> {
+        match self.db.archives.get(id.to_be_bytes())? {
+            Some(slice) => match archive_slice_range(slice.len(), offset, limit) {
+                Some(range) => {
+                    let is_last = range.end == slice.len();
+                    Ok(Some((slice[range].to_vec(), is_last)))
+                }
+                None => Err(BlockStorageError::InvalidOffset.into()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Checks that all handles marked as archived have their segments in the archive.
+    ///
+    /// NOTE: `sample` limits the number of checked archived handles
+    pub fn audit_archived_handles(&self, sample: Option<usize>) -> Result<ArchiveAuditReport> {
+        let mut report = ArchiveAuditReport::default();
+        let mut archives = FastHashMap::<u32, AuditedArchive>::default();
+
+        let block_handles_cf = self.db.block_handles.cf();
+        let mut read_options = self.db.block_handles.new_read_config();
+        read_options.set_total_order_seek(true);
+
+        let mut iter = self
+            .db
+            .raw()
+            .raw_iterator_cf_opt(&block_handles_cf, read_options);
+        iter.seek_to_first();
+
+        loop {
+            if matches!(sample, Some(sample) if report.checked_handles >= sample) {
+                break;
+            }
+
+            let (key, value) = match iter.item() {
+                Some(item) => item,
+                None => break iter.status()?,
+            };
+
+            let root_hash: [u8; 32] = match key.try_into() {
+                Ok(root_hash) => root_hash,
+                Err(_) => {
+                    iter.next();
+                    continue;
+                }
+            };
+
+            let meta = BlockMeta::from_slice(value)?;
+            if !meta.is_archived() {
+                iter.next();
+                continue;
+            }
+            report.checked_handles += 1;
+
+            let archive_id = self.get_archive_id_for_meta(&meta);
+            let archive = match archive_id {
+                Some(archive_id) => match archives.entry(archive_id) {
+                    hash_map::Entry::Occupied(entry) => Some(&*entry.into_mut()),
+                    hash_map::Entry::Vacant(entry) => {
+                        let archive = match self.db.archives.get(archive_id.to_be_bytes())? {
+                            Some(data) => AuditedArchive::read(data.as_ref()),
+                            None => AuditedArchive::Missing,
+                        };
+                        Some(&*entry.insert(archive))
+                    }
+                },
+                None => None,
+            };
+
+            let status = match archive {
+                None | Some(AuditedArchive::Missing) => {
+                    Some(BrokenArchivedHandleStatus::MissingArchive)
+                }
+                Some(AuditedArchive::Corrupted) => {
+                    Some(BrokenArchivedHandleStatus::CorruptedArchive)
+                }
+                Some(AuditedArchive::Readable { blocks, proofs }) => {
+                    let missing_block = meta.has_data() && !blocks.contains(&root_hash);
+                    let missing_proof =
+                        (meta.has_proof() || meta.has_proof_link()) && !proofs.contains(&root_hash);
+                    (missing_block || missing_proof).then(|| {
+                        BrokenArchivedHandleStatus::MissingEntries {
+                            missing_block,
+                            missing_proof,
+                        }
+                    })
+                }
+            };
+
+            if let Some(status) = status {
+                report.broken_handles.push(BrokenArchivedHandle {
+                    root_hash: UInt256::from(root_hash),
+                    archive_id,
+                    status,
+                });
+            }
+
+            iter.next();
+        }
+
+        Ok(report)
+    }
+
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            key_block_id = %key_block_id.display(),
+            ?max_blocks_per_batch,
+            ?gc_type,
+        )
+    )]
+    pub async fn remove_outdated_blocks(
+        &self,
+        key_block_id: &ton_block::BlockIdExt,
+        max_blocks_per_batch: Option<usize>,
+        gc_type: BlocksGcKind,
+        proofs_retention_sec: Option<u32>,
+        keep_proofs: bool,
+    ) -> Result<()> {
+        let _compaction_guard = self.db.delay_compaction().await;
+
+        // Find target block
+        let target_block = match gc_type {
+            BlocksGcKind::BeforePreviousKeyBlock => self
+                .block_handle_storage
+                .find_prev_key_block(key_block_id.seq_no)?,
+            BlocksGcKind::BeforePreviousPersistentState => self
+                .block_handle_storage
+                .find_prev_persistent_key_block(key_block_id.seq_no)?,
+        };
+
+        // Load target block data
+        let top_blocks = match target_block {
+            Some(handle) if handle.meta().has_data() => {
+                tracing::info!(
+                    key_block_id = %key_block_id.display(),
+                    target_block_id = %handle.id().display(),
+                    "starting blocks GC",
+                );
+                self.load_block_data(&handle)
+                    .await
+                    .context("Failed to load target key block data")
+                    .and_then(|block_data| TopBlocks::from_mc_block(&block_data))
+                    .context("Failed to compute top blocks for target block")?
+            }
+            _ => {
+                tracing::info!(
+                    key_block_id = %key_block_id.display(),
+                    "blocks GC skipped"
+                );
+                return Ok(());
+            }
+        };
+
+        // Remove all expired entries
+        let top_blocks = Arc::new(ArcSwap::from_pointee(top_blocks));
+        let BlockGcStats {
+            mc_package_entries_removed,
+            total_package_entries_removed,
+            total_handles_removed,
+            total_cached_handles_removed,
+            orphan_entries_removed,
+            retained_proof_entries,
+            in_flight_archive_entries,
+        } = self
+            .remove_blocks_impl(
+                top_blocks,
+                max_blocks_per_batch,
+                proofs_retention_sec,
+                keep_proofs,
+            )
+            .await?;
+
+        tracing::info!(
+            key_block_id = %key_block_id.display(),
+            total_cached_handles_removed,
+            mc_package_entries_removed,
+            total_package_entries_removed,
+            total_handles_removed,
+            orphan_entries_removed,
+            retained_proof_entries,
+            in_flight_archive_entries,
+            "finished blocks GC"
+        );
+
+        // Done
+        Ok(())
+    }
+
+    /// Removes all blocks before the specified top blocks (except key blocks)
+    #[tracing::instrument(
+        skip_all,
+        fields(mc_seq_no = top_blocks.mc_block.1, ?max_blocks_per_batch)
+    )]
+    pub async fn remove_blocks_before(
+        &self,
+        top_blocks: &TopBlocks,
+        max_blocks_per_batch: Option<usize>,
+        proofs_retention_sec: Option<u32>,
+        keep_proofs: bool,
+    ) -> Result<BlockGcStats> {
+        let _compaction_guard = self.db.delay_compaction().await;
+        self.remove_blocks_impl(
+            Arc::new(ArcSwap::from_pointee(top_blocks.clone())),
+            max_blocks_per_batch,
+            proofs_retention_sec,
+            keep_proofs,
+        )
+        .await
+    }
+
+    /// Same as [`BlockStorage::remove_blocks_before`], but the top blocks can be
+    /// replaced during the GC run (e.g. after a new masterchain block was applied).
+    ///
+    /// Top blocks are re-read at each batch boundary. Batches which contain blocks
+    /// protected by the updated top blocks are discarded and rescanned
+    #[tracing::instrument(skip_all, fields(?max_blocks_per_batch))]
+    pub async fn remove_blocks_before_shared(
+        &self,
+        top_blocks: Arc<ArcSwap<TopBlocks>>,
+        max_blocks_per_batch: Option<usize>,
+        proofs_retention_sec: Option<u32>,
+        keep_proofs: bool,
+    ) -> Result<BlockGcStats> {
+        let _compaction_guard = self.db.delay_compaction().await;
+        self.remove_blocks_impl(
+            top_blocks,
+            max_blocks_per_batch,
+            proofs_retention_sec,
+            keep_proofs,
+        )
+        .await
+    }
+
+    async fn remove_blocks_impl(
+        &self,
+        top_blocks: Arc<ArcSwap<TopBlocks>>,
+        max_blocks_per_batch: Option<usize>,
+        proofs_retention_sec: Option<u32>,
+        keep_proofs: bool,
+    ) -> Result<BlockGcStats> {
+        let total_cached_handles_removed = self
+            .block_handle_storage
+            .gc_handles_cache(&top_blocks.load());
+
+        let db = self.db.clone();
+        let gc_lock = self.entries_gc_lock.clone();
+        let archiving_blocks = self.archiving_blocks.clone();
+        let readahead_size = self.gc_readahead_size;
+        let span = tracing::Span::current();
+        let mut stats = tokio::task::spawn_blocking(move || {
+            let _span = span.enter();
+            remove_blocks(
+                db,
+                &gc_lock,
+                &archiving_blocks,
+                readahead_size,
+                max_blocks_per_batch,
+                proofs_retention_sec,
+                keep_proofs,
+                &top_blocks,
+            )
+        })
+        .await??;
+        stats.total_cached_handles_removed = total_cached_handles_removed;
+
+        let removed_entries =
+            (stats.total_package_entries_removed + stats.orphan_entries_removed) as u64;
+        self.package_entries_tombstones
+            .fetch_add(removed_entries, Ordering::Relaxed);
+        self.lifetime_counters
+            .add_gc_removed_entries(removed_entries);
+        self.flush_metrics_logged();
+
+        Ok(stats)
+    }
+
+    /// Estimated number of package entries tombstones written since the last compaction
+    pub fn package_entries_tombstones(&self) -> u64 {
+        self.package_entries_tombstones.load(Ordering::Relaxed)
+    }
+
+    /// Unix timestamp of the last package entries compaction
+    pub fn last_package_entries_compaction(&self) -> Option<u32> {
+        match self.last_package_entries_compaction.load(Ordering::Relaxed) {
+            0 => None,
+            at => Some(at),
+        }
+    }
+
+    /// Compacts package entries and resets the tombstones estimate
+    pub async fn compact_package_entries(&self) {
+        let tombstones = self.package_entries_tombstones.swap(0, Ordering::Relaxed);
+        tracing::info!(tombstones, "compacting package entries");
+
+        self.db.compact_package_entries().await;
+        self.last_package_entries_compaction
+            .store(broxus_util::now(), Ordering::Relaxed);
+    }
+
+    /// Estimates the number of package entries which would be removed by blocks GC.
+    ///
+    /// NOTE: Only `sample` entries at random positions are checked, nothing is deleted
+    pub fn estimate_gc(&self, top_blocks: &TopBlocks, sample: usize) -> Result<GcEstimate> {
+        estimate_blocks_gc(&self.db, top_blocks, sample)
+    }
+
+    /// Protects the archive from archives GC. Returns `false` if it was already pinned
+    pub fn pin_archive(&self, id: u32) -> bool {
+        self.pinned_archive_ids.write().insert(id)
+    }
+
+    /// Allows archives GC to remove the archive. Returns `false` if it was not pinned
+    pub fn unpin_archive(&self, id: u32) -> bool {
+        self.pinned_archive_ids.write().remove(&id)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn remove_outdated_archives(&self, until_id: u32) -> Result<()> {
+        let _compaction_guard = self.db.delay_compaction().await;
+
+        let mut archive_ids = self.archive_ids.write();
+
+        let removed_ids = self.outdated_archive_ids(&archive_ids, until_id);
+        for id in &removed_ids {
+            archive_ids.remove(id);
+        }
+
+        // Print removed range bounds and compute real `until_id`
+        let until_id = match (removed_ids.first(), removed_ids.last()) {
+            (Some(first), Some(last)) => {
+                let len = removed_ids.len();
+                tracing::info!(
+                    archive_count = len,
+                    first,
+                    last,
+                    "archives GC: removing archives"
+                );
+
+                match archive_ids.range(last..).next() {
+                    Some(until_id) => *until_id,
+                    None => *last + 1,
+                }
+            }
+            _ => {
+                tracing::info!("archives GC: nothing to remove");
+                return Ok(());
+            }
+        };
+
+        let current_id_changed = self.update_current_archive_id(&archive_ids);
+
+        // Pinned archives are the only ones left below the removed range end
+        let pinned_ids = archive_ids.range(..until_id).copied().collect::<Vec<_>>();
+        if !pinned_ids.is_empty() {
+            tracing::info!(?pinned_ids, "archives GC: skipping pinned archives");
+        }
+
+        self.key_block_archive_ids
+            .write()
+            .retain(|id| removed_ids.binary_search(id).is_err());
+
+        // Remove archives (all ranges between pinned archives)
+        let archives_cf = self.db.archives.cf();
+        let write_options = self.db.archives.write_config();
+
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut from = 0u32;
+        for pinned_id in pinned_ids.into_iter().filter(|id| *id < until_id) {
+            if from < pinned_id {
+                batch.delete_range_cf(&archives_cf, from.to_be_bytes(), pinned_id.to_be_bytes());
+            }
+            from = pinned_id + 1;
+        }
+        if from < until_id {
+            batch.delete_range_cf(&archives_cf, from.to_be_bytes(), until_id.to_be_bytes());
+        }
+
+        // Key blocks are removed only below the oldest retained (possibly pinned)
+        // archive, so that blocks GC still protects key blocks of retained archives
+        if self.remove_key_blocks && self.archive_id_strategy == ArchiveIdStrategy::Seqno {
+            let retained_from = archive_ids.iter().next().copied().unwrap_or(until_id);
+            if retained_from > 0 {
+                tracing::info!(
+                    until_seqno = retained_from,
+                    "archives GC: removing key blocks"
+                );
+                batch.delete_range_cf(
+                    &self.db.key_blocks.cf(),
+                    0u32.to_be_bytes(),
+                    retained_from.to_be_bytes(),
+                );
+            }
+        }
+
+        self.remove_archive_index_entries(&mut batch, removed_ids.iter().copied())?;
+
+        self.log_archive_event(&mut batch, ArchiveEventKind::Removed { until_id });
+
+        self.db.raw().write_opt(batch, write_options)?;
+        drop(archive_ids);
+
+        if current_id_changed {
+            self.store_current_archive_id();
+        }
+
+        let mut verification = self.archive_verification.lock();
+        verification.verified_at = verification.verified_at.split_off(&until_id);
+        verification.quarantined_ids = verification.quarantined_ids.split_off(&until_id);
+        drop(verification);
+
+        {
+            let mut archive_sizes = self.archive_sizes.lock();
+            *archive_sizes = archive_sizes.split_off(&until_id);
+            let mut archive_seal_seqnos = self.archive_seal_seqnos.lock();
+            *archive_seal_seqnos = archive_seal_seqnos.split_off(&until_id);
+        }
+
+        tracing::info!("archives GC: done");
+        Ok(())
+    }
+
+    /// Same as [`BlockStorage::remove_outdated_archives`], but removes archives
+    /// in chunks of `chunk_size` archives and releases the archive ids lock between them.
+    ///
+    /// Each chunk is removed from the archive ids under the same lock as its deletion
+    /// is committed. Returns `false` if the operation was cancelled, archives of the
+    /// already committed chunks stay removed
+    pub async fn remove_outdated_archives_chunked<F>(
+        &self,
+        until_id: u32,
+        chunk_size: usize,
+        cancellation: &CancellationToken,
+        mut progress: F,
+    ) -> Result<bool>
+    where
+        F: FnMut(ArchivesGcProgress),
+    {
+        let _compaction_guard = self.db.delay_compaction().await;
+
+        let removed_ids = self.outdated_archive_ids(&self.archive_ids.read(), until_id);
+        if removed_ids.is_empty() {
+            tracing::info!("archives GC: nothing to remove");
+            return Ok(true);
+        }
+
+        tracing::info!(
+            archive_count = removed_ids.len(),
+            first = removed_ids.first(),
+            last = removed_ids.last(),
+            "archives GC: removing archives in chunks"
+        );
+
+        let archives_cf = self.db.archives.cf();
+        let write_options = self.db.archives.write_config();
+
+        let mut removed_archives = 0;
+        for chunk in removed_ids.chunks(chunk_size.max(1)) {
+            if cancellation.is_cancelled() {
+                tracing::info!(removed_archives, "archives GC: cancelled");
+                return Ok(false);
+            }
+
+            // NOTE: ids are sorted and there are no gaps between the chunks
+            let chunk_until_id = chunk[chunk.len() - 1] + 1;
+
+            let mut batch = rocksdb::WriteBatch::default();
+            for id in chunk {
+                batch.delete_cf(&archives_cf, id.to_be_bytes());
+            }
+            self.remove_archive_index_entries(&mut batch, chunk.iter().copied())?;
+
+            {
+                let mut archive_ids = self.archive_ids.write();
+
+                // Key blocks are removed only below the oldest retained archive
+                if self.remove_key_blocks && self.archive_id_strategy == ArchiveIdStrategy::Seqno {
+                    let retained_from = archive_ids
+                        .iter()
+                        .find(|id| chunk.binary_search(*id).is_err())
+                        .copied()
+                        .unwrap_or(chunk_until_id);
+                    batch.delete_range_cf(
+                        &self.db.key_blocks.cf(),
+                        0u32.to_be_bytes(),
+                        retained_from.to_be_bytes(),
+                    );
+                }
+
+                self.log_archive_event(
+                    &mut batch,
+                    ArchiveEventKind::Removed {
+                        until_id: chunk_until_id,
+                    },
+                );
+
+                self.db.raw().write_opt(batch, write_options)?;
+
+                for id in chunk {
+                    archive_ids.remove(id);
+                }
+                let changed = self.update_current_archive_id(&archive_ids);
+                drop(archive_ids);
+
+                if changed {
+                    self.store_current_archive_id();
+                }
+            }
+
+            {
+                let mut key_block_archive_ids = self.key_block_archive_ids.write();
+                let mut verification = self.archive_verification.lock();
+                let mut archive_sizes = self.archive_sizes.lock();
+                for id in chunk {
+                    key_block_archive_ids.remove(id);
+                    verification.verified_at.remove(id);
+                    verification.quarantined_ids.remove(id);
+                    archive_sizes.remove(id);
+                }
+            }
+
+            removed_archives += chunk.len();
+            progress(ArchivesGcProgress {
+                removed_archives,
+                total_archives: removed_ids.len(),
+            });
+
+            tokio::task::yield_now().await;
+        }
+
+        tracing::info!("archives GC: done");
+        Ok(true)
+    }
+
+    /// Returns ids of the archives which would be removed by archives GC
+    fn outdated_archive_ids(&self, archive_ids: &BTreeSet<u32>, until_id: u32) -> Vec<u32> {
+        // Always keep at least `min_retained_archives` newest archives
+        let min_retained_id = match self.min_retained_archives.checked_sub(1) {
+            Some(n) => match archive_ids.iter().rev().nth(n) {
+                Some(id) => Some(*id),
+                None => return Vec::new(),
+            },
+            None => None,
+        };
+
+        let split_id = archive_ids
+            .iter()
+            .rev()
+            .find(|&id| *id < until_id)
+            .map(|id| match min_retained_id {
+                Some(min_retained_id) => std::cmp::min(*id, min_retained_id),
+                None => *id,
+            });
+
+        let split_id = match split_id {
+            Some(split_id) => split_id,
+            None => return Vec::new(),
+        };
+
+        let pinned_archive_ids = self.pinned_archive_ids.read();
+        archive_ids
+            .range(..split_id)
+            .filter(|id| !pinned_archive_ids.contains(id))
+            .copied()
+            .collect()
+    }
+
+    /// Adds the removal of the index entries of the specified archives to the batch
+    fn remove_archive_index_entries<I>(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        archive_ids: I,
+    ) -> Result<(), rocksdb::Error>
+    where
+        I: IntoIterator<Item = u32>,
+    {
+        let index_cf = self.db.archive_block_index.cf();
+        let hashes_cf = self.db.archive_block_hashes.cf();
+
+        for archive_id in archive_ids {
+            for root_hash in self.archive_block_hashes(archive_id)? {
+                batch.delete_cf(&index_cf, root_hash);
+            }
+
+            let (from, to) = archive_block_hashes_range(archive_id);
+            batch.delete_range_cf(&hashes_cf, from, to);
+        }
+
+        Ok(())
+    }
+
+    /// Returns root hashes of the indexed blocks of the archive
+    fn archive_block_hashes(&self, archive_id: u32) -> Result<Vec<[u8; 32]>, rocksdb::Error> {
+        let (from, to) = archive_block_hashes_range(archive_id);
+
+        let mut read_options = self.db.archive_block_hashes.new_read_config();
+        read_options.set_iterate_upper_bound(to);
+
+        let mut iter = self
+            .db
+            .raw()
+            .raw_iterator_cf_opt(&self.db.archive_block_hashes.cf(), read_options);
+        iter.seek(from);
+
+        let mut result = Vec::new();
+        while let Some(key) = iter.key() {
+            if let Ok(root_hash) = key[4..].try_into() {
+                result.push(root_hash);
+            }
+            iter.next();
+        }
+        iter.status()?;
+
+        Ok(result)
+    }
+
+    /// Merges runs of adjacent small archives into the first archive of each run.
+    ///
+    /// An archive is small if it has fewer than `min_entries` entries. Each merged
+    /// archive is limited by `max_result_bytes` and keeps the id of the first archive,
+    /// so that all merged blocks are still resolved by [`BlockStorage::get_archive_id`].
+    /// Archives started by key blocks always start a new run, and the last archive
+    /// and pinned archives are never merged.
+    ///
+    /// Each run is merged with a single batch. Runs which were changed while
+    /// the merged archive was built are skipped.
+    pub async fn defragment_archives(
+        &self,
+        min_entries: usize,
+        max_result_bytes: usize,
+    ) -> Result<DefragReport> {
+        let mut report = DefragReport::default();
+
+        // NOTE: utime buckets can't be merged without breaking the lookup
+        if self.archive_id_strategy != ArchiveIdStrategy::Seqno {
+            return Ok(report);
+        }
+
+        // NOTE: archiving holds the shared lock until the archive is updated
+        let _gc_guard = self.entries_gc_lock.write().await;
+
+        let archive_ids = self.archive_ids.read().clone();
+
+        // Find runs of small archives
+        let mut runs = Vec::new();
+        {
+            let pinned_archive_ids = self.pinned_archive_ids.read().clone();
+            let key_block_archive_ids = self.key_block_archive_ids.read().clone();
+
+            let mut run = Vec::<u32>::new();
+            let mut run_bytes = 0;
+            let mut ids = archive_ids.iter().copied().peekable();
+            while let Some(id) = ids.next() {
+                // NOTE: the last archive can still grow
+                let next_id = match ids.peek() {
+                    Some(next_id) => *next_id,
+                    None => break,
+                };
+
+                let small_len = match self.db.archives.get(id.to_be_bytes())? {
+                    Some(data) if !pinned_archive_ids.contains(&id) => {
+                        match count_archive_entries(&data) {
+                            Ok(entries) if entries < min_entries => Some(data.len()),
+                            // Skip large or corrupted archives
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+
+                match (small_len, run.first()) {
+                    // All blocks of the appended archive have `mc_seq_no < next_id`
+                    // NOTE: key block archives always start a new run
+                    (Some(len), Some(&first_id))
+                        if !key_block_archive_ids.contains(&id)
+                            && next_id <= first_id.saturating_add(ARCHIVE_PACKAGE_SIZE)
+                            && run_bytes + len.saturating_sub(ARCHIVE_PREFIX.len())
+                                <= max_result_bytes =>
+                    {
+                        run.push(id);
+                        run_bytes += len.saturating_sub(ARCHIVE_PREFIX.len());
+                    }
+                    _ => {
+                        if run.len() > 1 {
+                            runs.push(std::mem::take(&mut run));
+                        }
+                        run.clear();
+
+                        if let Some(len) = small_len.filter(|len| *len <= max_result_bytes) {
+                            run.push(id);
+                            run_bytes = len;
+                        }
+                    }
+                }
+            }
+
+            if run.len() > 1 {
+                runs.push(run);
+            }
+        }
+
+        if runs.is_empty() {
+            tracing::info!("archives defragmentation: nothing to merge");
+            return Ok(report);
+        }
+
+        // Merge runs
+        let archives_cf = self.db.archives.cf();
+        let index_cf = self.db.archive_block_index.cf();
+        let hashes_cf = self.db.archive_block_hashes.cf();
+
+        for run in runs {
+            let target_id = run[0];
+
+            // Build the merged archive without holding the archives lock
+            let mut batch = rocksdb::WriteBatch::default();
+            let mut sizes = Vec::with_capacity(run.len());
+
+            let mut data = Vec::new();
+            for id in &run {
+                let archive = self
+                    .db
+                    .archives
+                    .get(id.to_be_bytes())?
+                    .context("Archive removed during defragmentation")?;
+                sizes.push(archive.len() as u64);
+                if data.is_empty() {
+                    data.extend_from_slice(&archive);
+                    continue;
+                }
+
+                // Move index entries of the removed archive into the target archive
+                let segments = archive.strip_prefix(&ARCHIVE_PREFIX).unwrap_or(&archive);
+                let shift = data.len() as u64 - (archive.len() - segments.len()) as u64;
+                for root_hash in self.archive_block_hashes(*id)? {
+                    let location = self
+                        .db
+                        .archive_block_index
+                        .get(root_hash)?
+                        .and_then(|value| decode_archive_index_entry(&value))
+                        .and_then(|(_, location)| location)
+                        .map(|(offset, len)| (offset + shift, len));
+                    batch.put_cf(
+                        &index_cf,
+                        root_hash,
+                        encode_archive_index_entry(target_id, location),
+                    );
+                    batch.put_cf(
+                        &hashes_cf,
+                        archive_block_hashes_key(target_id, &root_hash),
+                        b"",
+                    );
+                }
+                let (from, to) = archive_block_hashes_range(*id);
+                batch.delete_range_cf(&hashes_cf, from, to);
+
+                data.extend_from_slice(segments);
+            }
+            let data_len = data.len();
+
+            batch.put_cf(&archives_cf, target_id.to_be_bytes(), data);
+            for id in &run[1..] {
+                batch.delete_cf(&archives_cf, id.to_be_bytes());
+            }
+
+            {
+                let mut archive_ids = self.archive_ids.write();
+                // NOTE: blocks are appended without the entries GC lock
+                // by `move_into_archive_with_data` under this lock
+                let mut archive_sizes = self.archive_sizes.lock();
+
+                // Skip runs which were changed by archives GC, pins or late blocks
+                let pinned_archive_ids = self.pinned_archive_ids.read();
+                let mut unchanged = !run.iter().any(|id| pinned_archive_ids.contains(id));
+                drop(pinned_archive_ids);
+                for (id, size) in run.iter().zip(&sizes) {
+                    unchanged &= archive_ids.contains(id)
+                        && self.archive_size(&mut archive_sizes, *id)? == *size;
+                }
+                if !unchanged {
+                    tracing::warn!(
+                        target_id,
+                        "archives defragmentation: archives changed while merging, skipping"
+                    );
+                    continue;
+                }
+
+                self.db.raw().write(batch)?;
+
+                let mut merged_archive_ids = self.merged_archive_ids.lock();
+                for id in &run[1..] {
+                    archive_ids.remove(id);
+                    archive_sizes.remove(id);
+                    merged_archive_ids.insert(*id, target_id);
+                }
+                archive_sizes.insert(target_id, data_len as u64);
+                drop(merged_archive_ids);
+
+                let changed = self.update_current_archive_id(&archive_ids);
+                drop(archive_sizes);
+                drop(archive_ids);
+
+                if changed {
+                    self.store_current_archive_id();
+                }
+            }
+
+            {
+                let mut verification = self.archive_verification.lock();
+                for id in &run {
+                    verification.verified_at.remove(id);
+                }
+            }
+
+            tracing::info!(
+                target_id,
+                archive_count = run.len(),
+                byte_len = data_len,
+                "archives defragmentation: merged archives"
+            );
+
+            report.merged_archives += 1;
+            report.removed_archives += run.len() - 1;
+            report.rewritten_bytes += data_len as u64;
+        }
+
+        Ok(report)
+    }
+
+    /// Returns the archive which received the data of the archive removed by
+    /// defragmentation, or the same id if the archive was not merged
+    fn resolve_merged_archive_id(&self, mut archive_id: u32) -> u32 {
+        let merged_archive_ids = self.merged_archive_ids.lock();
+        while let Some(target_id) = merged_archive_ids.get(&archive_id) {
+            archive_id = *target_id;
+        }
+        archive_id
+    }
+
+    fn add_data<I>(&self, id: &PackageEntryId<I>, data: &[u8]) -> Result<(), rocksdb::Error>
+    where
+        I: Borrow<ton_block::BlockIdExt> + Hash,
+    {
+        let value = PackageEntryMeta::new(id, broxus_util::now()).encode_with_data(data);
+        self.db.package_entries.insert(id.to_vec(), value)
+    }
+
+    #[allow(dead_code)]
+    fn has_data<I>(&self, id: &PackageEntryId<I>) -> Result<bool, rocksdb::Error>
+    where
+        I: Borrow<ton_block::BlockIdExt> + Hash,
+    {
+        self.db.package_entries.contains_key(id.to_vec())
+    }
+
+    async fn get_data<I>(&self, handle: &BlockHandle, id: &PackageEntryId<I>) -> Result<Vec<u8>>
+    where
+        I: Borrow<ton_block::BlockIdExt> + Hash,
+    {
+        let _lock = match &id {
+            PackageEntryId::Block(_) => {
+                self.data_lock_metrics
+                    .timed(DataLockKind::Block, handle.block_data_lock().read())
+                    .await
+            }
+            PackageEntryId::Proof(_) | PackageEntryId::ProofLink(_) => {
+                self.data_lock_metrics
+                    .timed(DataLockKind::Proof, handle.proof_data_lock().read())
+                    .await
+            }
+        };
+
+        match self.db.package_entries.get(id.to_vec())? {
+            Some(a) => Ok(PackageEntryMeta::strip(&a).to_vec()),
+            None => Err(BlockStorageError::InvalidBlockData.into()),
+        }
+    }
+
+    /// Reads the entry data without acquiring the handle data lock.
+    ///
+    /// NOTE: the entry could be concurrently written, moved into an archive
+    /// or removed by blocks GC, so the result is only suitable for diagnostics
+    pub fn get_data_unlocked<I>(&self, id: &PackageEntryId<I>) -> Result<Option<Vec<u8>>>
+    where
+        I: Borrow<ton_block::BlockIdExt> + Hash,
+    {
+        Ok(self
+            .db
+            .package_entries
+            .get(id.to_vec())?
+            .map(|data| PackageEntryMeta::strip(&data).to_vec()))
+    }
+
+    /// Reads the stored handle meta of the block with the specified root hash.
+    ///
+    /// NOTE: cached handles are not checked, so the meta could be outdated
+    pub fn load_handle_meta_by_root(&self, root_hash: &UInt256) -> Result<Option<BlockMeta>> {
+        match self.db.block_handles.get(root_hash.as_slice())? {
+            Some(value) => BlockMeta::from_slice(value.as_ref()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Streams the entry data in chunks of `chunk_size` bytes.
+    ///
+    /// NOTE: the corresponding handle data lock is held until the stream is dropped
+    pub async fn get_data_stream<'a, I>(
+        &'a self,
+        handle: &'a BlockHandle,
+        id: &PackageEntryId<I>,
+        chunk_size: usize,
+    ) -> Result<impl futures_util::Stream<Item = Result<bytes::Bytes>> + 'a>
+    where
+        I: Borrow<ton_block::BlockIdExt> + Hash,
+    {
+        let lock = match id {
+            PackageEntryId::Block(_) => {
+                self.data_lock_metrics
+                    .timed(DataLockKind::Block, handle.block_data_lock().read())
+                    .await
+            }
+            PackageEntryId::Proof(_) | PackageEntryId::ProofLink(_) => {
+                self.data_lock_metrics
+                    .timed(DataLockKind::Proof, handle.proof_data_lock().read())
+                    .await
+            }
+        };
+
+        match self.db.package_entries.get(id.to_vec())? {
+            Some(data) => Ok(BlockContentsStream {
+                offset: data.len() - PackageEntryMeta::strip(&data).len(),
+                chunk_size: chunk_size.max(1),
+                data: BlockContentsLock { _lock: lock, data },
+            }),
+            None => Err(BlockStorageError::InvalidBlockData.into()),
+        }
+    }
+
+    async fn get_data_ref<'a, I>(
+        &'a self,
+        handle: &'a BlockHandle,
+        id: &PackageEntryId<I>,
+    ) -> Result<impl AsRef<[u8]> + 'a>
+    where
+        I: Borrow<ton_block::BlockIdExt> + Hash,
+    {
+        let lock = match id {
+            PackageEntryId::Block(_) => {
+                self.data_lock_metrics
+                    .timed(DataLockKind::Block, handle.block_data_lock().read())
+                    .await
+            }
+            PackageEntryId::Proof(_) | PackageEntryId::ProofLink(_) => {
+                self.data_lock_metrics
+                    .timed(DataLockKind::Proof, handle.proof_data_lock().read())
+                    .await
+            }
+        };
+
+        match self.db.package_entries.get(id.to_vec())? {
+            Some(data) => Ok(BlockContentsLock { _lock: lock, data }),
+            None => Err(BlockStorageError::InvalidBlockData.into()),
+        }
+    }
+
+    /// Selects an archive for the block.
+    ///
+    /// NOTE: resets the `is_moving_to_archive` flag on error, so that archiving can be retried
+    fn compute_checked_archive_id(&self, handle: &BlockHandle) -> Result<u32> {
+        let archive_id = self.compute_archive_id_impl(handle);
+        if let Err(e) = self.check_archive_id(archive_id, handle.masterchain_ref_seqno()) {
+            handle.meta().clear_is_moving_to_archive();
+            return Err(e.into());
+        }
+
+        // NOTE: quarantined archives must not receive new segments
+        if self.is_archive_quarantined(archive_id) {
+            handle.meta().clear_is_moving_to_archive();
+            return Err(BlockStorageError::ArchiveQuarantined { id: archive_id }.into());
+        }
+
+        Ok(archive_id)
+    }
+
+    /// Returns the stored size of the archive, including its header.
+    ///
+    /// NOTE: sizes of archives which are not tracked yet are read from the db
+    fn archive_size(
+        &self,
+        archive_sizes: &mut BTreeMap<u32, u64>,
+        archive_id: u32,
+    ) -> Result<u64, rocksdb::Error> {
+        if let Some(size) = archive_sizes.get(&archive_id) {
+            return Ok(*size);
+        }
+
+        // NOTE: the header is added with the first segment
+        let size = match self.db.archives.get(archive_id.to_be_bytes())? {
+            Some(data) => data.len(),
+            None => ARCHIVE_PREFIX.len(),
+        } as u64;
+        archive_sizes.insert(archive_id, size);
+        Ok(size)
+    }
+
+    /// Adds the location of the block data segment, which is appended
+    /// to the archive at `segment_offset`, into the archive block index
+    fn put_archive_index_entry(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        root_hash: &UInt256,
+        archive_id: u32,
+        segment_offset: u64,
+        segment: &[u8],
+    ) {
+        // NOTE: see `make_archive_segment` for the segment layout
+        let filename_len = u16::from_le_bytes([segment[2], segment[3]]) as u64;
+        let data_len = u32::from_le_bytes(segment[4..8].try_into().unwrap()) as u64;
+        let offset = segment_offset + 2 + 2 + 4 + filename_len;
+
+        batch.put_cf(
+            &self.db.archive_block_index.cf(),
+            root_hash.as_slice(),
+            encode_archive_index_entry(archive_id, Some((offset, data_len))),
+        );
+        batch.put_cf(
+            &self.db.archive_block_hashes.cf(),
+            archive_block_hashes_key(archive_id, root_hash.as_slice()),
+            b"",
+        );
+    }
+
+    /// Ensures that the block with the specified masterchain seqno
+    /// can be found in the archive (see [`BlockStorage::get_archive_id`])
+    fn check_archive_id(&self, archive_id: u32, mc_seq_no: u32) -> Result<(), BlockStorageError> {
+        if self.archive_id_strategy != ArchiveIdStrategy::Seqno {
+            return Ok(());
+        }
+
+        // NOTE: archives started concurrently between `archive_id` and `mc_seq_no`
+        // would shadow the block
+        let expected_id = self
+            .archive_ids
+            .read()
+            .range(..=mc_seq_no)
+            .next_back()
+            .copied();
+        match expected_id {
+            Some(id) if id == archive_id && mc_seq_no - id < ARCHIVE_PACKAGE_SIZE => Ok(()),
+            _ => Err(BlockStorageError::ArchiveIdMismatch {
+                archive_id,
+                mc_seq_no,
+            }),
+        }
+    }
+
+    fn compute_archive_id_impl(&self, handle: &BlockHandle) -> u32 {
+        if let ArchiveIdStrategy::Utime { bucket_sec } = self.archive_id_strategy {
+            let utime = handle.meta().gen_utime();
+            let archive_id = utime - utime % bucket_sec.max(1);
+            if !self.archive_ids.read().contains(&archive_id) {
+                self.start_archive(archive_id);
+            }
+            return archive_id;
+        }
+
+        let mc_seq_no = handle.masterchain_ref_seqno();
+
+        if handle.meta().is_key_block() {
+            self.key_block_archive_ids.write().insert(mc_seq_no);
+            self.start_archive(mc_seq_no);
+            return mc_seq_no;
+        }
+
+        let mut archive_id = mc_seq_no - mc_seq_no % ARCHIVE_SLICE_SIZE;
+
+        // NOTE: blocks are mostly archived in order, so the newest
+        // archive id is enough without scanning all ids
+        let prev_id = match self.current_archive_id() {
+            Some(current) if current <= mc_seq_no => Some(current),
+            _ => {
+                let latest_archives = self.archive_ids.read();
+                latest_archives.range(..=mc_seq_no).next_back().cloned()
+            }
+        };
+
+        if let Some(prev_id) = prev_id {
+            if archive_id < prev_id {
+                archive_id = prev_id;
+            }
+        }
+
+        if mc_seq_no.saturating_sub(archive_id) >= ARCHIVE_PACKAGE_SIZE
+            || (mc_seq_no > archive_id && self.is_archive_full(archive_id, mc_seq_no))
+        {
+            self.start_archive(mc_seq_no);
+            archive_id = mc_seq_no;
+        }
+
+        archive_id
+    }
+
+    fn start_archive(&self, archive_id: u32) {
+        // NOTE: quarantined archives are not restarted
+        if self.is_archive_quarantined(archive_id) {
+            return;
+        }
+
+        let mut archive_ids = self.archive_ids.write();
+        let prev_id = archive_ids.last().copied();
+        if !archive_ids.insert(archive_id) {
+            return;
+        }
+        let changed = self.update_current_archive_id(&archive_ids);
+        drop(archive_ids);
+
+        if changed {
+            self.store_current_archive_id();
+        }
+
+        // NOTE: the archive is stored with its first block,
+        // so the events are written in the same batch
+        if let Some(events) = &self.archive_events {
+            events.defer(ArchiveEventKind::Created { archive_id });
+            if let Some(prev_id) = prev_id.filter(|id| *id < archive_id) {
+                events.defer(ArchiveEventKind::Sealed {
+                    archive_id: prev_id,
+                });
+            }
+        }
+    }
+
+    fn log_archive_event(&self, batch: &mut rocksdb::WriteBatch, kind: ArchiveEventKind) {
+        if let Some(events) = &self.archive_events {
+            events.append(&self.db, batch, kind);
+        }
+    }
+
+    /// Checks whether the block with `mc_seq_no` must start a new archive
+    /// because the current one exceeds the size limit.
+    ///
+    /// NOTE: the archive is sealed at the masterchain seqno of the first block
+    /// which found it full, so that blocks of one seqno are never split
+    fn is_archive_full(&self, archive_id: u32, mc_seq_no: u32) -> bool {
+        let max_size = match self.max_archive_size {
+            Some(max_size) => max_size,
+            None => return false,
+        };
+
+        let is_full = matches!(
+            self.archive_sizes.lock().get(&archive_id),
+            Some(size) if *size >= max_size
+        );
+        if !is_full {
+            return false;
+        }
+
+        let seal_seqno = *self
+            .archive_seal_seqnos
+            .lock()
+            .entry(archive_id)
+            .or_insert(mc_seq_no);
+        mc_seq_no > seal_seqno
+    }
+
+    fn make_archive_segment<I>(&self, entry_id: &PackageEntryId<I>) -> Result<Vec<u8>>
+    where
+        I: Borrow<ton_block::BlockIdExt> + Hash,
+    {
+        match self.db.package_entries.get(entry_id.to_vec())? {
+            Some(data) => Ok(make_archive_segment(
+                &entry_id.filename(),
+                PackageEntryMeta::strip(&data),
+            )),
+            None => Err(BlockStorageError::InvalidBlockData.into()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum BlockProofHandle {
+    Existing(Arc<BlockHandle>),
+    New(BlockMetaData),
+}
+
+impl From<Arc<BlockHandle>> for BlockProofHandle {
+    fn from(handle: Arc<BlockHandle>) -> Self {
+        Self::Existing(handle)
+    }
+}
+
+impl From<BlockMetaData> for BlockProofHandle {
+    fn from(meta_data: BlockMetaData) -> Self {
+        Self::New(meta_data)
+    }
+}
+
+pub struct StoreBlockResult {
+    pub handle: Arc<BlockHandle>,
+    pub updated: bool,
+    pub new: bool,
+}
+
+/// NOTE: must be called outside of the async context, batches are written
+/// under the exclusive `gc_lock`
+fn remove_blocks(
+    db: Arc<Db>,
+    gc_lock: &tokio::sync::RwLock<()>,
+    archiving_blocks: &Mutex<FastHashSet<[u8; 32]>>,
+    readahead_size: Option<usize>,
+    max_blocks_per_batch: Option<usize>,
+    proofs_retention_sec: Option<u32>,
+    keep_proofs: bool,
+    top_blocks: &ArcSwap<TopBlocks>,
+) -> Result<BlockGcStats> {
+    let mut stats = BlockGcStats::default();
+
+    // NOTE: protection is re-read at each batch boundary
+    let mut current_top_blocks = top_blocks.load_full();
+
+    // NOTE: the scan is not skipped in this case, since orphan entries
+    // are removed regardless of the top blocks
+    if !has_outdated_shard_entries(&db, &current_top_blocks)? {
+        tracing::info!(
+            mc_seq_no = current_top_blocks.mc_block.1,
+            "all package entries are protected by top blocks, only orphan entries will be removed"
+        );
+    }
+
+    let raw = db.raw().as_ref();
+    let package_entries_cf = db.package_entries.cf();
+    let block_handles_cf = db.block_handles.cf();
+    let key_blocks_cf = db.key_blocks.cf();
+
+    // Create batch
+    let mut batch = GcBatch::default();
+    let mut batch_count = 0usize;
+
+    let mut package_entries_readopts = db.package_entries.new_read_config();
+    if let Some(readahead_size) = readahead_size {
+        // NOTE: most of the scanned entries are removed, so they are not cached
+        package_entries_readopts.set_readahead_size(readahead_size);
+        package_entries_readopts.fill_cache(false);
+    }
+    let key_blocks_readopts = db.key_blocks.new_read_config();
+    let block_handles_readopts = db.block_handles.new_read_config();
+
+    let now = broxus_util::now();
+
+    // Entries of the same block are adjacent, so the handle is removed
+    // only after all its entries were processed and none of them were retained
+    let mut pending_handle: Option<PendingGcHandle> = None;
+    // Whether the pending handle is being moved into archive, resolved lazily
+    let mut pending_archiving: Option<bool> = None;
+
+    // Iterate all entries and find expired items
+    let mut blocks_iter = raw.raw_iterator_cf_opt(&package_entries_cf, package_entries_readopts);
+    blocks_iter.seek_to_first();
+
+    'scan: loop {
+        let key = match blocks_iter.key() {
+            Some(key) => key,
+            None => {
+                blocks_iter.status()?;
+
+                if let Some(handle) = pending_handle {
+                    batch.finish_handle(raw, &block_handles_cf, handle)?;
+                }
+
+                if batch.len == 0 {
+                    break;
+                }
+
+                batch_count += 1;
+                tracing::info!(batch_count, batch_len = batch.len, "applying final batch");
+                match batch.write(
+                    raw,
+                    gc_lock,
+                    top_blocks,
+                    &mut current_top_blocks,
+                    &mut stats,
+                )? {
+                    Some(start) => {
+                        start.restore(
+                            &mut blocks_iter,
+                            &mut pending_handle,
+                            &mut pending_archiving,
+                        );
+                        continue 'scan;
+                    }
+                    None => break,
+                }
+            }
+        };
+
+        if batch.start.is_none() {
+            batch.start = Some(GcBatchStart {
+                key: key.to_vec(),
+                pending_handle,
+                pending_archiving,
+            });
+        }
+
+        // Read only prefix with shard ident and seqno
+        let (shard_ident, seq_no) = BlockIdShort::deserialize(&mut std::convert::identity(key))?;
+
+        // Key structure:
+        // [workchain id, 4 bytes]
+        // [shard id, 8 bytes]
+        // [seqno, 4 bytes]
+        // [root hash, 32 bytes] <-
+        // ..
+        let root_hash = key.get(16..48).map(|hash| {
+            let mut root_hash = [0; 32];
+            root_hash.copy_from_slice(hash);
+            root_hash
+        });
+
+        if let Some(root_hash) = root_hash {
+            match &mut pending_handle {
+                Some(pending) if pending.root_hash == root_hash => {}
+                pending => {
+                    if let Some(handle) = pending.replace(PendingGcHandle::new(root_hash)) {
+                        batch.finish_handle(raw, &block_handles_cf, handle)?;
+                    }
+                    pending_archiving = None;
+                }
+            }
+        }
+
+        // Don't gc latest blocks and key blocks
+        let is_outdated = is_outdated_entry(
+            raw,
+            &key_blocks_cf,
+            &key_blocks_readopts,
+            &current_top_blocks,
+            &shard_ident,
+            seq_no,
+        )?;
+
+        // Keep proofs which are not old enough (or all proofs if `keep_proofs` is set).
+        let is_retained = is_outdated
+            && ((keep_proofs
+                && matches!(
+                    PackageEntryKind::from_key(key),
+                    Some(PackageEntryKind::Proof | PackageEntryKind::ProofLink)
+                ))
+                || matches!(
+                    (proofs_retention_sec, blocks_iter.value().map(PackageEntryMeta::decode)),
+                    (Some(retention), Some((Some(meta), _)))
+                        if meta.kind != PackageEntryKind::Block
+                            && now < meta.stored_at.saturating_add(retention)
+                ));
+
+        // Entries of the blocks which are being moved into archives are
+        // removed by the next GC run after archiving is finished
+        let is_archiving = is_outdated
+            && !is_retained
+            && match root_hash {
+                Some(root_hash) => match pending_archiving {
+                    Some(is_archiving) => is_archiving,
+                    None => {
+                        let is_archiving = archiving_blocks.lock().contains(&root_hash)
+                            || match raw.get_pinned_cf_opt(
+                                &block_handles_cf,
+                                root_hash,
+                                &block_handles_readopts,
+                            )? {
+                                // NOTE: the flag is persisted if the handle was stored while moving
+                                Some(value) => {
+                                    let meta = BlockMeta::from_slice(value.as_ref())?;
+                                    meta.is_moving_to_archive() && !meta.is_archived()
+                                }
+                                None => false,
+                            };
+                        *pending_archiving.insert(is_archiving)
+                    }
+                },
+                None => false,
+            };
+
+        if !is_outdated || is_retained || is_archiving {
+            if let Some(pending) = &mut pending_handle {
+                pending.keep = true;
+            }
+        }
+
+        if is_retained {
+            batch.stats.retained_proof_entries += 1;
+        } else if is_archiving {
+            batch.stats.in_flight_archive_entries += 1;
+        } else if !is_outdated {
+            // But remove entries which are no longer referenced by any handle
+            let is_orphan = match root_hash {
+                Some(root_hash) => raw
+                    .get_pinned_cf_opt(&block_handles_cf, root_hash, &block_handles_readopts)?
+                    .is_none(),
+                None => false,
+            };
+
+            if is_orphan {
+                batch.batch.delete_cf(&package_entries_cf, key);
+                batch.stats.orphan_entries_removed += 1;
+                batch.len += 1;
+            }
+        } else {
+            // Add item to the batch
+            batch.batch.delete_cf(&package_entries_cf, key);
+            if let Some(pending) = &mut pending_handle {
+                pending.data_removed |=
+                    PackageEntryKind::from_key(key) == Some(PackageEntryKind::Block);
+            }
+            batch.stats.total_package_entries_removed += 1;
+            if shard_ident.is_masterchain() {
+                batch.stats.mc_package_entries_removed += 1;
+            }
+            batch.outdated_blocks.push((shard_ident, seq_no));
+
+            batch.len += 1;
+        }
+
+        if matches!(
+            max_blocks_per_batch,
+            Some(max_blocks_per_batch) if batch.len >= max_blocks_per_batch
+        ) {
+            batch_count += 1;
+            tracing::info!(
+                batch_count,
+                batch_len = batch.len,
+                total_package_entries_removed = stats.total_package_entries_removed,
+                "applying intermediate batch",
+            );
+            if let Some(start) = batch.write(
+                raw,
+                gc_lock,
+                top_blocks,
+                &mut current_top_blocks,
+                &mut stats,
+            )? {
+                start.restore(
+                    &mut blocks_iter,
+                    &mut pending_handle,
+                    &mut pending_archiving,
+                );
+                continue;
+            }
+        }
+
+        blocks_iter.next();
+    }
+
+    // Done
+    Ok(stats)
+}
+
+/// Removals of the blocks GC which are written at once
+#[derive(Default)]
+struct GcBatch {
+    batch: rocksdb::WriteBatch,
+    len: usize,
+    stats: BlockGcStats,
+    /// Blocks whose entries were removed as outdated by this batch
+    outdated_blocks: Vec<(ton_block::ShardIdent, u32)>,
+    /// Scan state before the first entry of this batch
+    start: Option<GcBatchStart>,
+}
+
+impl GcBatch {
+    /// Removes the handle if none of its entries were kept. Otherwise clears
+    /// the data flag of the kept handle (e.g. with retained proofs) if its block data was removed
+    fn finish_handle(
+        &mut self,
+        raw: &rocksdb::DB,
+        block_handles_cf: &BoundedCfHandle<'_>,
+        handle: PendingGcHandle,
+    ) -> Result<()> {
+        if !handle.keep {
+            self.batch.delete_cf(block_handles_cf, handle.root_hash);
+            self.stats.total_handles_removed += 1;
+            self.len += 1;
+        } else if handle.data_removed {
+            if let Some(value) = raw.get_pinned_cf(block_handles_cf, handle.root_hash)? {
+                let meta = BlockMeta::from_slice(value.as_ref())?;
+                if meta.clear_has_data() {
+                    self.batch
+                        .put_cf(block_handles_cf, handle.root_hash, meta.to_vec());
+                    self.len += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the batch unless some of its outdated blocks are protected
+    /// by the updated top blocks. In that case the batch is discarded
+    /// and the scan state to restart from is returned
+    fn write(
+        &mut self,
+        raw: &rocksdb::DB,
+        gc_lock: &tokio::sync::RwLock<()>,
+        top_blocks: &ArcSwap<TopBlocks>,
+        current_top_blocks: &mut Arc<TopBlocks>,
+        stats: &mut BlockGcStats,
+    ) -> Result<Option<GcBatchStart>> {
+        let _gc_guard = gc_lock.blocking_write();
+
+        let latest_top_blocks = top_blocks.load_full();
+        if !Arc::ptr_eq(&latest_top_blocks, current_top_blocks) {
+            *current_top_blocks = latest_top_blocks;
+
+            let is_protected = self.outdated_blocks.iter().any(|(shard_ident, seq_no)| {
+                current_top_blocks.contains_shard_seq_no(shard_ident, *seq_no)
+            });
+            if is_protected {
+                tracing::warn!(
+                    mc_seq_no = current_top_blocks.mc_block.1,
+                    "top blocks were updated during the blocks GC, rescanning the batch"
+                );
+                let start = self.start.take();
+                *self = Default::default();
+                return Ok(start);
+            }
+        }
+
+        raw.write(std::mem::take(&mut self.batch))?;
+        stats.merge(&self.stats);
+        *self = Default::default();
+        Ok(None)
+    }
+}
+
+/// Handle of the block whose package entries are being scanned by the blocks GC
+#[derive(Copy, Clone)]
+struct PendingGcHandle {
+    root_hash: [u8; 32],
+    /// Whether some entries of the block were kept
+    keep: bool,
+    /// Whether the block data entry was removed
+    data_removed: bool,
+}
+
+impl PendingGcHandle {
+    fn new(root_hash: [u8; 32]) -> Self {
+        Self {
+            root_hash,
+            keep: false,
+            data_removed: false,
+        }
+    }
+}
+
+struct GcBatchStart {
+    key: Vec<u8>,
+    pending_handle: Option<PendingGcHandle>,
+    pending_archiving: Option<bool>,
+}
+
+impl GcBatchStart {
+    fn restore(
+        self,
+        iter: &mut rocksdb::DBRawIterator<'_>,
+        pending_handle: &mut Option<PendingGcHandle>,
+        pending_archiving: &mut Option<bool>,
+    ) {
+        iter.seek(self.key);
+        *pending_handle = self.pending_handle;
+        *pending_archiving = self.pending_archiving;
+    }
+}
+
+fn estimate_blocks_gc(db: &Db, top_blocks: &TopBlocks, sample: usize) -> Result<GcEstimate> {
+    use rand::Rng;
+
+    let raw = db.raw().as_ref();
+    let package_entries_cf = db.package_entries.cf();
+    let key_blocks_cf = db.key_blocks.cf();
+    let key_blocks_readopts = db.key_blocks.new_read_config();
+
+    let total_entries = raw
+        .property_int_value_cf(&package_entries_cf, "rocksdb.estimate-num-keys")?
+        .unwrap_or_default();
+
+    let mut estimate = GcEstimate {
+        estimated_total_entries: total_entries,
+        ..Default::default()
+    };
+    if sample == 0 || total_entries == 0 {
+        return Ok(estimate);
+    }
+
+    let mut iter =
+        raw.raw_iterator_cf_opt(&package_entries_cf, db.package_entries.new_read_config());
+
+    // Find seqno ranges of all shards, so that seek keys are drawn from the real key space
+    let mut shards = Vec::<([u8; 12], u32, u32)>::new();
+    iter.seek_to_first();
+    while let Some(key) = iter.key() {
+        let (prefix, first_seq_no) = match split_entry_key_prefix(key) {
+            Some(item) => item,
+            None => break,
+        };
+
+        let mut next_shard = [0xff; 16 + 32 + 1];
+        next_shard[..12].copy_from_slice(&prefix);
+        iter.seek_for_prev(next_shard);
+        let last_seq_no = match iter.key().and_then(split_entry_key_prefix) {
+            Some((last_prefix, seq_no)) if last_prefix == prefix => seq_no,
+            _ => first_seq_no,
+        };
+
+        shards.push((prefix, first_seq_no, last_seq_no));
+        iter.seek(next_shard);
+    }
+    iter.status()?;
+
+    // NOTE: shards are weighted by their seqno spans
+    let total_span = shards
+        .iter()
+        .map(|(_, first, last)| (last - first) as u64 + 1)
+        .sum::<u64>();
+    if total_span == 0 {
+        return Ok(estimate);
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut seek_key = [0u8; 16 + 32];
+    for _ in 0..sample {
+        let mut position = rng.gen_range(0..total_span);
+        let (prefix, first_seq_no, last_seq_no) = shards
+            .iter()
+            .find(|(_, first, last)| {
+                let span = (last - first) as u64 + 1;
+                match position.checked_sub(span) {
+                    Some(rest) => {
+                        position = rest;
+                        false
+                    }
+                    None => true,
+                }
+            })
+            .copied()
+            .unwrap_or(shards[shards.len() - 1]);
+
+        seek_key[..12].copy_from_slice(&prefix);
+        seek_key[12..16].copy_from_slice(&rng.gen_range(first_seq_no..=last_seq_no).to_be_bytes());
+        rng.fill(&mut seek_key[16..]);
+
+        iter.seek(seek_key);
+        if !matches!(iter.key(), Some(key) if key.starts_with(&prefix)) {
+            // Random hash is after the last entry of the shard
+            iter.seek_for_prev(seek_key);
+        }
+
+        let (key, value) = match iter.item() {
+            Some(item) => item,
+            None => break iter.status()?,
+        };
+
+        let (shard_ident, seq_no) = BlockIdShort::deserialize(&mut std::convert::identity(key))?;
+
+        estimate.sampled_entries += 1;
+        if is_outdated_entry(
+            raw,
+            &key_blocks_cf,
+            &key_blocks_readopts,
+            top_blocks,
+            &shard_ident,
+            seq_no,
+        )? {
+            estimate.sampled_removable_entries += 1;
+            estimate.sampled_removable_bytes += value.len() as u64;
+        }
+    }
+
+    if estimate.sampled_entries > 0 {
+        let ratio = total_entries as f64 / estimate.sampled_entries as f64;
+        estimate.estimated_removable_entries =
+            (estimate.sampled_removable_entries as f64 * ratio) as u64;
+        estimate.estimated_removable_bytes =
+            (estimate.sampled_removable_bytes as f64 * ratio) as u64;
+    }
+
+    Ok(estimate)
+}
+
+/// Splits the package entry key into the shard prefix and the seqno
+fn split_entry_key_prefix(key: &[u8]) -> Option<([u8; 12], u32)> {
+    let prefix = key.get(..12)?.try_into().unwrap();
+    let seq_no = u32::from_be_bytes(key.get(12..16)?.try_into().unwrap());
+    Some((prefix, seq_no))
+}
+
+impl Drop for BlockStorage {
+    fn drop(&mut self) {
+        self.flush_metrics_logged();
+    }
+}
+
+pub struct BlockStorageOptions {
+    /// See [`ArchiveOptions::max_archive_size`](crate::config::ArchiveOptions)
+    pub max_archive_size: Option<u64>,
+    /// See [`ArchiveOptions::id_strategy`](crate::config::ArchiveOptions)
+    pub archive_id_strategy: ArchiveIdStrategy,
+    /// Number of the newest archives which are never removed by archives GC
+    pub min_retained_archives: usize,
+    /// Remove key block entries below the oldest retained archive during archives GC
+    pub remove_key_blocks: bool,
+    /// Track the newest archive id in the db instead of scanning archive ids
+    pub persist_current_archive_id: bool,
+    /// Read-ahead size of the blocks GC scan. Default read options are used if `None`
+    pub gc_readahead_size: Option<usize>,
+    /// Number of threads used to verify archives on startup
+    pub preload_threads: usize,
+    /// Archive events log retention. Disabled if `None`
+    pub event_log_retention_sec: Option<u32>,
+    /// Max rate of archive writes in bytes per second. Unlimited if `None`
+    pub max_archive_write_rate: Option<u64>,
+    /// Log archiving which holds the handle data locks longer than this. Disabled if `None`
+    pub slow_archive_lock_threshold: Option<Duration>,
+    /// Max number of blocks moved into archives concurrently. Unlimited if `None`
+    pub max_concurrent_archiving: Option<usize>,
+}
+
+impl Default for BlockStorageOptions {
+    fn default() -> Self {
+        Self {
+            max_archive_size: None,
+            archive_id_strategy: ArchiveIdStrategy::Seqno,
+            min_retained_archives: 0,
+            remove_key_blocks: false,
+            persist_current_archive_id: false,
+            gc_readahead_size: None,
+            preload_threads: num_cpus::get(),
+            event_log_retention_sec: None,
+            max_archive_write_rate: None,
+            slow_archive_lock_threshold: None,
+            max_concurrent_archiving: None,
+        }
+    }
+}
+
+struct ArchivingSemaphore {
+    inner: tokio::sync::Semaphore,
+    permits: usize,
+}
+
+impl ArchivingSemaphore {
+    /// Unlimited if `permits` is `None`
+    fn new(permits: Option<usize>) -> Self {
+        // NOTE: all permits are acquired at once on shutdown
+        let max_permits = std::cmp::min(tokio::sync::Semaphore::MAX_PERMITS, u32::MAX as usize);
+        let permits = permits.unwrap_or(max_permits).clamp(1, max_permits);
+        Self {
+            inner: tokio::sync::Semaphore::new(permits),
+            permits,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ArchiveVerificationState {
+    last_verified_id: Option<u32>,
+    verified_at: BTreeMap<u32, u32>,
+    quarantined_ids: BTreeSet<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchiveVerificationStatus {
+    /// Number of served archives
+    pub total_archives: usize,
+    /// Number of served archives which were verified at least once
+    pub verified_archives: usize,
+    pub last_verified_id: Option<u32>,
+    /// Last verification timestamp for each archive
+    pub verified_at: BTreeMap<u32, u32>,
+    /// Archives which failed verification and are no longer served
+    pub quarantined_ids: BTreeSet<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchiveStateDump {
+    pub archive_id_strategy: ArchiveIdStrategy,
+    /// All stored archive ids
+    pub archive_ids: BTreeSet<u32>,
+    /// Archives which no longer receive new blocks
+    pub sealed_ids: BTreeSet<u32>,
+    /// Archive ids which were started by key blocks
+    pub key_block_archive_ids: BTreeSet<u32>,
+    /// Archives which are protected from archives GC
+    pub pinned_archive_ids: BTreeSet<u32>,
+    /// Newest archive, which could still receive new blocks. `None` if there are no archives
+    pub open_archive: Option<ArchiveOpenState>,
+    /// Newest archive id stored in the db.
+    /// `None` if it is not tracked or there are no archives
+    pub persisted_current_id: Option<u32>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ArchiveOpenState {
+    pub id: u32,
+    /// Accumulated size of the archive in bytes
+    pub size: u64,
+}
+
+const ARCHIVE_INDEX_ENTRY_LEN: usize = 4 + 8 + 8;
+const ARCHIVE_VERIFY_WINDOW: usize = 1 << 20;
+/// Key of the newest archive id in the node states
+const CURRENT_ARCHIVE_ID: &[u8] = b"current_archive_id";
+/// Key of the archive lifetime counters in the node states
+const LIFETIME_COUNTERS: &[u8] = b"archive_lifetime_counters";
+/// Key prefix of the blocks skipped while archiving was paused in the node states
+const PAUSED_ARCHIVING_PREFIX: &[u8] = b"paused_archiving:";
+const NO_CURRENT_ARCHIVE: u32 = u32::MAX;
+
+/// Max number of the blocks remembered while archiving is paused
+const MAX_PAUSED_ARCHIVING_BLOCKS: usize = 100_000;
+
+fn paused_archiving_key(root_hash: &[u8; 32]) -> Vec<u8> {
+    [PAUSED_ARCHIVING_PREFIX, root_hash.as_slice()].concat()
+}
+
+/// Loads the blocks skipped while archiving was paused
+fn load_paused_archiving(db: &Db) -> Result<FastHashMap<[u8; 32], ton_block::BlockIdExt>> {
+    let mut result = FastHashMap::default();
+
+    let mut iter = db.node_states.raw_iterator();
+    iter.seek(PAUSED_ARCHIVING_PREFIX);
+    while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+        let root_hash = match key.strip_prefix(PAUSED_ARCHIVING_PREFIX) {
+            Some(root_hash) => root_hash.try_into()?,
+            None => break,
+        };
+        result.insert(root_hash, ton_block::BlockIdExt::from_slice(value)?);
+        iter.next();
+    }
+    iter.status()?;
+
+    Ok(result)
+}
+
+fn check_archive(value: &[u8]) -> Result<(), ArchivePackageError> {
+    let mut verifier = UniqueArchiveEntriesVerifier::default();
+    verifier.verify(value)?;
+    verifier.final_check()
+}
+
+/// Consistent view of the archives column (see [`BlockStorage::archives_snapshot`])
+pub struct ArchivesSnapshot<'a> {
+    db: &'a Db,
+    snapshot: rocksdb::Snapshot<'a>,
+    archive_ids: BTreeSet<u32>,
+}
+
+impl<'a> ArchivesSnapshot<'a> {
+    /// Archive ids at the moment of the snapshot creation
+    pub fn archive_ids(&self) -> &BTreeSet<u32> {
+        &self.archive_ids
+    }
+
+    /// Same as [`BlockStorage::get_archive_slice`], but reads through the snapshot
+    pub fn get_archive_slice(
+        &self,
+        id: u32,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        let archives_cf = self.db.archives.cf();
+        let read_options = self.read_options();
+
+        match self
+            .db
+            .raw()
+            .get_pinned_cf_opt(&archives_cf, id.to_be_bytes(), &read_options)?
+        {
+            Some(slice) => match archive_slice_range(slice.len(), offset, limit) {
+                Some(range) => Ok(Some(slice[range].to_vec())),
+                None => Err(BlockStorageError::InvalidOffset.into()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Same as [`BlockStorage::get_archives`], but reads through the snapshot
+    pub fn get_archives(
+        &self,
+        range: impl RangeBounds<u32> + 'static,
+    ) -> impl Iterator<Item = (u32, Vec<u8>)> + '_ {
+        let archives_cf = self.db.archives.cf();
+        let iter = self
+            .db
+            .raw()
+            .raw_iterator_cf_opt(&archives_cf, self.read_options());
+        ArchivesIterator::new(iter, range)
+    }
+
+    fn read_options(&self) -> rocksdb::ReadOptions {
+        let mut read_options = self.db.archives.new_read_config();
+        read_options.set_snapshot(&self.snapshot);
+        read_options
+    }
+}
+
+struct ArchivesIterator<'a> {
+    first: bool,
+    finished: bool,
+    ids: (Bound<u32>, Bound<u32>),
+    iter: rocksdb::DBRawIterator<'a>,
+}
+
+impl<'a> ArchivesIterator<'a> {
+    fn new(iter: rocksdb::DBRawIterator<'a>, range: impl RangeBounds<u32>) -> Self {
+        Self {
+            first: true,
+            finished: false,
+            ids: (range.start_bound().cloned(), range.end_bound().cloned()),
+            iter,
+        }
+    }
+}
+
+impl<'a> Iterator for ArchivesIterator<'a> {
+    type Item = (u32, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        if self.first {
+            self.first = false;
+            match self.ids.0 {
+                Bound::Included(id) => {
+                    self.iter.seek(id.to_be_bytes());
+                }
+                Bound::Excluded(id) => match id.checked_add(1) {
+                    Some(id) => self.iter.seek(id.to_be_bytes()),
+                    // Nothing can be after the max id
+                    None => {
+                        self.finished = true;
+                        return None;
+                    }
+                },
+                Bound::Unbounded => {
+                    self.iter.seek_to_first();
+                }
+            }
+        } else {
+            self.iter.next();
+        }
+
+        let item = match (self.iter.key(), self.iter.value()) {
+            (Some(key), Some(value)) => {
+                let id = u32::from_be_bytes(key.try_into().unwrap_or_default());
+                match self.ids.1 {
+                    Bound::Included(bound_id) if id > bound_id => None,
+                    Bound::Excluded(bound_id) if id >= bound_id => None,
+                    _ => Some((id, value.to_vec())),
+                }
+            }
+            _ => None,
+        };
+
+        self.finished = item.is_none();
+        item
+    }
+}
+
+/// Computes the range of the archive slice to read.
+///
+/// Offset equal to the archive length is valid and results in an empty range,
+/// offset beyond the archive length is an error (`None`)
+fn archive_slice_range(len: usize, offset: usize, limit: usize) -> Option<Range<usize>> {
+    if offset > len {
+        return None;
+    }
+    let end = std::cmp::min(offset.saturating_add(limit), len);
+    Some(offset..end)
+}
+
+/// Encodes the value of the archive block index.
+///
+/// NOTE: entries of earlier versions have no location
+fn encode_archive_index_entry(archive_id: u32, location: Option<(u64, u64)>) -> Vec<u8> {
+    let mut value = Vec::with_capacity(ARCHIVE_INDEX_ENTRY_LEN);
+    value.extend_from_slice(&archive_id.to_be_bytes());
+    if let Some((offset, len)) = location {
+        value.extend_from_slice(&offset.to_le_bytes());
+        value.extend_from_slice(&len.to_le_bytes());
+    }
+    value
+}
+
+/// Decodes the value of the archive block index
+fn decode_archive_index_entry(value: &[u8]) -> Option<(u32, Option<(u64, u64)>)> {
+    let archive_id = u32::from_be_bytes(value.get(..4)?.try_into().unwrap());
+    let location = if value.len() == ARCHIVE_INDEX_ENTRY_LEN {
+        let offset = u64::from_le_bytes(value[4..12].try_into().unwrap());
+        let len = u64::from_le_bytes(value[12..20].try_into().unwrap());
+        Some((offset, len))
+    } else {
+        None
+    };
+    Some((archive_id, location))
+}
+
+fn archive_block_hashes_key(archive_id: u32, root_hash: &[u8]) -> [u8; 4 + 32] {
+    let mut key = [0; 4 + 32];
+    key[..4].copy_from_slice(&archive_id.to_be_bytes());
+    key[4..].copy_from_slice(root_hash);
+    key
+}
+
+/// Returns the key range of all root hashes of the archive
+fn archive_block_hashes_range(archive_id: u32) -> ([u8; 4], [u8; 4 + 32 + 1]) {
+    let mut to = [0xff; 4 + 32 + 1];
+    to[..4].copy_from_slice(&archive_id.to_be_bytes());
+    (archive_id.to_be_bytes(), to)
+}
+
+/// Checks whether the oldest package entry of any shard is below the top blocks.
+///
+/// NOTE: only the first entry of each shard is read
+fn has_outdated_shard_entries(db: &Db, top_blocks: &TopBlocks) -> Result<bool> {
+    let mut iter = db.package_entries.raw_iterator();
+    iter.seek_to_first();
+
+    while let Some(key) = iter.key() {
+        let (shard_ident, seq_no) = BlockIdShort::deserialize(&mut std::convert::identity(key))?;
+        if !top_blocks.contains_shard_seq_no(&shard_ident, seq_no) {
+            return Ok(true);
+        }
+
+        // Skip the remaining entries of this shard
+        let mut next_shard = [0xff; 16 + 32 + 1];
+        next_shard[..12].copy_from_slice(&key[..12]);
+        iter.seek(next_shard);
+    }
+    iter.status()?;
+
+    Ok(false)
+}
+
+/// Returns `true` if the package entry for the specified block can be removed by blocks GC
+fn is_outdated_entry(
+    raw: &rocksdb::DB,
+    key_blocks_cf: &BoundedCfHandle<'_>,
+    key_blocks_readopts: &rocksdb::ReadOptions,
+    top_blocks: &TopBlocks,
+    shard_ident: &ton_block::ShardIdent,
+    seq_no: u32,
+) -> Result<bool, rocksdb::Error> {
+    // Don't gc latest blocks
+    if top_blocks.contains_shard_seq_no(shard_ident, seq_no) {
+        return Ok(false);
+    }
+
+    // Additionally check whether this item is a key block
+    let is_key_block = seq_no == 0
+        || shard_ident.is_masterchain()
+            && raw
+                .get_pinned_cf_opt(key_blocks_cf, seq_no.to_be_bytes(), key_blocks_readopts)?
+                .is_some();
+
+    Ok(!is_key_block)
+}
+
+/// Approximate blocks GC impact
+#[derive(Debug, Copy, Clone, Default)]
+pub struct GcEstimate {
+    pub sampled_entries: u64,
+    pub sampled_removable_entries: u64,
+    pub sampled_removable_bytes: u64,
+    pub estimated_total_entries: u64,
+    pub estimated_removable_entries: u64,
+    pub estimated_removable_bytes: u64,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BlockGcStats {
+    pub mc_package_entries_removed: usize,
+    pub total_package_entries_removed: usize,
+    pub total_handles_removed: usize,
+    pub total_cached_handles_removed: usize,
+    /// Entries of the retained blocks without a corresponding handle
+    pub orphan_entries_removed: usize,
+    /// Proof entries of the outdated blocks, retained by `proofs_retention_sec` or `keep_proofs`
+    pub retained_proof_entries: usize,
+    /// Entries of the outdated blocks which are being moved into archives
+    pub in_flight_archive_entries: usize,
+}
+
+impl BlockGcStats {
+    /// Adds stats of another GC run
+    pub fn merge(&mut self, other: &Self) {
+        self.mc_package_entries_removed += other.mc_package_entries_removed;
+        self.total_package_entries_removed += other.total_package_entries_removed;
+        self.total_handles_removed += other.total_handles_removed;
+        self.total_cached_handles_removed += other.total_cached_handles_removed;
+        self.orphan_entries_removed += other.orphan_entries_removed;
+        self.retained_proof_entries += other.retained_proof_entries;
+        self.in_flight_archive_entries += other.in_flight_archive_entries;
+    }
+}
+
+impl std::ops::AddAssign for BlockGcStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.merge(&rhs);
+    }
+}
+
+impl std::ops::Add for BlockGcStats {
+    type Output = Self;
+
+    fn add(mut self, rhs: Self) -> Self::Output {
+        self += rhs;
+        self
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MoveIntoArchiveStats {
+    /// Handles moved into archives by this batch
+    pub archived: usize,
+    /// Handles which were already archived
+    pub already_archived: usize,
+    /// Handles which are being moved concurrently or are stuck after
+    /// an interrupted move (see `BlockHandleStorage::recover_stuck_archiving`)
+    pub in_progress: usize,
+    /// Handles which were remembered because archiving is paused
+    pub paused: usize,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum MoveIntoArchiveStatus {
+    Moved { archive_id: u32 },
+    AlreadyArchived,
+    InProgress,
+    Paused,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ArchiveInfo {
+    pub id: u32,
+    pub byte_len: usize,
+    /// Number of entries read before the first framing error
+    pub entry_count: usize,
+    /// Whether the whole archive has valid framing
+    pub is_valid: bool,
+}
+
+/// See [`BlockStorage::archive_manifest`]
+#[derive(Debug, Clone)]
+pub struct ArchiveManifest {
+    pub id: u32,
+    pub byte_len: usize,
+    /// Entries in the archive order
+    pub entries: Vec<ArchiveManifestEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchiveManifestEntry {
+    pub filename: String,
+    /// Offset of the entry data from the beginning of the archive
+    pub data_offset: usize,
+    pub data_len: usize,
+    /// SHA-256 of the entry data
+    pub data_hash: [u8; 32],
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchiveVerifyReport {
+    pub id: u32,
+    pub byte_len: usize,
+    /// Number of entries read before the first error
+    pub entry_count: usize,
+    pub is_valid: bool,
+    /// Filename of the first repeated entry (e.g. after concurrent archiving)
+    pub duplicate_entry: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ArchivesGcProgress {
+    /// Number of archives removed so far
+    pub removed_archives: usize,
+    /// Total number of archives to remove
+    pub total_archives: usize,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct DefragReport {
+    /// Archives which received the data of the removed archives
+    pub merged_archives: usize,
+    /// Archives removed after merging
+    pub removed_archives: usize,
+    /// Total size of the merged archives
+    pub rewritten_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompositionReport {
+    pub blocks: usize,
+    pub proofs: usize,
+    pub proof_links: usize,
+    /// Entries with unrecognized names
+    pub unknown_entries: usize,
+    /// Archives which contain proofs but no blocks
+    /// (e.g. after an interrupted `move_into_archive`)
+    pub archives_without_blocks: BTreeSet<u32>,
+}
+
+impl CompositionReport {
+    fn add(&mut self, archive_id: u32, archive: ArchiveComposition) {
+        self.blocks += archive.blocks;
+        self.proofs += archive.proofs;
+        self.proof_links += archive.proof_links;
+        self.unknown_entries += archive.unknown_entries;
+        if archive.blocks == 0 && archive.proofs + archive.proof_links > 0 {
+            self.archives_without_blocks.insert(archive_id);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ArchiveComposition {
+    blocks: usize,
+    proofs: usize,
+    proof_links: usize,
+    unknown_entries: usize,
+}
+
+impl ArchiveComposition {
+    fn compute(data: &[u8]) -> Result<Self, ArchivePackageError> {
+        let mut result = Self::default();
+
+        let mut reader = ArchivePackageViewReader::new(data)?;
+        while let Some(entry) = reader.read_next()? {
+            match PackageEntryId::from_filename(entry.name) {
+                Ok(PackageEntryId::Block(_)) => result.blocks += 1,
+                Ok(PackageEntryId::Proof(_)) => result.proofs += 1,
+                Ok(PackageEntryId::ProofLink(_)) => result.proof_links += 1,
+                Err(_) => result.unknown_entries += 1,
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveAuditReport {
+    pub checked_handles: usize,
+    pub broken_handles: Vec<BrokenArchivedHandle>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BrokenArchivedHandle {
+    pub root_hash: UInt256,
+    /// `None` if there is no archive for the handle
+    pub archive_id: Option<u32>,
+    pub status: BrokenArchivedHandleStatus,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BrokenArchivedHandleStatus {
+    /// There is no archive for the handle
+    MissingArchive,
+    /// The archive package can't be parsed, so its entries are unknown
+    CorruptedArchive,
+    /// The archive is readable, but doesn't contain the handle segments
+    MissingEntries {
+        missing_block: bool,
+        missing_proof: bool,
+    },
+}
+
+/// Archive entries collected by [`BlockStorage::audit_archived_handles`]
+enum AuditedArchive {
+    Missing,
+    Corrupted,
+    Readable {
+        blocks: FastHashSet<[u8; 32]>,
+        proofs: FastHashSet<[u8; 32]>,
+    },
+}
+
+impl AuditedArchive {
+    fn read(data: &[u8]) -> Self {
+        let mut blocks = FastHashSet::default();
+        let mut proofs = FastHashSet::default();
+
+        let mut reader = match ArchivePackageViewReader::new(data) {
+            Ok(reader) => reader,
+            Err(_) => return Self::Corrupted,
+        };
+        loop {
+            match reader.read_next() {
+                Ok(Some(entry)) => match PackageEntryId::from_filename(entry.name) {
+                    Ok(PackageEntryId::Block(id)) => {
+                        blocks.insert(*id.root_hash.as_array());
+                    }
+                    Ok(PackageEntryId::Proof(id) | PackageEntryId::ProofLink(id)) => {
+                        proofs.insert(*id.root_hash.as_array());
+                    }
+                    Err(_) => continue,
+                },
+                Ok(None) => break,
+                Err(_) => return Self::Corrupted,
+            }
+        }
+
+        Self::Readable { blocks, proofs }
+    }
+}
+
+/// Keeps the block registered as being moved into archive.
+///
+/// Resets the `is_moving_to_archive` flag if the block was not archived (on error
+/// or when the future is dropped), so that blocks GC doesn't keep its entries forever.
+/// Flags persisted before a crash are cleared on startup by
+/// `BlockHandleStorage::recover_stuck_archiving`
+struct ArchivingGuard<'a> {
+    archiving_blocks: &'a Mutex<FastHashSet<[u8; 32]>>,
+    handle: &'a BlockHandle,
+}
+
+impl<'a> ArchivingGuard<'a> {
+    fn new(archiving_blocks: &'a Mutex<FastHashSet<[u8; 32]>>, handle: &'a BlockHandle) -> Self {
+        archiving_blocks
+            .lock()
+            .insert(*handle.id().root_hash.as_array());
+        Self {
+            archiving_blocks,
+            handle,
+        }
+    }
+}
+
+impl Drop for ArchivingGuard<'_> {
+    fn drop(&mut self) {
+        let meta = self.handle.meta();
+        if !meta.is_archived() {
+            meta.clear_is_moving_to_archive();
+        }
+        self.archiving_blocks
+            .lock()
+            .remove(self.handle.id().root_hash.as_array());
+    }
+}
+
+struct BlockContentsLock<'a> {
+    _lock: tokio::sync::RwLockReadGuard<'a, ()>,
+    data: rocksdb::DBPinnableSlice<'a>,
+}
+
+impl<'a> AsRef<[u8]> for BlockContentsLock<'a> {
+    fn as_ref(&self) -> &[u8] {
+        PackageEntryMeta::strip(self.data.as_ref())
+    }
+}
+
+struct BlockContentsStream<'a> {
+    data: BlockContentsLock<'a>,
+    offset: usize,
+    chunk_size: usize,
+}
+
+impl<'a> futures_util::Stream for BlockContentsStream<'a> {
+    type Item = Result<bytes::Bytes>;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        let data = this.data.data.as_ref();
+        if this.offset >= data.len() {
+            return std::task::Poll::Ready(None);
+        }
+
+        let end = std::cmp::min(this.offset.saturating_add(this.chunk_size), data.len());
+        let chunk = bytes::Bytes::copy_from_slice(&data[this.offset..end]);
+        this.offset = end;
+
+        std::task::Poll::Ready(Some(Ok(chunk)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.data.data.len().saturating_sub(self.offset);
+        let chunks = remaining / self.chunk_size + usize::from(remaining % self.chunk_size != 0);
+        (chunks, Some(chunks))
+    }
+}
+
+pub const ARCHIVE_PACKAGE_SIZE: u32 = 100;
+pub const ARCHIVE_SLICE_SIZE: u32 = 20_000;
+
+#[derive(thiserror::Error, Debug)]
+enum BlockStorageError {
+    #[error("Block data not found")]
+    BlockDataNotFound,
+    #[error("Block proof not found")]
+    BlockProofNotFound,
+    #[error("Block handle id mismatch")]
+    BlockHandleIdMismatch,
+    #[error("Invalid block data")]
+    InvalidBlockData,
+    #[error("Offset is outside of the archive slice")]
+    InvalidOffset,
+    #[error("Invalid archive index entry")]
+    InvalidArchiveIndexEntry,
+    #[error("Archive index entry has no location, archive locations must be reindexed")]
+    ArchiveIndexEntryNotLocated,
+    #[error("Archive not found")]
+    ArchiveNotFound,
+    #[error("Masterchain block {mc_seq_no} doesn't belong to archive {archive_id}")]
+    ArchiveIdMismatch { archive_id: u32, mc_seq_no: u32 },
+    #[error("Invalid framing of archive {id} at offset {offset}: {reason}")]
+    InvalidArchiveFraming {
+        id: u32,
+        offset: usize,
+        reason: ArchivePackageError,
+    },
+    #[error("Archive {id} of {size} bytes exceeds the memory budget of {budget} bytes")]
+    ArchiveExceedsBudget { id: u32, size: usize, budget: usize },
+    #[error("Archiving is stopped")]
+    ArchivingStopped,
+    #[error("Archive {id} is quarantined")]
+    ArchiveQuarantined { id: u32 },
+    #[error("Too many blocks are waiting for archiving to be resumed")]
+    TooManyPausedBlocks,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_slice_boundaries() {
+        assert_eq!(archive_slice_range(10, 0, 4), Some(0..4));
+        assert_eq!(archive_slice_range(10, 8, 4), Some(8..10));
+        assert_eq!(archive_slice_range(10, 0, usize::MAX), Some(0..10));
+        assert_eq!(archive_slice_range(10, usize::MAX, usize::MAX), None);
+
+        // Offset exactly at the end
+        assert_eq!(archive_slice_range(10, 10, 4), Some(10..10));
+        assert_eq!(archive_slice_range(0, 0, 4), Some(0..0));
+
+        // Offset beyond the end
+        assert_eq!(archive_slice_range(10, 11, 4), None);
+        assert_eq!(archive_slice_range(0, 1, 0), None);
+
+        // Zero limit
+        assert_eq!(archive_slice_range(10, 0, 0), Some(0..0));
+        assert_eq!(archive_slice_range(10, 5, 0), Some(5..5));
+    }
+
+    fn open_test_db(name: &str) -> Result<(Arc<Db>, std::path::PathBuf)> {
+        let path = std::env::temp_dir().join(format!("ton-indexer-{name}-{}", std::process::id()));
+        let db = Db::open(path.clone(), 64 << 20, Default::default())?;
+        Ok((db, path))
+    }
+
+    #[test]
+    fn archives_iterator_bounds() -> Result<()> {
+        let (db, path) = open_test_db("archives-iterator")?;
+
+        for id in [0u32, 5, 10, u32::MAX] {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+        }
+
+        let ids = |range: (Bound<u32>, Bound<u32>)| {
+            ArchivesIterator::new(db.archives.raw_iterator(), range)
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>()
+        };
+
+        use Bound::{Excluded, Included, Unbounded};
+
+        // Unbounded
+        assert_eq!(ids((Unbounded, Unbounded)), [0, 5, 10, u32::MAX]);
+        assert_eq!(ids((Unbounded, Included(5))), [0, 5]);
+        assert_eq!(ids((Unbounded, Excluded(5))), [0]);
+        assert_eq!(ids((Included(5), Unbounded)), [5, 10, u32::MAX]);
+        assert_eq!(ids((Excluded(5), Unbounded)), [10, u32::MAX]);
+
+        // Bounds equal to the present ids
+        assert_eq!(ids((Included(5), Included(10))), [5, 10]);
+        assert_eq!(ids((Included(5), Excluded(10))), [5]);
+        assert_eq!(ids((Excluded(5), Included(10))), [10]);
+        assert_eq!(ids((Excluded(5), Excluded(10))), Vec::<u32>::new());
+
+        // Bounds between the present ids
+        assert_eq!(ids((Included(1), Included(9))), [5]);
+        assert_eq!(ids((Excluded(1), Excluded(9))), [5]);
+
+        // Single-element and empty ranges
+        assert_eq!(ids((Included(5), Included(5))), [5]);
+        assert_eq!(ids((Included(6), Included(6))), Vec::<u32>::new());
+        assert_eq!(ids((Included(5), Excluded(5))), Vec::<u32>::new());
+        assert_eq!(ids((Included(10), Included(5))), Vec::<u32>::new());
+
+        // Max id
+        assert_eq!(ids((Included(u32::MAX), Unbounded)), [u32::MAX]);
+        assert_eq!(ids((Included(11), Included(u32::MAX))), [u32::MAX]);
+        assert_eq!(ids((Excluded(10), Excluded(u32::MAX))), Vec::<u32>::new());
+        assert_eq!(ids((Excluded(u32::MAX), Unbounded)), Vec::<u32>::new());
+
+        // Iterator stays finished
+        let mut iter = ArchivesIterator::new(db.archives.raw_iterator(), 0..5);
+        assert_eq!(iter.next().map(|(id, _)| id), Some(0));
+        assert!(iter.next().is_none());
+        assert!(iter.next().is_none());
+        drop(iter);
+
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn archives_export_resumes_from_cursor() -> Result<()> {
+        let (db, path) = open_test_db("archives-export")?;
+
+        for id in [0u32, 100, 200, 300] {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+        }
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        // Interrupted export
+        let (id, _, cursor) = block_storage.get_archives_from(0).nth(1).unwrap();
+        assert_eq!((id, cursor), (100, Some(101)));
+
+        // Resumed export
+        let ids = block_storage
+            .get_archives_from(cursor.unwrap())
+            .map(|(id, _, cursor)| (id, cursor))
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [(200, Some(201)), (300, Some(301))]);
+
+        // Archive at cursor was removed in the interim
+        db.archives.remove(200u32.to_be_bytes())?;
+        let ids = block_storage
+            .get_archives_from(200)
+            .map(|(id, ..)| id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [300]);
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn archive_id_respects_key_blocks() -> Result<()> {
+        let (db, path) = open_test_db("archive-id-key-blocks")?;
+        for id in [0u32, 200] {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+        }
+        // Key block 250 is not archived yet
+        for seqno in [200u32, 250] {
+            db.key_blocks.insert(seqno.to_be_bytes(), [])?;
+        }
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        assert!(block_storage.is_key_block_archive(200));
+        assert!(!block_storage.is_key_block_archive(0));
+
+        assert_eq!(block_storage.get_archive_id(40), Some(0));
+        assert_eq!(block_storage.get_archive_id(200), Some(200));
+        assert_eq!(block_storage.get_archive_id(249), Some(200));
+        assert_eq!(block_storage.get_archive_id(260), None);
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn archive_seqno_range() -> Result<()> {
+        let (db, path) = open_test_db("archive-seqno-range")?;
+        for id in [0u32, 60, 300] {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+        }
+        for seqno in [60u32, 100, 350] {
+            db.key_blocks.insert(seqno.to_be_bytes(), [])?;
+        }
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        // Bounded by the next archive
+        assert_eq!(block_storage.archive_seqno_range(0), Some((0, 59)));
+        // Bounded by the package size
+        assert_eq!(block_storage.archive_seqno_range(60), Some((60, 159)));
+        // Bounded by the key block which is not archived yet
+        assert_eq!(block_storage.archive_seqno_range(300), Some((300, 349)));
+        assert_eq!(block_storage.archive_seqno_range(100), None);
+
+        assert!(block_storage.key_blocks_in_archive(0)?.is_empty());
+        assert_eq!(block_storage.key_blocks_in_archive(60)?, [60, 100]);
+        assert!(block_storage.key_blocks_in_archive(300)?.is_empty());
+        assert!(block_storage.key_blocks_in_archive(100).is_err());
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn archives_for_seqno_range() -> Result<()> {
+        let (db, path) = open_test_db("archives-for-range")?;
+        for id in [0u32, 200, 250] {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+        }
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        assert_eq!(block_storage.archives_for_seqno_range(50, 60), [0]);
+        assert_eq!(block_storage.archives_for_seqno_range(90, 210), [0, 200]);
+        assert_eq!(block_storage.archives_for_seqno_range(240, 400), [200, 250]);
+        // Gaps between archives
+        assert!(block_storage.archives_for_seqno_range(150, 199).is_empty());
+        assert!(block_storage.archives_for_seqno_range(400, 500).is_empty());
+        assert!(block_storage.archives_for_seqno_range(60, 50).is_empty());
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archive_id_must_cover_block() -> Result<()> {
+        let (db, path) = open_test_db("archive-id-check")?;
+        for id in [0u32, 200] {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+        }
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+
+        assert!(block_storage.check_archive_id(0, 50).is_ok());
+        assert!(block_storage.check_archive_id(200, 250).is_ok());
+        // Shadowed by the newer archive
+        assert!(block_storage.check_archive_id(0, 250).is_err());
+        // Too far from the archive start
+        assert!(block_storage
+            .check_archive_id(200, 200 + ARCHIVE_PACKAGE_SIZE)
+            .is_err());
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 210,
+            root_hash: UInt256::from([1; 32]),
+            file_hash: UInt256::from([1; 32]),
+        };
+        let (handle, _) = block_handle_storage.create_or_load_handle(
+            &block_id,
+            BlockMetaData {
+                is_key_block: false,
+                gen_utime: 0,
+                mc_ref_seqno: Some(210),
+            },
+        )?;
+        block_storage
+            .move_into_archive_with_data(&handle, false, &[1], &[2])
+            .await?;
+        assert!(handle.meta().is_archived());
+        assert_eq!(block_storage.get_archive_id(210), Some(200));
+
+        drop(handle);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archive_events_are_written_with_archives() -> Result<()> {
+        let (db, path) = open_test_db("archive-events")?;
+        db.archives.insert(200u32.to_be_bytes(), ARCHIVE_PREFIX)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage = BlockStorage::new(
+            db.clone(),
+            block_handle_storage.clone(),
+            BlockStorageOptions {
+                event_log_retention_sec: Some(3600),
+                ..Default::default()
+            },
+        )?;
+
+        for (seq_no, is_key_block) in [(210, false), (300, true)] {
+            let block_id = ton_block::BlockIdExt {
+                shard_id: ton_block::ShardIdent::masterchain(),
+                seq_no,
+                root_hash: UInt256::from([seq_no as u8; 32]),
+                file_hash: UInt256::from([seq_no as u8; 32]),
+            };
+            let (handle, _) = block_handle_storage.create_or_load_handle(
+                &block_id,
+                BlockMetaData {
+                    is_key_block,
+                    gen_utime: 0,
+                    mc_ref_seqno: Some(seq_no),
+                },
+            )?;
+            block_storage
+                .move_into_archive_with_data(&handle, false, &[1], &[2])
+                .await?;
+        }
+        block_storage.remove_outdated_archives(400).await?;
+
+        let events = block_storage
+            .archive_event_log(0)?
+            .into_iter()
+            .map(|event| event.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                ArchiveEventKind::BlockMoved {
+                    archive_id: 200,
+                    mc_seq_no: 210
+                },
+                // Archive creation is stored with its first block
+                ArchiveEventKind::Created { archive_id: 300 },
+                ArchiveEventKind::Sealed { archive_id: 200 },
+                ArchiveEventKind::BlockMoved {
+                    archive_id: 300,
+                    mc_seq_no: 300
+                },
+                ArchiveEventKind::Removed { until_id: 300 },
+            ]
+        );
+        assert!(db.archives.get(200u32.to_be_bytes())?.is_none());
+        assert_eq!(block_storage.archive_count(), 1);
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn archive_manifest() -> Result<()> {
+        use sha2::Digest;
+
+        let (db, path) = open_test_db("archive-manifest")?;
+
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        archive.extend_from_slice(&make_archive_segment("first", &[1; 100]));
+        let second_offset = archive.len();
+        archive.extend_from_slice(&make_archive_segment("second", &[]));
+        db.archives.insert(0u32.to_be_bytes(), &archive)?;
+        db.archives
+            .insert(1u32.to_be_bytes(), &archive[..archive.len() - 1])?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        let manifest = block_storage.archive_manifest(0)?.unwrap();
+        assert_eq!(manifest.byte_len, archive.len());
+        assert_eq!(manifest.entries.len(), 2);
+        for (entry, filename) in manifest.entries.iter().zip(["first", "second"]) {
+            assert_eq!(entry.filename, filename);
+            let data = &archive[entry.data_offset..entry.data_offset + entry.data_len];
+            assert_eq!(
+                entry.data_hash,
+                <[u8; 32]>::from(sha2::Sha256::digest(data))
+            );
+        }
+        assert_eq!(manifest.entries[0].data_len, 100);
+
+        // Truncated second entry is reported with its offset
+        let error = block_storage.archive_manifest(1).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<BlockStorageError>(),
+            Some(BlockStorageError::InvalidArchiveFraming { id: 1, offset, .. })
+                if *offset == second_offset
+        ));
+
+        assert!(block_storage.archive_manifest(2)?.is_none());
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn archive_infos_report_corruption() -> Result<()> {
+        let (db, path) = open_test_db("archive-infos")?;
+
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        archive.extend_from_slice(&make_archive_segment("first", &[1; 10]));
+        archive.extend_from_slice(&make_archive_segment("second", &[2; 10]));
+        db.archives.insert(0u32.to_be_bytes(), &archive)?;
+        db.archives
+            .insert(1u32.to_be_bytes(), &archive[..archive.len() - 1])?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        let infos = block_storage.archive_infos(&[0, 1, 2])?;
+        assert!(matches!(
+            infos[0],
+            Some(ArchiveInfo {
+                entry_count: 2,
+                is_valid: true,
+                ..
+            })
+        ));
+        // Only the first entry is readable
+        assert!(matches!(
+            infos[1],
+            Some(ArchiveInfo {
+                entry_count: 1,
+                is_valid: false,
+                ..
+            })
+        ));
+        assert!(infos[2].is_none());
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn incomplete_archives_fail_on_corruption() -> Result<()> {
+        let (db, path) = open_test_db("incomplete-archives")?;
+
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        archive.extend_from_slice(&make_archive_segment("first", &[1; 10]));
+        db.archives.insert(0u32.to_be_bytes(), &archive)?;
+        archive.extend_from_slice(&make_archive_segment("second", &[2; 10]));
+        db.archives.insert(1u32.to_be_bytes(), &archive)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        assert_eq!(block_storage.find_incomplete_archives(|_| 2)?, [(0, 1, 2)]);
+
+        // Corrupted archive is not reported as an empty one
+        db.archives
+            .insert(2u32.to_be_bytes(), &archive[..archive.len() - 1])?;
+        assert!(block_storage.find_incomplete_archives(|_| 2).is_err());
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn archive_fingerprint() -> Result<()> {
+        let (db, path) = open_test_db("archive-fingerprint")?;
+
+        let first = make_archive_segment("first", &[1; 100]);
+        let second = make_archive_segment("second", &[2; 10]);
+        let archive = |segments: &[&[u8]]| {
+            let mut archive = ARCHIVE_PREFIX.to_vec();
+            for segment in segments {
+                archive.extend_from_slice(segment);
+            }
+            archive
+        };
+
+        db.archives
+            .insert(0u32.to_be_bytes(), archive(&[&first, &second]))?;
+        // Same entries in another order
+        db.archives
+            .insert(1u32.to_be_bytes(), archive(&[&second, &first]))?;
+        // Repeated segment
+        db.archives
+            .insert(2u32.to_be_bytes(), archive(&[&first, &second, &first]))?;
+        // Different data under the same name
+        db.archives.insert(
+            3u32.to_be_bytes(),
+            archive(&[&first, &make_archive_segment("second", &[3; 10])]),
+        )?;
+        // Truncated
+        let truncated = archive(&[&first, &second]);
+        db.archives
+            .insert(4u32.to_be_bytes(), &truncated[..truncated.len() - 1])?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        let fingerprint = block_storage.archive_fingerprint(0)?.unwrap();
+        assert_eq!(block_storage.archive_fingerprint(1)?, Some(fingerprint));
+        assert_ne!(block_storage.archive_fingerprint(2)?, Some(fingerprint));
+        assert_ne!(block_storage.archive_fingerprint(3)?, Some(fingerprint));
+        assert!(matches!(
+            block_storage
+                .archive_fingerprint(4)
+                .unwrap_err()
+                .downcast_ref::<BlockStorageError>(),
+            Some(BlockStorageError::InvalidArchiveFraming { id: 4, .. })
+        ));
+        assert!(block_storage.archive_fingerprint(5)?.is_none());
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn streaming_archive_verification() -> Result<()> {
+        let (db, path) = open_test_db("archive-verify-streaming")?;
+
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        archive.extend_from_slice(&make_archive_segment("first", &[1; 100]));
+        archive.extend_from_slice(&make_archive_segment("second", &[]));
+        db.archives.insert(0u32.to_be_bytes(), &archive)?;
+        db.archives
+            .insert(1u32.to_be_bytes(), &archive[..archive.len() - 1])?;
+        let mut duplicated = archive.clone();
+        duplicated.extend_from_slice(&make_archive_segment("first", &[1; 100]));
+        db.archives.insert(3u32.to_be_bytes(), &duplicated)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        let report = block_storage.verify_archive_streaming(0)?;
+        assert!(report.is_valid);
+        assert_eq!(report.entry_count, 2);
+        assert_eq!(report.byte_len, archive.len());
+
+        let report = block_storage.verify_archive_streaming(1)?;
+        assert!(!report.is_valid);
+        assert!(report.duplicate_entry.is_none());
+
+        assert!(block_storage.verify_archive_streaming(2).is_err());
+
+        let report = block_storage.verify_archive_streaming(3)?;
+        assert!(!report.is_valid);
+        assert_eq!(report.duplicate_entry.as_deref(), Some("first"));
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn gc_removes_orphans_of_recent_entries() -> Result<()> {
+        let (db, path) = open_test_db("blocks-gc-early-out")?;
+
+        let block_id = |shard_id: ton_block::ShardIdent, seq_no: u32| ton_block::BlockIdExt {
+            shard_id,
+            seq_no,
+            root_hash: UInt256::from([seq_no as u8; 32]),
+            file_hash: UInt256::from([seq_no as u8; 32]),
+        };
+        let mc = ton_block::ShardIdent::masterchain();
+        let shard = ton_block::ShardIdent::full(0);
+        for id in [block_id(mc, 10), block_id(mc, 11), block_id(shard, 5)] {
+            db.package_entries
+                .insert(PackageEntryId::Block(&id).to_vec(), [1, 2, 3])?;
+        }
+
+        let top_blocks = |mc_seq_no: u32, shard_seq_no: u32| TopBlocks {
+            mc_block: (mc, mc_seq_no),
+            shard_heights: FastHashMap::from_iter([(shard, shard_seq_no)]),
+        };
+
+        assert!(!has_outdated_shard_entries(&db, &top_blocks(10, 5))?);
+        assert!(has_outdated_shard_entries(&db, &top_blocks(11, 5))?);
+        assert!(has_outdated_shard_entries(&db, &top_blocks(10, 6))?);
+
+        let stats = remove_blocks(
+            db.clone(),
+            &Default::default(),
+            &Default::default(),
+            None,
+            None,
+            None,
+            false,
+            &ArcSwap::from_pointee(top_blocks(10, 5)),
+        )?;
+        assert_eq!(stats.total_package_entries_removed, 0);
+        // Entries have no handles, so they are still removed
+        assert_eq!(stats.orphan_entries_removed, 3);
+
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn blocks_gc_estimate_samples_all_shards() -> Result<()> {
+        let (db, path) = open_test_db("blocks-gc-estimate")?;
+
+        let block_id = |shard_id: ton_block::ShardIdent, seq_no: u32| ton_block::BlockIdExt {
+            shard_id,
+            seq_no,
+            root_hash: UInt256::from([seq_no as u8; 32]),
+            file_hash: UInt256::from([seq_no as u8; 32]),
+        };
+        let mc = ton_block::ShardIdent::masterchain();
+        let shard = ton_block::ShardIdent::full(0);
+        for seq_no in 1..=100 {
+            for id in [block_id(mc, seq_no), block_id(shard, seq_no)] {
+                db.package_entries
+                    .insert(PackageEntryId::Block(&id).to_vec(), [1, 2, 3])?;
+            }
+        }
+
+        // Only the shard entries are outdated
+        let top_blocks = TopBlocks {
+            mc_block: (mc, 1),
+            shard_heights: FastHashMap::from_iter([(shard, 101)]),
+        };
+        let estimate = estimate_blocks_gc(&db, &top_blocks, 1000)?;
+        assert_eq!(estimate.sampled_entries, 1000);
+
+        // Roughly a half of the samples must hit the shard entries
+        assert!(
+            (300..=700).contains(&estimate.sampled_removable_entries),
+            "{estimate:?}"
+        );
+
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn blocks_gc_rereads_top_blocks() -> Result<()> {
+        let (db, path) = open_test_db("blocks-gc-shared-top-blocks")?;
+
+        let mc = ton_block::ShardIdent::masterchain();
+        let block_ids = (1..=10u32)
+            .map(|seq_no| ton_block::BlockIdExt {
+                shard_id: mc,
+                seq_no,
+                root_hash: UInt256::from([seq_no as u8; 32]),
+                file_hash: UInt256::from([seq_no as u8; 32]),
+            })
+            .collect::<Vec<_>>();
+        for id in &block_ids {
+            db.package_entries
+                .insert(PackageEntryId::Block(id).to_vec(), [1, 2, 3])?;
+            db.block_handles
+                .insert(id.root_hash.as_slice(), BlockMeta::default().to_vec())?;
+        }
+
+        let top_blocks = |mc_seq_no: u32| TopBlocks {
+            mc_block: (mc, mc_seq_no),
+            shard_heights: Default::default(),
+        };
+
+        // All blocks are collectible at the start
+        let shared_top_blocks = Arc::new(ArcSwap::from_pointee(top_blocks(11)));
+        let gc_lock = Arc::new(tokio::sync::RwLock::new(()));
+
+        // Pause the GC before writing the batch
+        let gc_guard = gc_lock.clone().try_read_owned()?;
+        let gc = {
+            let db = db.clone();
+            let gc_lock = gc_lock.clone();
+            let top_blocks = shared_top_blocks.clone();
+            std::thread::spawn(move || {
+                remove_blocks(
+                    db,
+                    &gc_lock,
+                    &Default::default(),
+                    None,
+                    None,
+                    None,
+                    false,
+                    &top_blocks,
+                )
+            })
+        };
+
+        // NOTE: new readers are not admitted while the writer is waiting
+        while let Ok(guard) = gc_lock.try_read() {
+            drop(guard);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // Newer blocks become protected during the GC run
+        shared_top_blocks.store(Arc::new(top_blocks(6)));
+        drop(gc_guard);
+
+        let stats = gc.join().unwrap()?;
+        assert_eq!(stats.total_package_entries_removed, 5);
+        assert_eq!(stats.total_handles_removed, 5);
+
+        for id in &block_ids {
+            let entry = db.package_entries.get(PackageEntryId::Block(id).to_vec())?;
+            assert_eq!(entry.is_some(), id.seq_no >= 6);
+        }
+
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn block_gc_stats_sum() {
+        let first = BlockGcStats {
+            mc_package_entries_removed: 1,
+            total_package_entries_removed: 2,
+            total_handles_removed: 3,
+            total_cached_handles_removed: 4,
+            orphan_entries_removed: 5,
+            retained_proof_entries: 6,
+            in_flight_archive_entries: 7,
+        };
+        let second = BlockGcStats {
+            mc_package_entries_removed: 10,
+            total_package_entries_removed: 20,
+            total_handles_removed: 30,
+            total_cached_handles_removed: 40,
+            orphan_entries_removed: 50,
+            retained_proof_entries: 60,
+            in_flight_archive_entries: 70,
+        };
+
+        let mut total = BlockGcStats::default();
+        total += first;
+        total.merge(&second);
+
+        let sum = first + second;
+        for stats in [total, sum] {
+            assert_eq!(stats.mc_package_entries_removed, 11);
+            assert_eq!(stats.total_package_entries_removed, 22);
+            assert_eq!(stats.total_handles_removed, 33);
+            assert_eq!(stats.total_cached_handles_removed, 44);
+            assert_eq!(stats.orphan_entries_removed, 55);
+            assert_eq!(stats.retained_proof_entries, 66);
+            assert_eq!(stats.in_flight_archive_entries, 77);
+        }
+    }
+
+    #[test]
+    fn unlocked_data_read() -> Result<()> {
+        let (db, path) = open_test_db("unlocked-data-read")?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 1,
+            root_hash: UInt256::from([1; 32]),
+            file_hash: UInt256::from([1; 32]),
+        };
+        block_storage.add_data(&PackageEntryId::Block(&block_id), &[1, 2, 3])?;
+
+        assert_eq!(
+            block_storage.get_data_unlocked(&PackageEntryId::Block(&block_id))?,
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(
+            block_storage.get_data_unlocked(&PackageEntryId::Proof(&block_id))?,
+            None
+        );
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_block_data_clears_flag() -> Result<()> {
+        let (db, path) = open_test_db("remove-block-data")?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::full(0),
+            seq_no: 1,
+            root_hash: UInt256::from([1; 32]),
+            file_hash: UInt256::from([1; 32]),
+        };
+        let (handle, _) = block_handle_storage.create_or_load_handle(
+            &block_id,
+            BlockMetaData {
+                is_key_block: false,
+                gen_utime: 0,
+                mc_ref_seqno: Some(1),
+            },
+        )?;
+        db.package_entries
+            .insert(PackageEntryId::Block(&block_id).to_vec(), [1, 2, 3])?;
+        db.package_entries
+            .insert(PackageEntryId::Proof(&block_id).to_vec(), [4, 5])?;
+        handle.meta().set_has_data();
+        handle.meta().set_has_proof();
+        block_handle_storage.store_handle(&handle)?;
+
+        block_storage.remove_block_data(&handle).await?;
+
+        assert!(!handle.meta().has_data());
+        assert!(block_storage.load_block_data_raw(&handle).await.is_err());
+        assert!(db
+            .package_entries
+            .get(PackageEntryId::Block(&block_id).to_vec())?
+            .is_none());
+        assert!(db
+            .package_entries
+            .get(PackageEntryId::Proof(&block_id).to_vec())?
+            .is_some());
+
+        // The flag is persisted
+        let value = db
+            .block_handles
+            .get(block_id.root_hash.as_slice())?
+            .unwrap();
+        let meta = BlockMeta::from_slice(value.as_ref())?;
+        assert!(!meta.has_data());
+        assert!(meta.has_proof());
+
+        drop(handle);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn handle_meta_by_root() -> Result<()> {
+        let (db, path) = open_test_db("handle-meta-by-root")?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 1,
+            root_hash: UInt256::from([1; 32]),
+            file_hash: UInt256::from([1; 32]),
+        };
+        let (handle, _) = block_handle_storage.create_or_load_handle(
+            &block_id,
+            BlockMetaData {
+                is_key_block: true,
+                gen_utime: 123,
+                mc_ref_seqno: Some(1),
+            },
+        )?;
+        block_handle_storage.store_handle(&handle)?;
+
+        let meta = block_storage
+            .load_handle_meta_by_root(&block_id.root_hash)?
+            .unwrap();
+        assert!(meta.is_key_block());
+        assert_eq!(meta.gen_utime(), 123);
+
+        assert!(block_storage
+            .load_handle_meta_by_root(&UInt256::from([2; 32]))?
+            .is_none());
+
+        drop(handle);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn archive_entry_by_index() -> Result<()> {
+        let (db, path) = open_test_db("archive-entry-by-index")?;
+
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        archive.extend_from_slice(&make_archive_segment("first", &[1, 2, 3]));
+        archive.extend_from_slice(&make_archive_segment("second", &[4]));
+        db.archives.insert(0u32.to_be_bytes(), &archive)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        assert_eq!(
+            block_storage.get_archive_entry_by_index(0, 0)?,
+            Some(("first".to_owned(), vec![1, 2, 3]))
+        );
+        assert_eq!(
+            block_storage.get_archive_entry_by_index(0, 1)?,
+            Some(("second".to_owned(), vec![4]))
+        );
+        assert_eq!(block_storage.get_archive_entry_by_index(0, 2)?, None);
+        assert_eq!(block_storage.get_archive_entry_by_index(1, 0)?, None);
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn archive_slice_ext_reports_last_chunk() -> Result<()> {
+        let (db, path) = open_test_db("archive-slice-ext")?;
+        db.archives.insert(0u32.to_be_bytes(), [1u8; 10])?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        assert_eq!(block_storage.current_open_archive_id(), Some(0));
+        assert!(!block_storage.is_archive_sealed(0));
+
+        let chunk = |offset| block_storage.get_archive_slice_ext(0, offset, 4);
+        assert_eq!(chunk(0)?, Some((vec![1; 4], false)));
+        assert_eq!(chunk(6)?, Some((vec![1; 4], true)));
+        assert_eq!(chunk(8)?, Some((vec![1; 2], true)));
+        assert_eq!(chunk(10)?, Some((vec![], true)));
+        assert!(chunk(11).is_err());
+        assert_eq!(block_storage.get_archive_slice_ext(1, 0, 4)?, None);
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn move_into_archive_returns_id() -> Result<()> {
+        let (db, path) = open_test_db("move-into-archive-ret")?;
+        for id in [0u32, 200] {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+        }
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 210,
+            root_hash: UInt256::from([1; 32]),
+            file_hash: UInt256::from([1; 32]),
+        };
+        let (handle, _) = block_handle_storage.create_or_load_handle(
+            &block_id,
+            BlockMetaData {
+                is_key_block: false,
+                gen_utime: 0,
+                mc_ref_seqno: Some(210),
+            },
+        )?;
+
+        assert_eq!(
+            block_storage.move_into_archive_ret(&handle).await?,
+            Some(200)
+        );
+        // Already archived
+        assert_eq!(block_storage.move_into_archive_ret(&handle).await?, None);
+
+        drop(handle);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn full_archive_is_sealed_at_masterchain_seqno() -> Result<()> {
+        let (db, path) = open_test_db("full-archive-seal")?;
+        db.archives.insert(0u32.to_be_bytes(), ARCHIVE_PREFIX)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage = BlockStorage::new(
+            db.clone(),
+            block_handle_storage.clone(),
+            BlockStorageOptions {
+                max_archive_size: Some(16),
+                ..Default::default()
+            },
+        )?;
+
+        let archive = |i: u8, mc_seq_no: u32| {
+            let (db, block_handle_storage, block_storage) =
+                (&db, &block_handle_storage, &block_storage);
+            async move {
+                let block_id = ton_block::BlockIdExt {
+                    shard_id: ton_block::ShardIdent::full(0),
+                    seq_no: i as u32,
+                    root_hash: UInt256::from([i; 32]),
+                    file_hash: UInt256::from([i; 32]),
+                };
+                let (handle, _) = block_handle_storage.create_or_load_handle(
+                    &block_id,
+                    BlockMetaData {
+                        is_key_block: false,
+                        gen_utime: 0,
+                        mc_ref_seqno: Some(mc_seq_no),
+                    },
+                )?;
+                block_storage
+                    .move_into_archive_with_data(&handle, false, &[i; 8], &[i; 8])
+                    .await?;
+
+                let id = db.archive_block_index.get([i; 32])?.unwrap();
+                Ok::<_, anyhow::Error>(u32::from_be_bytes(id[..4].try_into()?))
+            }
+        };
+
+        // The first block fills the archive, but blocks of the same seqno stay in it
+        assert_eq!(archive(1, 1).await?, 0);
+        assert_eq!(archive(2, 1).await?, 0);
+        // The next seqno starts a new archive
+        assert_eq!(archive(3, 2).await?, 2);
+        // Late blocks of the sealed seqno are still in the old archive
+        assert_eq!(archive(4, 1).await?, 0);
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quarantined_archives_are_not_extended() -> Result<()> {
+        let (db, path) = open_test_db("quarantined-archives")?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage = BlockStorage::new(
+            db.clone(),
+            block_handle_storage.clone(),
+            BlockStorageOptions {
+                archive_id_strategy: ArchiveIdStrategy::Utime { bucket_sec: 3600 },
+                ..Default::default()
+            },
+        )?;
+        block_storage
+            .archive_verification
+            .lock()
+            .quarantined_ids
+            .insert(3600);
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 1,
+            root_hash: UInt256::from([1; 32]),
+            file_hash: UInt256::from([1; 32]),
+        };
+        let (handle, _) = block_handle_storage.create_or_load_handle(
+            &block_id,
+            BlockMetaData {
+                is_key_block: false,
+                gen_utime: 3700,
+                mc_ref_seqno: Some(1),
+            },
+        )?;
+
+        let e = block_storage
+            .move_into_archive_with_data(&handle, false, &[1, 2, 3], &[4, 5])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<BlockStorageError>(),
+            Some(BlockStorageError::ArchiveQuarantined { id: 3600 })
+        ));
+        assert!(!handle.meta().is_moving_to_archive());
+        assert!(db.archives.get(3600u32.to_be_bytes())?.is_none());
+
+        drop(handle);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn for_each_archive_within_budget() -> Result<()> {
+        let (db, path) = open_test_db("for-each-archive")?;
+
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        archive.extend_from_slice(&make_archive_segment("first", &[1; 100]));
+        for id in [0u32, 100] {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+        }
+        db.archives.insert(200u32.to_be_bytes(), &archive)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        let mut visited = Vec::new();
+        let processed = block_storage.for_each_archive(.., archive.len(), |id, data| {
+            visited.push((id, data.len()));
+            Ok(())
+        })?;
+        assert_eq!(processed, 3);
+        assert_eq!(
+            visited,
+            [
+                (0, ARCHIVE_PREFIX.len()),
+                (100, ARCHIVE_PREFIX.len()),
+                (200, archive.len())
+            ]
+        );
+
+        // Archives are processed until the one which doesn't fit
+        visited.clear();
+        let error = block_storage
+            .for_each_archive(100.., archive.len() - 1, |id, _| {
+                visited.push((id, 0));
+                Ok(())
+            })
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<BlockStorageError>(),
+            Some(BlockStorageError::ArchiveExceedsBudget { id: 200, .. })
+        ));
+        assert_eq!(visited, [(100, 0)]);
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn audit_reports_corrupted_archives() -> Result<()> {
+        let (db, path) = open_test_db("audit-archived-handles")?;
+        db.archives.insert(0u32.to_be_bytes(), ARCHIVE_PREFIX)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 10,
+            root_hash: UInt256::from([1; 32]),
+            file_hash: UInt256::from([1; 32]),
+        };
+        let (handle, _) = block_handle_storage.create_or_load_handle(
+            &block_id,
+            BlockMetaData {
+                is_key_block: false,
+                gen_utime: 0,
+                mc_ref_seqno: Some(10),
+            },
+        )?;
+        block_storage
+            .move_into_archive_with_data(&handle, false, &[1; 10], &[2; 20])
+            .await?;
+
+        let statuses = |block_storage: &BlockStorage| -> Result<Vec<_>> {
+            let report = block_storage.audit_archived_handles(None)?;
+            assert_eq!(report.checked_handles, 1);
+            Ok(report
+                .broken_handles
+                .into_iter()
+                .map(|handle| (handle.archive_id, handle.status))
+                .collect::<Vec<_>>())
+        };
+        assert!(statuses(&block_storage)?.is_empty());
+
+        // Broken framing is not reported as missing entries
+        let mut data = db.archives.get(0u32.to_be_bytes())?.unwrap().to_vec();
+        data.extend_from_slice(&[0xff; 8]);
+        db.archives.insert(0u32.to_be_bytes(), data)?;
+        assert_eq!(
+            statuses(&block_storage)?,
+            [(Some(0), BrokenArchivedHandleStatus::CorruptedArchive)]
+        );
+
+        db.archives.remove(0u32.to_be_bytes())?;
+        assert_eq!(
+            statuses(&block_storage)?,
+            [(Some(0), BrokenArchivedHandleStatus::MissingArchive)]
+        );
+
+        drop(handle);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lifetime_counters_survive_restart() -> Result<()> {
+        let (db, path) = open_test_db("lifetime-counters")?;
+        db.archives.insert(0u32.to_be_bytes(), ARCHIVE_PREFIX)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+        assert_eq!(block_storage.lifetime_counters(), Default::default());
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 10,
+            root_hash: UInt256::from([1; 32]),
+            file_hash: UInt256::from([1; 32]),
+        };
+        let (handle, _) = block_handle_storage.create_or_load_handle(
+            &block_id,
+            BlockMetaData {
+                is_key_block: false,
+                gen_utime: 0,
+                mc_ref_seqno: Some(10),
+            },
+        )?;
+        block_storage
+            .move_into_archive_with_data(&handle, false, &[1; 10], &[2; 20])
+            .await?;
+
+        let counters = block_storage.lifetime_counters();
+        assert_eq!(counters.archived_blocks, 1);
+        assert_eq!(counters.archived_bytes, 30);
+
+        // Counters are flushed on drop
+        drop(block_storage);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+        assert_eq!(block_storage.lifetime_counters(), counters);
+
+        // Storages on the same db don't overwrite each other
+        let other =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+        let block_id = ton_block::BlockIdExt {
+            seq_no: 11,
+            root_hash: UInt256::from([2; 32]),
+            file_hash: UInt256::from([2; 32]),
+            ..block_id
+        };
+        let (other_handle, _) = block_handle_storage.create_or_load_handle(
+            &block_id,
+            BlockMetaData {
+                is_key_block: false,
+                gen_utime: 0,
+                mc_ref_seqno: Some(11),
+            },
+        )?;
+        other
+            .move_into_archive_with_data(&other_handle, false, &[3; 5], &[4; 5])
+            .await?;
+        other.flush_metrics()?;
+        block_storage.flush_metrics()?;
+        drop(other);
+
+        let restored = BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+        let counters = restored.lifetime_counters();
+        assert_eq!(counters.archived_blocks, 2);
+        assert_eq!(counters.archived_bytes, 40);
+
+        drop(restored);
+        drop(other_handle);
+        drop(handle);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn archive_state_dump() -> Result<()> {
+        let (db, path) = open_test_db("archive-state-dump")?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+
+        let dump = block_storage.dump_archive_state();
+        assert!(dump.archive_ids.is_empty() && dump.sealed_ids.is_empty());
+        assert_eq!(dump.open_archive, None);
+        drop(block_storage);
+
+        for id in [0u32, 100, 200] {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+        }
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        let dump = block_storage.dump_archive_state();
+        assert_eq!(dump.archive_id_strategy, ArchiveIdStrategy::Seqno);
+        assert_eq!(dump.archive_ids, BTreeSet::from([0, 100, 200]));
+        assert_eq!(dump.sealed_ids, BTreeSet::from([0, 100]));
+        assert_eq!(
+            dump.open_archive,
+            Some(ArchiveOpenState {
+                id: 200,
+                size: ARCHIVE_PREFIX.len() as u64,
+            })
+        );
+        assert_eq!(dump.persisted_current_id, None);
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_archiving_limit() -> Result<()> {
+        let (db, path) = open_test_db("concurrent-archiving-limit")?;
+        db.archives.insert(0u32.to_be_bytes(), ARCHIVE_PREFIX)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage = BlockStorage::new(
+            db.clone(),
+            block_handle_storage.clone(),
+            BlockStorageOptions {
+                max_concurrent_archiving: Some(1),
+                ..Default::default()
+            },
+        )?;
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 10,
+            root_hash: UInt256::from([1; 32]),
+            file_hash: UInt256::from([1; 32]),
+        };
+        let (handle, _) = block_handle_storage.create_or_load_handle(
+            &block_id,
+            BlockMetaData {
+                is_key_block: false,
+                gen_utime: 0,
+                mc_ref_seqno: Some(10),
+            },
+        )?;
+
+        // Occupy the only permit
+        let permit = block_storage.archiving_semaphore.inner.acquire().await?;
+        assert_eq!(block_storage.archiving_permits_in_use(), 1);
+
+        // Block waits for the permit instead of failing
+        let moved = tokio::time::timeout(
+            Duration::from_millis(50),
+            block_storage.move_into_archive_ret(&handle),
+        )
+        .await;
+        assert!(moved.is_err());
+        assert!(!handle.meta().is_archived());
+
+        // Blocks with data wait for the permit too
+        let moved = tokio::time::timeout(
+            Duration::from_millis(50),
+            block_storage.move_into_archive_with_data(&handle, false, &[1], &[2]),
+        )
+        .await;
+        assert!(moved.is_err());
+        assert!(!handle.meta().is_archived());
+
+        drop(permit);
+        assert_eq!(block_storage.archiving_permits_in_use(), 0);
+
+        assert_eq!(block_storage.move_into_archive_ret(&handle).await?, Some(0));
+        assert_eq!(block_storage.archiving_permits_in_use(), 0);
+
+        drop(handle);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_archiving() -> Result<()> {
+        let (db, path) = open_test_db("archiving-shutdown")?;
+        db.archives.insert(0u32.to_be_bytes(), ARCHIVE_PREFIX)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage = BlockStorage::new(
+            db.clone(),
+            block_handle_storage.clone(),
+            BlockStorageOptions {
+                max_concurrent_archiving: Some(2),
+                ..Default::default()
+            },
+        )?;
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 10,
+            root_hash: UInt256::from([1; 32]),
+            file_hash: UInt256::from([1; 32]),
+        };
+        let (handle, _) = block_handle_storage.create_or_load_handle(
+            &block_id,
+            BlockMetaData {
+                is_key_block: false,
+                gen_utime: 0,
+                mc_ref_seqno: Some(10),
+            },
+        )?;
+
+        {
+            // Simulate an in-flight move
+            let permit = block_storage.archiving_semaphore.inner.acquire().await?;
+
+            let shutdown = block_storage.shutdown();
+            tokio::pin!(shutdown);
+            assert!(
+                tokio::time::timeout(Duration::from_millis(50), &mut shutdown)
+                    .await
+                    .is_err()
+            );
+
+            drop(permit);
+            shutdown.await?;
+        }
+        assert_eq!(block_storage.archiving_permits_in_use(), 0);
+
+        // New blocks are rejected without being marked as moving
+        let error = block_storage.move_into_archive(&handle).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<BlockStorageError>(),
+            Some(BlockStorageError::ArchivingStopped)
+        ));
+        assert!(handle.meta().set_is_moving_to_archive());
+
+        drop(handle);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_archiving_resets_moving_flag() -> Result<()> {
+        let (db, path) = open_test_db("interrupted-archiving")?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 1,
+            root_hash: UInt256::from([1; 32]),
+            file_hash: UInt256::from([1; 32]),
+        };
+        let (handle, _) = block_handle_storage.create_or_load_handle(
+            &block_id,
+            BlockMetaData {
+                is_key_block: false,
+                gen_utime: 0,
+                mc_ref_seqno: Some(1),
+            },
+        )?;
+
+        // Move is interrupted before the block is archived
+        assert!(handle.meta().set_is_moving_to_archive());
+        let guard = ArchivingGuard::new(&block_storage.archiving_blocks, &handle);
+        assert!(block_storage
+            .archiving_blocks
+            .lock()
+            .contains(block_id.root_hash.as_array()));
+        drop(guard);
+
+        assert!(!handle.meta().is_moving_to_archive());
+        assert!(block_storage.archiving_blocks.lock().is_empty());
+
+        // Archived blocks keep the flag
+        assert!(handle.meta().set_is_moving_to_archive());
+        let guard = ArchivingGuard::new(&block_storage.archiving_blocks, &handle);
+        handle.meta().set_is_archived();
+        drop(guard);
+        assert!(handle.meta().is_moving_to_archive());
+
+        drop(handle);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn paused_archiving_is_drained() -> Result<()> {
+        let (db, path) = open_test_db("paused-archiving")?;
+        db.archives.insert(0u32.to_be_bytes(), ARCHIVE_PREFIX)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+
+        let mut handles = Vec::new();
+        for seq_no in [1u32, 2] {
+            let block_id = ton_block::BlockIdExt {
+                shard_id: ton_block::ShardIdent::masterchain(),
+                seq_no,
+                root_hash: UInt256::from([seq_no as u8; 32]),
+                file_hash: UInt256::from([seq_no as u8; 32]),
+            };
+            let (handle, _) = block_handle_storage.create_or_load_handle(
+                &block_id,
+                BlockMetaData {
+                    is_key_block: false,
+                    gen_utime: 0,
+                    mc_ref_seqno: Some(seq_no),
+                },
+            )?;
+            handles.push(handle);
+        }
+
+        block_storage.set_archiving_paused(true);
+        for handle in &handles {
+            block_storage.move_into_archive(handle).await?;
+            assert!(!handle.meta().is_moving_to_archive());
+            assert!(!handle.meta().is_archived());
+        }
+
+        // Still paused
+        let stats = block_storage.drain_pending_archiving(1).await?;
+        assert_eq!(stats.paused, 2);
+
+        // Remembered blocks are restored after a restart and kept by blocks GC
+        drop(block_storage);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+        assert_eq!(block_storage.archiving_blocks.lock().len(), 2);
+
+        let stats = block_storage.drain_pending_archiving(1).await?;
+        assert_eq!(stats.archived, 2);
+        assert!(handles.iter().all(|handle| handle.meta().is_archived()));
+        assert!(block_storage.archiving_blocks.lock().is_empty());
+
+        let stats = block_storage.drain_pending_archiving(1).await?;
+        assert_eq!(stats.archived, 0);
+        assert!(load_paused_archiving(&db)?.is_empty());
+
+        drop(handles);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn blocks_gc_does_not_tear_archiving() -> Result<()> {
+        let (db, path) = open_test_db("blocks-gc-vs-archiving")?;
+        db.archives.insert(0u32.to_be_bytes(), ARCHIVE_PREFIX)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage = Arc::new(BlockStorage::new(
+            db.clone(),
+            block_handle_storage.clone(),
+            Default::default(),
+        )?);
+
+        const BLOCK_COUNT: u32 = 64;
+
+        let mut handles = Vec::new();
+        for seq_no in 1..=BLOCK_COUNT {
+            let block_id = ton_block::BlockIdExt {
+                shard_id: ton_block::ShardIdent::masterchain(),
+                seq_no,
+                root_hash: UInt256::from([seq_no as u8; 32]),
+                file_hash: UInt256::from([seq_no as u8; 32]),
+            };
+            let (handle, _) = block_handle_storage.create_or_load_handle(
+                &block_id,
+                BlockMetaData {
+                    is_key_block: false,
+                    gen_utime: 0,
+                    mc_ref_seqno: Some(seq_no),
+                },
+            )?;
+            db.package_entries
+                .insert(PackageEntryId::Block(&block_id).to_vec(), [1, 2, 3])?;
+            db.package_entries
+                .insert(PackageEntryId::Proof(&block_id).to_vec(), [4, 5])?;
+            handle.meta().set_has_data();
+            handle.meta().set_has_proof();
+            handles.push(handle);
+        }
+
+        // All blocks are outdated
+        let top_blocks = TopBlocks {
+            mc_block: (ton_block::ShardIdent::masterchain(), BLOCK_COUNT + 1),
+            shard_heights: Default::default(),
+        };
+        let gc = {
+            let block_storage = block_storage.clone();
+            tokio::spawn(async move {
+                block_storage
+                    .remove_blocks_before(&top_blocks, None, None, false)
+                    .await
+            })
+        };
+
+        let mut archived = 0;
+        for handle in &handles {
+            match block_storage.move_into_archive(handle).await {
+                Ok(()) => archived += 1,
+                // Entries were removed before archiving started
+                Err(e) => assert!(matches!(
+                    e.downcast_ref::<BlockStorageError>(),
+                    Some(BlockStorageError::InvalidBlockData)
+                )),
+            }
+        }
+        gc.await??;
+
+        // Each archived block has both entries in the archive
+        let report = block_storage.verify_archive_streaming(0)?;
+        assert!(report.is_valid);
+        assert_eq!(report.entry_count, archived * 2);
+
+        drop(handles);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn blocks_gc_defers_in_flight_archiving() -> Result<()> {
+        let (db, path) = open_test_db("blocks-gc-in-flight-archiving")?;
+        db.archives.insert(0u32.to_be_bytes(), ARCHIVE_PREFIX)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage = Arc::new(BlockStorage::new(
+            db.clone(),
+            block_handle_storage.clone(),
+            BlockStorageOptions {
+                max_archive_write_rate: Some(1000),
+                ..Default::default()
+            },
+        )?);
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 1,
+            root_hash: UInt256::from([1; 32]),
+            file_hash: UInt256::from([1; 32]),
+        };
+        let (handle, _) = block_handle_storage.create_or_load_handle(
+            &block_id,
+            BlockMetaData {
+                is_key_block: false,
+                gen_utime: 0,
+                mc_ref_seqno: Some(1),
+            },
+        )?;
+        block_handle_storage.store_handle(&handle)?;
+        db.package_entries
+            .insert(PackageEntryId::Block(&block_id).to_vec(), [1, 2, 3])?;
+        db.package_entries
+            .insert(PackageEntryId::Proof(&block_id).to_vec(), [4, 5])?;
+        handle.meta().set_has_data();
+        handle.meta().set_has_proof();
+
+        // Pause archiving for a second after the moving flag is set
+        block_storage
+            .archive_write_limiter
+            .as_ref()
+            .unwrap()
+            .consume(2000);
+        let archiving = {
+            let block_storage = block_storage.clone();
+            let handle = handle.clone();
+            tokio::spawn(async move { block_storage.move_into_archive(&handle).await })
+        };
+        while !handle.meta().is_moving_to_archive() {
+            tokio::task::yield_now().await;
+        }
+
+        let top_blocks = TopBlocks {
+            mc_block: (ton_block::ShardIdent::masterchain(), 2),
+            shard_heights: Default::default(),
+        };
+        let stats = block_storage
+            .remove_blocks_before(&top_blocks, None, None, false)
+            .await?;
+        assert_eq!(stats.in_flight_archive_entries, 2);
+        assert_eq!(stats.total_package_entries_removed, 0);
+        assert_eq!(stats.total_handles_removed, 0);
+
+        // In-flight block survives and is archived
+        archiving.await??;
+        assert!(handle.meta().is_archived());
+        let report = block_storage.verify_archive_streaming(0)?;
+        assert!(report.is_valid);
+        assert_eq!(report.entry_count, 2);
+
+        // Entries are collected by the next GC run
+        let stats = block_storage
+            .remove_blocks_before(&top_blocks, None, None, false)
+            .await?;
+        assert_eq!(stats.in_flight_archive_entries, 0);
+        assert_eq!(stats.total_package_entries_removed, 2);
+
+        drop(handle);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn blocks_gc_clears_data_flag_of_retained_proofs() -> Result<()> {
+        let (db, path) = open_test_db("blocks-gc-retained-proofs")?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 1,
+            root_hash: UInt256::from([1; 32]),
+            file_hash: UInt256::from([1; 32]),
+        };
+        let (handle, _) = block_handle_storage.create_or_load_handle(
+            &block_id,
+            BlockMetaData {
+                is_key_block: false,
+                gen_utime: 0,
+                mc_ref_seqno: Some(1),
+            },
+        )?;
+        handle.meta().set_has_data();
+        handle.meta().set_has_proof();
+        block_handle_storage.store_handle(&handle)?;
+        drop(handle);
+        db.package_entries
+            .insert(PackageEntryId::Block(&block_id).to_vec(), [1, 2, 3])?;
+        db.package_entries
+            .insert(PackageEntryId::Proof(&block_id).to_vec(), [4, 5])?;
+
+        let top_blocks = TopBlocks {
+            mc_block: (ton_block::ShardIdent::masterchain(), 2),
+            shard_heights: Default::default(),
+        };
+        let stats = block_storage
+            .remove_blocks_before(&top_blocks, None, None, true)
+            .await?;
+        assert_eq!(stats.total_package_entries_removed, 1);
+        assert_eq!(stats.retained_proof_entries, 1);
+        assert_eq!(stats.total_handles_removed, 0);
+
+        // The kept handle no longer reports the removed data
+        let value = db
+            .block_handles
+            .get(block_id.root_hash.as_slice())?
+            .unwrap();
+        let meta = BlockMeta::from_slice(value.as_ref())?;
+        assert!(!meta.has_data());
+        assert!(meta.has_proof());
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn blocks_gc_tracks_tombstones() -> Result<()> {
+        let (db, path) = open_test_db("blocks-gc-tombstones")?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        for seq_no in 1..=10u32 {
+            let block_id = ton_block::BlockIdExt {
+                shard_id: ton_block::ShardIdent::masterchain(),
+                seq_no,
+                root_hash: UInt256::from([seq_no as u8; 32]),
+                file_hash: UInt256::from([seq_no as u8; 32]),
+            };
+            db.package_entries
+                .insert(PackageEntryId::Block(&block_id).to_vec(), [1, 2, 3])?;
+        }
+
+        assert_eq!(block_storage.package_entries_tombstones(), 0);
+        assert_eq!(block_storage.last_package_entries_compaction(), None);
+
+        let top_blocks = TopBlocks {
+            mc_block: (ton_block::ShardIdent::masterchain(), 6),
+            shard_heights: Default::default(),
+        };
+        let stats = block_storage
+            .remove_blocks_before(&top_blocks, None, None, false)
+            .await?;
+        assert_eq!(stats.total_package_entries_removed, 5);
+        assert_eq!(block_storage.package_entries_tombstones(), 5);
+
+        block_storage.compact_package_entries().await;
+        assert_eq!(block_storage.package_entries_tombstones(), 0);
+        assert!(block_storage.last_package_entries_compaction().is_some());
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn current_archive_id_survives_restart() -> Result<()> {
+        let (db, path) = open_test_db("current-archive-id")?;
+        db.archives.insert(0u32.to_be_bytes(), ARCHIVE_PREFIX)?;
+
+        let options = || BlockStorageOptions {
+            persist_current_archive_id: true,
+            ..Default::default()
+        };
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage = BlockStorage::new(db.clone(), block_handle_storage.clone(), options())?;
+        assert_eq!(block_storage.current_archive_id(), Some(0));
+
+        // Archive is started, but no blocks were written
+        block_storage.start_archive(100);
+        assert_eq!(block_storage.current_archive_id(), Some(100));
+        assert_eq!(block_storage.get_archive_id(150), Some(100));
+        drop(block_storage);
+
+        // Started archive has no row, so it is not adopted
+        let block_storage = BlockStorage::new(db.clone(), block_handle_storage.clone(), options())?;
+        assert_eq!(block_storage.current_archive_id(), Some(0));
+        assert_eq!(block_storage.get_archive_id(150), Some(0));
+        drop(block_storage);
+
+        // Archive with a row is kept
+        db.archives.insert(100u32.to_be_bytes(), ARCHIVE_PREFIX)?;
+        let block_storage = BlockStorage::new(db.clone(), block_handle_storage, options())?;
+        assert_eq!(block_storage.current_archive_id(), Some(100));
+        assert_eq!(block_storage.get_archive_id(150), Some(100));
+        assert_eq!(block_storage.get_archive_id(50), Some(0));
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn consistent_archive_view_survives_gc() -> Result<()> {
+        let (db, path) = open_test_db("consistent-archive-view")?;
+
+        for id in [0u32, 100, 200, 300] {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+        }
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        let (ids, snapshot) = block_storage.consistent_archive_view();
+        assert_eq!(ids, [0, 100, 200, 300]);
+
+        block_storage.remove_outdated_archives(300).await?;
+        assert!(block_storage.get_archive_slice(0, 0, 4)?.is_none());
+
+        let mut read_options = db.archives.new_read_config();
+        read_options.set_snapshot(&snapshot);
+        for id in ids {
+            let value =
+                db.raw()
+                    .get_pinned_cf_opt(&db.archives.cf(), id.to_be_bytes(), &read_options)?;
+            assert_eq!(value.as_deref(), Some(&ARCHIVE_PREFIX[..]));
+        }
+
+        drop(snapshot);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archives_gc_skips_pinned() -> Result<()> {
+        let (db, path) = open_test_db("pinned-archives")?;
+
+        for id in [0u32, 100, 200, 300] {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+        }
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+        assert!(block_storage.pin_archive(100));
+
+        block_storage.remove_outdated_archives(300).await?;
+
+        // Pinned archive is still resolved
+        assert_eq!(block_storage.get_archive_id(150), Some(100));
+        assert!(block_storage.get_archive_slice(100, 0, 4)?.is_some());
+
+        assert!(block_storage.get_archive_slice(0, 0, 4)?.is_none());
+        assert!(block_storage.get_archive_slice(200, 0, 4)?.is_some());
+        assert!(block_storage.get_archive_slice(300, 0, 4)?.is_some());
+
+        // Unpinned archive is removed by the next GC
+        assert!(block_storage.unpin_archive(100));
+        block_storage.remove_outdated_archives(300).await?;
+        assert!(block_storage.get_archive_slice(100, 0, 4)?.is_none());
+        assert_eq!(block_storage.get_archive_id(150), None);
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chunked_archives_gc() -> Result<()> {
+        let (db, path) = open_test_db("chunked-archives-gc")?;
+
+        for id in (0u32..10).map(|i| i * 100) {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+        }
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        // Cancelled after the first chunk
+        let cancellation = CancellationToken::new();
+        let mut reports = Vec::new();
+        let completed = block_storage
+            .remove_outdated_archives_chunked(800, 3, &cancellation, |progress| {
+                reports.push(progress.removed_archives);
+                cancellation.cancel();
+            })
+            .await?;
+        assert!(!completed);
+        assert_eq!(reports, [3]);
+
+        for id in [0, 100, 200] {
+            assert!(block_storage.get_archive_slice(id, 0, 4)?.is_none());
+        }
+        assert_eq!(block_storage.get_archive_id(350), Some(300));
+        assert!(block_storage.get_archive_slice(300, 0, 4)?.is_some());
+
+        // Remaining archives are removed by the next run
+        let mut reports = Vec::new();
+        let completed = block_storage
+            .remove_outdated_archives_chunked(800, 3, &CancellationToken::new(), |progress| {
+                reports.push((progress.removed_archives, progress.total_archives));
+            })
+            .await?;
+        assert!(completed);
+        assert_eq!(reports, [(3, 4), (4, 4)]);
+
+        assert_eq!(block_storage.get_archive_id(650), None);
+        assert!(block_storage.get_archive_slice(600, 0, 4)?.is_none());
+        assert!(block_storage.get_archive_slice(700, 0, 4)?.is_some());
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archives_gc_keeps_min_retained() -> Result<()> {
+        let (db, path) = open_test_db("min-retained-archives")?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage = BlockStorage::new(
+            db.clone(),
+            block_handle_storage,
+            BlockStorageOptions {
+                min_retained_archives: 3,
+                ..Default::default()
+            },
+        )?;
+
+        let ids = [0u32, 100, 200, 300, 400, 500];
+        for id in ids {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+            block_storage.archive_ids.write().insert(id);
+        }
+        let retained = || {
+            block_storage
+                .archive_ids
+                .read()
+                .iter()
+                .copied()
+                .collect::<Vec<_>>()
+        };
+
+        // Seqno-based retention is larger
+        block_storage.remove_outdated_archives(150).await?;
+        assert_eq!(retained(), [100, 200, 300, 400, 500]);
+
+        // Aggressive `until_id` is limited by the min retained count
+        block_storage.remove_outdated_archives(10000).await?;
+        assert_eq!(retained(), [300, 400, 500]);
+        assert!(block_storage.get_archive_slice(200, 0, 4)?.is_none());
+        assert!(block_storage.get_archive_slice(300, 0, 4)?.is_some());
+
+        // Nothing is removed if there are not enough archives
+        block_storage.remove_outdated_archives(10000).await?;
+        assert_eq!(retained(), [300, 400, 500]);
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archives_gc_removes_old_key_blocks() -> Result<()> {
+        let (db, path) = open_test_db("archives-gc-key-blocks")?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage = BlockStorage::new(
+            db.clone(),
+            block_handle_storage,
+            BlockStorageOptions {
+                remove_key_blocks: true,
+                ..Default::default()
+            },
+        )?;
+
+        for id in [0u32, 100, 200, 300] {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+            block_storage.archive_ids.write().insert(id);
+        }
+        for seqno in [50u32, 100, 150, 200, 250] {
+            db.key_blocks.insert(seqno.to_be_bytes(), [])?;
+        }
+        block_storage.pin_archive(100);
+
+        block_storage.remove_outdated_archives(250).await?;
+
+        // Key blocks of the pinned archive are retained
+        assert!(db.key_blocks.get(50u32.to_be_bytes())?.is_none());
+        for seqno in [100u32, 150, 200, 250] {
+            assert!(db.key_blocks.get(seqno.to_be_bytes())?.is_some());
+        }
+
+        // Retained key blocks are still protected from blocks GC
+        let key_blocks_cf = db.key_blocks.cf();
+        let readopts = db.key_blocks.new_read_config();
+        let mc = ton_block::ShardIdent::masterchain();
+        let top_blocks = TopBlocks {
+            mc_block: (mc, 1000),
+            shard_heights: Default::default(),
+        };
+        for (seqno, outdated) in [(50, true), (150, false), (250, false), (260, true)] {
+            let is_outdated =
+                is_outdated_entry(db.raw(), &key_blocks_cf, &readopts, &top_blocks, &mc, seqno)?;
+            assert_eq!(is_outdated, outdated, "seqno {seqno}");
+        }
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archive_index_is_written_on_append() -> Result<()> {
+        let (db, path) = open_test_db("archive-index-append")?;
+        for id in [0u32, 100] {
+            db.archives.insert(id.to_be_bytes(), ARCHIVE_PREFIX)?;
+        }
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage.clone(), Default::default())?;
+
+        let mut block_ids = Vec::new();
+        for (i, mc_seq_no) in [(1u8, 10), (2, 10), (3, 110)] {
+            let block_id = ton_block::BlockIdExt {
+                shard_id: ton_block::ShardIdent::full(0),
+                seq_no: i as u32,
+                root_hash: UInt256::from([i; 32]),
+                file_hash: UInt256::from([i; 32]),
+            };
+            let (handle, _) = block_handle_storage.create_or_load_handle(
+                &block_id,
+                BlockMetaData {
+                    is_key_block: false,
+                    gen_utime: 0,
+                    mc_ref_seqno: Some(mc_seq_no),
+                },
+            )?;
+            block_storage
+                .move_into_archive_with_data(&handle, false, &[i; 3], &[i; 5])
+                .await?;
+            block_ids.push(block_id);
+        }
+
+        for block_id in &block_ids {
+            let location = block_storage.locate_by_root_hash(&block_id.root_hash)?;
+            assert!(location.is_some());
+            assert_eq!(location, block_storage.locate_block_in_archive(block_id)?);
+        }
+
+        // Entries of earlier versions are not resolved by the lookup
+        let root_hash = block_ids[1].root_hash.as_slice();
+        let (archive_id, ..) = block_storage
+            .locate_by_root_hash(&block_ids[1].root_hash)?
+            .unwrap();
+        db.archive_block_index
+            .insert(root_hash, encode_archive_index_entry(archive_id, None))?;
+        db.archive_block_hashes
+            .remove(archive_block_hashes_key(archive_id, root_hash))?;
+
+        let error = block_storage
+            .locate_by_root_hash(&block_ids[1].root_hash)
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<BlockStorageError>(),
+            Some(BlockStorageError::ArchiveIndexEntryNotLocated)
+        ));
+        assert_eq!(
+            decode_archive_index_entry(&db.archive_block_index.get(root_hash)?.unwrap()),
+            Some((archive_id, None))
+        );
+
+        // But are located by the reindex
+        assert_eq!(block_storage.reindex_archive_locations()?, 1);
+        assert_eq!(
+            block_storage.locate_by_root_hash(&block_ids[1].root_hash)?,
+            block_storage.locate_block_in_archive(&block_ids[1])?
+        );
+        assert!(block_storage
+            .archive_block_hashes(archive_id)?
+            .contains(block_ids[1].root_hash.as_array()));
+        assert_eq!(block_storage.reindex_archive_locations()?, 0);
+
+        // Sizes are tracked without reading archives
+        let sizes = block_storage.archive_byte_lens()?;
+        for (id, size) in &sizes {
+            let data = db.archives.get(id.to_be_bytes())?.unwrap();
+            assert_eq!(data.len() as u64, *size);
+        }
+
+        // Index entries are removed with their archives
+        block_storage.remove_outdated_archives(200).await?;
+        assert!(!block_storage.archive_byte_lens()?.contains_key(&0));
+        assert!(block_storage.archive_block_hashes(0)?.is_empty());
+        assert!(block_storage
+            .locate_by_root_hash(&block_ids[0].root_hash)?
+            .is_none());
+        assert!(block_storage
+            .locate_by_root_hash(&block_ids[2].root_hash)?
+            .is_some());
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn defragment_merges_small_archives() -> Result<()> {
+        let (db, path) = open_test_db("archives-defrag")?;
+
+        let archive = |entries: usize| {
+            let mut data = ARCHIVE_PREFIX.to_vec();
+            for i in 0..entries {
+                data.extend_from_slice(&make_archive_segment(&format!("entry{i}"), &[1, 2, 3]));
+            }
+            data
+        };
+
+        // 0, 10, 20 are small, 30 is large, 40 is too far from 30, 200 is the last
+        for (id, entries) in [
+            (0u32, 1),
+            (10, 1),
+            (20, 1),
+            (30, 3),
+            (40, 1),
+            (150, 1),
+            (200, 1),
+        ] {
+            db.archives.insert(id.to_be_bytes(), archive(entries))?;
+        }
+        // Data of the single entry of the archive 10
+        let root_hash = [1; 32];
+        let entry_offset = ARCHIVE_PREFIX.len() + 2 + 2 + 4 + "entry0".len();
+        db.archive_block_index.insert(
+            root_hash,
+            encode_archive_index_entry(10, Some((entry_offset as u64, 3))),
+        )?;
+        db.archive_block_hashes
+            .insert(archive_block_hashes_key(10, &root_hash), b"")?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        let report = block_storage.defragment_archives(2, usize::MAX).await?;
+        assert_eq!(report.merged_archives, 1);
+        assert_eq!(report.removed_archives, 2);
+
+        let ids = block_storage
+            .archive_ids
+            .read()
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [0, 30, 40, 150, 200]);
+        assert_eq!(block_storage.current_open_archive_id(), Some(200));
+        assert!(block_storage.is_archive_sealed(150));
+        assert!(!block_storage.is_archive_sealed(200));
+        assert_eq!(block_storage.archive_entry_count(0)?, Some(3));
+        assert_eq!(block_storage.archive_entry_count(10)?, None);
+        assert_eq!(block_storage.get_archive_id(25), Some(0));
+        assert_eq!(block_storage.resolve_merged_archive_id(20), 0);
+        assert_eq!(block_storage.resolve_merged_archive_id(30), 30);
+
+        // Entry is shifted by the segments of the archive 0
+        let index_entry = db.archive_block_index.get(root_hash)?.unwrap();
+        let shifted_offset = entry_offset + archive(1).len() - ARCHIVE_PREFIX.len();
+        assert_eq!(
+            decode_archive_index_entry(&index_entry),
+            Some((0, Some((shifted_offset as u64, 3))))
+        );
+        let merged = db.archives.get(0u32.to_be_bytes())?.unwrap();
+        assert_eq!(&merged[shifted_offset..shifted_offset + 3], [1, 2, 3]);
+        assert_eq!(block_storage.archive_block_hashes(0)?, [root_hash]);
+        assert!(block_storage.archive_block_hashes(10)?.is_empty());
+
+        // Nothing left to merge
+        let report = block_storage.defragment_archives(2, usize::MAX).await?;
+        assert_eq!(report.merged_archives, 0);
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn pending_archiving_reconstructs_ids() -> Result<()> {
+        let (db, path) = open_test_db("pending-archiving")?;
+
+        let data = [1, 2, 3];
+        let block_id = |seq_no: u32, file_hash: UInt256| ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no,
+            root_hash: UInt256::from([seq_no as u8; 32]),
+            file_hash,
+        };
+        let cached_id = block_id(1, UInt256::from([1; 32]));
+        let stored_id = block_id(2, UInt256::calc_file_hash(&data));
+        let archived_id = block_id(3, UInt256::calc_file_hash(&data));
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+
+        let (cached, _) =
+            block_handle_storage.create_or_load_handle(&cached_id, BlockMetaData::zero_state(0))?;
+        cached.meta().set_is_moving_to_archive();
+        block_handle_storage.store_handle(&cached)?;
+
+        for (id, archived) in [(&stored_id, false), (&archived_id, true)] {
+            let meta = BlockMeta::with_data(BlockMetaData::zero_state(0));
+            meta.set_is_moving_to_archive();
+            if archived {
+                meta.set_is_archived();
+            }
+            db.block_handles
+                .insert(id.root_hash.as_slice(), meta.to_vec())?;
+
+            let entry_id = PackageEntryId::Block(id);
+            db.package_entries.insert(
+                entry_id.to_vec(),
+                PackageEntryMeta::new(&entry_id, 0).encode_with_data(&data),
+            )?;
+        }
+
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+        assert_eq!(block_storage.pending_archiving()?, [cached_id, stored_id]);
+
+        drop(cached);
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn preload_collects_composition() -> Result<()> {
+        let (db, path) = open_test_db("archives-composition")?;
+
+        let block_id = |seq_no: u32| ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no,
+            root_hash: UInt256::from([seq_no as u8; 32]),
+            file_hash: UInt256::from([seq_no as u8; 32]),
+        };
+        let archive = |entries: &[String]| {
+            let mut data = ARCHIVE_PREFIX.to_vec();
+            for name in entries {
+                data.extend_from_slice(&make_archive_segment(name, &[1, 2, 3]));
+            }
+            data
+        };
+
+        let full = archive(&[
+            PackageEntryId::Block(block_id(1)).filename(),
+            PackageEntryId::Proof(block_id(1)).filename(),
+            PackageEntryId::Block(block_id(2)).filename(),
+            PackageEntryId::ProofLink(block_id(2)).filename(),
+        ]);
+        let partial = archive(&[
+            PackageEntryId::Proof(block_id(100)).filename(),
+            "unknown".to_owned(),
+        ]);
+        db.archives.insert(0u32.to_be_bytes(), full)?;
+        db.archives.insert(100u32.to_be_bytes(), partial)?;
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        let block_storage =
+            BlockStorage::new(db.clone(), block_handle_storage, Default::default())?;
+
+        let report = block_storage.composition_report();
+        assert_eq!(report.blocks, 2);
+        assert_eq!(report.proofs, 2);
+        assert_eq!(report.proof_links, 1);
+        assert_eq!(report.unknown_entries, 1);
+        assert_eq!(report.archives_without_blocks, BTreeSet::from([100]));
+
+        drop(block_storage);
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn gc_removes_orphaned_entries() -> Result<()> {
+        let (db, path) = open_test_db("orphans")?;
+
+        let shard_id = ton_block::ShardIdent::full(0);
+        let block_id = |seq_no: u32, hash: u8| ton_block::BlockIdExt {
+            shard_id,
+            seq_no,
+            root_hash: UInt256::from([hash; 32]),
+            file_hash: UInt256::from([hash; 32]),
+        };
+
+        // Retained block with handle
+        let alive = block_id(100, 1);
+        db.package_entries
+            .insert(PackageEntryId::Block(&alive).to_vec(), [1, 2, 3])?;
+        db.block_handles
+            .insert(alive.root_hash.as_slice(), BlockMeta::default().to_vec())?;
+
+        // Retained block without handle
+        let orphan = block_id(101, 2);
+        let orphan_key = PackageEntryId::Block(&orphan).to_vec();
+        db.package_entries.insert(&orphan_key, [4, 5, 6])?;
+
+        let top_blocks = TopBlocks {
+            mc_block: (ton_block::ShardIdent::masterchain(), 0),
+            shard_heights: FastHashMap::from_iter([(shard_id, 100)]),
+        };
+
+        let stats = remove_blocks(
+            db.clone(),
+            &Default::default(),
+            &Default::default(),
+            None,
+            None,
+            None,
+            false,
+            &ArcSwap::from_pointee(top_blocks),
+        )?;
+        assert_eq!(stats.orphan_entries_removed, 1);
+        assert_eq!(stats.total_package_entries_removed, 0);
+
+        assert!(db.package_entries.get(&orphan_key)?.is_none());
+        assert!(db
+            .package_entries
+            .get(PackageEntryId::Block(&alive).to_vec())?
+            .is_some());
+
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn gc_scan_with_readahead() -> Result<()> {
+        let (db, path) = open_test_db("gc-readahead")?;
+
+        let shard_id = ton_block::ShardIdent::full(0);
+        let block_id = |seq_no: u32| ton_block::BlockIdExt {
+            shard_id,
+            seq_no,
+            root_hash: UInt256::from([seq_no as u8; 32]),
+            file_hash: UInt256::from([seq_no as u8; 32]),
+        };
+
+        for seq_no in 1..=20 {
+            let id = block_id(seq_no);
+            db.package_entries
+                .insert(PackageEntryId::Block(&id).to_vec(), [seq_no as u8; 128])?;
+            db.block_handles
+                .insert(id.root_hash.as_slice(), BlockMeta::default().to_vec())?;
+        }
+
+        let top_blocks = TopBlocks {
+            mc_block: (ton_block::ShardIdent::masterchain(), 0),
+            shard_heights: FastHashMap::from_iter([(shard_id, 11)]),
+        };
+
+        // Read-ahead must not change the result of the batched scan
+        let stats = remove_blocks(
+            db.clone(),
+            &Default::default(),
+            &Default::default(),
+            Some(64 * 1024),
+            Some(3),
+            None,
+            false,
+            &ArcSwap::from_pointee(top_blocks),
+        )?;
+        assert_eq!(stats.total_package_entries_removed, 10);
+        assert_eq!(stats.orphan_entries_removed, 0);
+
+        for seq_no in 1..=20 {
+            let stored = db
+                .package_entries
+                .get(PackageEntryId::Block(&block_id(seq_no)).to_vec())?;
+            assert_eq!(stored.is_some(), seq_no >= 11, "seq_no: {seq_no}");
+        }
+
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,87 @@
+use std::borrow::Borrow;
+
+use crate::utils::{PackageEntryId, PackageEntryKind};
+
+/// Versioned metadata prefix of the package entry value.
+///
+/// Value structure:
+/// - `[magic, 1 byte][version, 1 byte][kind, 1 byte][stored at, 4 bytes LE][data..]`
+/// - `[data..]` (legacy entries)
+///
+/// NOTE: the magic byte never starts a valid BOC, so legacy entries
+/// can be distinguished without migration
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PackageEntryMeta {
+    pub kind: PackageEntryKind,
+    pub stored_at: u32,
+}
+
+impl PackageEntryMeta {
+    pub fn new<I>(id: &PackageEntryId<I>, stored_at: u32) -> Self
+    where
+        I: Borrow<ton_block::BlockIdExt>,
+    {
+        Self {
+            kind: id.kind(),
+            stored_at,
+        }
+    }
+
+    /// Builds the full entry value with this metadata prefix
+    pub fn encode_with_data(&self, data: &[u8]) -> Vec<u8> {
+        let mut result = Vec::with_capacity(META_LEN + data.len());
+        result.extend_from_slice(&[META_MAGIC, META_VERSION, self.kind as u8]);
+        result.extend_from_slice(&self.stored_at.to_le_bytes());
+        result.extend_from_slice(data);
+        result
+    }
+
+    /// Splits the entry value into metadata (if any) and data
+    pub fn decode(value: &[u8]) -> (Option<Self>, &[u8]) {
+        match value {
+            [META_MAGIC, META_VERSION, kind, stored_at @ ..] if value.len() >= META_LEN => {
+                let kind = match PackageEntryKind::from_u8(*kind) {
+                    Some(kind) => kind,
+                    None => return (None, value),
+                };
+                let mut stored_at_bytes = [0; 4];
+                stored_at_bytes.copy_from_slice(&stored_at[..4]);
+                let meta = Self {
+                    kind,
+                    stored_at: u32::from_le_bytes(stored_at_bytes),
+                };
+                (Some(meta), &value[META_LEN..])
+            }
+            _ => (None, value),
+        }
+    }
+
+    /// Returns only the data part of the entry value
+    #[inline]
+    pub fn strip(value: &[u8]) -> &[u8] {
+        Self::decode(value).1
+    }
+}
+
+const META_MAGIC: u8 = 0xfe;
+const META_VERSION: u8 = 1;
+const META_LEN: usize = 3 + 4;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meta_roundtrip() {
+        let block_id = ton_block::BlockIdExt::default();
+        let data = [0xb5, 0xee, 0x9c, 0x72, 1, 2, 3];
+
+        let meta = PackageEntryMeta::new(&PackageEntryId::Proof(&block_id), 123456);
+        let value = meta.encode_with_data(&data);
+        assert_eq!(PackageEntryMeta::decode(&value), (Some(meta), &data[..]));
+
+        // Legacy entries are returned as is
+        assert_eq!(PackageEntryMeta::decode(&data), (None, &data[..]));
+        assert_eq!(PackageEntryMeta::decode(&[]), (None, &[][..]));
+    }
+}
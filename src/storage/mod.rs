@@ -5,8 +5,18 @@ use anyhow::Result;
 
 pub use self::block_connection_storage::*;
 pub use self::block_handle_storage::*;
+pub use self::block_storage::{
+    ArchiveEvent, ArchiveEventKind, ArchiveLifetimeCounters, ArchiveManifest, ArchiveManifestEntry,
+    ArchiveOpenState, ArchiveStateDump, ArchiveVerificationStatus, ArchiveVerifyReport,
+    ArchivesGcProgress, BlockStorageOptions, CompositionReport, DataLocksWaitMetrics, DefragReport,
+    MoveIntoArchiveStats,
+};
 pub use self::models::*;
 pub use self::runtime_storage::*;
+#[cfg(feature = "bench")]
+pub use self::shard_state_storage::CellStorage;
+pub use self::shard_state_storage::{CellStorageOptions, CellStoredCallback, CellsFilterStats};
+pub use self::storage_gc::*;
 
 use self::block_storage::*;
 use self::node_state_storage::*;
@@ -21,6 +31,7 @@ mod block_storage;
 mod node_state_storage;
 mod runtime_storage;
 mod shard_state_storage;
+mod storage_gc;
 
 pub struct Storage {
     file_db_path: PathBuf,
@@ -34,15 +45,29 @@ pub struct Storage {
 }
 
 impl Storage {
-    pub async fn new(db: Arc<Db>, file_db_path: PathBuf) -> Result<Arc<Self>> {
+    pub async fn new(
+        db: Arc<Db>,
+        file_db_path: PathBuf,
+        block_storage_options: BlockStorageOptions,
+        cell_storage_options: CellStorageOptions,
+    ) -> Result<Arc<Self>> {
         let block_handle_storage = Arc::new(BlockHandleStorage::new(db.clone())?);
+        match block_handle_storage.recover_stuck_archiving()? {
+            0 => {}
+            fixed => tracing::warn!(fixed, "recovered handles stuck while moving into archive"),
+        }
         let runtime_storage = Arc::new(RuntimeStorage::new(block_handle_storage.clone()));
-        let block_storage = Arc::new(BlockStorage::new(db.clone(), block_handle_storage.clone())?);
+        let block_storage = Arc::new(BlockStorage::new(
+            db.clone(),
+            block_handle_storage.clone(),
+            block_storage_options,
+        )?);
         let shard_state_storage = ShardStateStorage::new(
             db.clone(),
             block_handle_storage.clone(),
             block_storage.clone(),
             file_db_path.clone(),
+            cell_storage_options,
         )
         .await?;
         let node_state_storage = NodeStateStorage::new(db.clone())?;
@@ -95,9 +120,31 @@ impl Storage {
         &self.node_state_storage
     }
 
+    #[inline(always)]
+    pub fn gc(&self) -> StorageGc<'_> {
+        StorageGc::new(self)
+    }
+
     pub fn metrics(&self) -> DbMetrics {
         DbMetrics {
             shard_state_storage: self.shard_state_storage.metrics(),
+            data_locks: self.block_storage.data_locks_wait_metrics(),
+            archive_lifetime: self.block_storage.lifetime_counters(),
+            archiving_permits_in_use: self.block_storage.archiving_permits_in_use(),
+        }
+    }
+
+    pub fn health(&self, last_applied_mc_seq_no: u32) -> StorageHealth {
+        let newest_archive_id = self.block_storage.archive_span().map(|(_, last)| last);
+        StorageHealth {
+            last_applied_mc_seq_no,
+            newest_archive_id,
+            archive_lag: newest_archive_id.map(|id| last_applied_mc_seq_no.saturating_sub(id)),
+            cells_cache_hit_ratio: self.shard_state_storage.cells_cache_hit_ratio(),
+            recent_cells_cache_hit_ratio: self
+                .shard_state_storage
+                .recent_cells_cache_hit_ratio(RECENT_CACHE_STATS_WINDOW_SEC),
+            stuck_archiving_handles: self.block_handle_storage.count_stuck_archiving_handles(),
         }
     }
 }
@@ -105,4 +152,27 @@ impl Storage {
 #[derive(Debug, Copy, Clone)]
 pub struct DbMetrics {
     pub shard_state_storage: ShardStateStorageMetrics,
+    pub data_locks: DataLocksWaitMetrics,
+    /// Archive counters accumulated over all runs of the node
+    pub archive_lifetime: ArchiveLifetimeCounters,
+    /// Number of blocks which are being moved into archives
+    pub archiving_permits_in_use: usize,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct StorageHealth {
+    pub last_applied_mc_seq_no: u32,
+    /// `None` if there are no archives
+    pub newest_archive_id: Option<u32>,
+    /// Difference between the last applied masterchain block seqno and the newest archive id
+    pub archive_lag: Option<u32>,
+    /// `None` if no cells were loaded yet
+    pub cells_cache_hit_ratio: Option<f64>,
+    /// Cells cache hit ratio within the last minute.
+    /// `None` if no cells were loaded within this interval
+    pub recent_cells_cache_hit_ratio: Option<f64>,
+    /// Number of cached handles which are stuck while moving into the archive
+    pub stuck_archiving_handles: usize,
 }
+
+const RECENT_CACHE_STATS_WINDOW_SEC: u32 = 60;
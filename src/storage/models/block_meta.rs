@@ -87,6 +87,14 @@ impl BlockMeta {
         self.set_flag(BLOCK_META_FLAG_HAS_DATA)
     }
 
+    /// Returns `true` if the flag was set
+    pub fn clear_has_data(&self) -> bool {
+        self.flags
+            .fetch_and(!BLOCK_META_FLAG_HAS_DATA, Ordering::Release)
+            & BLOCK_META_FLAG_HAS_DATA
+            != 0
+    }
+
     pub fn has_data(&self) -> bool {
         self.test_flag(BLOCK_META_FLAG_HAS_DATA)
     }
@@ -173,6 +181,18 @@ impl BlockMeta {
         self.set_flag(BLOCK_META_FLAG_MOVING_TO_ARCHIVE)
     }
 
+    pub fn is_moving_to_archive(&self) -> bool {
+        self.test_flag(BLOCK_META_FLAG_MOVING_TO_ARCHIVE)
+    }
+
+    /// Returns `true` if the flag was set
+    pub fn clear_is_moving_to_archive(&self) -> bool {
+        self.flags
+            .fetch_and(!BLOCK_META_FLAG_MOVING_TO_ARCHIVE, Ordering::Release)
+            & BLOCK_META_FLAG_MOVING_TO_ARCHIVE
+            != 0
+    }
+
     pub fn set_is_archived(&self) -> bool {
         self.set_flag(BLOCK_META_FLAG_MOVED_TO_ARCHIVE)
     }
@@ -11,6 +11,7 @@ pub struct NodeStateStorage {
     last_mc_block_id: BlockIdCache,
     init_mc_block_id: BlockIdCache,
     shards_client_mc_block_id: BlockIdCache,
+    last_audited_mc_block_id: BlockIdCache,
 }
 
 impl NodeStateStorage {
@@ -20,6 +21,7 @@ impl NodeStateStorage {
             last_mc_block_id: (Default::default(), LAST_MC_BLOCK_ID),
             init_mc_block_id: (Default::default(), INIT_MC_BLOCK_ID),
             shards_client_mc_block_id: (Default::default(), SHARDS_CLIENT_MC_BLOCK_ID),
+            last_audited_mc_block_id: (Default::default(), LAST_AUDITED_MC_BLOCK_ID),
         })
     }
 
@@ -91,6 +93,14 @@ impl NodeStateStorage {
         self.load_block_id(&self.shards_client_mc_block_id)
     }
 
+    pub fn store_last_audited_mc_block_id(&self, id: &ton_block::BlockIdExt) -> Result<()> {
+        self.store_block_id(&self.last_audited_mc_block_id, id)
+    }
+
+    pub fn load_last_audited_mc_block_id(&self) -> Result<ton_block::BlockIdExt> {
+        self.load_block_id(&self.last_audited_mc_block_id)
+    }
+
     #[inline(always)]
     fn store_block_id(
         &self,
@@ -138,3 +148,4 @@ const LAST_UPLOADED_ARCHIVE: &[u8] = b"last_uploaded_archive";
 const LAST_MC_BLOCK_ID: &[u8] = b"LastMcBlockId";
 const INIT_MC_BLOCK_ID: &[u8] = b"InitMcBlockId";
 const SHARDS_CLIENT_MC_BLOCK_ID: &[u8] = b"ShardsClientMcBlockId";
+const LAST_AUDITED_MC_BLOCK_ID: &[u8] = b"LastAuditedMcBlockId";
@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Lock-free ring of per-second cache hits and misses
+pub struct CacheStatsWindow {
+    buckets: Box<[Bucket]>,
+}
+
+impl CacheStatsWindow {
+    /// Creates a ring which can summarize at most `max_window_sec` seconds
+    pub fn new(max_window_sec: u32) -> Self {
+        Self {
+            buckets: (0..max_window_sec.max(1))
+                .map(|_| Bucket::default())
+                .collect(),
+        }
+    }
+
+    pub fn record(&self, hit: bool) {
+        self.record_at(broxus_util::now(), hit);
+    }
+
+    /// Returns the hit ratio over the last `window_sec` seconds
+    /// (limited by the ring size).
+    ///
+    /// Returns `None` if no cells were loaded within the window
+    pub fn hit_ratio(&self, window_sec: u32) -> Option<f64> {
+        self.hit_ratio_at(broxus_util::now(), window_sec)
+    }
+
+    fn record_at(&self, now: u32, hit: bool) {
+        let bucket = &self.buckets[now as usize % self.buckets.len()];
+
+        let sec = bucket.sec.load(Ordering::Acquire);
+        if sec != now
+            && bucket
+                .sec
+                .compare_exchange(sec, now, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            // NOTE: concurrent updates between the exchange and the reset
+            // could be lost, which is fine for the approximate stats
+            bucket.hits.store(0, Ordering::Release);
+            bucket.misses.store(0, Ordering::Release);
+        }
+
+        let counter = if hit { &bucket.hits } else { &bucket.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn hit_ratio_at(&self, now: u32, window_sec: u32) -> Option<f64> {
+        let window_sec = std::cmp::min(window_sec, self.buckets.len() as u32);
+
+        let (mut hits, mut misses) = (0, 0);
+        for bucket in self.buckets.iter() {
+            let sec = bucket.sec.load(Ordering::Acquire);
+            if sec <= now && now - sec < window_sec {
+                hits += bucket.hits.load(Ordering::Relaxed);
+                misses += bucket.misses.load(Ordering::Relaxed);
+            }
+        }
+
+        let total = hits + misses;
+        (total > 0).then(|| hits as f64 / total as f64)
+    }
+}
+
+#[derive(Default)]
+struct Bucket {
+    sec: AtomicU32,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ratio_within_window() {
+        let stats = CacheStatsWindow::new(10);
+        assert_eq!(stats.hit_ratio_at(100, 10), None);
+
+        // Old healthy period
+        for _ in 0..30 {
+            stats.record_at(100, true);
+        }
+        stats.record_at(100, false);
+
+        // Recent thrashing
+        for sec in 105..108 {
+            stats.record_at(sec, false);
+        }
+        stats.record_at(107, true);
+
+        assert_eq!(stats.hit_ratio_at(107, 3), Some(0.25));
+        assert_eq!(stats.hit_ratio_at(107, 10), Some(31.0 / 35.0));
+
+        // Bucket of the same slot is reset after the full cycle
+        stats.record_at(110, false);
+        assert_eq!(stats.hit_ratio_at(110, 10), Some(1.0 / 5.0));
+    }
+}
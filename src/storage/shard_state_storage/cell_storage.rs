@@ -1,112 +1,298 @@
-use std::collections::hash_map;
+use std::collections::{hash_map, BTreeMap};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Weak};
 
 use anyhow::Result;
 use bumpalo::Bump;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use smallvec::SmallVec;
 use ton_types::{ByteOrderRead, CellImpl, UInt256};
 
+use super::cache_stats_window::CacheStatsWindow;
+use super::cells_cache::CellsCache;
+use super::cells_filter::{CellsFilter, CellsFilterStats};
+use crate::config::{CellCacheStrategy, CellsFilterOptions};
 use crate::db::*;
-use crate::utils::{FastDashMap, FastHashMap};
+use crate::utils::{FastDashMap, FastHashMap, FastHashSet, FastHasherState};
 
 pub struct CellStorage {
     db: Arc<Db>,
-    cells_cache: Arc<FastDashMap<UInt256, Weak<StorageCell>>>,
+    cells_cache: CellsCache,
+    /// Strong references to the warmed roots (see [`CellStorage::warm_cache`])
+    warm_roots: RwLock<Vec<Arc<StorageCell>>>,
+    /// Serialized cells from uncommitted batches (see [`CellStorage::begin_staging`])
+    staging: StagedCells,
+    /// Db reads of the cells which are being loaded, shared by concurrent loads of the same cell
+    in_flight_loads: FastDashMap<UInt256, Arc<InFlightLoad>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    /// Misses of the weak cache entries whose cells were already dropped
+    cache_weak_expired: AtomicU64,
+    recent_cache_stats: CacheStatsWindow,
+    /// Limits concurrent db reads on cache misses
+    read_limiter: Option<ReadLimiter>,
+    cache_leaf_cells: bool,
+    /// See [`CellStorageOptions::skip_contended_cache_inserts`]
+    skip_contended_cache_inserts: bool,
+    checksums: bool,
+    compact_references: bool,
+    /// Persisted cells format version (see [`CellStorage::format_version`])
+    format_version: u8,
+    split_columns: bool,
+    /// Hashes of the stored cells (see [`CellStorageOptions::cells_filter`])
+    cells_filter: Option<Arc<CellsFilter>>,
+    on_cell_stored: RwLock<Option<CellStoredCallback>>,
 }
 
 impl CellStorage {
-    pub fn new(db: Arc<Db>) -> Result<Arc<Self>> {
-        let cache = Arc::new(FastDashMap::default());
+    /// Creates cell storage with the specified number of cells cache shards.
+    ///
+    /// NOTE: `cache_shards` is rounded up to the next power of two (at least 2).
+    /// More shards reduce lock contention on parallel loads.
+    pub fn new(db: Arc<Db>, cache_shards: usize, options: CellStorageOptions) -> Result<Arc<Self>> {
+        let format_version = prepare_cells_format(&db, options.compact_references)?;
+        prepare_split_columns(&db, options.split_masterchain_cells)?;
+
+        let cells_filter = match options.cells_filter {
+            Some(options) => {
+                let cells_filter = Arc::new(CellsFilter::new(options));
+                spawn_fill_cells_filter(db.clone(), cells_filter.clone())?;
+                Some(cells_filter)
+            }
+            None => None,
+        };
+
         Ok(Arc::new(Self {
             db,
-            cells_cache: cache,
+            cells_cache: CellsCache::new(options.cache_strategy, cache_shards),
+            warm_roots: Default::default(),
+            staging: Default::default(),
+            in_flight_loads: Default::default(),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            cache_weak_expired: AtomicU64::new(0),
+            recent_cache_stats: CacheStatsWindow::new(MAX_CACHE_STATS_WINDOW_SEC),
+            read_limiter: options.max_concurrent_reads.map(ReadLimiter::new),
+            cache_leaf_cells: options.cache_leaf_cells,
+            skip_contended_cache_inserts: options.skip_contended_cache_inserts,
+            checksums: options.checksums,
+            compact_references: options.compact_references,
+            format_version,
+            split_columns: options.split_masterchain_cells,
+            cells_filter,
+            on_cell_stored: Default::default(),
         }))
     }
 
+    /// Returns the cells format version of the db.
+    ///
+    /// [`CELLS_FORMAT_COMPACT`] means that the db could contain compact cells
+    /// (see [`CellStorageOptions::compact_references`])
+    pub fn format_version(&self) -> u8 {
+        self.format_version
+    }
+
+    /// Sets the callback which is called for each new cell after its batch is written.
+    ///
+    /// Cells are reported by the operations which observe the commit:
+    /// [`CellStorage::store_cell_bounded`], [`CellStagingBatch::commit`],
+    /// [`PendingCells::committed`] and [`StoredCells::committed`].
+    ///
+    /// NOTE: cells which are already stored only get their refcount
+    /// increased and are not reported
+    pub fn set_on_cell_stored(&self, callback: Option<CellStoredCallback>) {
+        *self.on_cell_stored.write() = callback;
+    }
+
     pub fn store_cell(
         &self,
         batch: &mut rocksdb::WriteBatch,
         root: ton_types::Cell,
     ) -> Result<usize, CellStorageError> {
-        struct CellWithRefs<'a> {
-            rc: u32,
-            data: &'a [u8],
-        }
-
-        struct Context<'a> {
-            cells_cf: &'a BoundedCfHandle<'a>,
-            alloc: &'a Bump,
-            transaction: FastHashMap<[u8; 32], CellWithRefs<'a>>,
-            buffer: Vec<u8>,
-        }
-
-        impl Context<'_> {
-            fn insert_cell(
-                &mut self,
-                key: &[u8; 32],
-                cell: &ton_types::Cell,
-                value: Option<rocksdb::DBPinnableSlice<'_>>,
-            ) -> Result<bool, CellStorageError> {
-                let has_value = matches!(value, Some(value) if refcount::has_value(value.as_ref()));
-
-                Ok(match self.transaction.entry(*key) {
-                    hash_map::Entry::Occupied(mut value) => {
-                        value.get_mut().rc += 1;
-                        false
-                    }
-                    hash_map::Entry::Vacant(value) => {
-                        self.buffer.clear();
-                        if StorageCell::serialize_to(&**cell, &mut self.buffer).is_err() {
-                            return Err(CellStorageError::InvalidCell);
-                        }
-                        let data = self.alloc.alloc_slice_copy(self.buffer.as_slice());
-                        value.insert(CellWithRefs { rc: 1, data });
-                        !has_value
-                    }
-                })
-            }
+        self.store_cell_with_rc(batch, root, 1)
+    }
 
-            fn finalize(mut self, batch: &mut rocksdb::WriteBatch) -> usize {
-                let total = self.transaction.len();
-                for (key, CellWithRefs { rc, data }) in self.transaction {
-                    self.buffer.clear();
-                    refcount::add_positive_refount(rc, data, &mut self.buffer);
-                    batch.merge_cf(self.cells_cf, key.as_slice(), &self.buffer);
-                }
-                total
-            }
+    /// Same as [`CellStorage::store_cell`], but stores the tree into the specified column
+    pub fn store_cell_in(
+        &self,
+        column: CellColumn,
+        batch: &mut rocksdb::WriteBatch,
+        root: ton_types::Cell,
+    ) -> Result<usize, CellStorageError> {
+        self.store_cell_impl(column, batch, root, 1, None, None, false)
+            .map(|(count, _)| count)
+    }
+
+    /// Same as [`CellStorage::store_cell_in`], but additionally returns the new cells
+    /// for the [`CellStoredCallback`], which must be reported after the batch is written
+    pub fn store_cell_in_reported(
+        &self,
+        column: CellColumn,
+        batch: &mut rocksdb::WriteBatch,
+        root: ton_types::Cell,
+    ) -> Result<(usize, StoredCells), CellStorageError> {
+        self.store_cell_impl(column, batch, root, 1, None, None, false)
+    }
+
+    /// Same as [`CellStorage::store_cell`], but fails with [`CellStorageError::MissingChild`]
+    /// if any new cell references a child which is neither stored nor present
+    /// in the same tree, so that no dangling references are written.
+    ///
+    /// Children of the already stored cells are checked too, so the new tree
+    /// doesn't reference an incomplete stored subtree.
+    ///
+    /// NOTE: nothing is written into the batch on error
+    pub fn store_cell_checked(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        root: ton_types::Cell,
+    ) -> Result<usize, CellStorageError> {
+        self.store_cell_checked_in(CellColumn::Shared, batch, root)
+    }
+
+    /// Same as [`CellStorage::store_cell_checked`], but stores the tree into the specified column
+    pub fn store_cell_checked_in(
+        &self,
+        column: CellColumn,
+        batch: &mut rocksdb::WriteBatch,
+        root: ton_types::Cell,
+    ) -> Result<usize, CellStorageError> {
+        self.store_cell_impl(column, batch, root, 1, None, None, true)
+            .map(|(count, _)| count)
+    }
+
+    /// Same as [`CellStorage::store_cell`], but serializes the cells on the blocking pool.
+    ///
+    /// Returns the prepared batch which must be committed by the caller
+    pub async fn store_cell_async(
+        self: Arc<Self>,
+        root: ton_types::Cell,
+    ) -> Result<(rocksdb::WriteBatch, usize), CellStorageError> {
+        tokio::task::spawn_blocking(move || {
+            let mut batch = rocksdb::WriteBatch::default();
+            let count = self.store_cell(&mut batch, root)?;
+            Ok((batch, count))
+        })
+        .await
+        .map_err(|_| CellStorageError::BlockingTaskFailed)?
+    }
+
+    /// Stores the cell tree, adding `root_rc` references to the root cell at once.
+    ///
+    /// Equivalent to storing the same root `root_rc` times
+    pub fn store_cell_with_rc(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        root: ton_types::Cell,
+        root_rc: u32,
+    ) -> Result<usize, CellStorageError> {
+        self.store_cell_impl(CellColumn::Shared, batch, root, root_rc, None, None, false)
+            .map(|(count, _)| count)
+    }
+
+    /// Same as [`CellStorage::store_cell`], but additionally returns the new cells,
+    /// which can be inserted into the cache after the batch is committed.
+    ///
+    /// Cells which were already stored are not returned
+    pub fn store_cell_deferred(
+        self: &Arc<Self>,
+        batch: &mut rocksdb::WriteBatch,
+        root: ton_types::Cell,
+    ) -> Result<(usize, PendingCells), CellStorageError> {
+        let mut cells = Vec::new();
+        let (count, stored) = self.store_cell_impl(
+            CellColumn::Shared,
+            batch,
+            root,
+            1,
+            None,
+            Some(&mut cells),
+            false,
+        )?;
+        Ok((
+            count,
+            PendingCells {
+                cell_storage: self.clone(),
+                column: CellColumn::Shared,
+                cells,
+                stored,
+            },
+        ))
+    }
+
+    /// Creates a batch, cells of which are visible to [`CellStorage::load_cell`]
+    /// before it is committed.
+    ///
+    /// Staged cells are removed when the batch is committed or dropped
+    pub fn begin_staging(&self) -> CellStagingBatch<'_> {
+        CellStagingBatch {
+            cell_storage: self,
+            batch: Default::default(),
+            staged: Vec::new(),
+            stored: Default::default(),
+        }
+    }
+
+    fn store_cell_impl(
+        &self,
+        column: CellColumn,
+        batch: &mut rocksdb::WriteBatch,
+        root: ton_types::Cell,
+        root_rc: u32,
+        staged: Option<&mut Vec<[u8; 32]>>,
+        mut new_cells: Option<&mut Vec<ton_types::Cell>>,
+        check_children: bool,
+    ) -> Result<(usize, StoredCells), CellStorageError> {
+        if root_rc == 0 {
+            return Ok((0, StoredCells::default()));
         }
 
         // Prepare context and handles
         let alloc = Bump::new();
-        let cells = &self.db.cells;
-        let raw = cells.db();
-        let cells_cf = &cells.cf();
-        let read_options = cells.read_config();
+        let cells = self.cells_table(column);
+        let raw = cells.raw;
+        let cells_cf = &cells.cf;
+        let read_options = cells.read_options;
 
-        let mut ctx = Context {
+        let mut ctx = StoreContext {
             cells_cf,
             alloc: &alloc,
+            ref_lists: &self.db.cell_ref_lists,
             transaction: FastHashMap::with_capacity_and_hasher(128, Default::default()),
+            pending_ref_lists: Vec::new(),
             buffer: Vec::with_capacity(512),
+            checksums: self.checksums,
+            on_cell_stored: self.on_cell_stored.read().clone(),
+            cells_filter: self.cells_filter.as_deref(),
+            // NOTE: staged cells must be readable before their reference lists are written
+            compact_references: self.compact_references && staged.is_none(),
+            check_children,
         };
 
+        let mut refs = Vec::new();
+
         // Check root cell
         {
             let key = root.repr_hash();
             let key = key.as_array();
 
-            match raw.get_pinned_cf_opt(cells_cf, key, read_options) {
+            match self.read_stored_cell(raw, cells_cf, key, read_options) {
                 Ok(value) => {
-                    if !ctx.insert_cell(key, &root, value)? {
-                        return Ok(0);
+                    if !ctx.insert_cell(key, &root, value, root_rc)? {
+                        if check_children {
+                            self.check_stored_children(column, &ctx, key, &mut refs)?;
+                        }
+                        return Ok((0, StoredCells::default()));
                     }
                 }
                 Err(e) => return Err(CellStorageError::Internal(e)),
             }
         }
 
+        if let Some(new_cells) = &mut new_cells {
+            new_cells.push(root.clone());
+        }
+
         let mut stack = Vec::with_capacity(16);
         stack.push(root);
 
@@ -115,20 +301,28 @@ impl CellStorage {
             for i in 0..current.references_count() {
                 let cell = match current.reference(i) {
                     Ok(cell) => cell,
-                    Err(_) => return Err(CellStorageError::InvalidCell),
+                    Err(e) => return Err(ctx.reference_error(e)),
                 };
                 let key = cell.repr_hash();
                 let key = key.as_array();
 
-                match raw.get_pinned_cf_opt(cells_cf, key, read_options) {
+                let is_visited = ctx.transaction.contains_key(key);
+                match self.read_stored_cell(raw, cells_cf, key, read_options) {
                     Ok(value) => {
-                        if !ctx.insert_cell(key, &cell, value)? {
+                        if !ctx.insert_cell(key, &cell, value, 1)? {
+                            // NOTE: stored cells are skipped, so their children are checked once
+                            if check_children && !is_visited {
+                                self.check_stored_children(column, &ctx, key, &mut refs)?;
+                            }
                             continue;
                         }
                     }
                     Err(e) => return Err(CellStorageError::Internal(e)),
                 }
 
+                if let Some(new_cells) = &mut new_cells {
+                    new_cells.push(cell.clone());
+                }
                 stack.push(cell);
             }
         }
@@ -137,312 +331,4871 @@ impl CellStorage {
         drop(stack);
 
         // Write transaction to the `WriteBatch`
-        Ok(ctx.finalize(batch))
+        let staging = staged.map(|staged| (&self.staging, staged));
+        ctx.finalize(batch, staging)
     }
 
-    pub fn load_cell(
-        self: &Arc<Self>,
-        hash: UInt256,
-    ) -> Result<Arc<StorageCell>, CellStorageError> {
-        if let Some(cell) = self.cells_cache.get(&hash) {
-            if let Some(cell) = cell.upgrade() {
-                return Ok(cell);
+    /// Checks that the children of the already stored cell are stored
+    /// in the same column or are present in the transaction
+    fn check_stored_children(
+        &self,
+        column: CellColumn,
+        ctx: &StoreContext<'_>,
+        key: &[u8; 32],
+        refs: &mut Vec<[u8; 32]>,
+    ) -> Result<(), CellStorageError> {
+        self.read_cell_refs(column, key, refs)?;
+
+        let cells = self.cells_table(column);
+        for child in refs.iter() {
+            if ctx.transaction.contains_key(child) {
+                continue;
             }
-        }
 
-        let cell = match self.db.cells.get(hash.as_slice()) {
-            Ok(value) => 'cell: {
-                if let Some(value) = value {
-                    if let Some(value) = refcount::strip_refcount(&value) {
-                        match StorageCell::deserialize(self.clone(), value) {
-                            Ok(cell) => break 'cell Arc::new(cell),
-                            Err(_) => return Err(CellStorageError::InvalidCell),
-                        }
-                    }
-                }
-                return Err(CellStorageError::CellNotFound);
+            let value = cells.get(child).map_err(CellStorageError::Internal)?;
+            if !matches!(value, Some(value) if refcount::has_value(&value)) {
+                return Err(CellStorageError::MissingChild {
+                    parent: UInt256::from(*key),
+                    child: UInt256::from(*child),
+                });
             }
-            Err(e) => return Err(CellStorageError::Internal(e)),
-        };
-        self.cells_cache.insert(hash, Arc::downgrade(&cell));
+        }
 
-        Ok(cell)
+        Ok(())
     }
 
-    pub fn remove_cell(
+    /// Same as [`CellStorage::store_cell`], but with a bounded memory usage.
+    ///
+    /// The tree is processed in frontiers of at most `max_cells_in_memory` cells
+    /// (plus the references of the last cell). Each frontier is written to the db
+    /// before the next one, so the memory ceiling is roughly `max_cells_in_memory`
+    /// serialized cells plus the traversal stack. Cells which were already written
+    /// by the previous frontiers are handled the same way as the existing cells,
+    /// so the resulting refcounts are exactly the same as with [`CellStorage::store_cell`].
+    ///
+    /// NOTE: Frontiers are written separately, so the operation is not atomic
+    /// and must not be interrupted by the cells GC
+    pub fn store_cell_bounded(
         &self,
-        batch: &mut rocksdb::WriteBatch,
-        alloc: &Bump,
-        hash: UInt256,
+        root: ton_types::Cell,
+        max_cells_in_memory: usize,
     ) -> Result<usize, CellStorageError> {
-        #[derive(Clone, Copy)]
-        struct CellState<'a> {
-            rc: i64,
-            removes: u32,
-            refs: &'a [[u8; 32]],
-        }
-
-        impl<'a> CellState<'a> {
-            fn remove(&mut self) -> Result<Option<&'a [[u8; 32]]>, CellStorageError> {
-                self.removes += 1;
-                if self.removes as i64 <= self.rc {
-                    Ok(self.next_refs())
-                } else {
-                    Err(CellStorageError::CounterMismatch)
-                }
-            }
-
-            fn next_refs(&self) -> Option<&'a [[u8; 32]]> {
-                if self.rc > self.removes as i64 {
-                    None
-                } else {
-                    Some(self.refs)
-                }
-            }
-        }
+        let max_cells_in_memory = max_cells_in_memory.max(1);
 
+        let mut alloc = Bump::new();
         let cells = &self.db.cells;
         let raw = cells.db();
         let cells_cf = &cells.cf();
         let read_options = cells.read_config();
-        let mut transaction: FastHashMap<&[u8; 32], CellState> =
-            FastHashMap::with_capacity_and_hasher(128, Default::default());
-        let mut buffer = Vec::with_capacity(4);
+        let write_options = cells.write_config();
 
         let mut stack = Vec::with_capacity(16);
-        stack.push(hash.as_slice());
+        let mut total = 0;
+        let mut is_first = true;
 
-        // While some cells left
-        while let Some(cell_id) = stack.pop() {
-            let refs = match transaction.entry(cell_id) {
-                hash_map::Entry::Occupied(mut v) => v.get_mut().remove()?,
-                hash_map::Entry::Vacant(v) => {
-                    let rc = match raw.get_pinned_cf_opt(cells_cf, cell_id, read_options) {
-                        Ok(value) => 'rc: {
-                            if let Some(value) = value {
-                                buffer.clear();
-                                if let (rc, Some(value)) = refcount::decode_value_with_rc(&value) {
-                                    if StorageCell::deserialize_references(value, &mut buffer) {
-                                        break 'rc rc;
-                                    } else {
-                                        return Err(CellStorageError::InvalidCell);
-                                    }
-                                }
-                            }
-                            return Err(CellStorageError::CellNotFound);
-                        }
-                        Err(e) => return Err(CellStorageError::Internal(e)),
-                    };
+        while is_first || !stack.is_empty() {
+            alloc.reset();
 
-                    v.insert(CellState {
-                        rc,
-                        removes: 1,
-                        refs: alloc.alloc_slice_copy(buffer.as_slice()),
-                    })
-                    .next_refs()
-                }
+            let mut ctx = StoreContext {
+                cells_cf,
+                alloc: &alloc,
+                ref_lists: &self.db.cell_ref_lists,
+                transaction: FastHashMap::with_capacity_and_hasher(
+                    max_cells_in_memory.min(1 << 16),
+                    Default::default(),
+                ),
+                pending_ref_lists: Vec::new(),
+                buffer: Vec::with_capacity(512),
+                checksums: self.checksums,
+                on_cell_stored: self.on_cell_stored.read().clone(),
+                cells_filter: self.cells_filter.as_deref(),
+                compact_references: self.compact_references,
+                check_children: false,
             };
 
-            if let Some(refs) = refs {
-                // Add all children
-                for cell_id in refs {
-                    // Unknown cell, push to the stack to process it
-                    stack.push(cell_id);
+            // Check root cell
+            if std::mem::take(&mut is_first) {
+                let key = root.repr_hash();
+                let key = key.as_array();
+
+                match self.read_stored_cell(raw, cells_cf, key, read_options) {
+                    Ok(value) => {
+                        if !ctx.insert_cell(key, &root, value, 1)? {
+                            return Ok(0);
+                        }
+                    }
+                    Err(e) => return Err(CellStorageError::Internal(e)),
                 }
+
+                stack.push(root.clone());
             }
-        }
 
-        // Clear big chunks of data before finalization
-        drop(stack);
+            // Check other cells until the frontier is full
+            while ctx.transaction.len() < max_cells_in_memory {
+                let current = match stack.pop() {
+                    Some(current) => current,
+                    None => break,
+                };
 
-        // Write transaction to the `WriteBatch`
-        let total = transaction.len();
-        for (key, CellState { removes, .. }) in transaction {
-            batch.merge_cf(
-                cells_cf,
-                key.as_slice(),
-                refcount::encode_negative_refcount(removes),
-            );
+                for i in 0..current.references_count() {
+                    let cell = match current.reference(i) {
+                        Ok(cell) => cell,
+                        Err(_) => return Err(CellStorageError::InvalidCell),
+                    };
+                    let key = cell.repr_hash();
+                    let key = key.as_array();
+
+                    match self.read_stored_cell(raw, cells_cf, key, read_options) {
+                        Ok(value) => {
+                            if !ctx.insert_cell(key, &cell, value, 1)? {
+                                continue;
+                            }
+                        }
+                        Err(e) => return Err(CellStorageError::Internal(e)),
+                    }
+
+                    stack.push(cell);
+                }
+            }
+
+            // Write frontier so that the next one will see its cells
+            let mut batch = rocksdb::WriteBatch::default();
+            let (count, stored) = ctx.finalize(&mut batch, None)?;
+            raw.write_opt(batch, write_options)
+                .map_err(CellStorageError::Internal)?;
+            stored.committed();
+            total += count;
         }
+
         Ok(total)
     }
 
-    pub fn drop_cell(&self, hash: &UInt256) {
-        self.cells_cache.remove(hash);
+    pub fn load_cell(
+        self: &Arc<Self>,
+        hash: UInt256,
+    ) -> Result<Arc<StorageCell>, CellStorageError> {
+        self.load_cell_in(CellColumn::Shared, hash)
     }
-}
-
-#[derive(thiserror::Error, Debug)]
-pub enum CellStorageError {
-    #[error("Cell not found in cell db")]
-    CellNotFound,
-    #[error("Invalid cell")]
-    InvalidCell,
-    #[error("Cell counter mismatch")]
-    CounterMismatch,
-    #[error("Internal rocksdb error")]
-    Internal(#[source] rocksdb::Error),
-}
 
-pub struct StorageCell {
-    _c: countme::Count<Self>,
-    cell_storage: Arc<CellStorage>,
-    cell_data: ton_types::CellData,
-    references: RwLock<SmallVec<[StorageCellReference; 4]>>,
-    tree_bits_count: u64,
-    tree_cell_count: u64,
-}
+    /// Same as [`CellStorage::load_cell`], but prefers the specified column.
+    ///
+    /// NOTE: cells are cached by their hash only, so the cell could be
+    /// returned from the cache even if it was loaded from another column
+    pub fn load_cell_in(
+        self: &Arc<Self>,
+        column: CellColumn,
+        hash: UInt256,
+    ) -> Result<Arc<StorageCell>, CellStorageError> {
+        self.load_cell_traced_in(column, hash).map(|(cell, _)| cell)
+    }
 
-impl StorageCell {
-    pub fn repr_hash(&self) -> UInt256 {
-        self.hash(ton_types::MAX_LEVEL)
+    /// Same as [`CellStorage::load_cell`], but additionally returns
+    /// how the cell was found in the cache
+    pub fn load_cell_traced(
+        self: &Arc<Self>,
+        hash: UInt256,
+    ) -> Result<(Arc<StorageCell>, CacheOutcome), CellStorageError> {
+        self.load_cell_traced_in(CellColumn::Shared, hash)
     }
 
-    pub fn deserialize(boc_db: Arc<CellStorage>, mut data: &[u8]) -> Result<Self> {
-        // deserialize cell
-        let cell_data = ton_types::CellData::deserialize(&mut data)?;
-        let references_count = data.read_byte()?;
-        let mut references = SmallVec::with_capacity(references_count as usize);
+    fn load_cell_traced_in(
+        self: &Arc<Self>,
+        column: CellColumn,
+        hash: UInt256,
+    ) -> Result<(Arc<StorageCell>, CacheOutcome), CellStorageError> {
+        let outcome = match self.cells_cache.lookup(&hash) {
+            Ok(cell) => {
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                self.recent_cache_stats.record(true);
+                return Ok((cell, CacheOutcome::Hit));
+            }
+            Err(outcome) => outcome,
+        };
 
-        for _ in 0..references_count {
-            let hash = UInt256::from(data.read_u256()?);
-            references.push(StorageCellReference::Unloaded(hash));
+        self.record_cache_miss(outcome);
+
+        // Staged cells are not cached, since their batch can be dropped without commit
+        if let Some(cell) = self.load_staged_cell(column, &hash)? {
+            return Ok((cell, outcome));
         }
 
-        let (tree_bits_count, tree_cell_count) = match data.read_le_u64() {
-            Ok(tree_bits_count) => match data.read_le_u64() {
-                Ok(tree_cell_count) => (tree_bits_count, tree_cell_count),
-                Err(_) => (0, 0),
-            },
-            Err(_) => (0, 0),
-        };
+        // Only one thread reads the cell, others wait for its result.
+        // NOTE: the map entry is not locked during the read
+        let in_flight = self.in_flight_loads.entry(hash).or_default().clone();
+        let result = in_flight
+            .get_or_try_init(|| {
+                let cell = self.load_stored_cell(column, &hash, None)?;
 
-        Ok(Self {
-            _c: Default::default(),
-            cell_storage: boc_db,
-            cell_data,
-            references: RwLock::new(references),
-            tree_bits_count,
-            tree_cell_count,
-        })
+                // Leaf cells are cheap to load and are rarely reused
+                if self.cache_leaf_cells || cell.references_count() > 0 {
+                    self.cache_cell(hash, &cell);
+                }
+                Ok::<_, CellStorageError>(cell)
+            })
+            .cloned();
+        self.in_flight_loads
+            .remove_if(&hash, |_, load| Arc::ptr_eq(load, &in_flight));
+
+        Ok((result?, outcome))
     }
 
-    pub fn deserialize_references(mut data: &[u8], target: &mut Vec<[u8; 32]>) -> bool {
-        let reader = &mut data;
+    /// Reads the cell from the db bypassing the cache (see [`CellStorage::load_cell_uncached`]),
+    /// and recomputes its representation hash to compare it with `hash`.
+    ///
+    /// NOTE: child cells are loaded to get their hashes, but are not verified
+    pub fn load_cell_verified(
+        self: &Arc<Self>,
+        hash: UInt256,
+    ) -> Result<Arc<StorageCell>, CellStorageError> {
+        // NOTE: cached cells could differ from what is stored
+        let cell = self.load_cell_uncached(&hash)?;
 
-        if ton_types::CellData::deserialize(reader).is_err() {
-            return false;
-        }
-        let Ok(references_count) = reader.read_byte() else {
-            return false;
-        };
+        let got = compute_repr_hash(&cell)?;
 
-        for _ in 0..references_count {
-            let Ok(hash) = reader.read_u256() else {
-                return false;
-            };
-            target.push(hash);
+        if got != hash {
+            return Err(CellStorageError::HashMismatch {
+                expected: hash,
+                got,
+            });
         }
 
-        true
+        Ok(cell)
     }
 
-    pub fn serialize_to(cell: &dyn CellImpl, target: &mut Vec<u8>) -> Result<()> {
-        target.clear();
+    /// Checks that the hash of each cell in the subtree matches its key.
+    ///
+    /// Each cell hash is computed from the hashes of its children, so if all cells
+    /// are consistent, the whole subtree is consistent with the root hash.
+    ///
+    /// Returns `false` on the first mismatch
+    pub fn verify_subtree(self: &Arc<Self>, root: UInt256) -> Result<bool, CellStorageError> {
+        let mut visited = FastHashSet::default();
+        let mut stack = vec![root];
 
-        // serialize cell
-        let references_count = cell.references_count() as u8;
-        cell.cell_data().serialize(target)?;
-        target.push(references_count);
+        while let Some(hash) = stack.pop() {
+            if !visited.insert(hash) {
+                continue;
+            }
 
-        for i in 0..references_count {
-            target.extend_from_slice(cell.reference(i as usize)?.repr_hash().as_slice());
-        }
+            let cell = match self.load_cell_verified(hash) {
+                Ok(cell) => cell,
+                Err(CellStorageError::HashMismatch { expected, got }) => {
+                    tracing::warn!(
+                        root = %root.to_hex_string(),
+                        expected = %expected.to_hex_string(),
+                        got = %got.to_hex_string(),
+                        "cell hash mismatch"
+                    );
+                    return Ok(false);
+                }
+                Err(e) => return Err(e),
+            };
 
-        target.extend_from_slice(&cell.tree_bits_count().to_le_bytes());
-        target.extend_from_slice(&cell.tree_cell_count().to_le_bytes());
+            stack.extend((0..cell.references_count()).filter_map(|i| cell.reference_hash(i)));
+        }
 
-        Ok(())
+        Ok(true)
     }
 
-    pub fn reference(&self, index: usize) -> Result<Arc<StorageCell>> {
-        let hash = match &self.references.read().get(index) {
-            Some(StorageCellReference::Unloaded(hash)) => *hash,
+    /// Loads subtrees of the specified roots into the cache (at most
+    /// `max_cells_each` cells per root, breadth-first).
+    ///
+    /// Warmed roots are kept alive until the next call, so that cells
+    /// are not immediately evicted from the cache of weak references.
+    ///
+    /// Returns the total number of warmed cells
+    pub fn warm_cache(
+        self: &Arc<Self>,
+        roots: &[UInt256],
+        max_cells_each: usize,
+    ) -> Result<usize, CellStorageError> {
+        let mut warm_roots = Vec::with_capacity(roots.len());
+        let mut total = 0;
+
+        let mut queue = std::collections::VecDeque::new();
+        for root in roots {
+            if max_cells_each == 0 {
+                break;
+            }
+
+            let root = self.load_cell(*root)?;
+            let mut warmed = 1;
+
+            queue.clear();
+            queue.push_back(root.clone());
+            'subtree: while let Some(cell) = queue.pop_front() {
+                for i in 0..cell.references.read().len() {
+                    if warmed >= max_cells_each {
+                        break 'subtree;
+                    }
+
+                    let child = cell
+                        .reference(i)
+                        .map_err(|e| e.downcast().unwrap_or(CellStorageError::InvalidCell))?;
+                    queue.push_back(child);
+                    warmed += 1;
+                }
+            }
+
+            total += warmed;
+            warm_roots.push(root);
+        }
+
+        *self.warm_roots.write() = warm_roots;
+
+        Ok(total)
+    }
+
+    /// Loads the whole subtree of the root, so that the returned cell
+    /// can be traversed without further storage reads.
+    ///
+    /// Returns `None` as soon as the subtree turns out to contain more than
+    /// `max_cells` distinct cells, so at most `max_cells` cells are loaded
+    pub fn load_bounded_tree(
+        self: &Arc<Self>,
+        root: UInt256,
+        max_cells: usize,
+    ) -> Result<Option<ton_types::Cell>, CellStorageError> {
+        if max_cells == 0 {
+            return Ok(None);
+        }
+
+        let root = self.load_cell(root)?;
+
+        let mut visited = FastHashSet::default();
+        visited.insert(root.repr_hash());
+
+        let mut stack = vec![root.clone()];
+        while let Some(cell) = stack.pop() {
+            for i in 0..cell.references.read().len() {
+                // NOTE: the limit is checked before the child is loaded
+                let hash = match &cell.references.read()[i] {
+                    StorageCellReference::Unloaded(hash) => *hash,
+                    StorageCellReference::Loaded(child) => child.repr_hash(),
+                };
+                let is_new = visited.insert(hash);
+                if visited.len() > max_cells {
+                    return Ok(None);
+                }
+
+                // NOTE: shared cells are already loaded, so they are only linked
+                let child = cell
+                    .reference(i)
+                    .map_err(|e| e.downcast().unwrap_or(CellStorageError::InvalidCell))?;
+                if is_new {
+                    stack.push(child);
+                }
+            }
+        }
+
+        Ok(Some(ton_types::Cell::with_cell_impl_arc(root)))
+    }
+
+    /// Loads the cell from the db without inserting it into the cache.
+    ///
+    /// NOTE: references of the returned cell are still resolved through
+    /// the cache (see [`StorageCell::reference`])
+    pub fn load_cell_uncached(
+        self: &Arc<Self>,
+        hash: &UInt256,
+    ) -> Result<Arc<StorageCell>, CellStorageError> {
+        self.load_cell_uncached_in(CellColumn::Shared, hash)
+    }
+
+    /// Creates a consistent read-only view of the committed cells.
+    ///
+    /// Cells loaded from the snapshot (and their references) stay readable
+    /// even if they are removed concurrently, e.g. during the virtualization
+    /// of a historical state.
+    ///
+    /// NOTE: the snapshot prevents compaction from dropping the removed cells,
+    /// so it should not be kept longer than needed
+    pub fn cells_snapshot(self: &Arc<Self>) -> CellsSnapshot {
+        let snapshot = self.db.raw().snapshot();
+        // SAFETY: the snapshot is dropped before the storage which keeps the db alive
+        let snapshot = unsafe {
+            std::mem::transmute::<rocksdb::Snapshot<'_>, rocksdb::Snapshot<'static>>(snapshot)
+        };
+
+        let read_options = |mut read_options: rocksdb::ReadOptions| {
+            read_options.set_snapshot(&snapshot);
+            read_options
+        };
+
+        CellsSnapshot {
+            inner: Arc::new(CellsSnapshotInner {
+                cells_read_options: read_options(self.db.cells.new_read_config()),
+                mc_cells_read_options: read_options(self.db.mc_cells.new_read_config()),
+                ref_lists_read_options: read_options(self.db.cell_ref_lists.new_read_config()),
+                snapshot,
+                cell_storage: self.clone(),
+            }),
+        }
+    }
+
+    fn load_cell_uncached_in(
+        self: &Arc<Self>,
+        column: CellColumn,
+        hash: &UInt256,
+    ) -> Result<Arc<StorageCell>, CellStorageError> {
+        match self.load_staged_cell(column, hash)? {
+            Some(cell) => Ok(cell),
+            None => self.load_stored_cell(column, hash, None),
+        }
+    }
+
+    /// Returns the cell of the not yet committed staging batch
+    fn load_staged_cell(
+        self: &Arc<Self>,
+        column: CellColumn,
+        hash: &UInt256,
+    ) -> Result<Option<Arc<StorageCell>>, CellStorageError> {
+        match self.staging.get(hash.as_array()) {
+            Some(cell) => match StorageCell::deserialize(self.clone(), column, &cell.data) {
+                Ok(cell) => Ok(Some(Arc::new(cell))),
+                Err(e) => {
+                    log_invalid_cell(hash, &cell.data, &e);
+                    Err(CellStorageError::InvalidCell)
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the committed cell from the db, or through the snapshot if specified
+    fn load_stored_cell(
+        self: &Arc<Self>,
+        column: CellColumn,
+        hash: &UInt256,
+        snapshot: Option<&CellsSnapshot>,
+    ) -> Result<Arc<StorageCell>, CellStorageError> {
+        // NOTE: the filter is never cleared, so it also contains all cells of the snapshot
+        if !self.may_contain(hash.as_array()) {
+            return Err(CellStorageError::CellNotFound);
+        }
+
+        let value = {
+            let _permit = self.read_limiter.as_ref().map(ReadLimiter::acquire);
+            self.get_cell_value(column, hash, snapshot.map(|snapshot| &*snapshot.inner))
+        };
+
+        match value {
+            Ok(Some((column, value))) => self.decode_stored_cell(column, hash, &value, snapshot),
+            Ok(None) => Err(CellStorageError::CellNotFound),
+            Err(e) => Err(CellStorageError::Internal(e)),
+        }
+    }
+
+    /// Decodes the stored value (with the refcount prefix) of the cell
+    fn decode_stored_cell(
+        self: &Arc<Self>,
+        column: CellColumn,
+        hash: &UInt256,
+        value: &[u8],
+        snapshot: Option<&CellsSnapshot>,
+    ) -> Result<Arc<StorageCell>, CellStorageError> {
+        let data = match refcount::strip_refcount(value) {
+            Some(data) => data,
+            // NOTE: legacy values are recognized the same way as in the format census
+            None if StorageCell::is_legacy_layout(value) => {
+                return self.load_legacy_cell(
+                    column,
+                    hash,
+                    value,
+                    CellStorageError::CellNotFound.into(),
+                    snapshot,
+                )
+            }
+            None => return Err(CellStorageError::CellNotFound),
+        };
+
+        match StorageCell::deserialize_in(self.clone(), column, data, snapshot) {
+            Ok(cell) if cell.repr_hash() == *hash => Ok(Arc::new(cell)),
+            // NOTE: a legacy value could be decoded as a cell with an unrelated hash
+            Ok(_) => self.load_legacy_cell(
+                column,
+                hash,
+                value,
+                CellStorageError::InvalidCell.into(),
+                snapshot,
+            ),
+            Err(e) => match checksum_error(&e) {
+                Some(checksum_error) => {
+                    log_invalid_cell(hash, value, &e);
+                    Err(checksum_error)
+                }
+                None => self.load_legacy_cell(column, hash, value, e, snapshot),
+            },
+        }
+    }
+
+    /// Resolves multiple cells at once, consulting the cache, the staged cells
+    /// and the db (or the snapshot of the context) in this order.
+    ///
+    /// Db reads of all remaining cells are batched. Returns `None` for cells
+    /// which are not stored
+    pub async fn resolve_cells(
+        self: &Arc<Self>,
+        hashes: &[UInt256],
+        ctx: ResolveCtx,
+    ) -> Result<Vec<Option<Arc<StorageCell>>>, CellStorageError> {
+        // NOTE: the permit is awaited before occupying the blocking thread
+        let permit = match &self.read_limiter {
+            Some(read_limiter) => Some(read_limiter.acquire_owned().await),
+            None => None,
+        };
+
+        let this = self.clone();
+        let hashes = hashes.to_vec();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            this.resolve_cells_blocking(&hashes, &ctx)
+        })
+        .await
+        .map_err(|_| CellStorageError::BlockingTaskFailed)?
+    }
+
+    /// NOTE: must be called with the read limiter permit
+    fn resolve_cells_blocking(
+        self: &Arc<Self>,
+        hashes: &[UInt256],
+        ctx: &ResolveCtx,
+    ) -> Result<Vec<Option<Arc<StorageCell>>>, CellStorageError> {
+        let snapshot = ctx.snapshot.as_ref();
+
+        let mut result = vec![None; hashes.len()];
+        let mut misses = Vec::new();
+        for (i, hash) in hashes.iter().enumerate() {
+            // NOTE: cached and staged cells resolve their references
+            // through the live storage, so they are skipped for the snapshot
+            if snapshot.is_none() {
+                match self.cells_cache.lookup(hash) {
+                    Ok(cell) => {
+                        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                        self.recent_cache_stats.record(true);
+                        result[i] = Some(cell);
+                        continue;
+                    }
+                    Err(outcome) => self.record_cache_miss(outcome),
+                }
+
+                if let Some(cell) = self.load_staged_cell(ctx.column, hash)? {
+                    result[i] = Some(cell);
+                    continue;
+                }
+            }
+
+            if self.may_contain(hash.as_array()) {
+                misses.push(i);
+            }
+        }
+
+        // Read the preferred column first, then the other one (if columns are split)
+        let mut column = self.resolve_column(ctx.column);
+        for _ in 0..2 {
+            if misses.is_empty() {
+                break;
+            }
+
+            let values = self
+                .cells_table_in(column, snapshot.map(|snapshot| &*snapshot.inner))
+                .multi_get(misses.iter().map(|&i| hashes[i].as_slice()));
+
+            let mut remaining = Vec::new();
+            for (i, value) in misses.into_iter().zip(values) {
+                let value = match value.map_err(CellStorageError::Internal)? {
+                    Some(value) if refcount::has_value(&value) => value,
+                    _ => {
+                        remaining.push(i);
+                        continue;
+                    }
+                };
+
+                let cell = self.decode_stored_cell(column, &hashes[i], &value, snapshot)?;
+                if ctx.populate_cache
+                    && snapshot.is_none()
+                    && (self.cache_leaf_cells || cell.references_count() > 0)
+                {
+                    self.cache_cell(hashes[i], &cell);
+                }
+                result[i] = Some(cell);
+            }
+            misses = remaining;
+
+            if !self.split_columns {
+                break;
+            }
+            column = column.other();
+        }
+
+        Ok(result)
+    }
+
+    /// Checks whether the cell is stored without deserializing it.
+    ///
+    /// NOTE: absent cells are usually resolved without a db read
+    /// if the cells filter is enabled
+    pub fn contains_cell(&self, hash: &UInt256) -> Result<bool, CellStorageError> {
+        if self.staging.contains(hash.as_array()) {
+            return Ok(true);
+        }
+        if !self.may_contain(hash.as_array()) {
+            return Ok(false);
+        }
+
+        match self.get_cell_value(CellColumn::Shared, hash, None) {
+            Ok(value) => Ok(matches!(value, Some((_, value)) if refcount::has_value(&value))),
+            Err(e) => Err(CellStorageError::Internal(e)),
+        }
+    }
+
+    /// Registers the cell which was written to the cells column bypassing the storage
+    /// (e.g. by the persistent state import), so that the cells filter doesn't report
+    /// it as absent.
+    ///
+    /// NOTE: must be called before the write batch with the cell is written
+    pub fn register_stored_cell(&self, hash: &[u8; 32]) {
+        if let Some(cells_filter) = &self.cells_filter {
+            cells_filter.insert(hash);
+        }
+    }
+
+    /// Returns `None` if the cells filter is disabled
+    pub fn cells_filter_stats(&self) -> Option<CellsFilterStats> {
+        self.cells_filter.as_deref().map(CellsFilter::stats)
+    }
+
+    /// Returns `false` if the cell is definitely not stored
+    #[inline]
+    fn may_contain(&self, hash: &[u8; 32]) -> bool {
+        match &self.cells_filter {
+            Some(cells_filter) => cells_filter.may_contain(hash),
+            None => true,
+        }
+    }
+
+    /// Same as `get_pinned_cf_opt`, but skips the read of definitely absent cells
+    fn read_stored_cell<'a>(
+        &self,
+        raw: &'a rocksdb::DB,
+        cells_cf: &BoundedCfHandle<'_>,
+        key: &[u8; 32],
+        read_options: &rocksdb::ReadOptions,
+    ) -> Result<Option<rocksdb::DBPinnableSlice<'a>>, rocksdb::Error> {
+        if self.may_contain(key) {
+            raw.get_pinned_cf_opt(cells_cf, key, read_options)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Reads the stored cell from the specified column, falling back to the other one,
+    /// since trees of roots which were stored before the columns were split
+    /// (or during the import of persistent states) live in the shared column.
+    ///
+    /// Returns the column where the cell was found
+    fn get_cell_value<'a>(
+        &'a self,
+        column: CellColumn,
+        hash: &UInt256,
+        snapshot: Option<&'a CellsSnapshotInner>,
+    ) -> Result<Option<(CellColumn, rocksdb::DBPinnableSlice<'a>)>, rocksdb::Error> {
+        let column = self.resolve_column(column);
+
+        let value = self.cells_table_in(column, snapshot).get(hash.as_slice())?;
+        if !self.split_columns || matches!(&value, Some(value) if refcount::has_value(value)) {
+            return Ok(value.map(|value| (column, value)));
+        }
+
+        let other = column.other();
+        match self.cells_table_in(other, snapshot).get(hash.as_slice())? {
+            Some(other_value) if refcount::has_value(&other_value) => {
+                Ok(Some((other, other_value)))
+            }
+            _ => Ok(value.map(|value| (column, value))),
+        }
+    }
+
+    /// Returns the column which stores the tree of the root. Roots which are
+    /// not found in the specified column are looked up in the shared column
+    fn resolve_root_column(
+        &self,
+        column: CellColumn,
+        hash: &UInt256,
+    ) -> Result<CellColumn, CellStorageError> {
+        let column = self.resolve_column(column);
+        if column == CellColumn::Shared {
+            return Ok(column);
+        }
+
+        match self.cells_table(column).get(hash.as_slice()) {
+            Ok(Some(value)) if refcount::has_value(&value) => Ok(column),
+            Ok(_) => Ok(CellColumn::Shared),
+            Err(e) => Err(CellStorageError::Internal(e)),
+        }
+    }
+
+    /// Returns the column which is actually used with the current options
+    fn resolve_column(&self, column: CellColumn) -> CellColumn {
+        if self.split_columns {
+            column
+        } else {
+            CellColumn::Shared
+        }
+    }
+
+    fn cells_table(&self, column: CellColumn) -> CellsTable<'_> {
+        match self.resolve_column(column) {
+            CellColumn::Shared => CellsTable::new(&self.db.cells),
+            CellColumn::Masterchain => CellsTable::new(&self.db.mc_cells),
+        }
+    }
+
+    fn cells_table_in<'a>(
+        &'a self,
+        column: CellColumn,
+        snapshot: Option<&'a CellsSnapshotInner>,
+    ) -> CellsTable<'a> {
+        let column = self.resolve_column(column);
+        let table = self.cells_table(column);
+        match snapshot {
+            Some(snapshot) => table.with_read_options(match column {
+                CellColumn::Shared => &snapshot.cells_read_options,
+                CellColumn::Masterchain => &snapshot.mc_cells_read_options,
+            }),
+            None => table,
+        }
+    }
+
+    /// Tries to decode the value written by earlier versions without
+    /// a refcount prefix. The decoded cell is accepted only if its recomputed
+    /// representation hash matches.
+    ///
+    /// NOTE: child cells are loaded to get their hashes
+    fn load_legacy_cell(
+        self: &Arc<Self>,
+        column: CellColumn,
+        hash: &UInt256,
+        value: &[u8],
+        error: anyhow::Error,
+        snapshot: Option<&CellsSnapshot>,
+    ) -> Result<Arc<StorageCell>, CellStorageError> {
+        if let Some(cell) = StorageCell::deserialize_legacy(self.clone(), column, value, snapshot) {
+            let cell = Arc::new(cell);
+            if matches!(compute_repr_hash(&cell), Ok(got) if got == *hash) {
+                tracing::warn!(hash = %hash.to_hex_string(), "loaded cell with legacy layout");
+                return Ok(cell);
+            }
+        }
+
+        log_invalid_cell(hash, value, &error);
+        Err(CellStorageError::InvalidCell)
+    }
+
+    /// Serializes the stored cell tree into a standard BOC
+    pub fn export_boc(self: &Arc<Self>, root: UInt256) -> Result<Vec<u8>, CellStorageError> {
+        let mut result = Vec::new();
+        self.export_boc_to(root, &mut result)?;
+        Ok(result)
+    }
+
+    /// Serializes the stored cell tree into a standard BOC and writes it to `dst`.
+    ///
+    /// NOTE: cells are loaded lazily, but all unique cells of the tree
+    /// are kept in memory until the serialization is finished
+    pub fn export_boc_to<W: std::io::Write>(
+        self: &Arc<Self>,
+        root: UInt256,
+        dst: &mut W,
+    ) -> Result<(), CellStorageError> {
+        let root = ton_types::Cell::with_cell_impl_arc(self.load_cell(root)?);
+        ton_types::serialize_tree_of_cells(&root, dst)
+            .map_err(|_| CellStorageError::BocSerializationFailed)
+    }
+
+    /// Overwrites the stored value of the cell with the serialized `correct_cell`,
+    /// preserving its refcount.
+    ///
+    /// NOTE: must not be called concurrently with storing or removing cells,
+    /// otherwise refcount changes between the read and the write could be lost
+    pub fn repair_cell(
+        &self,
+        hash: UInt256,
+        correct_cell: ton_types::Cell,
+    ) -> Result<(), CellStorageError> {
+        let got = correct_cell.repr_hash();
+        if got != hash {
+            return Err(CellStorageError::HashMismatch {
+                expected: hash,
+                got,
+            });
+        }
+
+        let cells = &self.db.cells;
+        let rc = match cells.get(hash.as_slice()) {
+            Ok(Some(value)) => match refcount::decode_value_with_rc(&value) {
+                (rc, Some(_)) => rc,
+                _ => return Err(CellStorageError::CellNotFound),
+            },
+            Ok(None) => return Err(CellStorageError::CellNotFound),
+            Err(e) => return Err(CellStorageError::Internal(e)),
+        };
+
+        let mut data = Vec::with_capacity(512);
+        if StorageCell::serialize_to(&*correct_cell, &mut data).is_err() {
+            return Err(CellStorageError::InvalidCell);
+        }
+        if self.checksums {
+            StorageCell::append_checksum(&mut data);
+        }
+
+        let mut value = Vec::with_capacity(data.len() + 8);
+        refcount::encode_value_with_rc(rc, &data, &mut value);
+        cells
+            .insert(hash.as_slice(), value)
+            .map_err(CellStorageError::Internal)?;
+
+        // Force reloading of the repaired cell
+        self.invalidate(&hash);
+
+        Ok(())
+    }
+
+    /// Ratio of [`CellStorage::load_cell`] calls served from the cache.
+    ///
+    /// Returns `None` if no cells were loaded yet
+    pub fn cache_hit_ratio(&self) -> Option<f64> {
+        let hits = self.cache_hits.load(Ordering::Relaxed);
+        let total = hits + self.cache_misses.load(Ordering::Relaxed);
+        (total > 0).then(|| hits as f64 / total as f64)
+    }
+
+    /// Number of cache misses of the weak cache entries whose cells were
+    /// already dropped (see [`CacheOutcome::WeakExpired`]). Always zero for
+    /// the [`CellCacheStrategy::StrongLru`] cache.
+    ///
+    /// Such misses are counted separately from the misses of absent entries:
+    /// the cell was loaded before, but nothing held it until the next load.
+    /// A high rate means the weak cache is ineffective for the load pattern,
+    /// and the `StrongLru` strategy could be used instead
+    pub fn cache_weak_expired(&self) -> u64 {
+        self.cache_weak_expired.load(Ordering::Relaxed)
+    }
+
+    /// Ratio of loads of the previously cached cells which were still alive,
+    /// i.e. hits to hits with expired weak entries.
+    ///
+    /// Returns `None` if there were no such loads yet
+    pub fn cache_effectiveness(&self) -> Option<f64> {
+        let hits = self.cache_hits.load(Ordering::Relaxed);
+        let total = hits + self.cache_weak_expired.load(Ordering::Relaxed);
+        (total > 0).then(|| hits as f64 / total as f64)
+    }
+
+    fn cache_cell(&self, hash: UInt256, cell: &Arc<StorageCell>) {
+        self.cells_cache
+            .insert(hash, cell, self.skip_contended_cache_inserts);
+    }
+
+    fn record_cache_miss(&self, outcome: CacheOutcome) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        if outcome == CacheOutcome::WeakExpired {
+            self.cache_weak_expired.fetch_add(1, Ordering::Relaxed);
+        }
+        self.recent_cache_stats.record(false);
+    }
+
+    /// Ratio of [`CellStorage::load_cell`] calls served from the cache
+    /// within the last `window_sec` seconds (at most 5 minutes).
+    ///
+    /// Returns `None` if no cells were loaded within the window
+    pub fn recent_cache_hit_ratio(&self, window_sec: u32) -> Option<f64> {
+        self.recent_cache_stats.hit_ratio(window_sec)
+    }
+
+    /// Returns an approximate number of stored cells without a full scan
+    pub fn approx_cell_count(&self) -> Result<u64, CellStorageError> {
+        let cells = &self.db.cells;
+        cells
+            .db()
+            .property_int_value_cf(&cells.cf(), "rocksdb.estimate-num-keys")
+            .map(Option::unwrap_or_default)
+            .map_err(CellStorageError::Internal)
+    }
+
+    /// Counts stored cells by the number of their references.
+    ///
+    /// NOTE: performs a full scan of the cells column, but parses
+    /// only the cell data and the references count
+    pub fn reference_count_histogram(&self) -> Result<[u64; 5], CellStorageError> {
+        let mut histogram = [0u64; 5];
+
+        let mut iter = self.db.cells.raw_iterator();
+        iter.seek_to_first();
+        while let Some(value) = iter.value() {
+            if let Some(mut data) = refcount::strip_refcount(value) {
+                let references_count = StorageCell::read_references_count(&mut data)
+                    .ok_or(CellStorageError::InvalidCell)?;
+                let references_count = references_count & !CELL_REF_LIST_FLAG;
+                histogram[std::cmp::min(references_count as usize, 4)] += 1;
+            }
+            iter.next();
+        }
+        iter.status().map_err(CellStorageError::Internal)?;
+
+        Ok(histogram)
+    }
+
+    /// Iterates over cells with a non-positive refcount which still have
+    /// a physical value in the cells columns (until compaction removes them).
+    ///
+    /// NOTE: performs a full scan of the cells columns, stops after the first error
+    pub fn iter_collectible_cells(
+        &self,
+        limit: Option<usize>,
+    ) -> impl Iterator<Item = Result<UInt256>> + '_ {
+        let mut iterators = [
+            self.db.cells.raw_iterator(),
+            self.db.mc_cells.raw_iterator(),
+        ]
+        .into_iter()
+        .map(|mut iter| {
+            iter.seek_to_first();
+            iter
+        });
+        let mut current = iterators.next();
+
+        let collectible = std::iter::from_fn(move || loop {
+            let iter = current.as_mut()?;
+            match (iter.key(), iter.value()) {
+                (Some(key), Some(value)) => {
+                    let hash = (refcount::decode_value_with_rc(value).0 <= 0)
+                        .then(|| UInt256::from_be_bytes(key));
+                    iter.next();
+                    if let Some(hash) = hash {
+                        return Some(Ok(hash));
+                    }
+                }
+                _ => {
+                    if let Err(e) = iter.status() {
+                        current = None;
+                        return Some(Err(e.into()));
+                    }
+                    current = iterators.next();
+                }
+            }
+        });
+
+        collectible.take(limit.unwrap_or(usize::MAX))
+    }
+
+    /// Classifies stored cells by their layout.
+    ///
+    /// Only `sample` cells at random positions of each cells column are checked
+    /// if specified, otherwise all cells are scanned
+    pub fn cell_format_census(&self, sample: Option<usize>) -> Result<FormatCensus> {
+        let mut census = FormatCensus::default();
+
+        let iterators = [
+            self.db.cells.raw_iterator(),
+            self.db.mc_cells.raw_iterator(),
+        ];
+        for mut iter in iterators {
+            for_each_sampled_item(&mut iter, sample, |_, value| {
+                census.record(value);
+                Ok(())
+            })?;
+        }
+
+        Ok(census)
+    }
+
+    /// Computes cells which were added and removed between two stored states.
+    ///
+    /// Both subtrees are walked in lockstep without loading the cells, references
+    /// at the same position are compared and identical subtrees are skipped.
+    ///
+    /// NOTE: cells are matched by their position, so descendants of a subtree
+    /// which moved to another position could be reported even if they are shared
+    pub fn diff_states(
+        &self,
+        old_root: UInt256,
+        new_root: UInt256,
+    ) -> Result<StateDiff, CellStorageError> {
+        let mut added = FastHashSet::default();
+        let mut removed = FastHashSet::default();
+
+        let mut old_refs = Vec::new();
+        let mut new_refs = Vec::new();
+
+        let mut stack = vec![(Some(*old_root.as_array()), Some(*new_root.as_array()))];
+        while let Some((old, new)) = stack.pop() {
+            if old == new {
+                continue;
+            }
+
+            old_refs.clear();
+            if let Some(old) = old {
+                if removed.insert(old) {
+                    self.find_cell_refs(&old, &mut old_refs)?;
+                }
+            }
+
+            new_refs.clear();
+            if let Some(new) = new {
+                if added.insert(new) {
+                    self.find_cell_refs(&new, &mut new_refs)?;
+                }
+            }
+
+            for i in 0..std::cmp::max(old_refs.len(), new_refs.len()) {
+                stack.push((old_refs.get(i).copied(), new_refs.get(i).copied()));
+            }
+        }
+
+        // Cells which just moved to another position
+        let moved = added.intersection(&removed).copied().collect::<Vec<_>>();
+        for hash in moved {
+            added.remove(&hash);
+            removed.remove(&hash);
+        }
+
+        Ok(StateDiff {
+            added: added.into_iter().map(UInt256::from).collect(),
+            removed: removed.into_iter().map(UInt256::from).collect(),
+        })
+    }
+
+    /// Returns the total number of bits and cells in the subtree of the stored cell
+    /// (see [`StorageCell::compute_tree_size`])
+    pub fn subtree_stats(self: &Arc<Self>, root: UInt256) -> Result<(u64, u64)> {
+        self.load_cell(root)?.compute_tree_size()
+    }
+
+    /// Walks all cells reachable from the roots without loading them
+    /// and returns hashes of the missing cells.
+    ///
+    /// Compact cells without their reference list are reported as missing.
+    ///
+    /// NOTE: hashes of all visited cells are kept in memory
+    pub fn check_reachable(&self, roots: &[UInt256]) -> Result<Vec<UInt256>, CellStorageError> {
+        let mut visited = FastHashSet::default();
+        let mut missing = Vec::new();
+
+        let mut refs = Vec::new();
+        let mut stack = roots
+            .iter()
+            .map(|root| *root.as_array())
+            .collect::<Vec<_>>();
+        while let Some(hash) = stack.pop() {
+            if !visited.insert(hash) {
+                continue;
+            }
+
+            match self.find_cell_refs(&hash, &mut refs) {
+                Ok(()) => stack.extend_from_slice(&refs),
+                Err(CellStorageError::CellNotFound) => missing.push(UInt256::from(hash)),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(missing)
+    }
+
+    /// Sums the stored value lengths (including the refcount prefix) of all
+    /// unique cells of the subtree and their reference lists.
+    ///
+    /// Unlike the logical tree size, shared cells are counted once, so the result
+    /// is close to the disk space which the state occupies (without the keys
+    /// and the compression).
+    ///
+    /// NOTE: hashes of all visited cells are kept in memory
+    pub fn state_disk_size(&self, root: UInt256) -> Result<u64, CellStorageError> {
+        let mut visited = FastHashSet::default();
+        let mut visited_ref_lists = FastHashSet::default();
+        let mut total = 0u64;
+
+        // NOTE: children are looked up in the column of their parent first
+        let mut refs = Vec::new();
+        let mut stack = vec![(CellColumn::Masterchain, root)];
+        while let Some((column, hash)) = stack.pop() {
+            if !visited.insert(hash) {
+                continue;
+            }
+
+            let (column, value) = self
+                .get_cell_value(column, &hash, None)
+                .map_err(CellStorageError::Internal)?
+                .ok_or(CellStorageError::CellNotFound)?;
+            let data = refcount::strip_refcount(&value).ok_or(CellStorageError::CellNotFound)?;
+            total += value.len() as u64;
+
+            refs.clear();
+            if let Some(id) = self.read_references(data, &mut refs)? {
+                if visited_ref_lists.insert(id) {
+                    let list = self
+                        .db
+                        .cell_ref_lists
+                        .get(id.to_be_bytes())
+                        .map_err(CellStorageError::Internal)?
+                        .ok_or(CellStorageError::CellNotFound)?;
+                    total += list.len() as u64;
+                }
+            }
+            stack.extend(refs.iter().map(|child| (column, UInt256::from(*child))));
+        }
+
+        Ok(total)
+    }
+
+    /// Walks the stored trees of the roots without loading the cells
+    /// and returns the deepest root with its depth.
+    ///
+    /// Cells without references have zero depth, shared subtrees are walked once.
+    /// Returns a zero hash with zero depth if there are no roots.
+    ///
+    /// NOTE: depths of all visited cells are kept in memory
+    pub fn max_tree_depth(&self, roots: &[UInt256]) -> Result<(UInt256, u16), CellStorageError> {
+        let mut depths = FastHashMap::<[u8; 32], u16>::default();
+        let mut result = None;
+
+        // Cells are popped twice: to push their children and to compute their depth
+        let mut stack = Vec::new();
+        let mut refs = Vec::new();
+        for root in roots {
+            stack.push((*root.as_array(), None));
+            while let Some((hash, children)) = stack.pop() {
+                match children {
+                    None if depths.contains_key(&hash) => {}
+                    None => {
+                        self.find_cell_refs(&hash, &mut refs)?;
+                        stack.push((hash, Some(refs.clone())));
+                        stack.extend(
+                            refs.iter()
+                                .filter(|child| !depths.contains_key(*child))
+                                .map(|child| (*child, None)),
+                        );
+                    }
+                    Some(children) => {
+                        let depth = children
+                            .iter()
+                            .filter_map(|child| depths.get(child))
+                            .max()
+                            .map_or(0, |depth| depth.saturating_add(1));
+                        depths.insert(hash, depth);
+                    }
+                }
+            }
+
+            let depth = depths.get(root.as_array()).copied().unwrap_or_default();
+            if !matches!(result, Some((_, max_depth)) if max_depth >= depth) {
+                result = Some((*root, depth));
+            }
+        }
+
+        Ok(result.unwrap_or_default())
+    }
+
+    /// Iterates over unique cells of the stored subtree, children before parents.
+    ///
+    /// Children are visited in the order of references, so the same tree always
+    /// produces the same sequence. Each item contains the cell hash and its
+    /// canonical encoding (cell data, references count and child hashes), which
+    /// doesn't depend on the storage layout of the cell.
+    ///
+    /// NOTE: hashes of all visited cells are kept in memory
+    pub fn iter_subtree_postorder(
+        &self,
+        root: UInt256,
+    ) -> impl Iterator<Item = Result<(UInt256, Vec<u8>), CellStorageError>> + '_ {
+        SubtreePostorderIter {
+            cell_storage: self,
+            visited: Default::default(),
+            stack: Vec::new(),
+            root: Some(*root.as_array()),
+        }
+    }
+
+    /// Reads the canonical encoding and child hashes of the stored cell
+    fn read_canonical_cell(
+        &self,
+        hash: &[u8; 32],
+    ) -> Result<(Vec<u8>, Vec<[u8; 32]>), CellStorageError> {
+        let value = self
+            .get_cell_value(CellColumn::Shared, &UInt256::from(*hash), None)
+            .map_err(CellStorageError::Internal)?
+            .ok_or(CellStorageError::CellNotFound)?;
+        let data = refcount::strip_refcount(&value.1).ok_or(CellStorageError::CellNotFound)?;
+
+        let mut children = Vec::new();
+        self.read_references(data, &mut children)?;
+
+        let mut reader = data;
+        StorageCell::read_references_count(&mut reader).ok_or(CellStorageError::InvalidCell)?;
+        let cell_data_len = data.len() - reader.len() - 1;
+
+        let mut result = Vec::with_capacity(cell_data_len + 1 + children.len() * 32);
+        result.extend_from_slice(&data[..cell_data_len]);
+        result.push(children.len() as u8);
+        for child in &children {
+            result.extend_from_slice(child);
+        }
+
+        Ok((result, children))
+    }
+
+    /// Rewrites refcounts of all stored cells to the number of references
+    /// from the cells reachable from `roots` (each root counts as one more reference).
+    /// Cells which are not reachable from any root get zero refcount and will
+    /// be removed by compaction.
+    ///
+    /// Refcounts of the reference lists are rewritten to the sum of the new
+    /// refcounts of the compact cells which use them.
+    ///
+    /// NOTE: must not be called while states are stored or removed.
+    pub fn rebuild_refcounts(&self, roots: &[UInt256]) -> Result<RebuildReport, CellStorageError> {
+        const BATCH_SIZE: usize = 10000;
+
+        let mut report = RebuildReport::default();
+
+        // Compute the expected refcounts. Each column holds complete trees,
+        // so references are counted within the column of the parent
+        let mut counts = FastHashMap::<(CellColumn, [u8; 32]), i64>::default();
+        let mut visited = FastHashSet::default();
+
+        let mut refs = Vec::new();
+        let mut stack = Vec::with_capacity(roots.len());
+        for root in roots {
+            let column = self.resolve_root_column(CellColumn::Masterchain, root)?;
+            *counts.entry((column, *root.as_array())).or_default() += 1;
+            stack.push((column, *root.as_array()));
+        }
+        while let Some((column, hash)) = stack.pop() {
+            if !visited.insert((column, hash)) {
+                continue;
+            }
+
+            match self.read_cell_refs(column, &hash, &mut refs) {
+                Ok(_) => {
+                    for child in &refs {
+                        *counts.entry((column, *child)).or_default() += 1;
+                    }
+                    stack.extend(refs.iter().map(|child| (column, *child)));
+                }
+                Err(CellStorageError::CellNotFound) => report.missing_cells += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        drop(visited);
+        report.reachable_cells = counts.len();
+
+        // Rewrite all mismatched counters
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut buffer = Vec::new();
+
+        // Expected refcounts of the reference lists
+        let mut ref_list_counts = FastHashMap::<u64, i64>::default();
+
+        let columns = [
+            (CellColumn::Shared, CellsTable::new(&self.db.cells)),
+            (CellColumn::Masterchain, CellsTable::new(&self.db.mc_cells)),
+        ];
+        for (column, cells) in columns {
+            let mut iter = cells.raw.raw_iterator_cf_opt(&cells.cf, Default::default());
+            iter.seek_to_first();
+            while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+                let expected = key
+                    .try_into()
+                    .ok()
+                    .and_then(|key: [u8; 32]| counts.get(&(column, key)).copied())
+                    .unwrap_or_default();
+
+                let (rc, data) = refcount::decode_value_with_rc(value);
+                if let Some(id) = data.and_then(StorageCell::ref_list_id) {
+                    *ref_list_counts.entry(id).or_default() += expected;
+                }
+
+                match (rc, data) {
+                    (rc, Some(data)) if rc != expected => {
+                        buffer.clear();
+                        refcount::encode_value_with_rc(expected, data, &mut buffer);
+                        batch.put_cf(&cells.cf, key, &buffer);
+
+                        report.updated_cells += 1;
+                        if expected == 0 {
+                            report.unreachable_cells += 1;
+                        }
+                    }
+                    // Stored negative counters are reset
+                    (rc, None) if rc != 0 => {
+                        batch.delete_cf(&cells.cf, key);
+                        report.updated_cells += 1;
+                    }
+                    _ => {}
+                }
+
+                if batch.len() >= BATCH_SIZE {
+                    cells
+                        .raw
+                        .write(std::mem::take(&mut batch))
+                        .map_err(CellStorageError::Internal)?;
+                }
+                iter.next();
+            }
+            iter.status().map_err(CellStorageError::Internal)?;
+        }
+
+        // Rewrite all mismatched reference list counters
+        let ref_lists = &self.db.cell_ref_lists;
+        let ref_lists_cf = ref_lists.cf();
+
+        let mut iter = ref_lists.raw_iterator();
+        iter.seek_to_first();
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            let expected = key
+                .try_into()
+                .ok()
+                .and_then(|key: [u8; 8]| ref_list_counts.get(&u64::from_be_bytes(key)).copied())
+                .unwrap_or_default();
+
+            match refcount::decode_value_with_rc(value) {
+                (rc, Some(data)) if rc != expected => {
+                    buffer.clear();
+                    refcount::encode_value_with_rc(expected, data, &mut buffer);
+                    batch.put_cf(&ref_lists_cf, key, &buffer);
+                    report.updated_ref_lists += 1;
+                }
+                (rc, None) if rc != 0 => {
+                    batch.delete_cf(&ref_lists_cf, key);
+                    report.updated_ref_lists += 1;
+                }
+                _ => {}
+            }
+
+            if batch.len() >= BATCH_SIZE {
+                ref_lists
+                    .db()
+                    .write(std::mem::take(&mut batch))
+                    .map_err(CellStorageError::Internal)?;
+            }
+            iter.next();
+        }
+        iter.status().map_err(CellStorageError::Internal)?;
+
+        if !batch.is_empty() {
+            ref_lists
+                .db()
+                .write(batch)
+                .map_err(CellStorageError::Internal)?;
+        }
+
+        tracing::info!(
+            reachable_cells = report.reachable_cells,
+            missing_cells = report.missing_cells,
+            updated_cells = report.updated_cells,
+            unreachable_cells = report.unreachable_cells,
+            updated_ref_lists = report.updated_ref_lists,
+            "rebuilt cell refcounts"
+        );
+
+        Ok(report)
+    }
+
+    pub fn remove_cell(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        alloc: &Bump,
+        hash: UInt256,
+    ) -> Result<usize, CellStorageError> {
+        self.remove_cell_in(CellColumn::Shared, batch, alloc, hash)
+    }
+
+    /// Same as [`CellStorage::remove_cell`], but removes the tree from the specified column.
+    ///
+    /// NOTE: roots which are not found in the column are removed from the shared one
+    pub fn remove_cell_in(
+        &self,
+        column: CellColumn,
+        batch: &mut rocksdb::WriteBatch,
+        alloc: &Bump,
+        hash: UInt256,
+    ) -> Result<usize, CellStorageError> {
+        #[derive(Clone, Copy)]
+        struct CellState<'a> {
+            rc: i64,
+            removes: u32,
+            refs: &'a [[u8; 32]],
+            ref_list: Option<u64>,
+            /// Whether the reference list of the compact cell was read
+            refs_loaded: bool,
+        }
+
+        impl<'a> CellState<'a> {
+            fn remove(&mut self) -> Result<Option<&'a [[u8; 32]]>, CellStorageError> {
+                self.removes += 1;
+                if self.removes as i64 <= self.rc {
+                    Ok(self.next_refs())
+                } else {
+                    Err(CellStorageError::CounterMismatch)
+                }
+            }
+
+            fn next_refs(&self) -> Option<&'a [[u8; 32]]> {
+                // NOTE: children of the compact cell are visited when its list is read
+                if self.rc > self.removes as i64 || !self.refs_loaded {
+                    None
+                } else {
+                    Some(self.refs)
+                }
+            }
+        }
+
+        let cells = self.cells_table(self.resolve_root_column(column, &hash)?);
+        let raw = cells.raw;
+        let cells_cf = &cells.cf;
+        let read_options = cells.read_options;
+        let mut transaction: FastHashMap<&[u8; 32], CellState> =
+            FastHashMap::with_capacity_and_hasher(128, Default::default());
+        let mut buffer = Vec::with_capacity(4);
+
+        let mut stack = Vec::with_capacity(16);
+        stack.push(hash.as_slice());
+
+        // Compact cells whose reference lists are not read yet
+        let mut pending_ref_lists = Vec::new();
+
+        loop {
+            // While some cells left
+            while let Some(cell_id) = stack.pop() {
+                let refs = match transaction.entry(cell_id) {
+                    hash_map::Entry::Occupied(mut v) => v.get_mut().remove()?,
+                    hash_map::Entry::Vacant(v) => {
+                        let (rc, ref_list) =
+                            match raw.get_pinned_cf_opt(cells_cf, cell_id, read_options) {
+                                Ok(value) => 'rc: {
+                                    if let Some(value) = value {
+                                        buffer.clear();
+                                        if let (rc, Some(value)) =
+                                            refcount::decode_value_with_rc(&value)
+                                        {
+                                            let ref_list = if StorageCell::deserialize_references(
+                                                value,
+                                                &mut buffer,
+                                            ) {
+                                                None
+                                            } else {
+                                                Some(
+                                                    StorageCell::ref_list_id(value)
+                                                        .ok_or(CellStorageError::InvalidCell)?,
+                                                )
+                                            };
+                                            break 'rc (rc, ref_list);
+                                        }
+                                    }
+                                    return Err(CellStorageError::CellNotFound);
+                                }
+                                Err(e) => return Err(CellStorageError::Internal(e)),
+                            };
+
+                        if let Some(id) = ref_list {
+                            pending_ref_lists.push((cell_id, id));
+                        }
+
+                        v.insert(CellState {
+                            rc,
+                            removes: 1,
+                            refs: alloc.alloc_slice_copy(buffer.as_slice()),
+                            ref_list,
+                            refs_loaded: ref_list.is_none(),
+                        })
+                        .next_refs()
+                    }
+                };
+
+                if let Some(refs) = refs {
+                    // Add all children
+                    for cell_id in refs {
+                        // Unknown cell, push to the stack to process it
+                        stack.push(cell_id);
+                    }
+                }
+            }
+
+            if pending_ref_lists.is_empty() {
+                break;
+            }
+
+            // Read the reference lists of the visited compact cells at once
+            let ids = pending_ref_lists
+                .iter()
+                .map(|(_, id)| *id)
+                .collect::<Vec<_>>();
+            let lists = self.load_ref_lists(&ids)?;
+
+            for ((cell_id, _), list) in pending_ref_lists.drain(..).zip(lists) {
+                let state = transaction
+                    .get_mut(cell_id)
+                    .ok_or(CellStorageError::CounterMismatch)?;
+                state.refs = alloc.alloc_slice_copy(&list);
+                state.refs_loaded = true;
+
+                if let Some(refs) = state.next_refs() {
+                    stack.extend(refs.iter());
+                }
+            }
+        }
+
+        // Clear big chunks of data before finalization
+        drop(stack);
+
+        // Write transaction to the `WriteBatch`
+        let ref_lists_cf = &self.db.cell_ref_lists.cf();
+        let total = transaction.len();
+        for (
+            key,
+            CellState {
+                rc,
+                removes,
+                ref_list,
+                ..
+            },
+        ) in transaction
+        {
+            // NOTE: removed cells must not be served from the cache
+            if removes as i64 >= rc {
+                self.cells_cache.evict_removed(&UInt256::from(*key));
+            }
+
+            batch.merge_cf(
+                cells_cf,
+                key.as_slice(),
+                refcount::encode_negative_refcount(removes),
+            );
+            if let Some(id) = ref_list {
+                batch.merge_cf(
+                    ref_lists_cf,
+                    id.to_be_bytes(),
+                    refcount::encode_negative_refcount(removes),
+                );
+            }
+        }
+        Ok(total)
+    }
+
+    /// Moves `amount` references from one stored cell to another within the same batch,
+    /// so that there is no window where the source cell could be collected
+    /// before the target one is referenced.
+    ///
+    /// NOTE: both cells must exist, the batch must be written atomically
+    pub fn transfer_refcount(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        from: UInt256,
+        to: UInt256,
+        amount: i64,
+    ) -> Result<(), CellStorageError> {
+        if amount == 0 || from == to {
+            return Ok(());
+        }
+
+        let cells = &self.db.cells;
+        let cells_cf = &cells.cf();
+        let ref_lists_cf = &self.db.cell_ref_lists.cf();
+
+        // NOTE: both cells are checked before modifying the batch
+        let mut ref_lists = [None; 2];
+        for (hash, ref_list) in [from, to].iter().zip(&mut ref_lists) {
+            let value = cells
+                .get(hash.as_slice())
+                .map_err(CellStorageError::Internal)?;
+            let data = value
+                .as_deref()
+                .and_then(refcount::strip_refcount)
+                .ok_or(CellStorageError::CellNotFound)?;
+            *ref_list = StorageCell::ref_list_id(data);
+        }
+
+        let mut buffer = Vec::with_capacity(8);
+        for ((hash, delta), ref_list) in [(from, -amount), (to, amount)].into_iter().zip(ref_lists)
+        {
+            buffer.clear();
+            refcount::encode_value_with_rc(delta, &[], &mut buffer);
+            batch.merge_cf(cells_cf, hash.as_slice(), &buffer);
+
+            // Reference lists are owned by the references of compact cells
+            if let Some(id) = ref_list {
+                batch.merge_cf(ref_lists_cf, id.to_be_bytes(), &buffer);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates a removal session which can be shared between threads.
+    ///
+    /// Unlike [`CellStorage::remove_cell`], cells shared between the removed roots
+    /// are read once and decremented in a single merge operand
+    pub fn begin_removal(&self) -> CellRemovalSession<'_> {
+        CellRemovalSession {
+            cell_storage: self,
+            transaction: Default::default(),
+        }
+    }
+
+    /// Returns the refcount and the reference list id of the compact cell
+    fn read_cell_refs(
+        &self,
+        column: CellColumn,
+        cell_id: &[u8; 32],
+        buffer: &mut Vec<[u8; 32]>,
+    ) -> Result<(i64, Option<u64>), CellStorageError> {
+        let value = self
+            .cells_table(column)
+            .get(cell_id)
+            .map_err(CellStorageError::Internal)?
+            .ok_or(CellStorageError::CellNotFound)?;
+
+        buffer.clear();
+        match refcount::decode_value_with_rc(&value) {
+            (rc, Some(value)) => Ok((rc, self.read_references(value, buffer)?)),
+            (_, None) => Err(CellStorageError::CellNotFound),
+        }
+    }
+
+    /// Same as [`CellStorage::read_cell_refs`], but looks up the cell in both columns
+    fn find_cell_refs(
+        &self,
+        cell_id: &[u8; 32],
+        buffer: &mut Vec<[u8; 32]>,
+    ) -> Result<(), CellStorageError> {
+        let (_, value) = self
+            .get_cell_value(CellColumn::Shared, &UInt256::from(*cell_id), None)
+            .map_err(CellStorageError::Internal)?
+            .ok_or(CellStorageError::CellNotFound)?;
+
+        buffer.clear();
+        let value = refcount::strip_refcount(&value).ok_or(CellStorageError::CellNotFound)?;
+        self.read_references(value, buffer)?;
+        Ok(())
+    }
+
+    /// Reads child hashes of the stored cell (without the refcount prefix).
+    ///
+    /// Returns the reference list id if the cell is compact
+    fn read_references(
+        &self,
+        value: &[u8],
+        target: &mut Vec<[u8; 32]>,
+    ) -> Result<Option<u64>, CellStorageError> {
+        if StorageCell::deserialize_references(value, target) {
+            return Ok(None);
+        }
+
+        match StorageCell::ref_list_id(value) {
+            Some(id) => {
+                self.load_ref_list(id, target, None)?;
+                Ok(Some(id))
+            }
+            None => Err(CellStorageError::InvalidCell),
+        }
+    }
+
+    fn load_ref_list(
+        &self,
+        id: u64,
+        target: &mut Vec<[u8; 32]>,
+        snapshot: Option<&CellsSnapshotInner>,
+    ) -> Result<(), CellStorageError> {
+        let ref_lists = &self.db.cell_ref_lists;
+        let read_options = match snapshot {
+            Some(snapshot) => &snapshot.ref_lists_read_options,
+            None => ref_lists.read_config(),
+        };
+
+        let value = ref_lists
+            .db()
+            .get_pinned_cf_opt(&ref_lists.cf(), id.to_be_bytes(), read_options)
+            .map_err(CellStorageError::Internal)?
+            .ok_or(CellStorageError::CellNotFound)?;
+
+        parse_ref_list(&value, target)
+    }
+
+    /// Same as [`CellStorage::load_ref_list`], but reads all lists at once
+    fn load_ref_lists(&self, ids: &[u64]) -> Result<Vec<Vec<[u8; 32]>>, CellStorageError> {
+        let ref_lists = &self.db.cell_ref_lists;
+        let ref_lists_cf = ref_lists.cf();
+        let values = ref_lists.db().multi_get_cf_opt(
+            ids.iter().map(|id| (&ref_lists_cf, id.to_be_bytes())),
+            ref_lists.read_config(),
+        );
+
+        values
+            .into_iter()
+            .map(|value| {
+                let value = value
+                    .map_err(CellStorageError::Internal)?
+                    .ok_or(CellStorageError::CellNotFound)?;
+                let mut list = Vec::new();
+                parse_ref_list(&value, &mut list)?;
+                Ok(list)
+            })
+            .collect()
+    }
+
+    pub fn drop_cell(&self, hash: &UInt256) {
+        self.cells_cache.remove_dropped(hash);
+    }
+
+    /// Removes the cell from the cache, so that the next [`CellStorage::load_cell`]
+    /// reads it from the db.
+    ///
+    /// NOTE: must be called after any out-of-band modification of the cell
+    /// in the cells column. Already loaded cells (and references resolved
+    /// through them) are not affected.
+    pub fn invalidate(&self, hash: &UInt256) {
+        self.cells_cache.remove(hash);
+    }
+
+    /// Clears the whole cells cache.
+    ///
+    /// NOTE: must be called after the cells column was compacted or repaired
+    /// externally (see [`CellStorage::invalidate`])
+    pub fn invalidate_all(&self) {
+        self.cells_cache.clear();
+    }
+}
+
+/// Column of the cells db which stores the cell tree.
+///
+/// Each column holds complete trees, so cells which are shared between the trees
+/// of different columns are stored (and refcounted) in each of them. The shared
+/// column is the designated one: it is used by all methods without an explicit column,
+/// for the imported persistent states, and as a fallback for the loaded cells.
+///
+/// NOTE: diagnostic and repair methods look up cells in both columns
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum CellColumn {
+    Shared,
+    /// Used only with [`CellStorageOptions::split_masterchain_cells`]
+    Masterchain,
+}
+
+impl CellColumn {
+    pub fn for_shard(shard_ident: &ton_block::ShardIdent) -> Self {
+        if shard_ident.is_masterchain() {
+            Self::Masterchain
+        } else {
+            Self::Shared
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            Self::Shared => Self::Masterchain,
+            Self::Masterchain => Self::Shared,
+        }
+    }
+}
+
+struct CellsTable<'a> {
+    raw: &'a rocksdb::DB,
+    cf: BoundedCfHandle<'a>,
+    read_options: &'a rocksdb::ReadOptions,
+}
+
+impl<'a> CellsTable<'a> {
+    fn new<T: ColumnFamily>(table: &'a Table<T>) -> Self {
+        Self {
+            raw: table.db(),
+            cf: table.cf(),
+            read_options: table.read_config(),
+        }
+    }
+
+    fn with_read_options(mut self, read_options: &'a rocksdb::ReadOptions) -> Self {
+        self.read_options = read_options;
+        self
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<rocksdb::DBPinnableSlice<'a>>, rocksdb::Error> {
+        self.raw.get_pinned_cf_opt(&self.cf, key, self.read_options)
+    }
+
+    fn multi_get<K, I>(&self, keys: I) -> Vec<Result<Option<Vec<u8>>, rocksdb::Error>>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+    {
+        self.raw.multi_get_cf_opt(
+            keys.into_iter().map(|key| (&self.cf, key)),
+            self.read_options,
+        )
+    }
+}
+
+/// Sources of [`CellStorage::resolve_cells`]
+#[derive(Clone)]
+pub struct ResolveCtx {
+    /// Column which is read first
+    pub column: CellColumn,
+    /// Read the committed cells through the snapshot, skipping the cache
+    /// and the staged cells
+    pub snapshot: Option<CellsSnapshot>,
+    /// Insert cells read from the db into the cache
+    pub populate_cache: bool,
+}
+
+impl Default for ResolveCtx {
+    fn default() -> Self {
+        Self {
+            column: CellColumn::Shared,
+            snapshot: None,
+            populate_cache: true,
+        }
+    }
+}
+
+/// Consistent read-only view of the stored cells (see [`CellStorage::cells_snapshot`])
+#[derive(Clone)]
+pub struct CellsSnapshot {
+    inner: Arc<CellsSnapshotInner>,
+}
+
+impl CellsSnapshot {
+    /// Same as [`CellStorage::load_cell`], but reads through the snapshot.
+    ///
+    /// NOTE: loaded cells are not cached, so their subtrees should be
+    /// traversed from the same root cell
+    pub fn load_cell(&self, hash: UInt256) -> Result<Arc<StorageCell>, CellStorageError> {
+        self.load_cell_in(CellColumn::Shared, hash)
+    }
+
+    /// Same as [`CellStorage::load_cell_in`], but reads through the snapshot
+    pub fn load_cell_in(
+        &self,
+        column: CellColumn,
+        hash: UInt256,
+    ) -> Result<Arc<StorageCell>, CellStorageError> {
+        self.inner
+            .cell_storage
+            .load_stored_cell(column, &hash, Some(self))
+    }
+}
+
+struct CellsSnapshotInner {
+    // NOTE: fields are dropped in the declaration order, so the snapshot
+    // (with the options referencing it) is released before the db
+    cells_read_options: rocksdb::ReadOptions,
+    mc_cells_read_options: rocksdb::ReadOptions,
+    ref_lists_read_options: rocksdb::ReadOptions,
+    snapshot: rocksdb::Snapshot<'static>,
+    cell_storage: Arc<CellStorage>,
+}
+
+struct CellWithRefs<'a> {
+    rc: u32,
+    data: &'a [u8],
+    /// Reference list id and child hashes of the compact cell
+    ref_list: Option<(u64, &'a [u8])>,
+    /// Whether the cell was not stored before this batch
+    is_new: bool,
+}
+
+struct StoreContext<'a> {
+    cells_cf: &'a BoundedCfHandle<'a>,
+    alloc: &'a Bump,
+    ref_lists: &'a Table<tables::CellRefLists>,
+    transaction: FastHashMap<[u8; 32], CellWithRefs<'a>>,
+    /// New cells which could be stored with a reference list (see [`StoreContext::resolve_ref_lists`])
+    pending_ref_lists: Vec<[u8; 32]>,
+    buffer: Vec<u8>,
+    checksums: bool,
+    on_cell_stored: Option<CellStoredCallback>,
+    cells_filter: Option<&'a CellsFilter>,
+    compact_references: bool,
+    /// Report unavailable children as [`CellStorageError::MissingChild`]
+    check_children: bool,
+}
+
+impl StoreContext<'_> {
+    fn reference_error(&self, error: anyhow::Error) -> CellStorageError {
+        match error.downcast_ref::<MissingChildContext>() {
+            Some(context) if self.check_children => CellStorageError::MissingChild {
+                parent: context.parent,
+                child: context.child,
+            },
+            _ => CellStorageError::InvalidCell,
+        }
+    }
+
+    fn insert_cell(
+        &mut self,
+        key: &[u8; 32],
+        cell: &ton_types::Cell,
+        value: Option<rocksdb::DBPinnableSlice<'_>>,
+        rc: u32,
+    ) -> Result<bool, CellStorageError> {
+        let stored = value.as_deref().and_then(refcount::strip_refcount);
+        let has_value = stored.is_some();
+
+        Ok(match self.transaction.entry(*key) {
+            hash_map::Entry::Occupied(mut value) => {
+                value.get_mut().rc += rc;
+                false
+            }
+            hash_map::Entry::Vacant(value) => {
+                self.buffer.clear();
+                if let Err(e) = StorageCell::serialize_to(&**cell, &mut self.buffer) {
+                    return Err(self.reference_error(e));
+                }
+
+                // NOTE: child hashes are followed by the tree counters
+                let references_count = cell.references_count();
+                let refs_end = self.buffer.len() - 16;
+                let refs_start = refs_end - references_count * 32;
+                let references = &self.buffer[refs_start..refs_end];
+
+                let ref_list_id = match stored {
+                    // Existing compact cells keep their reference lists alive
+                    Some(stored) => StorageCell::ref_list_id(stored),
+                    // NOTE: new cells are stored inline until their lists are checked
+                    None if self.compact_references && references_count >= MIN_REF_LIST_LEN => {
+                        self.pending_ref_lists.push(*key);
+                        Some(compute_ref_list_id(references))
+                    }
+                    None => None,
+                };
+
+                let ref_list =
+                    ref_list_id.map(|id| (id, &*self.alloc.alloc_slice_copy(references)));
+
+                if self.checksums {
+                    StorageCell::append_checksum(&mut self.buffer);
+                }
+                let data = self.alloc.alloc_slice_copy(self.buffer.as_slice());
+                value.insert(CellWithRefs {
+                    rc,
+                    data,
+                    ref_list,
+                    is_new: !has_value,
+                });
+                !has_value
+            }
+        })
+    }
+
+    /// Reads the reference lists of the new cells at once, and replaces child
+    /// hashes of the cells with the ids of the available lists
+    fn resolve_ref_lists(&mut self) -> Result<(), CellStorageError> {
+        if self.pending_ref_lists.is_empty() {
+            return Ok(());
+        }
+
+        let keys = std::mem::take(&mut self.pending_ref_lists);
+        let ids = keys
+            .iter()
+            .filter_map(|key| self.transaction.get(key)?.ref_list.map(|(id, _)| id))
+            .collect::<Vec<_>>();
+        if ids.len() != keys.len() {
+            return Err(CellStorageError::InvalidCell);
+        }
+
+        let ref_lists_cf = self.ref_lists.cf();
+        let values = self.ref_lists.db().multi_get_cf_opt(
+            ids.iter().map(|id| (&ref_lists_cf, id.to_be_bytes())),
+            self.ref_lists.read_config(),
+        );
+
+        for (key, value) in keys.iter().zip(values) {
+            let value = value.map_err(CellStorageError::Internal)?;
+            let cell = match self.transaction.get_mut(key) {
+                Some(cell) => cell,
+                None => continue,
+            };
+            let (id, references) = match cell.ref_list {
+                Some(ref_list) => ref_list,
+                None => continue,
+            };
+
+            // NOTE: lists with the same id but different content are not shared
+            if let Some(existing) = value.as_deref().and_then(refcount::strip_refcount) {
+                if existing != references {
+                    cell.ref_list = None;
+                    continue;
+                }
+            }
+
+            self.buffer.clear();
+            self.buffer.extend_from_slice(cell.data);
+            if self.checksums {
+                self.buffer
+                    .truncate(self.buffer.len() - CELL_CHECKSUM_TRAILER_LEN);
+            }
+
+            // NOTE: child hashes are followed by the tree counters
+            let refs_end = self.buffer.len() - 16;
+            let refs_start = refs_end - references.len();
+            self.buffer[refs_start - 1] |= CELL_REF_LIST_FLAG;
+            self.buffer.splice(refs_start..refs_end, id.to_le_bytes());
+
+            if self.checksums {
+                StorageCell::append_checksum(&mut self.buffer);
+            }
+            cell.data = self.alloc.alloc_slice_copy(self.buffer.as_slice());
+        }
+
+        Ok(())
+    }
+
+    fn finalize(
+        mut self,
+        batch: &mut rocksdb::WriteBatch,
+        mut staging: Option<(&StagedCells, &mut Vec<[u8; 32]>)>,
+    ) -> Result<(usize, StoredCells), CellStorageError> {
+        self.resolve_ref_lists()?;
+
+        let mut stored = Vec::new();
+
+        let ref_lists_cf = &self.ref_lists.cf();
+
+        let total = self.transaction.len();
+        for (key, cell) in self.transaction {
+            let CellWithRefs {
+                rc,
+                data,
+                ref_list,
+                is_new,
+            } = cell;
+
+            if let Some((staging, staged)) = &mut staging {
+                staging.insert(key, data);
+                staged.push(key);
+            }
+
+            self.buffer.clear();
+            refcount::add_positive_refount(rc, data, &mut self.buffer);
+            batch.merge_cf(self.cells_cf, key.as_slice(), &self.buffer);
+
+            if let Some((id, references)) = ref_list {
+                self.buffer.clear();
+                refcount::add_positive_refount(rc, references, &mut self.buffer);
+                batch.merge_cf(ref_lists_cf, id.to_be_bytes(), &self.buffer);
+            }
+
+            // NOTE: the filter is updated before the batch is written,
+            // so the stored cell is never reported as absent
+            if let (Some(cells_filter), true) = (self.cells_filter, is_new) {
+                cells_filter.insert(&key);
+            }
+
+            if self.on_cell_stored.is_some() && is_new {
+                stored.push((key, data.to_vec()));
+            }
+        }
+
+        let stored = StoredCells {
+            callback: self.on_cell_stored,
+            cells: stored,
+        };
+        Ok((total, stored))
+    }
+}
+
+/// Cache lookup result of [`CellStorage::load_cell_traced`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CacheOutcome {
+    /// The cell was found in the cache
+    Hit,
+    /// The cache entry existed, but the cell was already dropped
+    WeakExpired,
+    /// The cell was not in the cache
+    Miss,
+}
+
+/// Receives the hash and the serialized value (without refcount) of the new cell
+pub type CellStoredCallback = Arc<dyn Fn(&UInt256, &[u8]) + Send + Sync>;
+
+/// Cells format without reference lists
+pub const CELLS_FORMAT_INLINE: u8 = 0;
+/// Cells format with optional reference lists (see [`CellStorageOptions::compact_references`])
+pub const CELLS_FORMAT_COMPACT: u8 = 1;
+
+const CELLS_FORMAT_VERSION: &[u8] = b"cells_format_version";
+/// Set once masterchain cells are stored in the separate column
+const CELLS_SPLIT_COLUMNS: &[u8] = b"cells_split_columns";
+
+/// Set in the references count byte of cells which store
+/// a reference list id instead of the child hashes
+/// Number of scanned cells between the checks of the cell storage liveness
+const CELLS_FILTER_CHECK_INTERVAL: usize = 100_000;
+
+const CELL_REF_LIST_FLAG: u8 = 0x80;
+/// Cells with fewer references are always stored inline
+const MIN_REF_LIST_LEN: usize = 2;
+
+fn compute_ref_list_id(references: &[u8]) -> u64 {
+    use sha2::Digest;
+
+    let hash = sha2::Sha256::digest(references);
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
+
+/// Returns `false` if the id is already used by another reference list
+/// Reads child hashes from the stored reference list (with the refcount prefix)
+fn parse_ref_list(value: &[u8], target: &mut Vec<[u8; 32]>) -> Result<(), CellStorageError> {
+    let list = refcount::strip_refcount(value).ok_or(CellStorageError::CellNotFound)?;
+    if list.is_empty() || list.len() % 32 != 0 {
+        return Err(CellStorageError::InvalidCell);
+    }
+
+    target.extend(list.chunks_exact(32).map(|hash| {
+        let mut result = [0; 32];
+        result.copy_from_slice(hash);
+        result
+    }));
+    Ok(())
+}
+
+/// Checks the persisted cells format version and marks the db
+/// before the first compact cell is stored. Returns the resulting version
+fn prepare_cells_format(db: &Db, compact_references: bool) -> Result<u8> {
+    let version = match db.node_states.get(CELLS_FORMAT_VERSION)? {
+        Some(value) => match value.as_ref() {
+            [version] => *version,
+            _ => return Err(CellStorageError::UnsupportedFormatVersion { version: None }.into()),
+        },
+        None => CELLS_FORMAT_INLINE,
+    };
+
+    if version > CELLS_FORMAT_COMPACT {
+        return Err(CellStorageError::UnsupportedFormatVersion {
+            version: Some(version),
+        }
+        .into());
+    }
+
+    if compact_references && version < CELLS_FORMAT_COMPACT {
+        db.node_states
+            .insert(CELLS_FORMAT_VERSION, [CELLS_FORMAT_COMPACT])?;
+        return Ok(CELLS_FORMAT_COMPACT);
+    }
+
+    Ok(version)
+}
+
+/// Marks the db when masterchain cells are stored in the separate column,
+/// since their states are not readable once the column is disabled
+fn prepare_split_columns(db: &Db, split_columns: bool) -> Result<()> {
+    let is_marked = db.node_states.get(CELLS_SPLIT_COLUMNS)?.is_some();
+    match (is_marked, split_columns) {
+        (false, true) => db.node_states.insert(CELLS_SPLIT_COLUMNS, [1])?,
+        (true, false) => {
+            let mut iter = db.mc_cells.raw_iterator();
+            iter.seek_to_first();
+            if iter.valid() {
+                return Err(CellStorageError::SplitColumnsRequired.into());
+            }
+            iter.status()?;
+
+            // Nothing was stored in the separate column yet
+            db.node_states.remove(CELLS_SPLIT_COLUMNS)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Result of the db read shared by concurrent loads of the same cell.
+///
+/// NOTE: if the read fails, one of the waiting loads retries it
+type InFlightLoad = once_cell::sync::OnceCell<Arc<StorageCell>>;
+const MAX_CACHE_STATS_WINDOW_SEC: u32 = 300;
+
+/// Limits the number of concurrent uncached reads
+struct ReadLimiter {
+    semaphore: Arc<tokio::sync::Semaphore>,
+}
+
+impl ReadLimiter {
+    fn new(permits: usize) -> Self {
+        Self {
+            semaphore: Arc::new(tokio::sync::Semaphore::new(permits.max(1))),
+        }
+    }
+
+    /// Waits for a permit without blocking the thread
+    async fn acquire_owned(&self) -> tokio::sync::OwnedSemaphorePermit {
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("read limiter is never closed")
+    }
+
+    /// Waits for a permit on the current thread.
+    ///
+    /// NOTE: the worker thread of the multi-threaded runtime is handed over
+    /// to other tasks while waiting
+    fn acquire(&self) -> tokio::sync::SemaphorePermit<'_> {
+        if let Ok(permit) = self.semaphore.try_acquire() {
+            return permit;
+        }
+
+        let acquire = self.semaphore.acquire();
+        let permit = match tokio::runtime::Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(|| handle.block_on(acquire))
+            }
+            // NOTE: permits are never held across awaits, so they are
+            // released even if the current runtime is blocked
+            _ => park_on(acquire),
+        };
+        permit.expect("read limiter is never closed")
+    }
+}
+
+/// Polls the future on the current thread, parking it while the future is pending
+fn park_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::future::Future;
+
+    struct ThreadWaker(std::thread::Thread);
+
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    tokio::pin!(future);
+    let waker = std::task::Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = std::task::Context::from_waker(&waker);
+    loop {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::park();
+    }
+}
+
+/// Accumulated cells removal (see [`CellStorage::begin_removal`])
+pub struct CellRemovalSession<'a> {
+    cell_storage: &'a CellStorage,
+    transaction: FastDashMap<[u8; 32], RemovedCell>,
+}
+
+impl CellRemovalSession<'_> {
+    /// Decrements the reference count of the root and all cells which become unreachable
+    pub fn remove_cell(&self, hash: &UInt256) -> Result<(), CellStorageError> {
+        let mut buffer = Vec::with_capacity(4);
+
+        let mut stack = Vec::with_capacity(16);
+        stack.push(*hash.as_slice());
+
+        // While some cells left
+        while let Some(cell_id) = stack.pop() {
+            // NOTE: map guard must be released before the `entry` call
+            let existing = self
+                .transaction
+                .get_mut(&cell_id)
+                .map(|mut cell| cell.remove());
+
+            let refs = match existing {
+                Some(refs) => refs?,
+                None => {
+                    // NOTE: read outside the map lock, the cell could be inserted concurrently
+                    let (rc, ref_list) = self.cell_storage.read_cell_refs(
+                        CellColumn::Shared,
+                        &cell_id,
+                        &mut buffer,
+                    )?;
+                    self.transaction
+                        .entry(cell_id)
+                        .or_insert_with(|| RemovedCell {
+                            rc,
+                            removes: 0,
+                            refs: buffer.as_slice().into(),
+                            ref_list,
+                        })
+                        .remove()?
+                }
+            };
+
+            if let Some(refs) = refs {
+                // Add all children
+                stack.extend_from_slice(&refs);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes accumulated removes into the batch.
+    ///
+    /// Returns the number of affected cells
+    pub fn commit(self, batch: &mut rocksdb::WriteBatch) -> usize {
+        let cells_cf = &self.cell_storage.db.cells.cf();
+        let ref_lists_cf = &self.cell_storage.db.cell_ref_lists.cf();
+
+        let total = self.transaction.len();
+        for (
+            key,
+            RemovedCell {
+                removes, ref_list, ..
+            },
+        ) in self.transaction
+        {
+            batch.merge_cf(cells_cf, key, refcount::encode_negative_refcount(removes));
+            if let Some(id) = ref_list {
+                batch.merge_cf(
+                    ref_lists_cf,
+                    id.to_be_bytes(),
+                    refcount::encode_negative_refcount(removes),
+                );
+            }
+        }
+        total
+    }
+}
+
+struct RemovedCell {
+    rc: i64,
+    removes: u32,
+    refs: Box<[[u8; 32]]>,
+    ref_list: Option<u64>,
+}
+
+impl RemovedCell {
+    /// Returns children if the last reference was removed
+    fn remove(&mut self) -> Result<Option<Box<[[u8; 32]]>>, CellStorageError> {
+        self.removes += 1;
+        match (self.removes as i64).cmp(&self.rc) {
+            std::cmp::Ordering::Less => Ok(None),
+            std::cmp::Ordering::Equal => Ok(Some(self.refs.clone())),
+            std::cmp::Ordering::Greater => Err(CellStorageError::CounterMismatch),
+        }
+    }
+}
+
+/// New cells of the uncommitted batch (see [`CellStorage::store_cell_deferred`])
+#[must_use]
+pub struct PendingCells {
+    cell_storage: Arc<CellStorage>,
+    column: CellColumn,
+    cells: Vec<ton_types::Cell>,
+    stored: StoredCells,
+}
+
+impl PendingCells {
+    /// Inserts the cells into the cache without reading them back.
+    ///
+    /// NOTE: must only be called after the batch is written to the db.
+    /// With the weak cache, cells stay cached only while the returned
+    /// references are alive
+    pub fn committed(self) -> Vec<Arc<StorageCell>> {
+        self.stored.committed();
+
+        let cell_storage = &self.cell_storage;
+
+        let mut result = Vec::with_capacity(self.cells.len());
+        for cell in self.cells {
+            if !cell_storage.cache_leaf_cells && cell.references_count() == 0 {
+                continue;
+            }
+
+            let stored = match StorageCell::from_cell(cell_storage.clone(), self.column, &cell) {
+                Ok(stored) => Arc::new(stored),
+                Err(_) => continue,
+            };
+            cell_storage.cache_cell(cell.repr_hash(), &stored);
+            result.push(stored);
+        }
+        result
+    }
+}
+
+/// New cells of the uncommitted batch for the [`CellStoredCallback`]
+#[must_use]
+#[derive(Default)]
+pub struct StoredCells {
+    callback: Option<CellStoredCallback>,
+    cells: Vec<([u8; 32], Vec<u8>)>,
+}
+
+impl StoredCells {
+    /// Reports the cells to the callback.
+    ///
+    /// NOTE: must only be called after the batch is written to the db
+    pub fn committed(self) {
+        if let Some(callback) = self.callback {
+            for (key, data) in self.cells {
+                callback(&UInt256::from(key), &data);
+            }
+        }
+    }
+
+    fn extend(&mut self, other: Self) {
+        if other.callback.is_some() {
+            self.callback = other.callback;
+        }
+        self.cells.extend(other.cells);
+    }
+}
+
+/// Write batch with staged cells (see [`CellStorage::begin_staging`])
+pub struct CellStagingBatch<'a> {
+    cell_storage: &'a CellStorage,
+    batch: rocksdb::WriteBatch,
+    staged: Vec<[u8; 32]>,
+    stored: StoredCells,
+}
+
+impl CellStagingBatch<'_> {
+    /// Same as [`CellStorage::store_cell`], but stored cells are immediately visible
+    pub fn store_cell(&mut self, root: ton_types::Cell) -> Result<usize, CellStorageError> {
+        let (count, stored) = self.cell_storage.store_cell_impl(
+            CellColumn::Shared,
+            &mut self.batch,
+            root,
+            1,
+            Some(&mut self.staged),
+            None,
+            false,
+        )?;
+        self.stored.extend(stored);
+        Ok(count)
+    }
+
+    /// Underlying batch for additional operations
+    pub fn batch_mut(&mut self) -> &mut rocksdb::WriteBatch {
+        &mut self.batch
+    }
+
+    /// Writes the batch to the db and removes staged cells
+    pub fn commit(mut self) -> Result<(), CellStorageError> {
+        let batch = std::mem::take(&mut self.batch);
+        self.cell_storage
+            .db
+            .raw()
+            .write(batch)
+            .map_err(CellStorageError::Internal)?;
+        std::mem::take(&mut self.stored).committed();
+        Ok(())
+    }
+}
+
+impl Drop for CellStagingBatch<'_> {
+    fn drop(&mut self) {
+        let staging = &self.cell_storage.staging;
+        for key in self.staged.drain(..) {
+            staging.release(&key);
+        }
+    }
+}
+
+/// Fills the cells filter in background. The thread stops if the storage is dropped
+fn spawn_fill_cells_filter(db: Arc<Db>, cells_filter: Arc<CellsFilter>) -> Result<()> {
+    std::thread::Builder::new()
+        .name("cells-filter".to_owned())
+        .spawn(move || {
+            if let Err(e) = fill_cells_filter(&db, &cells_filter) {
+                // NOTE: the filter is never used in this case
+                tracing::error!("failed to fill cells filter: {e:?}");
+            }
+        })?;
+    Ok(())
+}
+
+/// Inserts hashes of all stored cells from both columns into the filter
+/// and marks it as ready
+fn fill_cells_filter(db: &Db, cells_filter: &Arc<CellsFilter>) -> Result<()> {
+    let started_at = std::time::Instant::now();
+
+    for cells in [CellsTable::new(&db.cells), CellsTable::new(&db.mc_cells)] {
+        let mut read_options = rocksdb::ReadOptions::default();
+        read_options.fill_cache(false);
+
+        let mut iter = cells.raw.raw_iterator_cf_opt(&cells.cf, read_options);
+        iter.seek_to_first();
+        let mut scanned = 0usize;
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            if let (Ok(key), true) = (key.try_into(), refcount::has_value(value)) {
+                cells_filter.insert(key);
+            }
+
+            // Stop if the cell storage was dropped
+            scanned += 1;
+            if scanned % CELLS_FILTER_CHECK_INTERVAL == 0 && Arc::strong_count(cells_filter) == 1 {
+                return Ok(());
+            }
+
+            iter.next();
+        }
+        iter.status()?;
+    }
+
+    cells_filter.set_ready();
+
+    let stats = cells_filter.stats();
+    tracing::info!(
+        elapsed_ms = started_at.elapsed().as_millis(),
+        inserted = stats.inserted,
+        memory_usage = stats.memory_usage,
+        false_positive_rate = stats.false_positive_rate,
+        "filled cells filter"
+    );
+
+    Ok(())
+}
+
+/// Recomputes the representation hash from the cell data and its children
+fn compute_repr_hash(cell: &Arc<StorageCell>) -> Result<UInt256, CellStorageError> {
+    ton_types::BuilderData::from_cell(&ton_types::Cell::with_cell_impl_arc(cell.clone()))
+        .and_then(|builder| builder.into_cell())
+        .map(|cell| cell.repr_hash())
+        .map_err(|_| CellStorageError::InvalidCell)
+}
+
+fn log_invalid_cell(hash: &UInt256, value: &[u8], error: &anyhow::Error) {
+    const PREFIX_LEN: usize = 32;
+
+    tracing::error!(
+        hash = %hash.to_hex_string(),
+        value_len = value.len(),
+        value_prefix = %hex::encode(&value[..std::cmp::min(value.len(), PREFIX_LEN)]),
+        "failed to deserialize cell: {error:?}"
+    );
+}
+
+/// Context of the child load which failed because the child is not stored
+/// (see [`CellStorage::store_cell_checked`])
+#[derive(Debug)]
+struct MissingChildContext {
+    parent: UInt256,
+    child: UInt256,
+}
+
+impl std::fmt::Display for MissingChildContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cell {} references missing child {}",
+            self.parent, self.child
+        )
+    }
+}
+
+/// Returns the checksum verification error, which must not fall back to other layouts
+fn checksum_error(error: &anyhow::Error) -> Option<CellStorageError> {
+    match error.downcast_ref::<CellStorageError>() {
+        Some(CellStorageError::ChecksumMismatch) => Some(CellStorageError::ChecksumMismatch),
+        Some(CellStorageError::UnknownChecksumVersion { version }) => {
+            Some(CellStorageError::UnknownChecksumVersion { version: *version })
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CellStorageOptions {
+    /// If specified, uncached loads wait until the number of concurrent
+    /// db reads is below the limit
+    pub max_concurrent_reads: Option<usize>,
+    /// Whether to insert cells without references into the cache
+    pub cache_leaf_cells: bool,
+    /// Whether to skip the cache insert if the cache shard is locked by another thread.
+    /// The loaded cell is returned uncached and is cached by its next load,
+    /// so parallel loads don't wait for each other on hot shards.
+    ///
+    /// See `benches/cells_cache.rs` for the throughput and hit ratio comparison
+    pub skip_contended_cache_inserts: bool,
+    /// Whether to append a checksum to newly stored cells
+    pub checksums: bool,
+    /// Whether to store reference lists of new cells with at least
+    /// two references in a separate column, deduplicated by their content.
+    ///
+    /// Compact cells store a reference list id instead of the child hashes
+    /// and are marked by the highest bit of the references count.
+    /// Both layouts are always readable, so the option can be toggled
+    /// at any time, existing cells are not rewritten.
+    ///
+    /// The db is marked with [`CELLS_FORMAT_COMPACT`] when the option is enabled,
+    /// and storages which don't know the format refuse to open it.
+    ///
+    /// NOTE: databases with compact cells can't be read by earlier versions
+    pub compact_references: bool,
+    /// Whether to store trees of the masterchain states in a separate column
+    /// (see [`CellColumn`])
+    pub split_masterchain_cells: bool,
+    pub cache_strategy: CellCacheStrategy,
+    /// In-memory bloom filter over the stored cell hashes, which is checked
+    /// before reading the cell from the db. Disabled if `None`.
+    ///
+    /// NOTE: cells written to the cells column bypassing the storage
+    /// must be registered with [`CellStorage::register_stored_cell`]
+    pub cells_filter: Option<CellsFilterOptions>,
+}
+
+impl Default for CellStorageOptions {
+    fn default() -> Self {
+        Self {
+            max_concurrent_reads: None,
+            cache_leaf_cells: true,
+            skip_contended_cache_inserts: true,
+            checksums: false,
+            compact_references: false,
+            split_masterchain_cells: false,
+            cache_strategy: Default::default(),
+            cells_filter: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct StateDiff {
+    /// Cells which are only reachable from the new root
+    pub added: Vec<UInt256>,
+    /// Cells which are only reachable from the old root
+    pub removed: Vec<UInt256>,
+}
+
+/// Cells of all uncommitted staging batches
+#[derive(Default)]
+struct StagedCells {
+    cells: FastDashMap<[u8; 32], StagedCell>,
+    /// Number of staged cells, so that lookups don't lock the map shards while it is empty
+    len: AtomicUsize,
+}
+
+impl StagedCells {
+    fn get(
+        &self,
+        key: &[u8; 32],
+    ) -> Option<dashmap::mapref::one::Ref<'_, [u8; 32], StagedCell, FastHasherState>> {
+        if self.len.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        self.cells.get(key)
+    }
+
+    fn contains(&self, key: &[u8; 32]) -> bool {
+        self.len.load(Ordering::Acquire) > 0 && self.cells.contains_key(key)
+    }
+
+    fn insert(&self, key: [u8; 32], data: &[u8]) {
+        match self.cells.entry(key) {
+            dashmap::mapref::entry::Entry::Occupied(mut entry) => entry.get_mut().batches += 1,
+            dashmap::mapref::entry::Entry::Vacant(entry) => {
+                entry.insert(StagedCell {
+                    data: Box::from(data),
+                    batches: 1,
+                });
+                self.len.fetch_add(1, Ordering::Release);
+            }
+        }
+    }
+
+    /// Removes the cell once all batches which staged it are committed or dropped
+    fn release(&self, key: &[u8; 32]) {
+        if let dashmap::mapref::entry::Entry::Occupied(mut entry) = self.cells.entry(*key) {
+            let cell = entry.get_mut();
+            cell.batches -= 1;
+            if cell.batches == 0 {
+                entry.remove();
+                self.len.fetch_sub(1, Ordering::Release);
+            }
+        }
+    }
+}
+
+struct StagedCell {
+    data: Box<[u8]>,
+    batches: usize,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CellStorageError {
+    #[error("Cell not found in cell db")]
+    CellNotFound,
+    #[error("Invalid cell")]
+    InvalidCell,
+    #[error("Cell counter mismatch")]
+    CounterMismatch,
+    #[error("Failed to serialize BOC")]
+    BocSerializationFailed,
+    #[error("Cell hash mismatch. Expected: {expected}, got: {got}")]
+    HashMismatch { expected: UInt256, got: UInt256 },
+    #[error("Cell checksum mismatch")]
+    ChecksumMismatch,
+    #[error("Unknown cell checksum version: {version}")]
+    UnknownChecksumVersion { version: u8 },
+    #[error("Cell {parent} references missing child {child}")]
+    MissingChild { parent: UInt256, child: UInt256 },
+    #[error("Internal rocksdb error")]
+    Internal(#[source] rocksdb::Error),
+    #[error("Blocking task failed")]
+    BlockingTaskFailed,
+    #[error("Cell storage was dropped")]
+    StorageDropped,
+    #[error("Unsupported cells format version: {version:?}")]
+    UnsupportedFormatVersion { version: Option<u8> },
+    #[error("Masterchain cells are stored in the separate column, but it is disabled")]
+    SplitColumnsRequired,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RebuildReport {
+    /// Number of distinct cells reachable from the roots in each column
+    pub reachable_cells: usize,
+    /// Reachable cells which are not stored
+    pub missing_cells: usize,
+    /// Cells with the rewritten refcount
+    pub updated_cells: usize,
+    /// Stored cells which are not reachable from the roots
+    pub unreachable_cells: usize,
+    /// Reference lists with the rewritten refcount
+    pub updated_ref_lists: usize,
+}
+
+/// See [`CellStorage::iter_subtree_postorder`]
+struct SubtreePostorderIter<'a> {
+    cell_storage: &'a CellStorage,
+    visited: FastHashSet<[u8; 32]>,
+    stack: Vec<PostorderFrame>,
+    root: Option<[u8; 32]>,
+}
+
+struct PostorderFrame {
+    hash: [u8; 32],
+    data: Vec<u8>,
+    children: Vec<[u8; 32]>,
+    next_child: usize,
+}
+
+impl SubtreePostorderIter<'_> {
+    fn push(&mut self, hash: [u8; 32]) -> Result<(), CellStorageError> {
+        let (data, children) = self.cell_storage.read_canonical_cell(&hash)?;
+        self.stack.push(PostorderFrame {
+            hash,
+            data,
+            children,
+            next_child: 0,
+        });
+        Ok(())
+    }
+}
+
+impl Iterator for SubtreePostorderIter<'_> {
+    type Item = Result<(UInt256, Vec<u8>), CellStorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            self.visited.insert(root);
+            if let Err(e) = self.push(root) {
+                return Some(Err(e));
+            }
+        }
+
+        loop {
+            let frame = self.stack.last_mut()?;
+            match frame.children.get(frame.next_child) {
+                Some(child) => {
+                    let child = *child;
+                    frame.next_child += 1;
+                    if self.visited.insert(child) {
+                        if let Err(e) = self.push(child) {
+                            // NOTE: iteration stops after the first error
+                            self.stack.clear();
+                            return Some(Err(e));
+                        }
+                    }
+                }
+                None => {
+                    let frame = self.stack.pop()?;
+                    return Some(Ok((UInt256::from(frame.hash), frame.data)));
+                }
+            }
+        }
+    }
+}
+
+/// Stored cells grouped by their layout (see [`CellStorage::cell_format_census`])
+#[derive(Debug, Clone, Default)]
+pub struct FormatCensus {
+    /// Number of checked values
+    pub total: u64,
+    /// Cells with the tree counters
+    pub with_counters: u64,
+    /// Cells stored before the tree counters were added
+    pub without_counters: u64,
+    /// Cells stored without a refcount
+    pub legacy: u64,
+    /// Cells with a reference list id instead of the child hashes
+    pub compact: u64,
+    /// Cells with a checksum trailer by the trailer version
+    pub checksum_versions: BTreeMap<u8, u64>,
+    /// Values without data (fully dereferenced cells which were not compacted yet)
+    pub removed: u64,
+    /// Values which don't match any known layout
+    pub unknown: u64,
+}
+
+impl FormatCensus {
+    fn record(&mut self, value: &[u8]) {
+        self.total += 1;
+
+        let (rc, data) = refcount::decode_value_with_rc(value);
+        if let Some(data) = data {
+            if self.record_data(data) {
+                return;
+            }
+        }
+
+        // NOTE: legacy values are checked the same way as on load
+        if StorageCell::is_legacy_layout(value) {
+            self.legacy += 1;
+        } else if rc <= 0 {
+            self.removed += 1;
+        } else {
+            self.unknown += 1;
+        }
+    }
+
+    /// Returns `false` if the value has an unknown layout
+    fn record_data(&mut self, mut data: &[u8]) -> bool {
+        let Some(references_count) = StorageCell::read_references_count(&mut data) else {
+            return false;
+        };
+
+        let is_compact = references_count & CELL_REF_LIST_FLAG != 0;
+        let references_len = if is_compact {
+            8
+        } else {
+            references_count as usize * 32
+        };
+        let Some(trailer) = data.get(references_len..) else {
+            return false;
+        };
+
+        let (has_counters, checksum_version) = match trailer {
+            [] => (false, None),
+            _ if trailer.len() == 16 => (true, None),
+            [.., version] if trailer.len() == CELL_CHECKSUM_TRAILER_LEN => (false, Some(*version)),
+            [.., version] if trailer.len() == 16 + CELL_CHECKSUM_TRAILER_LEN => {
+                (true, Some(*version))
+            }
+            _ => return false,
+        };
+
+        if has_counters {
+            self.with_counters += 1;
+        } else {
+            self.without_counters += 1;
+        }
+        if is_compact {
+            self.compact += 1;
+        }
+        if let Some(version) = checksum_version {
+            *self.checksum_versions.entry(version).or_default() += 1;
+        }
+        true
+    }
+}
+
+pub struct StorageCell {
+    _c: countme::Count<Self>,
+    /// NOTE: weak, so that cells resident in the cache don't keep the storage alive
+    cell_storage: Weak<CellStorage>,
+    /// Column which the references are loaded from
+    column: CellColumn,
+    /// Snapshot which the references are loaded through
+    snapshot: Option<CellsSnapshot>,
+    cell_data: ton_types::CellData,
+    references: RwLock<SmallVec<[StorageCellReference; 4]>>,
+    /// NOTE: both counters are zero for cells stored without them
+    /// until [`StorageCell::compute_tree_size`] is called
+    tree_bits_count: AtomicU64,
+    tree_cell_count: AtomicU64,
+}
+
+impl StorageCell {
+    pub fn repr_hash(&self) -> UInt256 {
+        self.hash(ton_types::MAX_LEVEL)
+    }
+
+    pub fn deserialize(boc_db: Arc<CellStorage>, column: CellColumn, data: &[u8]) -> Result<Self> {
+        Self::deserialize_in(boc_db, column, data, None)
+    }
+
+    fn deserialize_in(
+        boc_db: Arc<CellStorage>,
+        column: CellColumn,
+        mut data: &[u8],
+        snapshot: Option<&CellsSnapshot>,
+    ) -> Result<Self> {
+        let full_data = data;
+
+        // deserialize cell
+        let cell_data = ton_types::CellData::deserialize(&mut data)?;
+        let references_count = data.read_byte()?;
+
+        let references = if references_count & CELL_REF_LIST_FLAG != 0 {
+            let mut list = Vec::new();
+            boc_db.load_ref_list(
+                data.read_le_u64()?,
+                &mut list,
+                snapshot.map(|snapshot| &*snapshot.inner),
+            )?;
+            if list.len() != (references_count & !CELL_REF_LIST_FLAG) as usize {
+                return Err(CellStorageError::InvalidCell.into());
+            }
+
+            list.into_iter()
+                .map(|hash| StorageCellReference::Unloaded(UInt256::from(hash)))
+                .collect()
+        } else {
+            let mut references = SmallVec::with_capacity(references_count as usize);
+            for _ in 0..references_count {
+                let hash = UInt256::from(data.read_u256()?);
+                references.push(StorageCellReference::Unloaded(hash));
+            }
+            references
+        };
+
+        let (tree_bits_count, tree_cell_count) = match data.read_le_u64() {
+            Ok(tree_bits_count) => match data.read_le_u64() {
+                Ok(tree_cell_count) => (tree_bits_count, tree_cell_count),
+                Err(_) => (0, 0),
+            },
+            Err(_) => (0, 0),
+        };
+
+        // NOTE: values without a checksum trailer are accepted as is
+        match data {
+            [] => {}
+            [checksum @ .., version] if data.len() == CELL_CHECKSUM_TRAILER_LEN => {
+                // Corrupted version must not disable the verification
+                if *version != CELL_CHECKSUM_VERSION {
+                    return Err(
+                        CellStorageError::UnknownChecksumVersion { version: *version }.into(),
+                    );
+                }
+                let body = &full_data[..full_data.len() - CELL_CHECKSUM_TRAILER_LEN];
+                if CELL_CRC.checksum(body).to_le_bytes() != checksum {
+                    return Err(CellStorageError::ChecksumMismatch.into());
+                }
+            }
+            _ => return Err(CellStorageError::InvalidCell.into()),
+        }
+
+        Ok(Self {
+            _c: Default::default(),
+            cell_storage: Arc::downgrade(&boc_db),
+            column,
+            snapshot: snapshot.cloned(),
+            cell_data,
+            references: RwLock::new(references),
+            tree_bits_count: AtomicU64::new(tree_bits_count),
+            tree_cell_count: AtomicU64::new(tree_cell_count),
+        })
+    }
+
+    /// Creates a cell with unloaded references from the in-memory cell
+    /// of the same content, without serializing it
+    fn from_cell(
+        boc_db: Arc<CellStorage>,
+        column: CellColumn,
+        cell: &ton_types::Cell,
+    ) -> Result<Self> {
+        let mut references = SmallVec::with_capacity(cell.references_count());
+        for i in 0..cell.references_count() {
+            let hash = cell.reference(i)?.repr_hash();
+            references.push(StorageCellReference::Unloaded(hash));
+        }
+
+        Ok(Self {
+            _c: Default::default(),
+            cell_storage: Arc::downgrade(&boc_db),
+            column,
+            snapshot: None,
+            cell_data: cell.cell_data().clone(),
+            references: RwLock::new(references),
+            tree_bits_count: AtomicU64::new(cell.tree_bits_count()),
+            tree_cell_count: AtomicU64::new(cell.tree_cell_count()),
+        })
+    }
+
+    /// Decodes the value without a refcount prefix. Unlike [`StorageCell::deserialize`],
+    /// requires both tree counters and no trailing bytes to reduce false positives
+    fn deserialize_legacy(
+        boc_db: Arc<CellStorage>,
+        column: CellColumn,
+        mut data: &[u8],
+        snapshot: Option<&CellsSnapshot>,
+    ) -> Option<Self> {
+        let cell_data = ton_types::CellData::deserialize(&mut data).ok()?;
+        let references_count = data.read_byte().ok()?;
+        let mut references = SmallVec::with_capacity(references_count as usize);
+
+        for _ in 0..references_count {
+            let hash = UInt256::from(data.read_u256().ok()?);
+            references.push(StorageCellReference::Unloaded(hash));
+        }
+
+        let tree_bits_count = data.read_le_u64().ok()?;
+        let tree_cell_count = data.read_le_u64().ok()?;
+        if !data.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            _c: Default::default(),
+            cell_storage: Arc::downgrade(&boc_db),
+            column,
+            snapshot: snapshot.cloned(),
+            cell_data,
+            references: RwLock::new(references),
+            tree_bits_count: AtomicU64::new(tree_bits_count),
+            tree_cell_count: AtomicU64::new(tree_cell_count),
+        })
+    }
+
+    /// Checks the value the same way as [`StorageCell::deserialize_legacy`]
+    fn is_legacy_layout(mut data: &[u8]) -> bool {
+        let Some(references_count) = Self::read_references_count(&mut data) else {
+            return false;
+        };
+        data.len() == references_count as usize * 32 + 16
+    }
+
+    /// Reads child hashes of the inline cell.
+    ///
+    /// NOTE: returns `false` for compact cells (see [`CellStorageOptions::compact_references`])
+    pub fn deserialize_references(mut data: &[u8], target: &mut Vec<[u8; 32]>) -> bool {
+        let reader = &mut data;
+
+        let Some(references_count) = Self::read_references_count(reader) else {
+            return false;
+        };
+        if references_count & CELL_REF_LIST_FLAG != 0 {
+            return false;
+        }
+
+        for _ in 0..references_count {
+            let Ok(hash) = reader.read_u256() else {
+                return false;
+            };
+            target.push(hash);
+        }
+
+        true
+    }
+
+    /// Returns the reference list id of the compact cell
+    fn ref_list_id(mut data: &[u8]) -> Option<u64> {
+        let reader = &mut data;
+        let references_count = Self::read_references_count(reader)?;
+        if references_count & CELL_REF_LIST_FLAG == 0 {
+            return None;
+        }
+        reader.read_le_u64().ok()
+    }
+
+    /// Skips the cell data and reads the number of references
+    fn read_references_count(reader: &mut &[u8]) -> Option<u8> {
+        ton_types::CellData::deserialize(reader).ok()?;
+        reader.read_byte().ok()
+    }
+
+    pub fn serialize_to(cell: &dyn CellImpl, target: &mut Vec<u8>) -> Result<()> {
+        target.clear();
+
+        // serialize cell
+        let references_count = cell.references_count() as u8;
+        cell.cell_data().serialize(target)?;
+        target.push(references_count);
+
+        for i in 0..references_count {
+            target.extend_from_slice(cell.reference(i as usize)?.repr_hash().as_slice());
+        }
+
+        target.extend_from_slice(&cell.tree_bits_count().to_le_bytes());
+        target.extend_from_slice(&cell.tree_cell_count().to_le_bytes());
+
+        Ok(())
+    }
+
+    /// Same as [`StorageCell::serialize_to`], but writes the cell directly
+    /// into the arena allocation of the exact size
+    pub fn serialize_into_bump<'a>(cell: &dyn CellImpl, alloc: &'a Bump) -> Result<&'a [u8]> {
+        // Compute cell data length without an intermediate buffer
+        let mut counter = ByteCounter::default();
+        cell.cell_data().serialize(&mut counter)?;
+        let data_len = counter.0;
+
+        let references_count = cell.references_count() as u8;
+        let len = data_len + 1 + references_count as usize * 32 + 16;
+
+        let target = alloc.alloc_slice_fill_copy(len, 0u8);
+        let (data, rest) = target.split_at_mut(data_len);
+        cell.cell_data().serialize(&mut &mut *data)?;
+
+        let (references, counters) = rest.split_at_mut(1 + references_count as usize * 32);
+        references[0] = references_count;
+        for (i, chunk) in references[1..].chunks_exact_mut(32).enumerate() {
+            chunk.copy_from_slice(cell.reference(i)?.repr_hash().as_slice());
+        }
+
+        counters[..8].copy_from_slice(&cell.tree_bits_count().to_le_bytes());
+        counters[8..].copy_from_slice(&cell.tree_cell_count().to_le_bytes());
+
+        Ok(target)
+    }
+
+    /// Appends the checksum trailer to the serialized cell.
+    ///
+    /// Trailer structure:
+    /// - `[CRC32C of the serialized cell, 4 bytes LE][version, 1 byte]`
+    pub fn append_checksum(target: &mut Vec<u8>) {
+        let checksum = CELL_CRC.checksum(target);
+        target.extend_from_slice(&checksum.to_le_bytes());
+        target.push(CELL_CHECKSUM_VERSION);
+    }
+
+    /// Returns the hash of the child cell without loading it
+    pub fn reference_hash(&self, index: usize) -> Option<UInt256> {
+        match self.references.read().get(index)? {
+            StorageCellReference::Unloaded(hash) => Some(*hash),
+            StorageCellReference::Loaded(cell) => Some(cell.repr_hash()),
+        }
+    }
+
+    /// Returns hashes of all child cells without loading them
+    pub fn reference_hashes(&self) -> SmallVec<[UInt256; 4]> {
+        self.references
+            .read()
+            .iter()
+            .map(|reference| match reference {
+                StorageCellReference::Unloaded(hash) => *hash,
+                StorageCellReference::Loaded(cell) => cell.repr_hash(),
+            })
+            .collect()
+    }
+
+    pub fn reference(&self, index: usize) -> Result<Arc<StorageCell>> {
+        let hash = match &self.references.read().get(index) {
+            Some(StorageCellReference::Unloaded(hash)) => *hash,
             Some(StorageCellReference::Loaded(cell)) => return Ok(cell.clone()),
             None => return Err(StorageCellError::AccessingInvalidReference.into()),
         };
 
-        let storage_cell = self.cell_storage.load_cell(hash)?;
-        self.references.write()[index] = StorageCellReference::Loaded(storage_cell.clone());
+        let storage_cell = match &self.snapshot {
+            Some(snapshot) => snapshot.load_cell_in(self.column, hash),
+            None => match self.cell_storage.upgrade() {
+                Some(cell_storage) => cell_storage.load_cell_in(self.column, hash),
+                None => Err(CellStorageError::StorageDropped),
+            },
+        }
+        .map_err(|e| match e {
+            // NOTE: the error is still `CellNotFound`, the context is used by the checked store
+            CellStorageError::CellNotFound => anyhow::Error::from(e).context(MissingChildContext {
+                parent: self.repr_hash(),
+                child: hash,
+            }),
+            e => e.into(),
+        })?;
+        self.references.write()[index] = StorageCellReference::Loaded(storage_cell.clone());
+
+        Ok(storage_cell)
+    }
+
+    /// Returns the total number of bits and cells in the subtree.
+    ///
+    /// Cells stored without the tree counters have them zeroed, so their
+    /// subtree is walked once and the result is cached in each visited cell.
+    ///
+    /// NOTE: the walked subtree stays loaded while this cell is alive
+    pub fn compute_tree_size(&self) -> Result<(u64, u64)> {
+        // NOTE: the cell count includes the cell itself, so it is never zero
+        let tree_cell_count = self.tree_cell_count.load(Ordering::Acquire);
+        if tree_cell_count != 0 {
+            return Ok((
+                self.tree_bits_count.load(Ordering::Acquire),
+                tree_cell_count,
+            ));
+        }
+
+        let mut tree_bits_count = self.cell_data.bit_length() as u64;
+        let mut tree_cell_count = 1;
+        for i in 0..self.references_count() {
+            let (bits, cells) = self.reference(i)?.compute_tree_size()?;
+            tree_bits_count += bits;
+            tree_cell_count += cells;
+        }
+
+        // NOTE: bits are stored first, so they are visible with a non-zero cell count
+        self.tree_bits_count
+            .store(tree_bits_count, Ordering::Release);
+        self.tree_cell_count
+            .store(tree_cell_count, Ordering::Release);
+
+        Ok((tree_bits_count, tree_cell_count))
+    }
+}
+
+impl CellImpl for StorageCell {
+    fn data(&self) -> &[u8] {
+        self.cell_data.data()
+    }
+
+    fn cell_data(&self) -> &ton_types::CellData {
+        &self.cell_data
+    }
+
+    fn bit_length(&self) -> usize {
+        self.cell_data.bit_length() as usize
+    }
+
+    fn references_count(&self) -> usize {
+        self.references.read().len()
+    }
+
+    fn reference(&self, index: usize) -> Result<ton_types::Cell> {
+        Ok(ton_types::Cell::with_cell_impl_arc(self.reference(index)?))
+    }
+
+    fn cell_type(&self) -> ton_types::CellType {
+        self.cell_data.cell_type()
+    }
+
+    fn level_mask(&self) -> ton_types::LevelMask {
+        self.cell_data.level_mask()
+    }
+
+    fn hash(&self, index: usize) -> UInt256 {
+        self.cell_data.hash(index)
+    }
+
+    fn depth(&self, index: usize) -> u16 {
+        self.cell_data.depth(index)
+    }
+
+    fn store_hashes(&self) -> bool {
+        self.cell_data.store_hashes()
+    }
+
+    fn tree_bits_count(&self) -> u64 {
+        self.tree_bits_count.load(Ordering::Acquire)
+    }
+
+    fn tree_cell_count(&self) -> u64 {
+        self.tree_cell_count.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for StorageCell {
+    fn drop(&mut self) {
+        if let Some(cell_storage) = self.cell_storage.upgrade() {
+            cell_storage.drop_cell(&self.repr_hash())
+        }
+    }
+}
+
+const CELL_CHECKSUM_VERSION: u8 = 1;
+const CELL_CHECKSUM_TRAILER_LEN: usize = 4 + 1;
+
+static CELL_CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
+
+#[derive(Clone)]
+pub enum StorageCellReference {
+    Loaded(Arc<StorageCell>),
+    Unloaded(UInt256),
+}
+
+#[derive(thiserror::Error, Debug)]
+enum StorageCellError {
+    #[error("Accessing invalid cell reference")]
+    AccessingInvalidReference,
+}
+
+/// Writer which only counts the written bytes
+#[derive(Default)]
+struct ByteCounter(usize);
+
+impl std::io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_cell(value: u32, refs: &[ton_types::Cell]) -> ton_types::Cell {
+        let mut builder = ton_types::BuilderData::new();
+        builder.append_u32(value).unwrap();
+        for cell in refs {
+            builder.checked_append_reference(cell.clone()).unwrap();
+        }
+        builder.into_cell().unwrap()
+    }
+
+    fn open_test_db(name: &str) -> Result<(Arc<Db>, std::path::PathBuf)> {
+        let path =
+            std::env::temp_dir().join(format!("ton-indexer-cells-{name}-{}", std::process::id()));
+        let db = Db::open(path.clone(), 64 << 20, Default::default())?;
+        Ok((db, path))
+    }
+
+    fn cell_rc(db: &Db, cell: &ton_types::Cell) -> Result<i64> {
+        Ok(match db.cells.get(cell.repr_hash().as_slice())? {
+            Some(value) => refcount::decode_value_with_rc(&value).0,
+            None => 0,
+        })
+    }
+
+    #[test]
+    fn serialize_into_bump_matches_vec() -> Result<()> {
+        let leaf = make_cell(0, &[]);
+        let root = make_cell(1, &[leaf.clone(), leaf]);
+
+        let alloc = Bump::new();
+        let mut target = Vec::new();
+        for cell in [&root, &root.reference(0)?] {
+            StorageCell::serialize_to(&**cell, &mut target)?;
+            let data = StorageCell::serialize_into_bump(&**cell, &alloc)?;
+            assert_eq!(data, target.as_slice());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_removal_of_overlapping_states() -> Result<()> {
+        let (db, path) = open_test_db("removal")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let shared = make_cell(1, &[leaf.clone()]);
+        let first = make_cell(2, &[shared.clone()]);
+        let second = make_cell(3, &[shared.clone(), leaf.clone()]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, first.clone())?;
+        db.raw().write(batch)?;
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, second.clone())?;
+        db.raw().write(batch)?;
+
+        assert_eq!(cell_rc(&db, &shared)?, 2);
+        assert_eq!(cell_rc(&db, &leaf)?, 2);
+
+        let session = cell_storage.begin_removal();
+        std::thread::scope(|scope| {
+            for root in [&first, &second] {
+                let session = &session;
+                scope.spawn(move || session.remove_cell(&root.repr_hash()).unwrap());
+            }
+        });
+
+        let mut batch = rocksdb::WriteBatch::default();
+        assert_eq!(session.commit(&mut batch), 4);
+        db.raw().write(batch)?;
+
+        for cell in [&first, &second, &shared, &leaf] {
+            assert_eq!(cell_rc(&db, cell)?, 0);
+        }
+
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn diff_states_skips_shared_subtrees() -> Result<()> {
+        let (db, path) = open_test_db("diff")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let shared = make_cell(1, &[make_cell(0, &[])]);
+        let old_leaf = make_cell(2, &[]);
+        let new_leaf = make_cell(3, &[]);
+        let old_mid = make_cell(4, &[old_leaf.clone()]);
+        let new_mid = make_cell(4, &[new_leaf.clone()]);
+        let old_root = make_cell(10, &[shared.clone(), old_mid.clone()]);
+        let new_root = make_cell(11, &[shared, new_mid.clone()]);
+
+        for root in [&old_root, &new_root] {
+            let mut batch = rocksdb::WriteBatch::default();
+            cell_storage.store_cell(&mut batch, root.clone())?;
+            db.raw().write(batch)?;
+        }
+
+        let diff = cell_storage.diff_states(old_root.repr_hash(), new_root.repr_hash())?;
+        let sorted = |mut hashes: Vec<UInt256>| {
+            hashes.sort();
+            hashes
+        };
+        let expected = |cells: [&ton_types::Cell; 3]| {
+            sorted(cells.iter().map(|cell| cell.repr_hash()).collect())
+        };
+
+        assert_eq!(
+            sorted(diff.added),
+            expected([&new_root, &new_mid, &new_leaf])
+        );
+        assert_eq!(
+            sorted(diff.removed),
+            expected([&old_root, &old_mid, &old_leaf])
+        );
+
+        // Same roots
+        let diff = cell_storage.diff_states(old_root.repr_hash(), old_root.repr_hash())?;
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn leaf_cells_are_not_cached() -> Result<()> {
+        let (db, path) = open_test_db("leaf-cells-cache")?;
+
+        let leaf = make_cell(0, &[]);
+        let root = make_cell(1, &[leaf.clone()]);
+
+        for cache_leaf_cells in [true, false] {
+            let cell_storage = CellStorage::new(
+                db.clone(),
+                2,
+                CellStorageOptions {
+                    cache_leaf_cells,
+                    ..Default::default()
+                },
+            )?;
+
+            let mut batch = rocksdb::WriteBatch::default();
+            cell_storage.store_cell(&mut batch, root.clone())?;
+            db.raw().write(batch)?;
+
+            let loaded_root = cell_storage.load_cell(root.repr_hash())?;
+            let loaded_leaf = cell_storage.load_cell(leaf.repr_hash())?;
+
+            // Cells are cached only while they are alive
+            assert!(Arc::ptr_eq(
+                &loaded_root,
+                &cell_storage.load_cell(root.repr_hash())?
+            ));
+            assert_eq!(
+                Arc::ptr_eq(&loaded_leaf, &cell_storage.load_cell(leaf.repr_hash())?),
+                cache_leaf_cells
+            );
+        }
+
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn cells_without_refcount_are_loaded() -> Result<()> {
+        let (db, path) = open_test_db("legacy-cells")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let root = make_cell(1, &[leaf.clone()]);
+
+        // Values written by earlier versions without a refcount
+        let mut value = Vec::new();
+        for cell in [&leaf, &root] {
+            StorageCell::serialize_to(&**cell, &mut value)?;
+            db.cells.insert(cell.repr_hash().as_slice(), &value)?;
+        }
+
+        let loaded = cell_storage.load_cell_uncached(&root.repr_hash())?;
+        assert_eq!(loaded.repr_hash(), root.repr_hash());
+        assert_eq!(loaded.reference(0)?.repr_hash(), leaf.repr_hash());
+
+        // Stored hash is kept, but the data is changed
+        StorageCell::serialize_to(&*leaf, &mut value)?;
+        let data_offset = value
+            .windows(5)
+            .position(|window| window == [0, 0, 0, 0, 0x80])
+            .unwrap();
+        let mut tampered_value = value.clone();
+        tampered_value[data_offset + 3] = 1;
+        db.cells
+            .insert(leaf.repr_hash().as_slice(), &tampered_value)?;
+
+        assert!(matches!(
+            cell_storage.load_cell_uncached(&leaf.repr_hash()),
+            Err(CellStorageError::InvalidCell)
+        ));
+
+        drop(loaded);
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn tree_size_of_cells_without_counters() -> Result<()> {
+        let (db, path) = open_test_db("tree-size")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let mid = make_cell(1, &[leaf.clone()]);
+        let root = make_cell(2, &[mid.clone(), leaf.clone()]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, root.clone())?;
+        db.raw().write(batch)?;
+
+        // Strip tree counters as in the old format
+        for cell in [&leaf, &mid, &root] {
+            let key = cell.repr_hash();
+            let value = db.cells.get(key.as_slice())?.unwrap();
+            let (rc, data) = refcount::decode_value_with_rc(&value);
+            let data = data.unwrap();
+
+            let mut legacy = Vec::new();
+            refcount::encode_value_with_rc(rc, &data[..data.len() - 16], &mut legacy);
+            db.cells.insert(key.as_slice(), legacy)?;
+        }
+
+        let loaded = cell_storage.load_cell(root.repr_hash())?;
+        assert_eq!(loaded.tree_cell_count(), 0);
+
+        let expected = (root.tree_bits_count(), root.tree_cell_count());
+        assert_eq!(expected.1, 4);
+        assert_eq!(loaded.compute_tree_size()?, expected);
+        assert_eq!(cell_storage.subtree_stats(root.repr_hash())?, expected);
+        assert_eq!(loaded.tree_cell_count(), 4);
+
+        drop(loaded);
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn verify_subtree_detects_corrupted_cell() -> Result<()> {
+        let (db, path) = open_test_db("verify")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let root = make_cell(2, &[make_cell(1, &[leaf.clone()])]);
+        let other = make_cell(3, &[]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, root.clone())?;
+        cell_storage.store_cell(&mut batch, other.clone())?;
+        db.raw().write(batch)?;
+
+        assert!(cell_storage.verify_subtree(root.repr_hash())?);
+
+        // Keep the valid leaf in the cache
+        let cached_leaf = cell_storage.load_cell(leaf.repr_hash())?;
+
+        // Replace the leaf with another cell
+        let other_value = db
+            .cells
+            .get(other.repr_hash().as_slice())?
+            .unwrap()
+            .to_vec();
+        db.cells.insert(leaf.repr_hash().as_slice(), other_value)?;
+
+        // Stored cells are verified even if the valid ones are cached
+        assert!(!cell_storage.verify_subtree(root.repr_hash())?);
+
+        drop(cached_leaf);
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn transfer_refcount_between_cells() -> Result<()> {
+        let (db, path) = open_test_db("transfer")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let old = make_cell(0, &[]);
+        let new = make_cell(1, &[]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell_with_rc(&mut batch, old.clone(), 2)?;
+        cell_storage.store_cell(&mut batch, new.clone())?;
+        db.raw().write(batch)?;
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.transfer_refcount(&mut batch, old.repr_hash(), new.repr_hash(), 2)?;
+        db.raw().write(batch)?;
+
+        assert_eq!(cell_rc(&db, &old)?, 0);
+        assert_eq!(cell_rc(&db, &new)?, 3);
+
+        // Target cell must exist
+        let mut batch = rocksdb::WriteBatch::default();
+        assert!(matches!(
+            cell_storage.transfer_refcount(&mut batch, new.repr_hash(), old.repr_hash(), 1),
+            Err(CellStorageError::CellNotFound)
+        ));
+
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn check_reachable_finds_missing_cells() -> Result<()> {
+        let (db, path) = open_test_db("reachable")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let first = make_cell(1, &[leaf.clone()]);
+        let second = make_cell(2, &[leaf.clone(), make_cell(3, &[])]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, first.clone())?;
+        cell_storage.store_cell(&mut batch, second.clone())?;
+        db.raw().write(batch)?;
+
+        let roots = [first.repr_hash(), second.repr_hash()];
+        assert!(cell_storage.check_reachable(&roots)?.is_empty());
+
+        // Collected while still referenced
+        db.cells.remove(leaf.repr_hash().as_slice())?;
+        assert_eq!(cell_storage.check_reachable(&roots)?, [leaf.repr_hash()]);
+
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn checked_store_rejects_missing_children() -> Result<()> {
+        let (db, path) = open_test_db("checked-store")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let parent = make_cell(1, &[leaf.clone()]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell_checked(&mut batch, parent.clone())?;
+        db.raw().write(batch)?;
+
+        // Loaded lazily, so the child is not resolved yet
+        let loaded =
+            ton_types::Cell::with_cell_impl_arc(cell_storage.load_cell(parent.repr_hash())?);
+        db.cells.remove(parent.repr_hash().as_slice())?;
+        db.cells.remove(leaf.repr_hash().as_slice())?;
+
+        let root = make_cell(2, &[loaded.clone()]);
+        let mut batch = rocksdb::WriteBatch::default();
+        assert!(matches!(
+            cell_storage.store_cell(&mut batch, root.clone()),
+            Err(CellStorageError::InvalidCell)
+        ));
+
+        // Plain lazy loads still report the missing cell
+        let error = loaded.reference(0).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CellStorageError>(),
+            Some(CellStorageError::CellNotFound)
+        ));
+        match cell_storage.store_cell_checked(&mut batch, root.clone()) {
+            Err(CellStorageError::MissingChild { parent: p, child }) => {
+                assert_eq!(p, parent.repr_hash());
+                assert_eq!(child, leaf.repr_hash());
+            }
+            _ => panic!("missing child not detected"),
+        }
+        assert!(batch.is_empty());
+
+        // Children which are present in the same tree are accepted
+        let other = make_cell(3, &[make_cell(4, &[]), parent.clone()]);
+        assert_eq!(cell_storage.store_cell_checked(&mut batch, other)?, 4);
+
+        // Children of the already stored cells are checked in the target column
+        let stored_leaf = make_cell(5, &[]);
+        let stored = make_cell(6, &[stored_leaf.clone()]);
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell_checked(&mut batch, stored.clone())?;
+        db.raw().write(batch)?;
+        db.cells.remove(stored_leaf.repr_hash().as_slice())?;
+
+        let root = make_cell(7, &[stored.clone()]);
+        let mut batch = rocksdb::WriteBatch::default();
+        assert_eq!(cell_storage.store_cell(&mut batch, root.clone())?, 1);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        match cell_storage.store_cell_checked(&mut batch, root.clone()) {
+            Err(CellStorageError::MissingChild { parent: p, child }) => {
+                assert_eq!(p, stored.repr_hash());
+                assert_eq!(child, stored_leaf.repr_hash());
+            }
+            _ => panic!("missing child of the stored cell not detected"),
+        }
+        assert!(batch.is_empty());
+
+        // Unless the missing child is present in the same tree
+        let other = make_cell(8, &[stored_leaf.clone(), stored.clone()]);
+        assert_eq!(cell_storage.store_cell_checked(&mut batch, other)?, 2);
+
+        drop(root);
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn rebuild_refcounts_from_roots() -> Result<()> {
+        let (db, path) = open_test_db("rebuild-rc")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let first = make_cell(1, &[leaf.clone(), leaf.clone()]);
+        let other = make_cell(3, &[]);
+        let second = make_cell(2, &[leaf.clone(), other.clone()]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, first.clone())?;
+        cell_storage.store_cell(&mut batch, second.clone())?;
+        db.raw().write(batch)?;
+        assert_eq!(cell_rc(&db, &leaf)?, 3);
+
+        // Corrupt the counter
+        let mut value = Vec::new();
+        refcount::encode_value_with_rc(10, &leaf_data(&db, &leaf)?, &mut value);
+        db.cells.insert(leaf.repr_hash().as_slice(), value)?;
+
+        // The second state is no longer alive
+        let report = cell_storage.rebuild_refcounts(&[first.repr_hash()])?;
+        assert_eq!(report.reachable_cells, 2);
+        assert_eq!(report.missing_cells, 0);
+        assert_eq!(report.updated_cells, 3);
+        assert_eq!(report.unreachable_cells, 2);
+
+        assert_eq!(cell_rc(&db, &first)?, 1);
+        assert_eq!(cell_rc(&db, &leaf)?, 2);
+        assert_eq!(cell_rc(&db, &second)?, 0);
+        assert_eq!(cell_rc(&db, &other)?, 0);
+
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn rebuild_refcounts_of_ref_lists() -> Result<()> {
+        let (db, path) = open_test_db("rebuild-rc-compact")?;
+        let cell_storage = CellStorage::new(
+            db.clone(),
+            2,
+            CellStorageOptions {
+                compact_references: true,
+                ..Default::default()
+            },
+        )?;
+
+        let (left, right) = (make_cell(0, &[]), make_cell(1, &[]));
+        let first = make_cell(2, &[left.clone(), right.clone()]);
+        let second = make_cell(3, &[left.clone(), right.clone()]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, first.clone())?;
+        cell_storage.store_cell(&mut batch, second.clone())?;
+        db.raw().write(batch)?;
+
+        let id = compute_ref_list_id(
+            &[*left.repr_hash().as_array(), *right.repr_hash().as_array()].concat(),
+        );
+        let ref_list_rc = || -> Result<i64> {
+            Ok(match db.cell_ref_lists.get(id.to_be_bytes())? {
+                Some(value) => refcount::decode_value_with_rc(&value).0,
+                None => 0,
+            })
+        };
+        assert_eq!(ref_list_rc()?, 2);
+
+        // The second state is no longer alive
+        let report = cell_storage.rebuild_refcounts(&[first.repr_hash()])?;
+        assert_eq!(report.updated_ref_lists, 1);
+        assert_eq!(ref_list_rc()?, 1);
+
+        // The list stays readable while the first cell is alive
+        let cell = cell_storage.load_cell_uncached(&first.repr_hash())?;
+        assert_eq!(
+            cell.reference_hashes().as_slice(),
+            [left.repr_hash(), right.repr_hash()]
+        );
+
+        drop(cell);
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    fn leaf_data(db: &Db, cell: &ton_types::Cell) -> Result<Vec<u8>> {
+        let value = db.cells.get(cell.repr_hash().as_slice())?.unwrap();
+        Ok(refcount::strip_refcount(&value).unwrap().to_vec())
+    }
+
+    #[test]
+    fn deferred_cache_insertion() -> Result<()> {
+        let (db, path) = open_test_db("deferred")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let root = make_cell(1, &[leaf.clone()]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        let (count, pending) = cell_storage.store_cell_deferred(&mut batch, root.clone())?;
+        assert_eq!(count, 2);
+        db.raw().write(batch)?;
+
+        let cached = pending.committed();
+        assert_eq!(cached.len(), 2);
+
+        let (loaded, outcome) = cell_storage.load_cell_traced(root.repr_hash())?;
+        assert_eq!(outcome, CacheOutcome::Hit);
+        assert_eq!(loaded.tree_cell_count(), root.tree_cell_count());
+        assert_eq!(loaded.reference(0)?.repr_hash(), leaf.repr_hash());
+
+        // Already stored cells are not returned
+        let mut batch = rocksdb::WriteBatch::default();
+        let (_, pending) = cell_storage.store_cell_deferred(&mut batch, root)?;
+        db.raw().write(batch)?;
+        assert!(pending.committed().is_empty());
+
+        drop(cached);
+        drop(loaded);
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn cells_snapshot_survives_removal() -> Result<()> {
+        let (db, path) = open_test_db("snapshot")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let root = make_cell(1, &[make_cell(2, &[leaf.clone()]), leaf]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, root.clone())?;
+        db.raw().write(batch)?;
+
+        let snapshot = cell_storage.cells_snapshot();
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.remove_cell(&mut batch, &Bump::new(), root.repr_hash())?;
+        db.raw().write(batch)?;
+        assert_eq!(cell_rc(&db, &root)?, 0);
+
+        // The whole tree is still readable through the snapshot
+        let loaded = snapshot.load_cell(root.repr_hash())?;
+        let loaded = ton_types::Cell::with_cell_impl_arc(loaded);
+        assert_eq!(loaded.repr_hash(), root.repr_hash());
+        assert_eq!(loaded.tree_cell_count(), 3);
+        let child = loaded.reference(0)?.reference(0)?;
+        assert_eq!(child.repr_hash(), root.reference(1)?.repr_hash());
+
+        assert!(matches!(
+            cell_storage.load_cell(root.repr_hash()),
+            Err(CellStorageError::CellNotFound)
+        ));
+
+        drop((loaded, child, snapshot));
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn iter_collectible_cells() -> Result<()> {
+        let (db, path) = open_test_db("collectible")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let shared = make_cell(0, &[]);
+        let removed = make_cell(1, &[make_cell(2, &[]), shared.clone()]);
+        let alive = make_cell(3, &[shared]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, removed.clone())?;
+        cell_storage.store_cell(&mut batch, alive)?;
+        db.raw().write(batch)?;
+        assert_eq!(cell_storage.iter_collectible_cells(None).count(), 0);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.remove_cell(&mut batch, &Bump::new(), removed.repr_hash())?;
+        db.raw().write(batch)?;
+
+        let mut collectible = cell_storage
+            .iter_collectible_cells(None)
+            .collect::<Result<Vec<_>>>()?;
+        collectible.sort();
+        let mut expected = vec![removed.repr_hash(), removed.reference(0)?.repr_hash()];
+        expected.sort();
+        assert_eq!(collectible, expected);
+
+        assert_eq!(cell_storage.iter_collectible_cells(Some(1)).count(), 1);
+
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn load_bounded_tree() -> Result<()> {
+        let (db, path) = open_test_db("bounded-tree")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let root = make_cell(1, &[make_cell(2, &[leaf.clone()]), leaf]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, root.clone())?;
+        db.raw().write(batch)?;
+
+        // Shared leaf is counted once
+        assert!(cell_storage
+            .load_bounded_tree(root.repr_hash(), 2)?
+            .is_none());
+        let loaded = cell_storage
+            .load_bounded_tree(root.repr_hash(), 3)?
+            .unwrap();
+        assert_eq!(loaded.repr_hash(), root.repr_hash());
+
+        // The whole tree is loaded, so it is readable without the storage
+        db.cells.remove(root.reference(1)?.repr_hash().as_slice())?;
+        assert_eq!(
+            loaded.reference(0)?.reference(0)?.repr_hash(),
+            root.reference(1)?.repr_hash()
+        );
+
+        drop(loaded);
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn state_disk_size() -> Result<()> {
+        let (db, path) = open_test_db("disk-size")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let shared = make_cell(1, &[leaf.clone()]);
+        let root = make_cell(2, &[shared.clone(), shared.clone(), leaf.clone()]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, root.clone())?;
+        db.raw().write(batch)?;
+
+        let value_len = |cell: &ton_types::Cell| -> Result<u64> {
+            Ok(db.cells.get(cell.repr_hash().as_slice())?.unwrap().len() as u64)
+        };
+        let expected = value_len(&leaf)? + value_len(&shared)? + value_len(&root)?;
+        assert_eq!(cell_storage.state_disk_size(root.repr_hash())?, expected);
+        assert_eq!(
+            cell_storage.state_disk_size(leaf.repr_hash())?,
+            value_len(&leaf)?
+        );
+
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
 
-        Ok(storage_cell)
+    #[test]
+    fn max_tree_depth() -> Result<()> {
+        let (db, path) = open_test_db("depth")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let shared = make_cell(1, &[leaf.clone()]);
+        let shallow = make_cell(2, &[shared.clone(), leaf.clone()]);
+        let deep = make_cell(3, &[make_cell(4, &[shared.clone()]), shared]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        for root in [&leaf, &shallow, &deep] {
+            cell_storage.store_cell(&mut batch, root.clone())?;
+        }
+        db.raw().write(batch)?;
+
+        assert_eq!(
+            cell_storage.max_tree_depth(&[leaf.repr_hash()])?,
+            (leaf.repr_hash(), 0)
+        );
+        let roots = [leaf.repr_hash(), deep.repr_hash(), shallow.repr_hash()];
+        assert_eq!(cell_storage.max_tree_depth(&roots)?, (deep.repr_hash(), 3));
+
+        assert!(matches!(
+            cell_storage.max_tree_depth(&[make_cell(5, &[]).repr_hash()]),
+            Err(CellStorageError::CellNotFound)
+        ));
+
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
     }
-}
 
-impl CellImpl for StorageCell {
-    fn data(&self) -> &[u8] {
-        self.cell_data.data()
+    #[test]
+    fn cells_filter_skips_absent_cells() -> Result<()> {
+        let (db, path) = open_test_db("filter")?;
+        let options = CellStorageOptions {
+            cells_filter: Some(CellsFilterOptions {
+                capacity: 1000,
+                false_positive_rate: 0.01,
+            }),
+            ..Default::default()
+        };
+
+        let wait_ready = |cell_storage: &CellStorage| {
+            while !cell_storage.cells_filter_stats().unwrap().ready {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        };
+
+        let stored = make_cell(1, &[make_cell(0, &[])]);
+        {
+            let cell_storage = CellStorage::new(db.clone(), 2, options)?;
+            wait_ready(&cell_storage);
+
+            let mut batch = rocksdb::WriteBatch::default();
+            cell_storage.store_cell(&mut batch, stored.clone())?;
+            db.raw().write(batch)?;
+
+            assert!(cell_storage.contains_cell(&stored.repr_hash())?);
+            assert_eq!(cell_storage.cells_filter_stats().unwrap().inserted, 2);
+        }
+
+        // Filter is filled from the stored cells on startup
+        let cell_storage = CellStorage::new(db.clone(), 2, options)?;
+        wait_ready(&cell_storage);
+        assert_eq!(cell_storage.cells_filter_stats().unwrap().inserted, 2);
+        assert!(cell_storage.contains_cell(&stored.repr_hash())?);
+        cell_storage.load_cell(stored.repr_hash())?;
+
+        let absent = make_cell(2, &[]);
+        assert!(!cell_storage.contains_cell(&absent.repr_hash())?);
+        assert!(matches!(
+            cell_storage.load_cell(absent.repr_hash()),
+            Err(CellStorageError::CellNotFound)
+        ));
+
+        // Cells written bypassing the storage must be registered
+        let mut value = Vec::new();
+        StorageCell::serialize_to(&*absent, &mut value)?;
+        let mut stored_value = Vec::new();
+        refcount::encode_value_with_rc(1, &value, &mut stored_value);
+        db.cells
+            .insert(absent.repr_hash().as_slice(), stored_value)?;
+        cell_storage.register_stored_cell(absent.repr_hash().as_array());
+        assert!(cell_storage.contains_cell(&absent.repr_hash())?);
+
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
     }
 
-    fn cell_data(&self) -> &ton_types::CellData {
-        &self.cell_data
+    #[test]
+    fn cell_format_census() -> Result<()> {
+        let (db, path) = open_test_db("census")?;
+        let plain_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+        let checked_storage = CellStorage::new(
+            db.clone(),
+            2,
+            CellStorageOptions {
+                checksums: true,
+                compact_references: true,
+                ..Default::default()
+            },
+        )?;
+
+        let leaf = make_cell(0, &[]);
+        let plain = make_cell(1, &[leaf.clone()]);
+        let compact = make_cell(2, &[leaf.clone(), make_cell(3, &[])]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        plain_storage.store_cell(&mut batch, plain.clone())?;
+        db.raw().write(batch)?;
+
+        let mut batch = rocksdb::WriteBatch::default();
+        checked_storage.store_cell(&mut batch, compact)?;
+        db.raw().write(batch)?;
+
+        // Value stored before the tree counters were added
+        let mut data = Vec::new();
+        StorageCell::serialize_to(&*plain, &mut data)?;
+        let mut value = Vec::new();
+        refcount::encode_value_with_rc(1, &data[..data.len() - 16], &mut value);
+        db.cells.insert(plain.repr_hash().as_slice(), value)?;
+
+        // Fully dereferenced value
+        let mut value = Vec::new();
+        refcount::encode_value_with_rc(0, &[], &mut value);
+        db.cells.insert([0xff; 32], value)?;
+
+        let census = plain_storage.cell_format_census(None)?;
+        assert_eq!(census.total, 5);
+        assert_eq!(census.with_counters, 3);
+        assert_eq!(census.without_counters, 1);
+        assert_eq!(census.compact, 1);
+        assert_eq!(
+            census.checksum_versions.get(&CELL_CHECKSUM_VERSION),
+            Some(&2)
+        );
+        assert_eq!(census.removed, 1);
+        assert_eq!(census.unknown, 0);
+
+        let census = plain_storage.cell_format_census(Some(3))?;
+        assert_eq!(census.total, 3);
+
+        drop(plain_storage);
+        drop(checked_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
     }
 
-    fn bit_length(&self) -> usize {
-        self.cell_data.bit_length() as usize
+    #[test]
+    fn subtree_postorder() -> Result<()> {
+        let (db, path) = open_test_db("postorder")?;
+        let plain_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+        let compact_storage = CellStorage::new(
+            db.clone(),
+            2,
+            CellStorageOptions {
+                checksums: true,
+                compact_references: true,
+                ..Default::default()
+            },
+        )?;
+
+        let leaf = make_cell(0, &[]);
+        let first = make_cell(1, &[leaf.clone()]);
+        let second = make_cell(2, &[leaf.clone()]);
+        let root = make_cell(3, &[first.clone(), second.clone()]);
+        let other_root = make_cell(4, &[first.clone(), second.clone()]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        plain_storage.store_cell(&mut batch, root.clone())?;
+        db.raw().write(batch)?;
+
+        let mut batch = rocksdb::WriteBatch::default();
+        compact_storage.store_cell(&mut batch, other_root.clone())?;
+        db.raw().write(batch)?;
+
+        let cells = plain_storage
+            .iter_subtree_postorder(root.repr_hash())
+            .collect::<Result<Vec<_>, _>>()?;
+        let hashes = cells.iter().map(|(hash, _)| *hash).collect::<Vec<_>>();
+        assert_eq!(
+            hashes,
+            [
+                leaf.repr_hash(),
+                first.repr_hash(),
+                second.repr_hash(),
+                root.repr_hash()
+            ]
+        );
+
+        let mut expected = Vec::new();
+        StorageCell::serialize_to(&*first, &mut expected)?;
+        expected.truncate(expected.len() - 16);
+        assert_eq!(cells[1].1, expected);
+
+        // Encoding doesn't depend on the storage layout
+        let other_cells = plain_storage
+            .iter_subtree_postorder(other_root.repr_hash())
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(other_cells[..3], cells[..3]);
+
+        let mut expected = Vec::new();
+        StorageCell::serialize_to(&*other_root, &mut expected)?;
+        expected.truncate(expected.len() - 16);
+        assert_eq!(other_cells[3].1, expected);
+
+        drop(plain_storage);
+        drop(compact_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
     }
 
-    fn references_count(&self) -> usize {
-        self.references.read().len()
+    #[test]
+    fn split_masterchain_cells() -> Result<()> {
+        let (db, path) = open_test_db("split")?;
+        let cell_storage = CellStorage::new(
+            db.clone(),
+            2,
+            CellStorageOptions {
+                split_masterchain_cells: true,
+                ..Default::default()
+            },
+        )?;
+
+        let shared = make_cell(0, &[]);
+        let old_root = make_cell(1, &[shared.clone()]);
+        let mc_root = make_cell(2, &[shared.clone()]);
+
+        // Stored before the columns were split
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, old_root.clone())?;
+        db.raw().write(batch)?;
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell_in(CellColumn::Masterchain, &mut batch, mc_root.clone())?;
+        db.raw().write(batch)?;
+
+        // Shared cells are stored in both columns
+        assert!(db.cells.get(mc_root.repr_hash().as_slice())?.is_none());
+        assert!(db.mc_cells.get(mc_root.repr_hash().as_slice())?.is_some());
+        assert!(db.mc_cells.get(shared.repr_hash().as_slice())?.is_some());
+        assert_eq!(cell_rc(&db, &shared)?, 1);
+
+        for root in [&old_root, &mc_root] {
+            let loaded = cell_storage.load_cell_in(CellColumn::Masterchain, root.repr_hash())?;
+            assert_eq!(loaded.reference(0)?.repr_hash(), shared.repr_hash());
+        }
+
+        // Walkers see both columns
+        let roots = [old_root.repr_hash(), mc_root.repr_hash()];
+        assert!(cell_storage.check_reachable(&roots)?.is_empty());
+
+        // Masterchain roots are measured in their own column
+        let mc_value_len = |cell: &ton_types::Cell| -> Result<u64> {
+            Ok(db.mc_cells.get(cell.repr_hash().as_slice())?.unwrap().len() as u64)
+        };
+        assert_eq!(
+            cell_storage.state_disk_size(mc_root.repr_hash())?,
+            mc_value_len(&mc_root)? + mc_value_len(&shared)?
+        );
+        let report = cell_storage.rebuild_refcounts(&roots)?;
+        assert_eq!(report.missing_cells, 0);
+        assert_eq!(report.updated_cells, 0);
+
+        // Old roots are removed from the shared column
+        let alloc = Bump::new();
+        let mut batch = rocksdb::WriteBatch::default();
+        for root in [&old_root, &mc_root] {
+            cell_storage.remove_cell_in(
+                CellColumn::Masterchain,
+                &mut batch,
+                &alloc,
+                root.repr_hash(),
+            )?;
+        }
+        db.raw().write(batch)?;
+        assert_eq!(cell_rc(&db, &shared)?, 0);
+        assert_eq!(cell_rc(&db, &old_root)?, 0);
+
+        // The separate column can't be disabled once it was used
+        drop(cell_storage);
+        assert!(CellStorage::new(db.clone(), 2, Default::default()).is_err());
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
     }
 
-    fn reference(&self, index: usize) -> Result<ton_types::Cell> {
-        Ok(ton_types::Cell::with_cell_impl_arc(self.reference(index)?))
+    #[test]
+    fn traced_load_reports_cache_outcome() -> Result<()> {
+        let (db, path) = open_test_db("traced")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let root = make_cell(1, &[make_cell(0, &[])]);
+        let hash = root.repr_hash();
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, root)?;
+        db.raw().write(batch)?;
+
+        let (cell, outcome) = cell_storage.load_cell_traced(hash)?;
+        assert_eq!(outcome, CacheOutcome::Miss);
+        assert_eq!(cell_storage.load_cell_traced(hash)?.1, CacheOutcome::Hit);
+
+        // Dropped cells are removed from the cache
+        drop(cell);
+        assert_eq!(cell_storage.load_cell_traced(hash)?.1, CacheOutcome::Miss);
+
+        if let CellsCache::Weak(cells) = &cell_storage.cells_cache {
+            cells.insert(hash, std::sync::Weak::new());
+        }
+        assert_eq!(
+            cell_storage.load_cell_traced(hash)?.1,
+            CacheOutcome::WeakExpired
+        );
+
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
     }
 
-    fn cell_type(&self) -> ton_types::CellType {
-        self.cell_data.cell_type()
+    #[test]
+    fn concurrent_loads_with_contended_cache() -> Result<()> {
+        let (db, path) = open_test_db("concurrent-loads")?;
+        // Minimal number of shards, so that most inserts are contended
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaves = (0..256).map(|i| make_cell(i, &[])).collect::<Vec<_>>();
+        let roots = leaves
+            .chunks(4)
+            .enumerate()
+            .map(|(i, leaves)| make_cell(1000 + i as u32, leaves))
+            .collect::<Vec<_>>();
+        let mut batch = rocksdb::WriteBatch::default();
+        for root in &roots {
+            cell_storage.store_cell(&mut batch, root.clone())?;
+        }
+        db.raw().write(batch)?;
+
+        let hashes = roots
+            .iter()
+            .chain(&leaves)
+            .map(|cell| cell.repr_hash())
+            .collect::<Vec<_>>();
+
+        let loaded = std::thread::scope(|scope| {
+            let threads = (0..8)
+                .map(|i| {
+                    let (cell_storage, hashes) = (&cell_storage, &hashes);
+                    scope.spawn(move || {
+                        // Threads walk the cells in different order
+                        let mut loaded = Vec::with_capacity(hashes.len());
+                        for j in 0..hashes.len() {
+                            let hash = hashes[(j * 7 + i * 31) % hashes.len()];
+                            let cell = cell_storage.load_cell(hash).unwrap();
+                            assert_eq!(cell.repr_hash(), hash);
+                            loaded.push(cell);
+                        }
+                        loaded
+                    })
+                })
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert!(loaded.iter().all(|cells| cells.len() == hashes.len()));
+
+        // Inserts skipped under contention are done by the next uncontended load
+        let reloaded = hashes
+            .iter()
+            .map(|hash| cell_storage.load_cell(*hash))
+            .collect::<Result<Vec<_>, _>>()?;
+        for hash in &hashes {
+            assert_eq!(cell_storage.load_cell_traced(*hash)?.1, CacheOutcome::Hit);
+        }
+
+        drop((loaded, reloaded));
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
     }
 
-    fn level_mask(&self) -> ton_types::LevelMask {
-        self.cell_data.level_mask()
+    #[test]
+    fn contended_cache_insert_is_retried_by_next_load() -> Result<()> {
+        let (db, path) = open_test_db("contended-insert")?;
+        let cell_storage = CellStorage::new(
+            db.clone(),
+            2,
+            CellStorageOptions {
+                cache_strategy: CellCacheStrategy::StrongLru { capacity: 16 },
+                ..Default::default()
+            },
+        )?;
+
+        let leaf = make_cell(0, &[]);
+        let hash = leaf.repr_hash();
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, leaf)?;
+        db.raw().write(batch)?;
+
+        let cell = cell_storage.load_cell(hash)?;
+        cell_storage.cells_cache.remove(&hash);
+
+        // Insert is skipped while another thread holds the shard
+        let shards = match &cell_storage.cells_cache {
+            CellsCache::StrongLru(shards) => shards,
+            CellsCache::Weak(_) => unreachable!(),
+        };
+        let guards = shards.iter().map(|shard| shard.lock()).collect::<Vec<_>>();
+        std::thread::spawn({
+            let cell_storage = cell_storage.clone();
+            let cell = cell.clone();
+            move || cell_storage.cells_cache.insert(hash, &cell, true)
+        })
+        .join()
+        .unwrap();
+        drop(guards);
+        assert!(matches!(
+            cell_storage.cells_cache.lookup(&hash),
+            Err(CacheOutcome::Miss)
+        ));
+
+        // Only the next load misses, the rest hit the cache
+        assert_eq!(cell_storage.load_cell_traced(hash)?.1, CacheOutcome::Miss);
+        for _ in 0..8 {
+            assert_eq!(cell_storage.load_cell_traced(hash)?.1, CacheOutcome::Hit);
+        }
+
+        drop(cell);
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
     }
 
-    fn hash(&self, index: usize) -> UInt256 {
-        self.cell_data.hash(index)
+    #[test]
+    fn loads_join_in_flight_reads() -> Result<()> {
+        let (db, path) = open_test_db("in-flight")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let root = make_cell(1, &[make_cell(0, &[])]);
+        let hash = root.repr_hash();
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, root)?;
+        db.raw().write(batch)?;
+
+        // Simulate a read of another load which has just finished
+        let cell = cell_storage.load_cell_uncached(&hash)?;
+        let in_flight = Arc::new(InFlightLoad::with_value(cell.clone()));
+        cell_storage.in_flight_loads.insert(hash, in_flight);
+
+        let loaded = cell_storage.load_cell(hash)?;
+        assert!(Arc::ptr_eq(&loaded, &cell));
+        assert!(cell_storage.in_flight_loads.is_empty());
+
+        // Failed reads are not shared
+        let missing = make_cell(2, &[]).repr_hash();
+        assert!(cell_storage.load_cell(missing).is_err());
+        assert!(cell_storage.in_flight_loads.is_empty());
+
+        drop((loaded, cell));
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
     }
 
-    fn depth(&self, index: usize) -> u16 {
-        self.cell_data.depth(index)
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn read_limiter_bounds_concurrent_reads() {
+        let limiter = Arc::new(ReadLimiter::new(1));
+        let permit = limiter.acquire_owned().await;
+
+        let async_waiter = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { drop(limiter.acquire_owned().await) })
+        };
+        // Blocking waiter leaves the worker thread to other tasks
+        let blocking_waiter = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { drop(limiter.acquire()) })
+        };
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!async_waiter.is_finished());
+        assert!(!blocking_waiter.is_finished());
+
+        drop(permit);
+        async_waiter.await.unwrap();
+        blocking_waiter.await.unwrap();
+        assert_eq!(limiter.semaphore.available_permits(), 1);
     }
 
-    fn store_hashes(&self) -> bool {
-        self.cell_data.store_hashes()
+    #[test]
+    fn read_limiter_outside_runtime() {
+        let limiter = Arc::new(ReadLimiter::new(1));
+        let permit = limiter.acquire();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let waiter = {
+            let limiter = limiter.clone();
+            std::thread::spawn(move || {
+                drop(limiter.acquire());
+                tx.send(()).unwrap();
+            })
+        };
+
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+
+        drop(permit);
+        waiter.join().unwrap();
+        assert!(rx.try_recv().is_ok());
+        assert_eq!(limiter.semaphore.available_permits(), 1);
     }
 
-    fn tree_bits_count(&self) -> u64 {
-        self.tree_bits_count
+    #[test]
+    fn dropped_staging_batch_is_not_visible() -> Result<()> {
+        let (db, path) = open_test_db("staging")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let root = make_cell(1, &[leaf.clone()]);
+
+        let mut staging = cell_storage.begin_staging();
+        staging.store_cell(root.clone())?;
+        let loaded = cell_storage.load_cell(root.repr_hash())?;
+        assert_eq!(loaded.repr_hash(), root.repr_hash());
+        assert!(cell_storage.contains_cell(&leaf.repr_hash())?);
+
+        // Staged cells must not outlive their batch, even while they are still referenced
+        drop(staging);
+        assert!(matches!(
+            cell_storage.load_cell(root.repr_hash()),
+            Err(CellStorageError::CellNotFound)
+        ));
+        assert!(!cell_storage.contains_cell(&leaf.repr_hash())?);
+
+        drop(loaded);
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
     }
 
-    fn tree_cell_count(&self) -> u64 {
-        self.tree_cell_count
+    #[tokio::test]
+    async fn resolve_cells_from_all_sources() -> Result<()> {
+        let (db, path) = open_test_db("resolve")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let leaf = make_cell(0, &[]);
+        let root = make_cell(1, &[leaf.clone()]);
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, root.clone())?;
+        db.raw().write(batch)?;
+
+        // Cached root
+        let cached = cell_storage.load_cell(root.repr_hash())?;
+
+        let snapshot = cell_storage.cells_snapshot();
+        let staged = make_cell(2, &[]);
+        let mut staging = cell_storage.begin_staging();
+        staging.store_cell(staged.clone())?;
+
+        let hashes = [
+            root.repr_hash(),
+            leaf.repr_hash(),
+            staged.repr_hash(),
+            make_cell(3, &[]).repr_hash(),
+        ];
+
+        let resolved = cell_storage
+            .resolve_cells(&hashes, Default::default())
+            .await?;
+        assert!(Arc::ptr_eq(resolved[0].as_ref().unwrap(), &cached));
+        for (cell, hash) in resolved.iter().zip(&hashes).take(3) {
+            assert_eq!(cell.as_ref().unwrap().repr_hash(), *hash);
+        }
+        assert!(resolved[3].is_none());
+
+        // Staged cells are not visible through the snapshot
+        let ctx = ResolveCtx {
+            snapshot: Some(snapshot),
+            ..Default::default()
+        };
+        let resolved = cell_storage.resolve_cells(&hashes, ctx).await?;
+        assert!(!Arc::ptr_eq(resolved[0].as_ref().unwrap(), &cached));
+        assert!(resolved[1].is_some());
+        assert!(resolved[2].is_none() && resolved[3].is_none());
+
+        drop((resolved, cached, staging));
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
     }
-}
 
-impl Drop for StorageCell {
-    fn drop(&mut self) {
-        self.cell_storage.drop_cell(&self.repr_hash())
+    #[tokio::test]
+    async fn store_cell_on_blocking_pool() -> Result<()> {
+        let (db, path) = open_test_db("async")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let root = make_cell(1, &[make_cell(0, &[])]);
+        let (batch, count) = cell_storage.clone().store_cell_async(root.clone()).await?;
+        assert_eq!(count, 2);
+        assert_eq!(cell_rc(&db, &root)?, 0);
+
+        db.raw().write(batch)?;
+        assert_eq!(cell_rc(&db, &root)?, 1);
+
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
     }
-}
 
-#[derive(Clone)]
-pub enum StorageCellReference {
-    Loaded(Arc<StorageCell>),
-    Unloaded(UInt256),
-}
+    #[test]
+    fn cell_stored_callback_reports_new_cells() -> Result<()> {
+        let (db, path) = open_test_db("callback")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
 
-#[derive(thiserror::Error, Debug)]
-enum StorageCellError {
-    #[error("Accessing invalid cell reference")]
-    AccessingInvalidReference,
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        cell_storage.set_on_cell_stored(Some({
+            let stored = stored.clone();
+            Arc::new(move |hash: &UInt256, _: &[u8]| stored.lock().push(*hash))
+        }));
+
+        let leaf = make_cell(0, &[]);
+        let first = make_cell(1, &[leaf.clone()]);
+        let second = make_cell(2, &[leaf.clone()]);
+
+        // Cells are reported only after the batch is written
+        let mut batch = cell_storage.begin_staging();
+        batch.store_cell(first.clone())?;
+        assert!(stored.lock().is_empty());
+        batch.commit()?;
+        assert_eq!(stored.lock().len(), 2);
+
+        // Only the new root is reported
+        stored.lock().clear();
+        let mut batch = cell_storage.begin_staging();
+        batch.store_cell(first)?;
+        batch.store_cell(second.clone())?;
+        batch.commit()?;
+        assert_eq!(*stored.lock(), [second.repr_hash()]);
+
+        // Uncommitted cells are never reported
+        stored.lock().clear();
+        let mut batch = cell_storage.begin_staging();
+        batch.store_cell(make_cell(3, &[leaf]))?;
+        drop(batch);
+        assert!(stored.lock().is_empty());
+
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn compact_references_are_deduplicated() -> Result<()> {
+        let (db, path) = open_test_db("compact")?;
+        let inline_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+        let cell_storage = CellStorage::new(
+            db.clone(),
+            2,
+            CellStorageOptions {
+                compact_references: true,
+                ..Default::default()
+            },
+        )?;
+
+        let (left, right) = (make_cell(0, &[]), make_cell(1, &[]));
+        let first = make_cell(2, &[left.clone(), right.clone()]);
+        let second = make_cell(3, &[left.clone(), right.clone()]);
+
+        for root in [&first, &second] {
+            let mut batch = rocksdb::WriteBatch::default();
+            cell_storage.store_cell(&mut batch, root.clone())?;
+            db.raw().write(batch)?;
+        }
+
+        let id = compute_ref_list_id(
+            &[*left.repr_hash().as_array(), *right.repr_hash().as_array()].concat(),
+        );
+        let ref_list_rc = || -> Result<i64> {
+            Ok(match db.cell_ref_lists.get(id.to_be_bytes())? {
+                Some(value) => refcount::decode_value_with_rc(&value).0,
+                None => 0,
+            })
+        };
+        assert_eq!(ref_list_rc()?, 2);
+
+        // Compact cells are readable regardless of the option
+        for storage in [&cell_storage, &inline_storage] {
+            let cell = storage.load_cell_uncached(&first.repr_hash())?;
+            assert_eq!(cell.repr_hash(), first.repr_hash());
+            assert_eq!(
+                cell.reference_hashes().as_slice(),
+                [left.repr_hash(), right.repr_hash()]
+            );
+        }
+
+        let session = cell_storage.begin_removal();
+        session.remove_cell(&first.repr_hash())?;
+        session.remove_cell(&second.repr_hash())?;
+        let mut batch = rocksdb::WriteBatch::default();
+        assert_eq!(session.commit(&mut batch), 4);
+        db.raw().write(batch)?;
+
+        assert_eq!(ref_list_rc()?, 0);
+
+        // The db stays marked as compact even if the option is disabled
+        assert_eq!(cell_storage.format_version(), CELLS_FORMAT_COMPACT);
+        let reopened = CellStorage::new(db.clone(), 2, Default::default())?;
+        assert_eq!(reopened.format_version(), CELLS_FORMAT_COMPACT);
+        drop(reopened);
+
+        db.node_states
+            .insert(CELLS_FORMAT_VERSION, [CELLS_FORMAT_COMPACT + 1])?;
+        assert!(CellStorage::new(db.clone(), 2, Default::default()).is_err());
+
+        drop(inline_storage);
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn checksum_detects_corrupted_value() -> Result<()> {
+        let (db, path) = open_test_db("checksum")?;
+        let legacy_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+        let cell_storage = CellStorage::new(
+            db.clone(),
+            2,
+            CellStorageOptions {
+                checksums: true,
+                ..Default::default()
+            },
+        )?;
+
+        let legacy = make_cell(0, &[]);
+        let cell = make_cell(1, &[]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        legacy_storage.store_cell(&mut batch, legacy.clone())?;
+        cell_storage.store_cell(&mut batch, cell.clone())?;
+        db.raw().write(batch)?;
+
+        // Values without checksum are still readable
+        cell_storage.load_cell_uncached(&legacy.repr_hash())?;
+        cell_storage.load_cell_uncached(&cell.repr_hash())?;
+
+        // Flip a bit in the cell data (after the refcount and descriptor bytes)
+        let mut value = db.cells.get(cell.repr_hash().as_slice())?.unwrap().to_vec();
+        value[8 + 2] ^= 1;
+        db.cells.insert(cell.repr_hash().as_slice(), &value)?;
+
+        assert!(matches!(
+            cell_storage.load_cell_uncached(&cell.repr_hash()),
+            Err(CellStorageError::ChecksumMismatch)
+        ));
+
+        // Corrupted checksum version doesn't disable the verification
+        value[8 + 2] ^= 1;
+        *value.last_mut().unwrap() = CELL_CHECKSUM_VERSION + 1;
+        db.cells.insert(cell.repr_hash().as_slice(), &value)?;
+
+        assert!(matches!(
+            cell_storage.load_cell_uncached(&cell.repr_hash()),
+            Err(CellStorageError::UnknownChecksumVersion { version })
+                if version == CELL_CHECKSUM_VERSION + 1
+        ));
+
+        drop(legacy_storage);
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn weak_expired_cache_misses() -> Result<()> {
+        let (db, path) = open_test_db("weak")?;
+        let cell_storage = CellStorage::new(db.clone(), 2, Default::default())?;
+
+        let root = make_cell(1, &[make_cell(0, &[])]);
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.store_cell(&mut batch, root.clone())?;
+        db.raw().write(batch)?;
+
+        let loaded = cell_storage.load_cell(root.repr_hash())?;
+        drop(cell_storage.load_cell(root.repr_hash())?);
+        assert_eq!(cell_storage.cache_weak_expired(), 0);
+        assert_eq!(cell_storage.cache_effectiveness(), Some(1.0));
+        drop(loaded);
+
+        // Entry of the dropped cell which was not removed yet
+        match &cell_storage.cells_cache {
+            CellsCache::Weak(cells) => cells.insert(root.repr_hash(), std::sync::Weak::new()),
+            CellsCache::StrongLru(_) => unreachable!(),
+        };
+        drop(cell_storage.load_cell(root.repr_hash())?);
+        assert_eq!(cell_storage.cache_weak_expired(), 1);
+        assert_eq!(cell_storage.cache_effectiveness(), Some(0.5));
+        assert_eq!(cell_storage.cache_hit_ratio(), Some(1.0 / 3.0));
+
+        drop(cell_storage);
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn strong_lru_cache_keeps_unreferenced_cells() -> Result<()> {
+        let (db, path) = open_test_db("lru")?;
+        let cell_storage = CellStorage::new(
+            db.clone(),
+            2,
+            CellStorageOptions {
+                cache_strategy: CellCacheStrategy::StrongLru { capacity: 2 },
+                ..Default::default()
+            },
+        )?;
+
+        let cells = [make_cell(0, &[]), make_cell(1, &[]), make_cell(2, &[])];
+        let mut batch = rocksdb::WriteBatch::default();
+        for cell in &cells {
+            cell_storage.store_cell(&mut batch, cell.clone())?;
+        }
+        db.raw().write(batch)?;
+
+        // Cell is cached even after the last external reference is dropped
+        drop(cell_storage.load_cell(cells[0].repr_hash())?);
+        drop(cell_storage.load_cell(cells[0].repr_hash())?);
+        assert_eq!(cell_storage.cache_hit_ratio(), Some(0.5));
+
+        // Invalidated cell is loaded again
+        cell_storage.invalidate(&cells[0].repr_hash());
+        drop(cell_storage.load_cell(cells[0].repr_hash())?);
+        assert_eq!(cell_storage.cache_misses.load(Ordering::Relaxed), 2);
+
+        // Removed cell is not served from the cache
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage.remove_cell(&mut batch, &Bump::new(), cells[0].repr_hash())?;
+        db.raw().write(batch)?;
+        assert!(cell_storage.load_cell(cells[0].repr_hash()).is_err());
+
+        // Resident cells don't keep the storage alive
+        drop(cell_storage.load_cell(cells[1].repr_hash())?);
+        let weak = Arc::downgrade(&cell_storage);
+        drop(cell_storage);
+        assert!(weak.upgrade().is_none());
+
+        drop(db);
+        std::fs::remove_dir_all(path).ok();
+        Ok(())
+    }
 }
@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Weak};
+
+use parking_lot::Mutex;
+use ton_types::UInt256;
+
+use super::cell_storage::{CacheOutcome, StorageCell};
+use crate::config::CellCacheStrategy;
+use crate::utils::{FastDashMap, FastHashMap};
+
+/// Loaded cells cache (see [`CellCacheStrategy`])
+pub enum CellsCache {
+    Weak(FastDashMap<UInt256, Weak<StorageCell>>),
+    StrongLru(Box<[Mutex<LruShard>]>),
+}
+
+impl CellsCache {
+    /// NOTE: `shards` is rounded up to the next power of two (at least 2)
+    pub fn new(strategy: CellCacheStrategy, shards: usize) -> Self {
+        let shards = shards.max(2).next_power_of_two();
+        match strategy {
+            CellCacheStrategy::WeakUnbounded => {
+                Self::Weak(FastDashMap::with_capacity_and_hasher_and_shard_amount(
+                    0,
+                    Default::default(),
+                    shards,
+                ))
+            }
+            CellCacheStrategy::StrongLru { capacity } => {
+                let shard_capacity = std::cmp::max(capacity / shards, 1);
+                Self::StrongLru(
+                    (0..shards)
+                        .map(|_| Mutex::new(LruShard::new(shard_capacity)))
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    /// Returns the cached cell or the reason why it was not found
+    pub fn lookup(&self, hash: &UInt256) -> Result<Arc<StorageCell>, CacheOutcome> {
+        match self {
+            Self::Weak(cells) => match cells.get(hash) {
+                Some(weak) => weak.upgrade().ok_or(CacheOutcome::WeakExpired),
+                None => Err(CacheOutcome::Miss),
+            },
+            Self::StrongLru(shards) => shards[shard_index(shards, hash)]
+                .lock()
+                .get(hash)
+                .ok_or(CacheOutcome::Miss),
+        }
+    }
+
+    /// Inserts the cell. If `skip_contended` is set, the insert is skipped
+    /// when the cache shard is locked by another thread
+    pub fn insert(&self, hash: UInt256, cell: &Arc<StorageCell>, skip_contended: bool) {
+        match self {
+            Self::Weak(cells) => {
+                if !skip_contended {
+                    cells.insert(hash, Arc::downgrade(cell));
+                } else if let Some(entry) = cells.try_entry(hash) {
+                    entry.insert(Arc::downgrade(cell));
+                }
+            }
+            Self::StrongLru(shards) => {
+                let shard = &shards[shard_index(shards, &hash)];
+                let mut shard = if !skip_contended {
+                    shard.lock()
+                } else {
+                    match shard.try_lock() {
+                        Some(shard) => shard,
+                        None => return,
+                    }
+                };
+                let evicted = shard.insert(hash, cell.clone());
+                drop(shard);
+                // NOTE: evicted cell is dropped outside the shard lock
+                drop(evicted);
+            }
+        }
+    }
+
+    /// Removes the entry of the dropped cell.
+    ///
+    /// NOTE: resident cells are only removed on eviction
+    pub fn remove_dropped(&self, hash: &UInt256) {
+        if let Self::Weak(cells) = self {
+            // NOTE: uncached duplicates must not evict the live cached cell
+            cells.remove_if(hash, |_, weak| weak.strong_count() == 0);
+        }
+    }
+
+    /// Removes the resident cell of the removed tree.
+    ///
+    /// NOTE: weak entries are removed when their cells are dropped
+    pub fn evict_removed(&self, hash: &UInt256) {
+        if let Self::StrongLru(shards) = self {
+            let removed = shards[shard_index(shards, hash)].lock().remove(hash);
+            drop(removed);
+        }
+    }
+
+    pub fn remove(&self, hash: &UInt256) {
+        let removed = match self {
+            Self::Weak(cells) => {
+                cells.remove(hash);
+                None
+            }
+            Self::StrongLru(shards) => shards[shard_index(shards, hash)].lock().remove(hash),
+        };
+        drop(removed);
+    }
+
+    pub fn clear(&self) {
+        match self {
+            Self::Weak(cells) => cells.clear(),
+            Self::StrongLru(shards) => {
+                for shard in shards.iter() {
+                    let cells = shard.lock().clear();
+                    drop(cells);
+                }
+            }
+        }
+    }
+}
+
+fn shard_index(shards: &[Mutex<LruShard>], hash: &UInt256) -> usize {
+    // NOTE: the number of shards is a power of two
+    hash.as_slice()[0] as usize & (shards.len() - 1)
+}
+
+#[derive(Default)]
+pub struct LruShard {
+    capacity: usize,
+    tick: u64,
+    cells: FastHashMap<UInt256, (Arc<StorageCell>, u64)>,
+    /// Hashes ordered by the last access tick
+    order: BTreeMap<u64, UInt256>,
+}
+
+impl LruShard {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    fn get(&mut self, hash: &UInt256) -> Option<Arc<StorageCell>> {
+        let (cell, tick) = self.cells.get_mut(hash)?;
+        self.order.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.order.insert(self.tick, *hash);
+        Some(cell.clone())
+    }
+
+    /// Returns the evicted cell
+    fn insert(&mut self, hash: UInt256, cell: Arc<StorageCell>) -> Option<Arc<StorageCell>> {
+        self.tick += 1;
+        if let Some((_, tick)) = self.cells.insert(hash, (cell, self.tick)) {
+            self.order.remove(&tick);
+        }
+        self.order.insert(self.tick, hash);
+
+        if self.cells.len() <= self.capacity {
+            return None;
+        }
+
+        let oldest_tick = *self.order.keys().next()?;
+        let oldest = self.order.remove(&oldest_tick)?;
+        self.cells.remove(&oldest).map(|(cell, _)| cell)
+    }
+
+    fn remove(&mut self, hash: &UInt256) -> Option<Arc<StorageCell>> {
+        let (cell, tick) = self.cells.remove(hash)?;
+        self.order.remove(&tick);
+        Some(cell)
+    }
+
+    /// Returns the removed cells
+    fn clear(&mut self) -> FastHashMap<UInt256, (Arc<StorageCell>, u64)> {
+        self.order.clear();
+        std::mem::take(&mut self.cells)
+    }
+}
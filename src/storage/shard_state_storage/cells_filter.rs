@@ -0,0 +1,146 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::config::CellsFilterOptions;
+
+/// Lock-free bloom filter over the stored cell hashes.
+///
+/// Cell hashes are uniformly distributed, so the bit positions are derived
+/// directly from the hash bytes instead of rehashing them.
+///
+/// The filter is filled in background on startup and reports all hashes
+/// as possibly present until it is ready.
+///
+/// NOTE: removed cells are not cleared from the filter, so the false positive
+/// rate grows with the number of removed cells until the next restart
+pub struct CellsFilter {
+    bits: Box<[AtomicU64]>,
+    hash_count: u32,
+    inserted: AtomicU64,
+    ready: AtomicBool,
+}
+
+impl CellsFilter {
+    pub fn new(options: CellsFilterOptions) -> Self {
+        let capacity = options.capacity.max(1) as f64;
+        let false_positive_rate = options.false_positive_rate.clamp(1e-9, 0.5);
+
+        let ln2 = std::f64::consts::LN_2;
+        let bit_count = (-capacity * false_positive_rate.ln() / (ln2 * ln2)).ceil() as u64;
+        let word_count = std::cmp::max((bit_count + 63) / 64, 1);
+        let hash_count = ((word_count * 64) as f64 / capacity * ln2)
+            .round()
+            .clamp(1.0, MAX_HASH_COUNT as f64) as u32;
+
+        Self {
+            bits: (0..word_count).map(|_| AtomicU64::new(0)).collect(),
+            hash_count,
+            inserted: AtomicU64::new(0),
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    pub fn insert(&self, hash: &[u8; 32]) {
+        for bit in self.bit_positions(hash) {
+            self.bits[(bit / 64) as usize].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+        self.inserted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Marks the filter as filled with all stored cells
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::Release);
+    }
+
+    /// Returns `false` if the hash was definitely not inserted.
+    /// Always returns `true` until the filter is ready
+    pub fn may_contain(&self, hash: &[u8; 32]) -> bool {
+        if !self.ready.load(Ordering::Acquire) {
+            return true;
+        }
+        self.bit_positions(hash).all(|bit| {
+            self.bits[(bit / 64) as usize].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0
+        })
+    }
+
+    pub fn stats(&self) -> CellsFilterStats {
+        let bit_count = (self.bits.len() * 64) as f64;
+        let inserted = self.inserted.load(Ordering::Relaxed);
+
+        // (1 - e^(-kn/m))^k
+        let k = self.hash_count as f64;
+        let false_positive_rate = (1.0 - (-k * inserted as f64 / bit_count).exp()).powf(k);
+
+        CellsFilterStats {
+            inserted,
+            false_positive_rate,
+            memory_usage: self.bits.len() * std::mem::size_of::<AtomicU64>(),
+            ready: self.ready.load(Ordering::Acquire),
+        }
+    }
+
+    fn bit_positions(&self, hash: &[u8; 32]) -> impl Iterator<Item = u64> {
+        let mut h1 = [0; 8];
+        h1.copy_from_slice(&hash[0..8]);
+        let mut h2 = [0; 8];
+        h2.copy_from_slice(&hash[8..16]);
+
+        // Double hashing, see "Less Hashing, Same Performance: Building a Better Bloom Filter"
+        let h1 = u64::from_le_bytes(h1);
+        let h2 = u64::from_le_bytes(h2) | 1;
+        let bit_count = self.bits.len() as u64 * 64;
+        (0..self.hash_count as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bit_count)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CellsFilterStats {
+    /// Number of inserted hashes (including the removed cells)
+    pub inserted: u64,
+    /// Estimated false positive rate for the number of inserted hashes
+    pub false_positive_rate: f64,
+    /// Size of the filter in bytes
+    pub memory_usage: usize,
+    /// Whether the filter is filled and used to skip reads
+    pub ready: bool,
+}
+
+const MAX_HASH_COUNT: u32 = 16;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives() {
+        let filter = CellsFilter::new(CellsFilterOptions {
+            capacity: 10_000,
+            false_positive_rate: 0.01,
+        });
+
+        // Filter is not used until it is ready
+        assert!(filter.may_contain(&rand::random()));
+        filter.set_ready();
+
+        let inserted = (0..10_000)
+            .map(|_| rand::random::<[u8; 32]>())
+            .collect::<Vec<_>>();
+        for hash in &inserted {
+            filter.insert(hash);
+        }
+        assert!(inserted.iter().all(|hash| filter.may_contain(hash)));
+
+        let false_positives = (0..10_000)
+            .filter(|_| filter.may_contain(&rand::random()))
+            .count();
+        assert!(
+            false_positives < 300,
+            "too many false positives: {}",
+            false_positives
+        );
+
+        let stats = filter.stats();
+        assert_eq!(stats.inserted, 10_000);
+        assert!(stats.false_positive_rate > 0.005 && stats.false_positive_rate < 0.02);
+        assert!(stats.memory_usage >= 10_000);
+    }
+}
@@ -6,15 +6,24 @@ use std::time::Instant;
 use anyhow::{Context, Result};
 use ton_types::UInt256;
 
+#[cfg(feature = "bench")]
+pub use self::cell_storage::CellStorage;
 use self::cell_storage::*;
+pub use self::cell_storage::{
+    CellStorageOptions, CellStoredCallback, CELLS_FORMAT_COMPACT, CELLS_FORMAT_INLINE,
+};
+pub use self::cells_filter::CellsFilterStats;
 use self::files_context::FilesContext;
 use self::replace_transaction::ShardStateReplaceTransaction;
 use super::{BlockHandle, BlockHandleStorage, BlockStorage};
 use crate::db::*;
 use crate::utils::*;
 
+mod cache_stats_window;
 mod cell_storage;
 mod cell_writer;
+mod cells_cache;
+mod cells_filter;
 mod entries_buffer;
 mod files_context;
 mod replace_transaction;
@@ -40,10 +49,12 @@ impl ShardStateStorage {
         block_handle_storage: Arc<BlockHandleStorage>,
         block_storage: Arc<BlockStorage>,
         file_db_path: PathBuf,
+        cell_storage_options: CellStorageOptions,
     ) -> Result<Self> {
         let downloads_dir = prepare_file_db_dir(file_db_path, "downloads").await?;
 
-        let cell_storage = CellStorage::new(db.clone())?;
+        let cache_shards = num_cpus::get() * CELLS_CACHE_SHARDS_PER_CPU;
+        let cell_storage = CellStorage::new(db.clone(), cache_shards, cell_storage_options)?;
 
         let res = Self {
             db,
@@ -72,9 +83,24 @@ impl ShardStateStorage {
             storage_cell_max_live_count: storage_cell.max_live,
             max_new_mc_cell_count: self.max_new_mc_cell_count.swap(0, Ordering::AcqRel),
             max_new_sc_cell_count: self.max_new_sc_cell_count.swap(0, Ordering::AcqRel),
+            cells_filter: self.cell_storage.cells_filter_stats(),
+            cells_cache_weak_expired: self.cell_storage.cache_weak_expired(),
+            cells_cache_effectiveness: self.cell_storage.cache_effectiveness(),
         }
     }
 
+    pub fn cells_cache_hit_ratio(&self) -> Option<f64> {
+        self.cell_storage.cache_hit_ratio()
+    }
+
+    pub fn recent_cells_cache_hit_ratio(&self, window_sec: u32) -> Option<f64> {
+        self.cell_storage.recent_cache_hit_ratio(window_sec)
+    }
+
+    pub fn set_on_cell_stored(&self, callback: Option<CellStoredCallback>) {
+        self.cell_storage.set_on_cell_stored(callback);
+    }
+
     pub fn min_ref_mc_state(&self) -> &Arc<MinRefMcState> {
         &self.min_ref_mc_state
     }
@@ -99,9 +125,11 @@ impl ShardStateStorage {
 
         let _gc_lock = self.gc_lock.read().await;
 
-        let len = self
-            .cell_storage
-            .store_cell(&mut batch, state.root_cell().clone())?;
+        let (len, stored_cells) = self.cell_storage.store_cell_in_reported(
+            CellColumn::for_shard(&block_id.shard_id),
+            &mut batch,
+            state.root_cell().clone(),
+        )?;
 
         if block_id.shard_id.is_masterchain() {
             self.max_new_mc_cell_count.fetch_max(len, Ordering::Release);
@@ -121,6 +149,7 @@ impl ShardStateStorage {
         );
 
         self.db.raw().write(batch)?;
+        stored_cells.committed();
 
         Ok(if handle.meta().set_has_state() {
             self.block_handle_storage.store_handle(handle)?;
@@ -135,7 +164,9 @@ impl ShardStateStorage {
         block_id: &ton_block::BlockIdExt,
     ) -> Result<Arc<ShardStateStuff>> {
         let cell_id = self.load_state_root(block_id.shard_id, block_id.seq_no)?;
-        let cell = self.cell_storage.load_cell(cell_id)?;
+        let cell = self
+            .cell_storage
+            .load_cell_in(CellColumn::for_shard(&block_id.shard_id), cell_id)?;
 
         ShardStateStuff::new(
             block_id.clone(),
@@ -166,6 +197,17 @@ impl ShardStateStorage {
             .await?
             .context("Recent blocks edge not found")?;
 
+        self.remove_states_impl(&top_blocks).await?;
+        Ok(top_blocks)
+    }
+
+    /// Removes all states before the specified top blocks
+    pub async fn remove_states_before(&self, top_blocks: &TopBlocks) -> Result<ShardStatesGcStats> {
+        let _compaction_guard = self.db.delay_compaction().await;
+        self.remove_states_impl(top_blocks).await
+    }
+
+    async fn remove_states_impl(&self, top_blocks: &TopBlocks) -> Result<ShardStatesGcStats> {
         tracing::info!(
             block_id = %top_blocks.mc_block.display(),
             "starting shard states GC",
@@ -214,9 +256,12 @@ impl ShardStateStorage {
             let mut batch = rocksdb::WriteBatch::default();
             {
                 let _guard = self.gc_lock.write().await;
-                let total = self
-                    .cell_storage
-                    .remove_cell(&mut batch, &alloc, root_hash)?;
+                let total = self.cell_storage.remove_cell_in(
+                    CellColumn::for_shard(&shard_ident),
+                    &mut batch,
+                    &alloc,
+                    root_hash,
+                )?;
                 batch.delete_cf(&shard_states_cf.bound(), key);
                 raw.write_opt(batch, cells_write_options)?;
 
@@ -239,7 +284,10 @@ impl ShardStateStorage {
             elapsed_sec = instant.elapsed().as_secs_f64(),
             "finished shard states GC",
         );
-        Ok(top_blocks)
+        Ok(ShardStatesGcStats {
+            removed_states,
+            removed_cells,
+        })
     }
 
     /// Searches for an edge with the least referenced masterchain block
@@ -341,6 +389,12 @@ impl ShardStateStorage {
     }
 }
 
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ShardStatesGcStats {
+    pub removed_states: usize,
+    pub removed_cells: usize,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct ShardStateStorageMetrics {
     #[cfg(feature = "count-cells")]
@@ -349,8 +403,21 @@ pub struct ShardStateStorageMetrics {
     pub storage_cell_max_live_count: usize,
     pub max_new_mc_cell_count: usize,
     pub max_new_sc_cell_count: usize,
+    /// `None` if the cells filter is disabled
+    pub cells_filter: Option<CellsFilterStats>,
+    /// Cells cache misses of dropped cells (see [`CellStorage::cache_weak_expired`])
+    pub cells_cache_weak_expired: u64,
+    /// `None` if no cached cells were loaded yet
+    /// (see [`CellStorage::cache_effectiveness`])
+    pub cells_cache_effectiveness: Option<f64>,
 }
 
+/// Number of cells cache shards per CPU.
+///
+/// NOTE: the default shard amount of `DashMap` is 4 per CPU. Compare the
+/// values with the `cells_cache` bench (`cargo bench --features bench`)
+const CELLS_CACHE_SHARDS_PER_CPU: usize = 16;
+
 async fn prepare_file_db_dir(file_db_path: PathBuf, folder: &str) -> Result<Arc<PathBuf>> {
     let dir = Arc::new(file_db_path.join(folder));
     tokio::fs::create_dir_all(dir.as_ref()).await?;
@@ -418,6 +418,7 @@ impl<'a> ShardStateReplaceTransaction<'a> {
             current_entry.as_reader().hash(MAX_LEVEL)
         };
 
+        self.cell_storage.register_stored_cell(repr_hash);
         ctx.write_batch
             .merge_cf(&ctx.cells_cf, repr_hash, output_buffer.as_slice());
         ctx.cell_usages.insert(*repr_hash, -1);
@@ -0,0 +1,139 @@
+use anyhow::Result;
+
+pub use self::retention_policy::*;
+use super::block_handle_storage::KeyBlocksDirection;
+use super::block_storage::BlockGcStats;
+use super::shard_state_storage::ShardStatesGcStats;
+use super::Storage;
+
+mod retention_policy;
+
+/// Runs GC for all storage column families in a consistent order:
+/// blocks, then archives, then shard states (cells).
+///
+/// All steps share the same top blocks edge, so they never disagree
+/// about which data is still required.
+pub struct StorageGc<'a> {
+    storage: &'a Storage,
+}
+
+impl<'a> StorageGc<'a> {
+    pub(super) fn new(storage: &'a Storage) -> Self {
+        Self { storage }
+    }
+
+    /// Computes the retention edge using the policy.
+    ///
+    /// Returns `None` if the top blocks edge for `mc_seq_no` was not found
+    pub async fn compute_retention(
+        &self,
+        policy: &dyn RetentionPolicy,
+        mc_seq_no: u32,
+    ) -> Result<Option<RetentionCutoff>> {
+        let top_blocks = match self
+            .storage
+            .shard_state_storage()
+            .compute_recent_blocks(mc_seq_no)
+            .await?
+        {
+            Some(top_blocks) => top_blocks,
+            None => return Ok(None),
+        };
+
+        let archives = self.storage.block_storage().archive_byte_lens()?;
+
+        let block_handle_storage = self.storage.block_handle_storage();
+        let mut key_blocks = Vec::new();
+        for block_id in block_handle_storage.key_blocks_iterator(KeyBlocksDirection::ForwardFrom(0))
+        {
+            let block_id = block_id?;
+            if let Some(handle) = block_handle_storage.load_handle(&block_id)? {
+                key_blocks.push((block_id.seq_no, handle.meta().gen_utime()));
+            }
+        }
+
+        Ok(Some(policy.compute(&RetentionContext {
+            archives: &archives,
+            top_blocks: &top_blocks,
+            key_blocks: &key_blocks,
+            now: broxus_util::now(),
+        })))
+    }
+
+    pub async fn run(&self, retention: GcRetention) -> Result<StorageGcStats> {
+        let mut stats = StorageGcStats::default();
+
+        // Compute recent block ids once for all steps
+        let top_blocks = match self
+            .storage
+            .shard_state_storage()
+            .compute_recent_blocks(retention.mc_seq_no)
+            .await?
+        {
+            Some(top_blocks) => top_blocks,
+            None => {
+                tracing::info!(
+                    mc_seq_no = retention.mc_seq_no,
+                    "storage GC: recent blocks edge not found, skipping"
+                );
+                return Ok(stats);
+            }
+        };
+
+        tracing::info!(
+            block_id = %top_blocks.mc_block.display(),
+            "storage GC: started"
+        );
+
+        // NOTE: each step acquires its own compaction guard
+        let block_storage = self.storage.block_storage();
+        stats.blocks = block_storage
+            .remove_blocks_before(
+                &top_blocks,
+                retention.max_blocks_per_batch,
+                retention.proofs_retention_sec,
+                retention.keep_proofs,
+            )
+            .await?;
+
+        if let Some(until_id) = retention.archives_until_id {
+            block_storage.remove_outdated_archives(until_id).await?;
+            stats.archives_removed = true;
+        }
+
+        stats.states = self
+            .storage
+            .shard_state_storage()
+            .remove_states_before(&top_blocks)
+            .await?;
+
+        tracing::info!(
+            block_id = %top_blocks.mc_block.display(),
+            ?stats,
+            "storage GC: finished"
+        );
+
+        Ok(stats)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GcRetention {
+    /// Masterchain seqno used to compute the top blocks edge
+    pub mc_seq_no: u32,
+    /// Remove all archives before this id (archives GC is skipped if `None`)
+    pub archives_until_id: Option<u32>,
+    /// Max number of removed package entries per write batch
+    pub max_blocks_per_batch: Option<usize>,
+    /// Min age of proof entries before they can be removed
+    pub proofs_retention_sec: Option<u32>,
+    /// Never remove proof entries
+    pub keep_proofs: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StorageGcStats {
+    pub blocks: BlockGcStats,
+    pub archives_removed: bool,
+    pub states: ShardStatesGcStats,
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+
+use crate::config::RetentionPolicyConfig;
+use crate::utils::TopBlocks;
+
+/// Computes the retention edge for [`StorageGc`](super::StorageGc)
+pub trait RetentionPolicy: Send + Sync {
+    fn compute(&self, ctx: &RetentionContext<'_>) -> RetentionCutoff;
+}
+
+/// Current storage state used by [`RetentionPolicy`]
+pub struct RetentionContext<'a> {
+    /// Stored archives with their sizes in bytes
+    pub archives: &'a BTreeMap<u32, u64>,
+    /// Top blocks of the latest masterchain block
+    pub top_blocks: &'a TopBlocks,
+    /// Stored key blocks as `(seqno, gen_utime)`, ordered by seqno
+    pub key_blocks: &'a [(u32, u32)],
+    /// Current unix time
+    pub now: u32,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RetentionCutoff {
+    /// Masterchain seqno used to compute the blocks and states GC edge
+    pub mc_seq_no: u32,
+    /// Remove all archives before this id (archives GC is skipped if `None`)
+    pub archives_until_id: Option<u32>,
+}
+
+/// Keeps the specified number of the latest masterchain blocks
+#[derive(Debug, Copy, Clone)]
+pub struct SeqnoWindow {
+    pub mc_blocks: u32,
+}
+
+impl RetentionPolicy for SeqnoWindow {
+    fn compute(&self, ctx: &RetentionContext<'_>) -> RetentionCutoff {
+        let mc_seq_no = ctx.top_blocks.seqno().saturating_sub(self.mc_blocks);
+        RetentionCutoff {
+            mc_seq_no,
+            archives_until_id: Some(mc_seq_no),
+        }
+    }
+}
+
+/// Keeps blocks since the latest key block older than `retention_sec`.
+///
+/// NOTE: archive ids are assumed to be masterchain seqnos
+#[derive(Debug, Copy, Clone)]
+pub struct TimeWindow {
+    pub retention_sec: u32,
+}
+
+impl RetentionPolicy for TimeWindow {
+    fn compute(&self, ctx: &RetentionContext<'_>) -> RetentionCutoff {
+        let until_utime = ctx.now.saturating_sub(self.retention_sec);
+        let mc_seq_no = ctx
+            .key_blocks
+            .iter()
+            .rev()
+            .find(|(_, gen_utime)| *gen_utime <= until_utime)
+            .map(|(seq_no, _)| std::cmp::min(*seq_no, ctx.top_blocks.seqno()))
+            .unwrap_or_default();
+
+        RetentionCutoff {
+            mc_seq_no,
+            archives_until_id: Some(mc_seq_no),
+        }
+    }
+}
+
+/// Keeps the newest archives which fit into `max_archives_bytes`.
+///
+/// NOTE: archive ids are assumed to be masterchain seqnos
+#[derive(Debug, Copy, Clone)]
+pub struct DiskBudget {
+    pub max_archives_bytes: u64,
+}
+
+impl RetentionPolicy for DiskBudget {
+    fn compute(&self, ctx: &RetentionContext<'_>) -> RetentionCutoff {
+        let mut total = 0u64;
+        let mut until_id = None;
+        for (id, size) in ctx.archives.iter().rev() {
+            total = total.saturating_add(*size);
+            if total > self.max_archives_bytes {
+                break;
+            }
+            until_id = Some(*id);
+        }
+
+        // NOTE: the newest archive is always retained
+        let until_id = until_id.or_else(|| ctx.archives.keys().next_back().copied());
+
+        RetentionCutoff {
+            mc_seq_no: match until_id {
+                Some(until_id) => std::cmp::min(until_id, ctx.top_blocks.seqno()),
+                None => ctx.top_blocks.seqno(),
+            },
+            archives_until_id: until_id,
+        }
+    }
+}
+
+impl From<RetentionPolicyConfig> for Box<dyn RetentionPolicy> {
+    fn from(config: RetentionPolicyConfig) -> Self {
+        match config {
+            RetentionPolicyConfig::SeqnoWindow { mc_blocks } => Box::new(SeqnoWindow { mc_blocks }),
+            RetentionPolicyConfig::TimeWindow { retention_sec } => {
+                Box::new(TimeWindow { retention_sec })
+            }
+            RetentionPolicyConfig::DiskBudget { max_archives_bytes } => {
+                Box::new(DiskBudget { max_archives_bytes })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn top_blocks(seqno: u32) -> TopBlocks {
+        TopBlocks {
+            mc_block: (ton_block::ShardIdent::masterchain(), seqno),
+            shard_heights: Default::default(),
+        }
+    }
+
+    #[test]
+    fn builtin_policies() {
+        let archives = BTreeMap::from([(0, 100), (100, 100), (200, 100), (300, 50)]);
+        let top_blocks = top_blocks(350);
+        let key_blocks = [(0, 1000), (150, 2000), (250, 3000)];
+        let ctx = RetentionContext {
+            archives: &archives,
+            top_blocks: &top_blocks,
+            key_blocks: &key_blocks,
+            now: 3500,
+        };
+
+        let cutoff = SeqnoWindow { mc_blocks: 100 }.compute(&ctx);
+        assert_eq!(cutoff.mc_seq_no, 250);
+        assert_eq!(cutoff.archives_until_id, Some(250));
+
+        let cutoff = TimeWindow {
+            retention_sec: 1000,
+        }
+        .compute(&ctx);
+        assert_eq!(cutoff.mc_seq_no, 150);
+
+        // No key blocks old enough
+        let cutoff = TimeWindow {
+            retention_sec: 10000,
+        }
+        .compute(&ctx);
+        assert_eq!(cutoff.mc_seq_no, 0);
+
+        let cutoff = DiskBudget {
+            max_archives_bytes: 200,
+        }
+        .compute(&ctx);
+        assert_eq!(cutoff.archives_until_id, Some(200));
+        assert_eq!(cutoff.mc_seq_no, 200);
+
+        // The newest archive is retained even if it exceeds the budget
+        let cutoff = DiskBudget {
+            max_archives_bytes: 10,
+        }
+        .compute(&ctx);
+        assert_eq!(cutoff.archives_until_id, Some(300));
+    }
+}
@@ -1,3 +1,5 @@
+use super::FastHashSet;
+
 /// Encodes archive package segment
 pub fn make_archive_segment(filename: &str, data: &[u8]) -> Vec<u8> {
     let mut vec = Vec::with_capacity(2 + 2 + 4 + filename.len() + data.len());
@@ -23,6 +25,11 @@ impl<'a> ArchivePackageViewReader<'a> {
         Ok(Self { data, offset })
     }
 
+    /// Offset of the next archive package segment
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
     /// Reads next archive package segment
     pub fn read_next(
         &mut self,
@@ -31,6 +38,37 @@ impl<'a> ArchivePackageViewReader<'a> {
     }
 }
 
+/// Counts entries of the archive package without copying their data
+pub fn count_archive_entries(data: &[u8]) -> Result<usize, ArchivePackageError> {
+    let (count, result) = count_readable_archive_entries(data);
+    result.map(|_| count)
+}
+
+/// Same as [`count_archive_entries`], but also returns the number
+/// of entries read before the framing error
+pub fn count_readable_archive_entries(data: &[u8]) -> (usize, Result<(), ArchivePackageError>) {
+    let mut reader = match ArchivePackageViewReader::new(data) {
+        Ok(reader) => reader,
+        Err(e) => return (0, Err(e)),
+    };
+
+    let mut count = 0;
+    loop {
+        match reader.read_next() {
+            Ok(Some(_)) => count += 1,
+            Ok(None) => break,
+            Err(e) => return (count, Err(e)),
+        }
+    }
+
+    // Trailing bytes which are not enough for the entry header
+    if reader.offset != data.len() {
+        return (count, Err(ArchivePackageError::UnexpectedArchiveEof));
+    }
+
+    (count, Ok(()))
+}
+
 fn read_package_header(buf: &[u8], offset: &mut usize) -> Result<(), ArchivePackageError> {
     let end = *offset;
 
@@ -122,7 +160,27 @@ impl ArchivePackageVerifier {
 
     /// Verifies next archive package segment
     pub fn verify(&mut self, part: &[u8]) -> Result<(), ArchivePackageError> {
+        self.verify_entries(part).map(|_| ())
+    }
+
+    /// Same as [`ArchivePackageVerifier::verify`], but returns the number
+    /// of entries whose headers were completed in this segment
+    pub fn verify_entries(&mut self, part: &[u8]) -> Result<usize, ArchivePackageError> {
+        self.verify_with(part, |_, _| Ok(()))
+    }
+
+    /// Same as [`ArchivePackageVerifier::verify_entries`], but passes filename parts
+    /// of the entries to `on_filename` along with the flag of the last part
+    fn verify_with<F>(
+        &mut self,
+        part: &[u8],
+        mut on_filename: F,
+    ) -> Result<usize, ArchivePackageError>
+    where
+        F: FnMut(&[u8], bool) -> Result<(), ArchivePackageError>,
+    {
         let mut offset = 0;
+        let mut entries = 0;
 
         let part_len = part.len();
 
@@ -160,13 +218,22 @@ impl ArchivePackageVerifier {
                         if buffer[..2] != ARCHIVE_ENTRY_PREFIX {
                             return Err(ArchivePackageError::InvalidArchiveEntryHeader);
                         }
+                        entries += 1;
 
-                        *self = Self::PackageFileName {
-                            filename_len: u16::from_le_bytes([buffer[2], buffer[3]]) as usize,
-                            data_len: u32::from_le_bytes([
-                                buffer[4], buffer[5], buffer[6], buffer[7],
-                            ]) as usize,
-                        }
+                        let filename_len = u16::from_le_bytes([buffer[2], buffer[3]]) as usize;
+                        let data_len =
+                            u32::from_le_bytes([buffer[4], buffer[5], buffer[6], buffer[7]])
+                                as usize;
+
+                        *self = if filename_len == 0 {
+                            on_filename(&[], true)?;
+                            Self::PackageData { data_len }
+                        } else {
+                            Self::PackageFileName {
+                                filename_len,
+                                data_len,
+                            }
+                        };
                     }
                 }
                 Self::PackageFileName {
@@ -175,6 +242,7 @@ impl ArchivePackageVerifier {
                 } => {
                     let remaining = std::cmp::min(remaining, *filename_len);
                     *filename_len -= remaining;
+                    on_filename(&part[offset..offset + remaining], *filename_len == 0)?;
                     offset += remaining;
 
                     if *filename_len == 0 {
@@ -198,7 +266,7 @@ impl ArchivePackageVerifier {
             }
         }
 
-        Ok(())
+        Ok(entries)
     }
 }
 
@@ -208,6 +276,49 @@ impl Default for ArchivePackageVerifier {
     }
 }
 
+/// Same as [`ArchivePackageVerifier`], but also checks that entry filenames are unique
+#[derive(Default)]
+pub struct UniqueArchiveEntriesVerifier {
+    inner: ArchivePackageVerifier,
+    /// Filename of the current entry
+    filename: Vec<u8>,
+    seen_filenames: FastHashSet<Box<[u8]>>,
+}
+
+impl UniqueArchiveEntriesVerifier {
+    /// Ensures that the verifier is in the correct state
+    pub fn final_check(&self) -> Result<(), ArchivePackageError> {
+        self.inner.final_check()
+    }
+
+    /// Verifies next archive package segment
+    pub fn verify(&mut self, part: &[u8]) -> Result<(), ArchivePackageError> {
+        self.verify_entries(part).map(|_| ())
+    }
+
+    /// Same as [`UniqueArchiveEntriesVerifier::verify`], but returns the number
+    /// of entries whose headers were completed in this segment
+    pub fn verify_entries(&mut self, part: &[u8]) -> Result<usize, ArchivePackageError> {
+        let filename = &mut self.filename;
+        let seen_filenames = &mut self.seen_filenames;
+        self.inner.verify_with(part, |name_part, is_last| {
+            filename.extend_from_slice(name_part);
+            if !is_last {
+                return Ok(());
+            }
+
+            // Remember the filename of the current entry, failing if it was already seen
+            if seen_filenames.contains(filename.as_slice()) {
+                return Err(ArchivePackageError::DuplicateEntry {
+                    filename: String::from_utf8_lossy(filename).into_owned(),
+                });
+            }
+            seen_filenames.insert(std::mem::take(filename).into_boxed_slice());
+            Ok(())
+        })
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum ArchivePackageError {
     #[error("Invalid archive header")]
@@ -222,8 +333,82 @@ pub enum ArchivePackageError {
     UnexpectedEntryEof,
     #[error("Too small initial batch")]
     TooSmallInitialBatch,
+    #[error("Duplicate archive entry {filename}")]
+    DuplicateEntry { filename: String },
 }
 
 pub const ARCHIVE_PREFIX: [u8; 4] = u32::to_le_bytes(0xae8fdd01);
 const ARCHIVE_ENTRY_PREFIX: [u8; 2] = u16::to_le_bytes(0x1e8b);
 const ARCHIVE_ENTRY_HEADER_LEN: usize = ARCHIVE_ENTRY_PREFIX.len() + 2 + 4; // magic + filename len + data len
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_entries() {
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        assert_eq!(count_archive_entries(&archive).unwrap(), 0);
+
+        archive.extend_from_slice(&make_archive_segment("first", &[1, 2, 3]));
+        archive.extend_from_slice(&make_archive_segment("second", &[]));
+        assert_eq!(count_archive_entries(&archive).unwrap(), 2);
+
+        // Truncated entry header
+        archive.extend_from_slice(&ARCHIVE_ENTRY_PREFIX);
+        assert!(count_archive_entries(&archive).is_err());
+        assert!(matches!(
+            count_readable_archive_entries(&archive),
+            (2, Err(ArchivePackageError::UnexpectedArchiveEof))
+        ));
+
+        // Invalid archive header
+        assert!(count_archive_entries(&[0; 4]).is_err());
+    }
+
+    #[test]
+    fn verify_in_windows() {
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        archive.extend_from_slice(&make_archive_segment("first", &[1, 2, 3]));
+        archive.extend_from_slice(&make_archive_segment("second", &[]));
+
+        for window in [4, 5, 7, archive.len()] {
+            let mut verifier = ArchivePackageVerifier::default();
+            let mut entries = 0;
+            for part in archive.chunks(window) {
+                entries += verifier.verify_entries(part).unwrap();
+            }
+            verifier.final_check().unwrap();
+            assert_eq!(entries, 2);
+        }
+
+        // Truncated entry data
+        let mut verifier = ArchivePackageVerifier::default();
+        verifier.verify(&archive[..archive.len() - 1]).unwrap();
+        assert!(verifier.final_check().is_err());
+    }
+
+    #[test]
+    fn verify_duplicate_entries() {
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        archive.extend_from_slice(&make_archive_segment("first", &[1, 2, 3]));
+        archive.extend_from_slice(&make_archive_segment("second", &[]));
+        archive.extend_from_slice(&make_archive_segment("first", &[1, 2, 3]));
+
+        for window in [7, 9, archive.len()] {
+            let mut verifier = UniqueArchiveEntriesVerifier::default();
+            let result = archive
+                .chunks(window)
+                .try_for_each(|part| verifier.verify(part));
+            assert!(matches!(
+                result,
+                Err(ArchivePackageError::DuplicateEntry { filename }) if filename == "first"
+            ));
+        }
+
+        // Plain verifier checks only the framing
+        let mut verifier = ArchivePackageVerifier::Start;
+        verifier.verify(&archive).unwrap();
+        verifier.final_check().unwrap();
+    }
+}
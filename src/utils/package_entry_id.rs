@@ -57,26 +57,83 @@ where
         }
     }
 
+    /// Returns package entry kind
+    pub fn kind(&self) -> PackageEntryKind {
+        match self {
+            Self::Block(_) => PackageEntryKind::Block,
+            Self::Proof(_) => PackageEntryKind::Proof,
+            Self::ProofLink(_) => PackageEntryKind::ProofLink,
+        }
+    }
+
     /// Constructs on-stack buffer with the serialized object
-    pub fn to_vec(&self) -> SmallVec<[u8; BlockIdShort::SIZE_HINT + 32 + 1]> {
-        let mut result = SmallVec::with_capacity(BlockIdShort::SIZE_HINT + 32 + 1);
-        let (block_id, ty) = match self {
-            Self::Block(id) => (id, 0),
-            Self::Proof(id) => (id, 1),
-            Self::ProofLink(id) => (id, 2),
-        };
+    pub fn to_vec(&self) -> SmallVec<[u8; PACKAGE_ENTRY_KEY_LEN]> {
+        let mut result = SmallVec::with_capacity(PACKAGE_ENTRY_KEY_LEN);
+        let (Self::Block(block_id) | Self::Proof(block_id) | Self::ProofLink(block_id)) = self;
         let block_id = block_id.borrow();
 
         result.extend_from_slice(&block_id.shard_id.workchain_id().to_be_bytes());
         result.extend_from_slice(&block_id.shard_id.shard_prefix_with_tag().to_be_bytes());
         result.extend_from_slice(&block_id.seq_no.to_be_bytes());
         result.extend_from_slice(block_id.root_hash.as_slice());
-        result.push(ty);
+        result.push(self.kind() as u8);
 
         result
     }
 }
 
+/// Kind of the package entry
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[repr(u8)]
+pub enum PackageEntryKind {
+    Block = 0,
+    Proof = 1,
+    ProofLink = 2,
+}
+
+impl PackageEntryKind {
+    /// Decodes the kind from the serialized package entry id (see [`PackageEntryId::to_vec`])
+    pub fn from_key(key: &[u8]) -> Option<Self> {
+        match key {
+            [.., kind] if key.len() == PACKAGE_ENTRY_KEY_LEN => Self::from_u8(*kind),
+            _ => None,
+        }
+    }
+
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        Some(match kind {
+            0 => Self::Block,
+            1 => Self::Proof,
+            2 => Self::ProofLink,
+            _ => return None,
+        })
+    }
+}
+
+/// Parsed key of the package entries column
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PackageEntryKey {
+    pub shard_ident: ton_block::ShardIdent,
+    pub seq_no: u32,
+    pub root_hash: ton_types::UInt256,
+    pub kind: PackageEntryKind,
+}
+
+impl PackageEntryKey {
+    pub fn from_slice(mut data: &[u8]) -> Result<Self> {
+        let (shard_ident, seq_no) = BlockIdShort::deserialize(&mut data)?;
+        match data {
+            [root_hash @ .., kind] if root_hash.len() == 32 => Ok(Self {
+                shard_ident,
+                seq_no,
+                root_hash: ton_types::UInt256::from_be_bytes(root_hash),
+                kind: PackageEntryKind::from_u8(*kind).ok_or(PackageEntryIdError::InvalidKey)?,
+            }),
+            _ => Err(PackageEntryIdError::InvalidKey.into()),
+        }
+    }
+}
+
 pub trait GetFileName {
     /// Returns string representation of the package entry id
     fn filename(&self) -> String;
@@ -158,6 +215,8 @@ fn parse_block_id(filename: &str) -> Result<ton_block::BlockIdExt> {
     })
 }
 
+const PACKAGE_ENTRY_KEY_LEN: usize = BlockIdShort::SIZE_HINT + 32 + 1;
+
 const PACKAGE_ENTRY_BLOCK: &str = "block_";
 const PACKAGE_ENTRY_PROOF: &str = "proof_";
 const PACKAGE_ENTRY_PROOF_LINK: &str = "prooflink_";
@@ -178,6 +237,8 @@ enum PackageEntryIdError {
     RootHashNotFound,
     #[error("File hash not found")]
     FileHashNotFound,
+    #[error("Invalid package entry key")]
+    InvalidKey,
 }
 
 #[cfg(test)]
@@ -204,4 +265,30 @@ mod tests {
         check_package_id(PackageEntryId::Proof(block_id.clone()));
         check_package_id(PackageEntryId::ProofLink(block_id));
     }
+
+    #[test]
+    fn kind_from_key() {
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::full(0),
+            seq_no: 123,
+            root_hash: ton_types::UInt256::rand(),
+            file_hash: ton_types::UInt256::rand(),
+        };
+
+        for id in [
+            PackageEntryId::Block(&block_id),
+            PackageEntryId::Proof(&block_id),
+            PackageEntryId::ProofLink(&block_id),
+        ] {
+            let key = id.to_vec();
+            assert_eq!(PackageEntryKind::from_key(&key), Some(id.kind()));
+            assert_eq!(PackageEntryKey::from_slice(&key).unwrap().kind, id.kind());
+        }
+
+        let mut key = PackageEntryId::Block(&block_id).to_vec();
+        assert_eq!(PackageEntryKind::from_key(&key[..16]), None);
+        *key.last_mut().unwrap() = 3;
+        assert_eq!(PackageEntryKind::from_key(&key), None);
+        assert!(PackageEntryKey::from_slice(&key).is_err());
+    }
 }
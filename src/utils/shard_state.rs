@@ -58,6 +58,14 @@ impl ShardStateStuff {
         })
     }
 
+    /// Returns the same state for another block with the same state root
+    pub fn with_block_id(&self, block_id: ton_block::BlockIdExt) -> Self {
+        Self {
+            block_id,
+            ..self.clone()
+        }
+    }
+
     pub fn construct_split_root(left: Cell, right: Cell) -> Result<Cell> {
         ton_block::ShardStateSplit { left, right }.serialize()
     }
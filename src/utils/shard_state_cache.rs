@@ -1,17 +1,34 @@
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 
+use dashmap::try_result::TryResult;
+use ton_types::UInt256;
+
 use super::shard_state::ShardStateStuff;
 use super::top_blocks::*;
 use super::FastDashMap;
-use crate::config::ShardStateCacheOptions;
+use crate::config::{ShardStateCacheInsertPolicy, ShardStateCacheOptions};
 
 /// LRU cache for shard states
 ///
 /// [`ShardStateStuff`]
 pub struct ShardStateCache {
     ttl: Option<Duration>,
+    capacity: Option<usize>,
+    insert_policy: ShardStateCacheInsertPolicy,
     map: Option<ShardStatesMap>,
+    /// Logical time of the last access, used to find the least recently used state
+    clock: AtomicU64,
+    /// Optional index of the cached states by root cell hash
+    roots: Option<FastDashMap<UInt256, Weak<ShardStateStuff>>>,
+    dedup_count: AtomicU64,
+    on_evict: Option<EvictionCallback>,
+    ttl_evictions: AtomicU64,
+    outdated_evictions: AtomicU64,
+    capacity_evictions: AtomicU64,
+    cleared_evictions: AtomicU64,
+    rejected_inserts: AtomicU64,
 }
 
 impl ShardStateCache {
@@ -19,21 +36,55 @@ impl ShardStateCache {
     pub fn new(config: Option<ShardStateCacheOptions>) -> Self {
         match config.map(|config| {
             let ttl = Duration::from_secs(config.ttl_sec);
-            (ttl, ShardStatesMap::default())
+            let roots = config.dedup_by_root.then(Default::default);
+            (ttl, ShardStatesMap::default(), roots, config)
         }) {
             // Cache is enabled and should be cleared every TTL interval
-            Some((ttl, map)) => Self {
+            Some((ttl, map, roots, config)) => Self {
                 ttl: Some(ttl),
+                capacity: config.capacity,
+                insert_policy: config.insert_policy,
                 map: Some(map),
+                clock: Default::default(),
+                roots,
+                dedup_count: Default::default(),
+                on_evict: None,
+                ttl_evictions: Default::default(),
+                outdated_evictions: Default::default(),
+                capacity_evictions: Default::default(),
+                cleared_evictions: Default::default(),
+                rejected_inserts: Default::default(),
             },
             // Cache is disabled
             None => Self {
                 ttl: None,
+                capacity: None,
+                insert_policy: Default::default(),
                 map: None,
+                clock: Default::default(),
+                roots: None,
+                dedup_count: Default::default(),
+                on_evict: None,
+                ttl_evictions: Default::default(),
+                outdated_evictions: Default::default(),
+                capacity_evictions: Default::default(),
+                cleared_evictions: Default::default(),
+                rejected_inserts: Default::default(),
             },
         }
     }
 
+    /// Sets a callback which is called for each evicted block id.
+    ///
+    /// NOTE: the callback is called after the cache shards are unlocked
+    pub fn with_on_evict<F>(mut self, on_evict: F) -> Self
+    where
+        F: Fn(&ton_block::BlockIdExt) + Send + Sync + 'static,
+    {
+        self.on_evict = Some(Box::new(on_evict));
+        self
+    }
+
     pub fn ttl(&self) -> Option<Duration> {
         self.ttl
     }
@@ -45,36 +96,219 @@ impl ShardStateCache {
     pub fn get(&self, block_id: &ton_block::BlockIdExt) -> Option<Arc<ShardStateStuff>> {
         if let Some(map) = &self.map {
             let entry = map.get(block_id)?;
-            Some(entry.value().clone())
+            Some(self.touch(entry.value()))
+        } else {
+            None
+        }
+    }
+
+    /// Same as [`ShardStateCache::get`], but doesn't wait for the locked shard.
+    ///
+    /// Returns `None` if the key doesn't exist, the cache is disabled or
+    /// the map shard is currently locked
+    pub fn try_get(&self, block_id: &ton_block::BlockIdExt) -> Option<Arc<ShardStateStuff>> {
+        if let Some(map) = &self.map {
+            match map.try_get(block_id) {
+                TryResult::Present(entry) => Some(self.touch(entry.value())),
+                TryResult::Absent | TryResult::Locked => None,
+            }
         } else {
             None
         }
     }
 
     /// Inserts a key-value pair into the cache (if enabled).
+    ///
+    /// If deduplication by root is enabled and there is a cached state with
+    /// the same root cell, its cells are reused instead of the new ones.
+    ///
+    /// If the cache is full, the state is inserted according to the insert policy
+    /// (see [`ShardStateCacheInsertPolicy`]).
+    ///
+    /// NOTE: concurrent insertions could slightly exceed the capacity
     pub fn set<F>(&self, block_id: &ton_block::BlockIdExt, factory: F)
     where
         F: FnOnce() -> Arc<ShardStateStuff>,
     {
         if let Some(map) = &self.map {
-            map.insert(block_id.clone(), factory());
+            let requested_at = self.tick();
+            let is_full = matches!(self.capacity, Some(capacity) if map.len() >= capacity)
+                && !map.contains_key(block_id);
+
+            if is_full && self.insert_policy == ShardStateCacheInsertPolicy::InsertIfRoom {
+                self.rejected_inserts.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+
+            let state = match &self.roots {
+                Some(roots) => self.dedup(roots, block_id, factory()),
+                None => factory(),
+            };
+
+            if is_full && !self.evict_tail(map, requested_at) {
+                self.rejected_inserts.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+
+            map.insert(
+                block_id.clone(),
+                CachedState {
+                    state,
+                    last_access: AtomicU64::new(self.tick()),
+                },
+            );
+        }
+    }
+
+    /// Removes the least recently used state to make room for the state
+    /// requested at `requested_at`. Returns whether the new state can be inserted
+    fn evict_tail(&self, map: &ShardStatesMap, requested_at: u64) -> bool {
+        let tail = map
+            .iter()
+            .map(|item| {
+                let last_access = item.value().last_access.load(Ordering::Acquire);
+                (last_access, item.key().clone())
+            })
+            .min_by_key(|(last_access, _)| *last_access);
+
+        let (last_access, block_id) = match tail {
+            Some(tail) => tail,
+            None => return true,
+        };
+
+        if self.insert_policy == ShardStateCacheInsertPolicy::InsertIfHotterThanTail
+            && last_access > requested_at
+        {
+            return false;
+        }
+
+        // NOTE: the tail is kept if it was accessed concurrently
+        let removed = map.remove_if(&block_id, |_, cached| {
+            cached.last_access.load(Ordering::Acquire) == last_access
+        });
+        if removed.is_some() {
+            self.capacity_evictions.fetch_add(1, Ordering::Relaxed);
+            if let Some(on_evict) = &self.on_evict {
+                on_evict(&block_id);
+            }
+        }
+        true
+    }
+
+    fn touch(&self, cached: &CachedState) -> Arc<ShardStateStuff> {
+        cached.last_access.store(self.tick(), Ordering::Release);
+        cached.state.clone()
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn dedup(
+        &self,
+        roots: &FastDashMap<UInt256, Weak<ShardStateStuff>>,
+        block_id: &ton_block::BlockIdExt,
+        state: Arc<ShardStateStuff>,
+    ) -> Arc<ShardStateStuff> {
+        let mut entry = roots.entry(state.root_cell().repr_hash()).or_default();
+        match entry.upgrade() {
+            Some(existing) if !Arc::ptr_eq(&existing, &state) => {
+                self.dedup_count.fetch_add(1, Ordering::Relaxed);
+                // NOTE: the shared root can belong to another block
+                if existing.block_id() == block_id {
+                    existing
+                } else {
+                    Arc::new(existing.with_block_id(block_id.clone()))
+                }
+            }
+            Some(_) => state,
+            None => {
+                *entry = Arc::downgrade(&state);
+                state
+            }
         }
     }
 
     /// Removes all outdated elements from the cache before the top blocks
     pub fn remove(&self, top_blocks: &TopBlocks) {
         if let Some(map) = &self.map {
-            map.retain(|key, _| top_blocks.contains(key));
+            let evicted = self.evict(map, |key| !top_blocks.contains(key));
+            self.outdated_evictions
+                .fetch_add(evicted, Ordering::Relaxed);
+        }
+        if let Some(roots) = &self.roots {
+            roots.retain(|_, state| state.strong_count() > 0);
         }
     }
 
     /// Removes all elements from the cache
     pub fn clear(&self) {
+        self.clear_impl(&self.cleared_evictions);
+    }
+
+    /// Removes all elements from the cache on the periodic TTL cleanup.
+    ///
+    /// NOTE: removed elements are counted as expired
+    pub fn clear_expired(&self) {
+        self.clear_impl(&self.ttl_evictions);
+    }
+
+    fn clear_impl(&self, counter: &AtomicU64) {
         if let Some(map) = &self.map {
-            map.clear();
+            let evicted = self.evict(map, |_| true);
+            counter.fetch_add(evicted, Ordering::Relaxed);
+        }
+        if let Some(roots) = &self.roots {
+            roots.clear();
         }
     }
 
+    /// Removes matching elements and returns their number
+    fn evict<F>(&self, map: &ShardStatesMap, mut f: F) -> u64
+    where
+        F: FnMut(&ton_block::BlockIdExt) -> bool,
+    {
+        let mut count = 0;
+        let mut evicted = Vec::new();
+        map.retain(|key, _| {
+            if !f(key) {
+                return true;
+            }
+            count += 1;
+            if self.on_evict.is_some() {
+                evicted.push(key.clone());
+            }
+            false
+        });
+
+        if let Some(on_evict) = &self.on_evict {
+            for block_id in &evicted {
+                on_evict(block_id);
+            }
+        }
+        count
+    }
+
+    /// Returns the number of evicted elements by reason
+    pub fn eviction_stats(&self) -> ShardStateCacheEvictions {
+        ShardStateCacheEvictions {
+            ttl: self.ttl_evictions.load(Ordering::Relaxed),
+            outdated: self.outdated_evictions.load(Ordering::Relaxed),
+            capacity: self.capacity_evictions.load(Ordering::Relaxed),
+            cleared: self.cleared_evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the number of `set` calls which didn't insert the state into the full cache
+    pub fn rejected_inserts(&self) -> u64 {
+        self.rejected_inserts.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of `set` calls which reused an already cached state
+    pub fn dedup_count(&self) -> u64 {
+        self.dedup_count.load(Ordering::Relaxed)
+    }
+
     /// Returns true if the cache is disabled or there are no non-expired entries in the cache
     pub fn is_empty(&self) -> bool {
         if let Some(map) = &self.map {
@@ -94,4 +328,144 @@ impl ShardStateCache {
     }
 }
 
-type ShardStatesMap = FastDashMap<ton_block::BlockIdExt, Arc<ShardStateStuff>>;
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ShardStateCacheEvictions {
+    /// Elements removed by the periodic TTL cleanup
+    pub ttl: u64,
+    /// Elements removed after the states GC
+    pub outdated: u64,
+    /// Elements removed from the full cache to insert new ones
+    pub capacity: u64,
+    /// Elements removed by explicit clears
+    pub cleared: u64,
+}
+
+type EvictionCallback = Box<dyn Fn(&ton_block::BlockIdExt) + Send + Sync>;
+
+struct CachedState {
+    state: Arc<ShardStateStuff>,
+    last_access: AtomicU64,
+}
+
+type ShardStatesMap = FastDashMap<ton_block::BlockIdExt, CachedState>;
+
+#[cfg(test)]
+mod tests {
+    use ton_block::Serializable;
+
+    use super::*;
+    use crate::utils::shard_state::MinRefMcState;
+
+    fn make_cache(capacity: usize, insert_policy: ShardStateCacheInsertPolicy) -> ShardStateCache {
+        ShardStateCache::new(Some(ShardStateCacheOptions {
+            capacity: Some(capacity),
+            insert_policy,
+            ..Default::default()
+        }))
+    }
+
+    fn make_state(
+        index: u8,
+        min_ref_mc_state: &Arc<MinRefMcState>,
+    ) -> (ton_block::BlockIdExt, Arc<ShardStateStuff>) {
+        let shard = ton_block::ShardIdent::full(0);
+        let root = ton_block::ShardStateUnsplit::with_ident(shard)
+            .serialize()
+            .unwrap();
+        let block_id = ton_block::BlockIdExt {
+            shard_id: shard,
+            seq_no: 0,
+            root_hash: UInt256::from([index; 32]),
+            file_hash: Default::default(),
+        };
+        let state = ShardStateStuff::new(block_id.clone(), root, min_ref_mc_state).unwrap();
+        (block_id, Arc::new(state))
+    }
+
+    #[test]
+    fn insert_policies() {
+        let min_ref_mc_state = MinRefMcState::new();
+        let (a, state_a) = make_state(1, &min_ref_mc_state);
+        let (b, state_b) = make_state(2, &min_ref_mc_state);
+        let (c, state_c) = make_state(3, &min_ref_mc_state);
+
+        // The least recently used state is evicted
+        let cache = make_cache(2, ShardStateCacheInsertPolicy::AlwaysInsert);
+        cache.set(&a, || state_a.clone());
+        cache.set(&b, || state_b.clone());
+        assert!(cache.get(&a).is_some());
+        cache.set(&c, || state_c.clone());
+        assert!(cache.get(&a).is_some() && cache.get(&b).is_none() && cache.get(&c).is_some());
+        assert_eq!(cache.eviction_stats().capacity, 1);
+
+        // Updating an existing entry doesn't require room
+        cache.set(&c, || state_c.clone());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.eviction_stats().capacity, 1);
+
+        // New states are not even built
+        let cache = make_cache(2, ShardStateCacheInsertPolicy::InsertIfRoom);
+        cache.set(&a, || state_a.clone());
+        cache.set(&b, || state_b.clone());
+        cache.set(&c, || unreachable!());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.rejected_inserts(), 1);
+
+        // The tail is kept if it was accessed while the new state was built
+        let cache = make_cache(2, ShardStateCacheInsertPolicy::InsertIfHotterThanTail);
+        cache.set(&a, || state_a.clone());
+        cache.set(&b, || state_b.clone());
+        cache.set(&c, || {
+            cache.get(&a).unwrap();
+            cache.get(&b).unwrap();
+            state_c.clone()
+        });
+        assert!(cache.get(&c).is_none());
+        assert_eq!(cache.rejected_inserts(), 1);
+
+        cache.set(&c, || state_c.clone());
+        assert!(cache.get(&a).is_none() && cache.get(&b).is_some() && cache.get(&c).is_some());
+        assert_eq!(cache.eviction_stats().capacity, 1);
+    }
+
+    #[test]
+    fn clears_are_counted_separately() {
+        let min_ref_mc_state = MinRefMcState::new();
+        let (a, state_a) = make_state(1, &min_ref_mc_state);
+        let (b, state_b) = make_state(2, &min_ref_mc_state);
+
+        let evicted = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let cache = make_cache(2, ShardStateCacheInsertPolicy::AlwaysInsert).with_on_evict({
+            let evicted = evicted.clone();
+            move |block_id| evicted.lock().push(block_id.clone())
+        });
+
+        cache.set(&a, || state_a.clone());
+        cache.clear_expired();
+        cache.set(&b, || state_b.clone());
+        cache.clear();
+
+        let stats = cache.eviction_stats();
+        assert_eq!((stats.ttl, stats.cleared), (1, 1));
+        assert_eq!(*evicted.lock(), [a, b]);
+    }
+
+    #[test]
+    fn dedup_keeps_block_ids() {
+        let min_ref_mc_state = MinRefMcState::new();
+        // NOTE: all test states have the same root
+        let (a, state_a) = make_state(1, &min_ref_mc_state);
+        let (b, state_b) = make_state(2, &min_ref_mc_state);
+
+        let cache = ShardStateCache::new(Some(ShardStateCacheOptions {
+            dedup_by_root: true,
+            ..Default::default()
+        }));
+        cache.set(&a, || state_a.clone());
+        cache.set(&b, || state_b.clone());
+        assert_eq!(cache.dedup_count(), 1);
+
+        assert_eq!(cache.get(&a).unwrap().block_id(), &a);
+        assert_eq!(cache.get(&b).unwrap().block_id(), &b);
+    }
+}
@@ -140,8 +140,58 @@ impl StoredValue for TopBlocks {
 
 #[cfg(test)]
 mod tests {
+    use ton_block::Serializable;
+    use ton_types::UInt256;
+
     use super::*;
 
+    fn make_mc_block(seq_no: u32, shards: ton_block::ShardHashes) -> Result<BlockStuff> {
+        let mut mc_extra = ton_block::McBlockExtra::default();
+        *mc_extra.hashes_mut() = shards;
+        let mut extra = ton_block::BlockExtra::default();
+        extra.write_custom(Some(&mc_extra))?;
+        let mut block = ton_block::Block::default();
+        block.write_extra(&extra)?;
+
+        let root = block.serialize()?;
+        let data = ton_types::serialize_toc(&root)?;
+        let id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no,
+            root_hash: root.repr_hash(),
+            file_hash: UInt256::calc_file_hash(&data),
+        };
+        BlockStuff::deserialize_checked(id, &data)
+    }
+
+    #[test]
+    fn from_multi_shard_mc_block() -> Result<()> {
+        let descr = |seq_no| ton_block::ShardDescr {
+            seq_no,
+            ..Default::default()
+        };
+
+        let mut basechain = ton_block::BinTree::with_item(&descr(0))?;
+        basechain.split(Default::default(), |_| Ok((descr(1000), descr(1001))))?;
+        let mut shards = ton_block::ShardHashes::default();
+        shards.set(&0i32, &ton_block::InRefValue(basechain))?;
+
+        let top_blocks = TopBlocks::from_mc_block(&make_mc_block(100, shards)?)?;
+
+        let (left_shard, right_shard) = ton_block::ShardIdent::full(0).split()?;
+        assert_eq!(top_blocks.seqno(), 100);
+        assert_eq!(top_blocks.len(), 3);
+        assert_eq!(top_blocks.shard_heights.get(&left_shard), Some(&1000));
+        assert_eq!(top_blocks.shard_heights.get(&right_shard), Some(&1001));
+
+        assert!(!top_blocks.contains_shard_seq_no(&ton_block::ShardIdent::masterchain(), 99));
+        assert!(top_blocks.contains_shard_seq_no(&ton_block::ShardIdent::masterchain(), 100));
+        assert!(!top_blocks.contains_shard_seq_no(&right_shard, 1000));
+        assert!(top_blocks.contains_shard_seq_no(&right_shard, 1001));
+
+        Ok(())
+    }
+
     #[test]
     fn test_split_shards() {
         let mut shard_heights = FastHashMap::default();